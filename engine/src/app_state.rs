@@ -1,32 +1,128 @@
+use std::time::Instant;
+
 use ash::prelude::VkResult;
 use gpu::Gpu;
+use winit::{
+    dpi::PhysicalSize,
+    window::{CursorGrabMode, Fullscreen, Window},
+};
 
-use crate::Time;
+use crate::{FrameStage, FrameStats, KeyboardState, Time};
 
 pub struct AppState {
     pub gpu: Gpu,
     pub time: Time,
+    pub keyboard: KeyboardState,
+
+    current_frame_stats: FrameStats,
+    frame_stats: FrameStats,
+    frame_start: Instant,
+    last_frame_end: Instant,
+    window_minimized: bool,
 }
 impl AppState {
     pub fn new(gpu: Gpu) -> Self {
         Self {
             gpu,
             time: Time::new(),
+            keyboard: KeyboardState::new(),
+            current_frame_stats: FrameStats::default(),
+            frame_stats: FrameStats::default(),
+            frame_start: Instant::now(),
+            last_frame_end: Instant::now(),
+            window_minimized: false,
         }
     }
 
     pub fn begin_frame(&mut self) -> VkResult<()> {
         self.time.begin_frame();
+        self.frame_start = Instant::now();
+        self.current_frame_stats = FrameStats::default();
+        self.record_frame_stage(FrameStage::Input, self.last_frame_end);
         Ok(())
     }
 
     pub fn end_frame(&mut self) -> VkResult<()> {
+        let present_start = Instant::now();
         self.gpu.present()?;
+        self.record_frame_stage(FrameStage::Present, present_start);
         self.time.end_frame();
+
+        let stats = &mut self.current_frame_stats;
+        stats.record = (stats.record - stats.acquire - stats.submit).max(0.0);
+        stats.total = stats.input + self.frame_start.elapsed().as_secs_f32();
+        self.frame_stats = self.current_frame_stats;
+        self.last_frame_end = Instant::now();
         Ok(())
     }
 
+    // Adds the time elapsed since start to the stage of the current frame
+    pub fn record_frame_stage(&mut self, stage: FrameStage, start: Instant) {
+        self.current_frame_stats
+            .add(stage, start.elapsed().as_secs_f32());
+    }
+
+    // The stats of the last completed frame
+    pub fn frame_stats(&self) -> &FrameStats {
+        &self.frame_stats
+    }
+
     pub fn time(&self) -> &Time {
         &self.time
     }
+
+    pub fn keyboard(&self) -> &KeyboardState {
+        &self.keyboard
+    }
+
+    pub fn window(&self) -> &Window {
+        &self.gpu.swapchain().window
+    }
+
+    /* A minimized window has no area, and nothing can be presented to it:
+     * no frame must be begun until the window is restored */
+    pub fn is_window_minimized(&self) -> bool {
+        self.window_minimized
+    }
+
+    // Called by the event loop when the window's size changes
+    pub fn set_window_minimized(&mut self, minimized: bool) {
+        if self.window_minimized && !minimized {
+            self.time.resume();
+            self.last_frame_end = Instant::now();
+        }
+        self.window_minimized = minimized;
+    }
+
+    // The swapchain is recreated when the window reports the new size
+    pub fn set_window_size(&self, width: u32, height: u32) {
+        self.window().set_inner_size(PhysicalSize { width, height });
+    }
+
+    pub fn set_fullscreen(&self, fullscreen: bool) {
+        self.window().set_fullscreen(if fullscreen {
+            Some(Fullscreen::Borderless(None))
+        } else {
+            None
+        });
+    }
+
+    pub fn is_fullscreen(&self) -> bool {
+        self.window().fullscreen().is_some()
+    }
+
+    // Grabbing the cursor also hides it.
+    // Not all platforms support confining the cursor, in that case it gets locked
+    pub fn set_cursor_grab(&self, grab: bool) -> anyhow::Result<()> {
+        let window = self.window();
+        if grab {
+            window
+                .set_cursor_grab(CursorGrabMode::Confined)
+                .or_else(|_| window.set_cursor_grab(CursorGrabMode::Locked))?;
+        } else {
+            window.set_cursor_grab(CursorGrabMode::None)?;
+        }
+        window.set_cursor_visible(!grab);
+        Ok(())
+    }
 }
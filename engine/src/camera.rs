@@ -16,6 +16,8 @@ projection: nalgebra::Matrix4::new_perspective(
 pub struct Camera {
     pub location: Point3<f32>,
     pub forward: Vector3<f32>,
+    // The up direction of the view, it doesn't need to be orthogonal to forward
+    pub up: Vector3<f32>,
     pub fov: f32,
     pub width: f32,
     pub height: f32,
@@ -28,6 +30,7 @@ impl Default for Camera {
         Self {
             location: Default::default(),
             forward: vector![0.0, 1.0, 0.0],
+            up: vector![0.0, 1.0, 0.0],
             fov: 45.0,
             width: 1240.0,
             height: 720.0,
@@ -38,14 +41,93 @@ impl Default for Camera {
 }
 
 impl Camera {
-    pub fn view(&self) -> Matrix4<f32> {
+    pub fn view_matrix(&self) -> Matrix4<f32> {
+        let forward = self.forward.normalize();
         Matrix4::look_at_rh(
             &self.location,
-            &(self.location + self.forward),
-            &vector![0.0, 1.0, 0.0],
+            &(self.location + forward),
+            &self.view_up(&forward),
         )
     }
+
+    /* look_at_rh can't handle a forward parallel to the up vector: in that case the
+     * axis least aligned with forward is used as the up vector instead */
+    fn view_up(&self, forward: &Vector3<f32>) -> Vector3<f32> {
+        if forward.cross(&self.up).norm_squared() > 1e-6 * self.up.norm_squared() {
+            return self.up;
+        }
+        [Vector3::x(), Vector3::y(), Vector3::z()]
+            .into_iter()
+            .min_by(|a, b| forward.dot(a).abs().total_cmp(&forward.dot(b).abs()))
+            .unwrap()
+    }
+
+    // The projection for the camera's own width / height
     pub fn projection(&self) -> Matrix4<f32> {
-        Matrix4::new_perspective(self.width / self.height, self.fov, self.near, self.far)
+        self.projection_matrix(self.width / self.height)
+    }
+
+    // The perspective projection for a viewport whose width / height ratio is aspect
+    pub fn projection_matrix(&self, aspect: f32) -> Matrix4<f32> {
+        Matrix4::new_perspective(aspect, self.fov, self.near, self.far)
+    }
+
+    /* Maps world space points to clip space, e.g for picking or frustum culling.
+     * The renderer additionally flips the x axis, see MATRIX_COORDINATE_X_FLIP */
+    pub fn view_projection(&self, aspect: f32) -> Matrix4<f32> {
+        self.projection_matrix(aspect) * self.view_matrix()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{point, vector, Point3};
+
+    use super::Camera;
+
+    #[test]
+    fn the_view_looks_down_negative_z() {
+        let camera = Camera {
+            location: point![1.0, 2.0, 3.0],
+            forward: vector![2.0, 0.0, 0.0],
+            ..Default::default()
+        };
+        let view = camera.view_matrix();
+        let eye = view.transform_point(&camera.location);
+        assert!(eye.coords.norm() < 1e-5);
+        let ahead = view.transform_point(&point![2.0, 2.0, 3.0]);
+        assert!((ahead - point![0.0, 0.0, -1.0]).norm() < 1e-5);
+        let above = view.transform_point(&point![1.0, 3.0, 3.0]);
+        assert!((above - point![0.0, 1.0, 0.0]).norm() < 1e-5);
+    }
+
+    #[test]
+    fn looking_along_the_up_vector_is_well_defined() {
+        let camera = Camera {
+            forward: vector![0.0, -1.0, 0.0],
+            ..Default::default()
+        };
+        let view = camera.view_matrix();
+        assert!(view.iter().all(|v| v.is_finite()));
+        let below = view.transform_point(&point![0.0, -1.0, 0.0]);
+        assert!((below - point![0.0, 0.0, -1.0]).norm() < 1e-5);
+        assert_eq!(view.transform_point(&Point3::origin()), Point3::origin());
+    }
+
+    #[test]
+    fn view_projection_centers_the_forward_direction() {
+        let camera = Camera {
+            location: point![0.0, 1.0, 0.0],
+            forward: vector![0.0, 0.0, 1.0],
+            ..Default::default()
+        };
+        let clip = camera.view_projection(2.0) * point![0.0, 1.0, 5.0].to_homogeneous();
+        assert!(clip.x.abs() < 1e-5 && clip.y.abs() < 1e-5);
+        assert!((clip.w - 5.0).abs() < 1e-5);
+
+        let wide = camera.projection_matrix(2.0);
+        let narrow = camera.projection_matrix(1.0);
+        assert!((narrow[(0, 0)] - wide[(0, 0)] * 2.0).abs() < 1e-5);
+        assert_eq!(wide[(1, 1)], narrow[(1, 1)]);
     }
 }
@@ -0,0 +1,47 @@
+use std::collections::HashSet;
+
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode};
+
+/* Tracks which keys are currently held down, fed with the window's keyboard events */
+#[derive(Default)]
+pub struct KeyboardState {
+    held_keys: HashSet<VirtualKeyCode>,
+}
+
+impl KeyboardState {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, input: &KeyboardInput) {
+        if let Some(key) = input.virtual_keycode {
+            match input.state {
+                ElementState::Pressed => {
+                    self.held_keys.insert(key);
+                }
+                ElementState::Released => {
+                    self.held_keys.remove(&key);
+                }
+            }
+        }
+    }
+
+    // Should be called when the window loses focus, since the key releases won't be received
+    pub fn release_all(&mut self) {
+        self.held_keys.clear();
+    }
+
+    pub fn is_pressed(&self, key: VirtualKeyCode) -> bool {
+        self.held_keys.contains(&key)
+    }
+
+    pub fn held_keys(&self) -> impl Iterator<Item = &VirtualKeyCode> {
+        self.held_keys.iter()
+    }
+
+    // Returns 1.0 if only positive is held, -1.0 if only negative is held, 0.0 otherwise
+    pub fn axis(&self, positive: VirtualKeyCode, negative: VirtualKeyCode) -> f32 {
+        let value = |key| if self.is_pressed(key) { 1.0 } else { 0.0 };
+        value(positive) - value(negative)
+    }
+}
@@ -1,11 +1,17 @@
 mod app_state;
 mod camera;
 mod gpu_pipeline;
+mod input;
 mod material;
 mod mesh;
+mod particles;
+mod planar_reflection;
+mod reflection;
 mod render_graph;
 mod scene;
+mod shadows;
 mod static_deferred_renderer;
+mod text;
 mod texture;
 mod time;
 mod utils;
@@ -18,11 +24,16 @@ use once_cell::unsync::OnceCell;
 pub use app_state::*;
 pub use camera::*;
 pub use gpu_pipeline::*;
+pub use input::*;
 pub use material::*;
 pub use mesh::*;
+pub use particles::*;
+pub use planar_reflection::*;
+pub use reflection::*;
 pub use render_graph::*;
 pub use scene::*;
 pub use static_deferred_renderer::*;
+pub use text::*;
 pub use texture::*;
 pub use time::*;
 pub use utils::constants::*;
@@ -62,6 +73,7 @@ pub fn init(app_name: &str, window: winit::window::Window) -> anyhow::Result<()>
             enable_debug_utilities,
             window,
             pipeline_cache_path: Some("pipeline_cache.pso"),
+            frames_in_flight: Gpu::DEFAULT_FRAMES_IN_FLIGHT,
         })?;
 
         let app_state = AppState::new(gpu);
@@ -1,15 +1,19 @@
 use std::{collections::HashMap, hash::Hash, mem::size_of, num::NonZeroU32};
 
+use anyhow::Context;
 use ash::vk::{self, CompareOp, PushConstantRange};
 use gpu::{
     BindingElement, BindingType, CullMode, DepthStencilState, FragmentStageInfo, FrontFace,
-    GlobalBinding, Gpu, LogicOp, Pipeline, PipelineDescription, PolygonMode, 
-    VertexAttributeDescription, VertexBindingDescription, VertexStageInfo,
+    GlobalBinding, Gpu, ImageFormat, LogicOp, Pipeline, PipelineDescription, PolygonMode,
+    PrimitiveTopology, SampleCount, StencilState, ToVk, VertexAttributeDescription, VertexBindingDescription, VertexStageInfo,
 };
 use nalgebra::{Vector2, Vector3};
 use resource_map::Resource;
 
-use crate::{MaterialDomain, MaterialParameterOffsetSize, PipelineTarget, TextureInput};
+use crate::{
+    reflect_shader, MaterialDomain, MaterialParameterOffsetSize, PipelineTarget, TextureInput,
+    TextureInputArray, VertexEncoding,
+};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ScalarType {
@@ -28,8 +32,20 @@ pub struct MaterialParameter {
 pub struct MasterMaterialDescription<'a> {
     pub name: &'a str,
     pub domain: MaterialDomain,
+    pub topology: PrimitiveTopology,
+    pub vertex_encoding: VertexEncoding,
+    // See MaterialDescription::vertex_input
+    pub vertex_input: Option<VertexBindingDescription<'static>>,
+    pub stencil_state: Option<StencilState>,
+    // The format of the renderer's depth attachments, the pipelines are built for it
+    pub depth_format: ImageFormat,
+    /* The sample counts the renderer may draw the surfaces with, besides a single sample:
+     * sample counts are baked in the pipelines, so they're all built upfront */
+    pub sample_counts: &'a [SampleCount],
+    // The per frame inputs bound by the renderer at GLOBAL_SET_INDEX
     pub global_inputs: &'a [BindingType],
     pub texture_inputs: &'a [TextureInput],
+    pub texture_array_inputs: &'a [TextureInputArray],
     pub material_parameters: HashMap<String, MaterialParameterOffsetSize>,
     pub vertex_info: &'a VertexStageInfo<'a>,
     pub fragment_info: &'a FragmentStageInfo<'a>,
@@ -39,15 +55,22 @@ pub struct MasterMaterialDescription<'a> {
     pub front_face: FrontFace,
     pub logic_op: Option<LogicOp>,
     pub push_constant_ranges: &'a [PushConstantRange],
+    pub conservative_raster: bool,
 }
 
 #[derive(Eq, PartialEq)]
 pub struct MasterMaterial {
     pub(crate) name: String,
-    pub(crate) pipelines: HashMap<PipelineTarget, Pipeline>,
+    pub(crate) pipelines: HashMap<(PipelineTarget, SampleCount), Pipeline>,
+    pub(crate) topology: PrimitiveTopology,
+    pub(crate) vertex_encoding: VertexEncoding,
+    pub(crate) vertex_input: Option<VertexBindingDescription<'static>>,
     pub(crate) texture_inputs: Vec<TextureInput>,
+    pub(crate) texture_array_inputs: Vec<TextureInputArray>,
     pub(crate) material_parameters: HashMap<String, MaterialParameterOffsetSize>,
     pub(crate) parameter_block_size: usize,
+    // The stages of each binding of the user set, as declared by the shaders
+    user_binding_stages: Vec<(u32, gpu::ShaderStage)>,
 }
 
 impl Hash for MasterMaterial {
@@ -63,25 +86,146 @@ impl Resource for MasterMaterial {
 }
 
 impl MasterMaterial {
+    /* The renderer binds the per frame data (camera, lights, instances) at set 0 once per pass,
+     * while each material instance binds its textures and parameters at set 1 */
+    pub const GLOBAL_SET_INDEX: u32 = 0;
+    pub const USER_SET_INDEX: u32 = 1;
+
     pub fn new(
         gpu: &Gpu,
         description: &MasterMaterialDescription,
     ) -> anyhow::Result<Self> {
-        let pipelines = Self::create_pipelines(gpu, description)?;
-        let parameter_block_size = size_of::<f32>() * 4 * description.material_parameters.len();
+        anyhow::ensure!(
+            !description.conservative_raster || gpu.supports_conservative_rasterization(),
+            "Material '{}' uses conservative rasterization, but the device does not support {}",
+            description.name,
+            "VK_EXT_conservative_rasterization"
+        );
+        Self::validate_depth_format(
+            description.name,
+            description.depth_format,
+            description.stencil_state.as_ref(),
+        )?;
+        let user_elements = Self::user_binding_elements(description)?;
+        let user_binding_stages = user_elements
+            .iter()
+            .map(|element| (element.index, element.stage))
+            .collect();
+        let pipelines = Self::create_pipelines(gpu, description, user_elements)?;
+        // The block ends after its last parameter, std140 rounds its size up to a vec4
+        let parameter_block_size = description
+            .material_parameters
+            .values()
+            .map(|parameter| parameter.offset + parameter.size)
+            .max()
+            .unwrap_or(0)
+            .next_multiple_of(size_of::<f32>() * 4);
         Ok(MasterMaterial {
             name: description.name.to_owned(),
             pipelines,
+            topology: description.topology,
+            vertex_encoding: description.vertex_encoding,
+            vertex_input: description.vertex_input,
             texture_inputs: description.texture_inputs.to_vec(),
+            texture_array_inputs: description.texture_array_inputs.to_vec(),
             material_parameters: description.material_parameters.clone(),
             parameter_block_size,
+            user_binding_stages,
         })
     }
 
+    // The stages reading a binding of the user set, which the instances' descriptor sets must match
+    pub(crate) fn user_binding_stage(&self, binding: u32) -> gpu::ShaderStage {
+        self.user_binding_stages
+            .iter()
+            .find(|(index, _)| *index == binding)
+            .map_or(gpu::ShaderStage::Fragment, |(_, stage)| *stage)
+    }
+
+    // The materials with a stencil state test the stencil aspect of the depth attachments
+    pub(crate) fn validate_depth_format(
+        name: &str,
+        depth_format: ImageFormat,
+        stencil_state: Option<&StencilState>,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            depth_format.is_depth(),
+            "Material '{name}' renders to {depth_format:?}, which is not a depth format"
+        );
+        anyhow::ensure!(
+            stencil_state.is_none() || depth_format.has_stencil(),
+            "Material '{name}' has a stencil state, but {depth_format:?} has no stencil aspect"
+        );
+        Ok(())
+    }
+
+    // The stages are filled in by validate_user_bindings
+    fn user_binding_elements(
+        description: &MasterMaterialDescription<'_>,
+    ) -> anyhow::Result<Vec<BindingElement>> {
+        let mut user_elements: Vec<_> = TextureInput::bindings(description.texture_inputs)
+            .map(|binding| BindingElement {
+                binding_type: BindingType::CombinedImageSampler,
+                index: binding,
+                stage: gpu::ShaderStage::empty(),
+            })
+            .collect();
+        user_elements.extend(
+            description
+                .texture_array_inputs
+                .iter()
+                .zip(TextureInputArray::bindings(
+                    description.texture_inputs,
+                    description.texture_array_inputs,
+                ))
+                .map(|(array, binding)| BindingElement {
+                    binding_type: BindingType::CombinedImageSamplerArray { count: array.count },
+                    index: binding,
+                    stage: gpu::ShaderStage::empty(),
+                }),
+        );
+        // Bound at the instance's offset, see MaterialInstance::dynamic_offset
+        if !description.material_parameters.is_empty() {
+            user_elements.push(BindingElement {
+                binding_type: BindingType::UniformDynamic,
+                index: TextureInput::parameter_block_binding(
+                    description.texture_inputs,
+                    description.texture_array_inputs,
+                ),
+                stage: gpu::ShaderStage::empty(),
+            })
+        }
+        for (i, element) in user_elements.iter().enumerate() {
+            anyhow::ensure!(
+                user_elements[..i]
+                    .iter()
+                    .all(|other| other.index != element.index),
+                "Material '{}': binding {} is used by more than one texture input or array",
+                description.name,
+                element.index
+            );
+        }
+        if let Some(array) = description
+            .texture_array_inputs
+            .iter()
+            .find(|array| array.count == 0)
+        {
+            anyhow::bail!(
+                "Material '{}': texture array '{}' must hold at least one texture",
+                description.name,
+                array.name
+            );
+        }
+        Self::validate_user_bindings(description, &mut user_elements)?;
+        Ok(user_elements)
+    }
+
     fn create_pipelines(
         gpu: &Gpu,
         description: &MasterMaterialDescription<'_>,
-    ) -> anyhow::Result<HashMap<PipelineTarget, Pipeline>> {
+        user_elements: Vec<BindingElement>,
+    ) -> anyhow::Result<HashMap<(PipelineTarget, SampleCount), Pipeline>> {
+        // The global set is allocated by the render graph, which exposes the pass reads to both stages
         let global_elements: Vec<_> = description
             .global_inputs
             .iter()
@@ -92,24 +236,6 @@ impl MasterMaterial {
                 stage: gpu::ShaderStage::VertexFragment,
             })
             .collect();
-        let mut user_elements: Vec<_> = description
-            .texture_inputs
-            .iter()
-            .enumerate()
-            .map(|(i, _)| BindingElement {
-                binding_type: BindingType::CombinedImageSampler,
-                index: i as _,
-                stage: gpu::ShaderStage::VertexFragment,
-            })
-            .collect();
-        if !description.material_parameters.is_empty() {
-            user_elements.push(BindingElement {
-                binding_type: BindingType::Uniform,
-                index: user_elements.len() as _,
-                stage: gpu::ShaderStage::VertexFragment,
-            })
-        }
-
         match description.domain {
             MaterialDomain::Surface => Self::create_surface_pipelines(
                 gpu,
@@ -126,6 +252,142 @@ impl MasterMaterial {
         }
     }
 
+    /* The user set is laid out from the texture inputs and arrays, followed by the parameter block:
+     * the shaders must declare the same bindings, or they'd silently read the wrong resources.
+     * Each element is made visible to the stages declaring it, the unused ones to the fragment stage */
+    fn validate_user_bindings(
+        description: &MasterMaterialDescription<'_>,
+        user_elements: &mut [BindingElement],
+    ) -> anyhow::Result<()> {
+        let name = description.name;
+        let named_bindings: Vec<(&str, u32)> = description
+            .texture_inputs
+            .iter()
+            .map(|input| input.name.as_str())
+            .zip(TextureInput::bindings(description.texture_inputs))
+            .chain(
+                description
+                    .texture_array_inputs
+                    .iter()
+                    .map(|array| array.name.as_str())
+                    .zip(TextureInputArray::bindings(
+                        description.texture_inputs,
+                        description.texture_array_inputs,
+                    )),
+            )
+            .collect();
+        for (stage, module) in [
+            ("vertex", description.vertex_info.module),
+            ("fragment", description.fragment_info.module),
+        ] {
+            let reflection = reflect_shader(module.code()).with_context(|| {
+                format!("Failed to reflect the {stage} shader of material '{name}'")
+            })?;
+            for reflected in reflection.bindings_in_set(Self::USER_SET_INDEX) {
+                let binding = reflected.binding;
+                let element = user_elements
+                    .iter_mut()
+                    .find(|element| element.index == binding)
+                    .with_context(|| {
+                        format!(
+                            "Material '{name}': the {stage} shader reads a {:?} at binding {binding}, \
+                            but the material binds nothing there",
+                            reflected.ty
+                        )
+                    })?;
+                anyhow::ensure!(
+                    reflected.ty.matches(element.binding_type),
+                    "Material '{name}': the {stage} shader expects a {:?} at binding {binding}, \
+                    but the material binds a {:?}",
+                    reflected.ty,
+                    element.binding_type
+                );
+                let count = match element.binding_type {
+                    BindingType::CombinedImageSamplerArray { count } => count,
+                    _ => 1,
+                };
+                anyhow::ensure!(
+                    reflected.count == count,
+                    "Material '{name}': the {stage} shader expects {} descriptors at binding {binding}, \
+                    but the material binds {count}",
+                    reflected.count
+                );
+                // Catches texture inputs listed in a different order than the shader's samplers
+                let input = reflected.name.as_ref().and_then(|reflected_name| {
+                    named_bindings
+                        .iter()
+                        .find(|(input_name, _)| input_name == reflected_name)
+                });
+                if let Some((input_name, input_binding)) = input {
+                    anyhow::ensure!(
+                        *input_binding == binding,
+                        "Material '{name}': texture input '{input_name}' is bound at binding {input_binding}, \
+                        but the {stage} shader declares it at binding {binding}"
+                    );
+                }
+                element.stage |= reflected.stage;
+            }
+        }
+        for element in user_elements.iter_mut() {
+            if element.stage.is_empty() {
+                element.stage = gpu::ShaderStage::Fragment;
+            }
+        }
+        Ok(())
+    }
+
+    // The surface attributes stored with a configurable encoding: normals, tangents and uvs
+    fn get_encoded_surface_attributes(
+        encoding: &VertexEncoding,
+    ) -> [VertexAttributeDescription; 3] {
+        [
+            VertexAttributeDescription {
+                location: 2,
+                format: encoding.normals.format(),
+                offset: 0,
+            },
+            VertexAttributeDescription {
+                location: 3,
+                format: encoding.normals.format(),
+                offset: 0,
+            },
+            VertexAttributeDescription {
+                location: 4,
+                format: encoding.uvs.format(),
+                offset: 0,
+            },
+        ]
+    }
+
+    fn get_surface_inputs<'a>(
+        encoding: &VertexEncoding,
+        encoded_attributes: &'a [VertexAttributeDescription; 3],
+    ) -> Vec<VertexBindingDescription<'a>> {
+        let mut inputs =
+            Self::get_inputs_for_material_domain(&MaterialDomain::Surface)[0..2].to_vec();
+        inputs.extend_from_slice(&[
+            VertexBindingDescription {
+                binding: 2,
+                input_rate: gpu::InputRate::PerVertex,
+                stride: encoding.normals.stride(),
+                attributes: &encoded_attributes[0..1],
+            },
+            VertexBindingDescription {
+                binding: 3,
+                input_rate: gpu::InputRate::PerVertex,
+                stride: encoding.normals.stride(),
+                attributes: &encoded_attributes[1..2],
+            },
+            VertexBindingDescription {
+                binding: 4,
+                input_rate: gpu::InputRate::PerVertex,
+                stride: encoding.uvs.stride(),
+                attributes: &encoded_attributes[2..3],
+            },
+        ]);
+        inputs
+    }
+
     fn get_inputs_for_material_domain(
         domain: &MaterialDomain,
     ) -> &'static [gpu::VertexBindingDescription<'static>] {
@@ -190,8 +452,24 @@ impl MasterMaterial {
         }
     }
 
-    pub(crate) fn get_pipeline(&self, target: PipelineTarget) -> Option<&Pipeline> {
-        self.pipelines.get(&target)
+    pub(crate) fn get_pipeline(
+        &self,
+        target: PipelineTarget,
+        samples: SampleCount,
+    ) -> Option<&Pipeline> {
+        self.pipelines.get(&(target, samples))
+    }
+
+    pub fn topology(&self) -> PrimitiveTopology {
+        self.topology
+    }
+
+    pub fn vertex_encoding(&self) -> VertexEncoding {
+        self.vertex_encoding
+    }
+
+    pub fn vertex_input(&self) -> Option<VertexBindingDescription<'static>> {
+        self.vertex_input
     }
 
     fn create_surface_pipelines(
@@ -199,23 +477,46 @@ impl MasterMaterial {
         description: &MasterMaterialDescription,
         global_elements: Vec<BindingElement>,
         user_elements: Vec<BindingElement>,
-    ) -> anyhow::Result<HashMap<PipelineTarget, Pipeline>> {
+    ) -> anyhow::Result<HashMap<(PipelineTarget, SampleCount), Pipeline>> {
         let mut pipelines = HashMap::new();
-        for target in [PipelineTarget::ColorAndDepth, PipelineTarget::DepthOnly] {
+        let encoded_attributes = Self::get_encoded_surface_attributes(&description.vertex_encoding);
+        let vertex_inputs = match description.vertex_input {
+            Some(vertex_input) => vec![vertex_input],
+            None => Self::get_surface_inputs(&description.vertex_encoding, &encoded_attributes),
+        };
+        let stencil_op_state = description
+            .stencil_state
+            .map(|s| s.to_vk())
+            .unwrap_or_default();
+        // The shadow maps are always rendered with a single sample
+        let sample_counts = std::iter::once(SampleCount::One).chain(
+            description
+                .sample_counts
+                .iter()
+                .copied()
+                .filter(|samples| *samples != SampleCount::One),
+        );
+        let targets = sample_counts.flat_map(|samples| {
+            [PipelineTarget::ColorAndDepth, PipelineTarget::DepthOnly]
+                .map(|target| (target, samples))
+        });
+        for (target, samples) in targets {
             let pipeline = Pipeline::new(
                 gpu,
                 &PipelineDescription {
                     global_bindings: &[
                         GlobalBinding {
-                            set_index: 0,
+                            set_index: Self::GLOBAL_SET_INDEX,
                             elements: &global_elements,
+                            push_descriptor: false,
                         },
                         GlobalBinding {
-                            set_index: 1,
+                            set_index: Self::USER_SET_INDEX,
                             elements: &user_elements,
+                            push_descriptor: false,
                         },
                     ],
-                    vertex_inputs: Self::get_inputs_for_material_domain(&description.domain),
+                    vertex_inputs: &vertex_inputs,
                     vertex_stage: Some(*description.vertex_info),
                     fragment_stage: match target {
                         PipelineTarget::ColorAndDepth | PipelineTarget::PostProcess => {
@@ -223,7 +524,7 @@ impl MasterMaterial {
                         }
                         PipelineTarget::DepthOnly => None,
                     },
-                    input_topology: gpu::PrimitiveTopology::TriangleList,
+                    input_topology: description.topology,
                     primitive_restart: description.primitive_restart,
                     polygon_mode: description.polygon_mode,
                     cull_mode: description.cull_mode,
@@ -234,9 +535,9 @@ impl MasterMaterial {
                                 depth_test_enable: true,
                                 depth_write_enable: false,
                                 depth_compare_op: CompareOp::EQUAL,
-                                stencil_test_enable: false,
-                                front: vk::StencilOpState::default(),
-                                back: vk::StencilOpState::default(),
+                                stencil_test_enable: description.stencil_state.is_some(),
+                                front: stencil_op_state,
+                                back: stencil_op_state,
                                 min_depth_bounds: 0.0,
                                 max_depth_bounds: 1.0,
                             }
@@ -245,18 +546,21 @@ impl MasterMaterial {
                             depth_test_enable: true,
                             depth_write_enable: true,
                             depth_compare_op: CompareOp::LESS,
-                            stencil_test_enable: false,
-                            front: vk::StencilOpState::default(),
-                            back: vk::StencilOpState::default(),
+                            stencil_test_enable: description.stencil_state.is_some(),
+                            front: stencil_op_state,
+                            back: stencil_op_state,
                             min_depth_bounds: 0.0,
                             max_depth_bounds: 1.0,
                         },
                     },
+                    depth_stencil_format: Some(description.depth_format),
                     logic_op: description.logic_op,
                     push_constant_ranges: description.push_constant_ranges,
+                    conservative_rasterization: description.conservative_raster,
+                    samples,
                 },
             )?;
-            pipelines.insert(target, pipeline);
+            pipelines.insert((target, samples), pipeline);
         }
 
         Ok(pipelines)
@@ -266,19 +570,21 @@ impl MasterMaterial {
         description: &MasterMaterialDescription,
         global_elements: Vec<BindingElement>,
         user_elements: Vec<BindingElement>,
-    ) -> anyhow::Result<HashMap<PipelineTarget, Pipeline>> {
+    ) -> anyhow::Result<HashMap<(PipelineTarget, SampleCount), Pipeline>> {
         let mut pipelines = HashMap::new();
         let pipeline = Pipeline::new(
             gpu,
             &PipelineDescription {
                 global_bindings: &[
                     GlobalBinding {
-                        set_index: 0,
+                        set_index: Self::GLOBAL_SET_INDEX,
                         elements: &global_elements,
+                        push_descriptor: false,
                     },
                     GlobalBinding {
-                        set_index: 1,
+                        set_index: Self::USER_SET_INDEX,
                         elements: &user_elements,
+                        push_descriptor: false,
                     },
                 ],
                 vertex_inputs: Self::get_inputs_for_material_domain(&description.domain),
@@ -299,11 +605,14 @@ impl MasterMaterial {
                     min_depth_bounds: 0.0,
                     max_depth_bounds: 1.0,
                 },
+                depth_stencil_format: Some(description.depth_format),
                 logic_op: description.logic_op,
                 push_constant_ranges: description.push_constant_ranges,
+                conservative_rasterization: description.conservative_raster,
+                samples: SampleCount::One,
             },
         )?;
-        pipelines.insert(PipelineTarget::PostProcess, pipeline);
+        pipelines.insert((PipelineTarget::PostProcess, SampleCount::One), pipeline);
 
         Ok(pipelines)
     }
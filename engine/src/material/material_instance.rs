@@ -1,29 +1,43 @@
-use ash::vk::{self, BufferUsageFlags, ImageLayout};
+use ash::vk::BufferUsageFlags;
 use gpu::{
     BufferCreateInfo, BufferRange, DescriptorInfo, DescriptorSetInfo, DescriptorType, Gpu,
-    GpuBuffer, GpuDescriptorSet, MemoryDomain,
+    GpuBuffer, GpuDescriptorSet, MemoryDomain, SamplerState,
 };
 use resource_map::{Resource, ResourceHandle, ResourceMap};
 use std::collections::HashMap;
 
-use crate::texture::Texture;
+use crate::{texture::Texture, ReflectionPlane, TextureInput, TextureInputArray};
 
-use super::master_material::MasterMaterial;
+use super::{master_material::MasterMaterial, MaterialParameterArena};
 
 #[derive(Clone)]
 pub struct MaterialInstanceDescription<'a> {
     pub name: &'a str,
     pub texture_inputs: HashMap<String, ResourceHandle<Texture>>,
+    // The textures of each texture array, in the order they're bound
+    pub texture_array_inputs: HashMap<String, Vec<ResourceHandle<Texture>>>,
+}
+
+// Where an instance's parameter block lives
+pub(crate) enum ParameterStorage {
+    // The master material has no parameters
+    None,
+    Buffer(Box<GpuBuffer>),
+    Arena {
+        arena: ResourceHandle<MaterialParameterArena>,
+        offset: u64,
+    },
 }
 
 pub struct MaterialInstance {
     pub(crate) name: String,
     pub(crate) owner: ResourceHandle<MasterMaterial>,
-    pub(crate) parameter_buffer: Option<GpuBuffer>,
+    pub(crate) parameters: ParameterStorage,
     pub(crate) user_descriptor_set: GpuDescriptorSet,
-    #[allow(dead_code)]
     pub(crate) current_inputs: HashMap<String, ResourceHandle<Texture>>,
+    pub(crate) current_array_inputs: HashMap<String, Vec<ResourceHandle<Texture>>>,
     pub(crate) parameter_block_size: usize,
+    pub(crate) reflection_plane: Option<ReflectionPlane>,
 }
 
 impl Resource for MaterialInstance {
@@ -41,78 +55,270 @@ impl MaterialInstance {
     ) -> anyhow::Result<MaterialInstance> {
         let master_owner = resource_map.get(&owner);
 
-        let parameter_buffer = if !master_owner.material_parameters.is_empty() {
-            Some(gpu.create_buffer(
+        let parameters = if !master_owner.material_parameters.is_empty() {
+            ParameterStorage::Buffer(Box::new(gpu.create_buffer(
                 &BufferCreateInfo {
                     label: Some(&format!("{} - Parameter buffer", description.name)),
                     size: master_owner.parameter_block_size,
-                    usage: BufferUsageFlags::UNIFORM_BUFFER | BufferUsageFlags::TRANSFER_DST,
+                    // Copied from when the instance is derived
+                    usage: BufferUsageFlags::UNIFORM_BUFFER
+                        | BufferUsageFlags::TRANSFER_SRC
+                        | BufferUsageFlags::TRANSFER_DST,
+                    sharing_mode: Default::default(),
                 },
                 MemoryDomain::DeviceLocal,
-            )?)
+            )?))
+        } else {
+            ParameterStorage::None
+        };
+        Self::new_impl(gpu, owner, resource_map, description, parameters)
+    }
+
+    /* Creates an instance whose parameter block is allocated in the arena:
+     * its parameters are written with write_parameters_batched,
+     * and they're uploaded along with the other blocks when the arena is flushed */
+    pub fn create_instance_in_arena(
+        gpu: &Gpu,
+        owner: ResourceHandle<MasterMaterial>,
+        resource_map: &mut ResourceMap,
+        arena: &ResourceHandle<MaterialParameterArena>,
+        description: &MaterialInstanceDescription,
+    ) -> anyhow::Result<MaterialInstance> {
+        let master_owner = resource_map.get(&owner);
+        let parameters = if !master_owner.material_parameters.is_empty() {
+            let block_size = master_owner.parameter_block_size;
+            ParameterStorage::Arena {
+                arena: arena.clone(),
+                offset: resource_map.get_mut(arena).allocate(block_size)?,
+            }
         } else {
-            None
+            ParameterStorage::None
         };
+        Self::new_impl(gpu, owner, resource_map, description, parameters)
+    }
+
+    fn new_impl(
+        gpu: &Gpu,
+        owner: ResourceHandle<MasterMaterial>,
+        resource_map: &ResourceMap,
+        description: &MaterialInstanceDescription,
+        parameters: ParameterStorage,
+    ) -> anyhow::Result<MaterialInstance> {
+        let master_owner = resource_map.get(&owner);
         let user_descriptor_set = Self::create_user_descriptor_set(
             gpu,
             resource_map,
             master_owner,
             description,
-            &parameter_buffer,
+            &parameters,
         )?;
         Ok(MaterialInstance {
             name: description.name.to_owned(),
             owner,
-            parameter_buffer,
+            parameters,
             user_descriptor_set,
             current_inputs: description.texture_inputs.clone(),
+            current_array_inputs: description.texture_array_inputs.clone(),
             parameter_block_size: master_owner.parameter_block_size,
+            reflection_plane: None,
         })
     }
 
+    /* Creates a new instance of the same master material, bound to the same textures
+     * and mirroring the same reflection plane:
+     * the new instance starts with a copy of this instance's parameters,
+     * but it has its own parameter block, so they can be changed independently.
+     * When this instance lives in an arena, the new one is allocated in the same arena */
+    pub fn derive(
+        &self,
+        gpu: &Gpu,
+        resource_map: &mut ResourceMap,
+        new_name: &str,
+    ) -> anyhow::Result<MaterialInstance> {
+        let description = MaterialInstanceDescription {
+            name: new_name,
+            texture_inputs: self.current_inputs.clone(),
+            texture_array_inputs: self.current_array_inputs.clone(),
+        };
+        let mut derived = match &self.parameters {
+            ParameterStorage::Arena { arena, .. } => Self::create_instance_in_arena(
+                gpu,
+                self.owner.clone(),
+                resource_map,
+                arena,
+                &description,
+            )?,
+            _ => Self::create_instance(gpu, self.owner.clone(), resource_map, &description)?,
+        };
+        match (&self.parameters, &derived.parameters) {
+            (ParameterStorage::Buffer(source), ParameterStorage::Buffer(dest)) => {
+                gpu.copy_buffer(source, dest, 0, self.parameter_block_size)?;
+            }
+            (
+                ParameterStorage::Arena { arena, offset },
+                ParameterStorage::Arena {
+                    offset: dest_offset,
+                    ..
+                },
+            ) => {
+                resource_map.get(arena).copy_block(
+                    *offset,
+                    *dest_offset,
+                    self.parameter_block_size,
+                );
+            }
+            _ => {}
+        }
+        derived.reflection_plane = self.reflection_plane;
+        Ok(derived)
+    }
+
     pub fn write_parameters<T: Sized + Copy>(&self, gpu: &Gpu, block: T) -> anyhow::Result<()> {
-        assert!(
-            std::mem::size_of::<T>() <= self.parameter_block_size
-                && self.parameter_buffer.is_some()
-        );
-        gpu.write_buffer_data(self.parameter_buffer.as_ref().unwrap(), &[block])?;
+        assert!(std::mem::size_of::<T>() <= self.parameter_block_size);
+        match &self.parameters {
+            ParameterStorage::Buffer(buffer) => gpu.write_buffer_data(buffer, &[block])?,
+            ParameterStorage::Arena { .. } => anyhow::bail!(
+                "Material instance '{}' lives in a parameter arena, use write_parameters_batched",
+                self.name
+            ),
+            ParameterStorage::None => {
+                anyhow::bail!("Material instance '{}' has no parameters", self.name)
+            }
+        }
+        Ok(())
+    }
+
+    /* Writes the parameters of an instance created with create_instance_in_arena:
+     * the block is uploaded by the arena's next flush */
+    pub fn write_parameters_batched<T: bytemuck::Pod>(
+        &self,
+        resource_map: &ResourceMap,
+        block: T,
+    ) -> anyhow::Result<()> {
+        assert!(std::mem::size_of::<T>() <= self.parameter_block_size);
+        let ParameterStorage::Arena { arena, offset } = &self.parameters else {
+            anyhow::bail!(
+                "Material instance '{}' does not live in a parameter arena, use write_parameters",
+                self.name
+            );
+        };
+        resource_map
+            .get(arena)
+            .write_bytes(*offset, bytemuck::bytes_of(&block));
         Ok(())
     }
 
+    /* The dynamic offset of the parameter block, to be passed when binding the user set:
+     * the blocks living in an arena are found in the copy flushed last */
+    pub(crate) fn dynamic_offset(&self, resource_map: &ResourceMap) -> Option<u32> {
+        match &self.parameters {
+            ParameterStorage::None => None,
+            ParameterStorage::Buffer(_) => Some(0),
+            ParameterStorage::Arena { arena, offset } => {
+                Some(resource_map.get(arena).dynamic_offset(*offset))
+            }
+        }
+    }
+
+    // The arena the parameter block lives in, if any
+    pub(crate) fn parameter_arena(&self) -> Option<&ResourceHandle<MaterialParameterArena>> {
+        match &self.parameters {
+            ParameterStorage::Arena { arena, .. } => Some(arena),
+            _ => None,
+        }
+    }
+
+    // The plane mirrored by the PlanarReflection sampled by this instance, if any
+    pub fn reflection_plane(&self) -> Option<ReflectionPlane> {
+        self.reflection_plane
+    }
+    pub fn set_reflection_plane(&mut self, plane: Option<ReflectionPlane>) {
+        self.reflection_plane = plane;
+    }
+
     fn create_user_descriptor_set(
         gpu: &Gpu,
         resource_map: &ResourceMap,
         master: &MasterMaterial,
         description: &MaterialInstanceDescription<'_>,
-        param_buffer: &Option<GpuBuffer>,
+        parameters: &ParameterStorage,
     ) -> anyhow::Result<GpuDescriptorSet> {
         let mut descriptors: Vec<_> = master
             .texture_inputs
             .iter()
-            .enumerate()
-            .map(|(i, tex)| {
+            .zip(TextureInput::bindings(&master.texture_inputs))
+            .map(|(tex, binding)| {
                 let tex = resource_map.get(&description.texture_inputs[&tex.name]);
-                DescriptorInfo {
-                    binding: i as _,
-                    element_type: DescriptorType::CombinedImageSampler(gpu::SamplerState {
-                        sampler: &resource_map.get(&tex.sampler).0,
-                        image_view: &resource_map.get(&tex.image_view).view,
-                        image_layout: ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-                    }),
-                    binding_stage: gpu::ShaderStage::VertexFragment,
-                }
+                DescriptorInfo::combined_image_sampler(
+                    binding,
+                    &resource_map.get(&tex.sampler).0,
+                    &resource_map.get(&tex.image_view).view,
+                    master.user_binding_stage(binding),
+                )
             })
             .collect();
 
-        if let Some(buffer) = &param_buffer {
+        for (array, binding) in master
+            .texture_array_inputs
+            .iter()
+            .zip(TextureInputArray::bindings(
+                &master.texture_inputs,
+                &master.texture_array_inputs,
+            ))
+        {
+            let textures = description
+                .texture_array_inputs
+                .get(&array.name)
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Material instance '{}': no textures for the texture array '{}'",
+                        description.name,
+                        array.name
+                    )
+                })?;
+            anyhow::ensure!(
+                textures.len() == array.count as usize,
+                "Material instance '{}': the texture array '{}' expects {} textures, but {} were given",
+                description.name,
+                array.name,
+                array.count,
+                textures.len()
+            );
+            let elements = textures
+                .iter()
+                .map(|tex| {
+                    let tex = resource_map.get(tex);
+                    SamplerState::new(
+                        &resource_map.get(&tex.sampler).0,
+                        &resource_map.get(&tex.image_view).view,
+                    )
+                })
+                .collect();
+            descriptors.push(DescriptorInfo::combined_image_sampler_array(
+                binding,
+                elements,
+                master.user_binding_stage(binding),
+            ));
+        }
+
+        let parameter_range = match parameters {
+            ParameterStorage::None => None,
+            ParameterStorage::Buffer(buffer) => Some(BufferRange::whole(buffer)),
+            ParameterStorage::Arena { arena, .. } => Some(
+                resource_map
+                    .get(arena)
+                    .block_range(master.parameter_block_size),
+            ),
+        };
+        if let Some(range) = parameter_range {
+            let binding = TextureInput::parameter_block_binding(
+                &master.texture_inputs,
+                &master.texture_array_inputs,
+            );
             descriptors.push(DescriptorInfo {
-                binding: descriptors.len() as _,
-                binding_stage: gpu::ShaderStage::VertexFragment,
-                element_type: DescriptorType::UniformBuffer(BufferRange {
-                    handle: buffer,
-                    offset: 0,
-                    size: vk::WHOLE_SIZE,
-                }),
+                binding,
+                element_type: DescriptorType::UniformBufferDynamic(range),
+                binding_stage: master.user_binding_stage(binding),
             });
         }
 
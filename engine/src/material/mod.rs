@@ -1,13 +1,19 @@
 mod master_material;
 mod material_instance;
+mod parameter_arena;
 
 use std::collections::HashMap;
 
-use gpu::{GpuShaderModule, ImageFormat};
+use gpu::{
+    GpuShaderModule, ImageFormat, PrimitiveTopology, StencilState, VertexBindingDescription,
+};
+
+use crate::VertexEncoding;
 pub use material_instance::*;
 
 pub use master_material::*;
 pub use material_instance::*;
+pub use parameter_arena::*;
 
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
 pub enum MaterialDomain {
@@ -32,13 +38,110 @@ pub struct MaterialParameterOffsetSize {
 pub struct TextureInput {
     pub name: String,
     pub format: ImageFormat,
+    // The binding in the user set, when None the input is bound at its position among the inputs
+    pub binding: Option<u32>,
+}
+
+// A fixed size array of textures bound at a single binding, e.g sampler2D[count]
+#[derive(Clone, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
+pub struct TextureInputArray {
+    pub name: String,
+    pub format: ImageFormat,
+    pub count: u32,
+    // The binding in the user set, when None the array is bound after the texture inputs
+    pub binding: Option<u32>,
+}
+
+impl TextureInput {
+    // The user set binding of each input, in the same order as the inputs
+    pub(crate) fn bindings(inputs: &[TextureInput]) -> impl Iterator<Item = u32> + '_ {
+        inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| input.binding.unwrap_or(i as u32))
+    }
+
+    // The parameter block is bound right after the last texture or texture array
+    pub(crate) fn parameter_block_binding(
+        inputs: &[TextureInput],
+        arrays: &[TextureInputArray],
+    ) -> u32 {
+        Self::bindings(inputs)
+            .chain(TextureInputArray::bindings(inputs, arrays))
+            .max()
+            .map_or(0, |binding| binding + 1)
+    }
+}
+
+impl TextureInputArray {
+    // The user set binding of each array, in the same order as the arrays
+    pub(crate) fn bindings<'a>(
+        inputs: &'a [TextureInput],
+        arrays: &'a [TextureInputArray],
+    ) -> impl Iterator<Item = u32> + 'a {
+        arrays
+            .iter()
+            .enumerate()
+            .map(|(i, array)| array.binding.unwrap_or((inputs.len() + i) as u32))
+    }
 }
 
 pub struct MaterialDescription<'a> {
     pub name: &'a str,
     pub domain: MaterialDomain,
+    pub topology: PrimitiveTopology,
+    pub vertex_encoding: VertexEncoding,
+    /* The interleaved vertex format of the meshes drawn by the material, e.g
+     * VertexBindingDescription::of::<V>(0, InputRate::PerVertex) for meshes created with
+     * Mesh::from_vertices::<V>. When None, the material draws the surface meshes, whose
+     * attributes are encoded as described by vertex_encoding */
+    pub vertex_input: Option<VertexBindingDescription<'static>>,
+    pub stencil_state: Option<StencilState>,
     pub texture_inputs: &'a [TextureInput],
+    pub texture_array_inputs: &'a [TextureInputArray],
     pub material_parameters: HashMap<String, MaterialParameterOffsetSize>,
     pub fragment_module: &'a GpuShaderModule,
     pub vertex_module: &'a GpuShaderModule,
+    // Rasterizes every pixel touched by a primitive (e.g for voxelization), see
+    // Gpu::supports_conservative_rasterization
+    pub conservative_raster: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use gpu::ImageFormat;
+
+    use super::{TextureInput, TextureInputArray};
+
+    fn input(binding: Option<u32>) -> TextureInput {
+        TextureInput {
+            name: "texture".to_owned(),
+            format: ImageFormat::Rgba8,
+            binding,
+        }
+    }
+
+    #[test]
+    fn explicit_bindings_override_the_input_position() {
+        let inputs = [input(None), input(Some(4)), input(None)];
+        let bindings: Vec<_> = TextureInput::bindings(&inputs).collect();
+        assert_eq!(bindings, vec![0, 4, 2]);
+        assert_eq!(TextureInput::parameter_block_binding(&inputs, &[]), 5);
+        assert_eq!(TextureInput::parameter_block_binding(&[], &[]), 0);
+    }
+
+    #[test]
+    fn texture_arrays_are_bound_after_the_inputs() {
+        let inputs = [input(None), input(None)];
+        let array = |binding| TextureInputArray {
+            name: "cascades".to_owned(),
+            format: ImageFormat::Depth,
+            count: 4,
+            binding,
+        };
+        let arrays = [array(None), array(Some(7))];
+        let bindings: Vec<_> = TextureInputArray::bindings(&inputs, &arrays).collect();
+        assert_eq!(bindings, vec![2, 7]);
+        assert_eq!(TextureInput::parameter_block_binding(&inputs, &arrays), 8);
+    }
 }
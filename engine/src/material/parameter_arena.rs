@@ -0,0 +1,138 @@
+use std::cell::{Cell, RefCell};
+
+use ash::vk::BufferUsageFlags;
+use gpu::{BufferCreateInfo, BufferRange, Gpu, GpuBuffer, MemoryDomain};
+use resource_map::Resource;
+
+/*
+A single uniform buffer holding the parameter blocks of many material instances,
+each one at its own offset: the blocks are written on the cpu side,
+and flush() uploads all the blocks written since the last flush with a single copy.
+The buffer holds a copy of the blocks for each frame in flight, so that a flush never writes
+the blocks a previous frame may still be reading: the instances bind it as a dynamic uniform
+buffer, at the offset of their block in the copy flushed last, see dynamic_offset.
+The blocks are never freed: create an arena for a group of instances living together
+(e.g the materials of a level) and drop it along with them
+ */
+pub struct MaterialParameterArena {
+    pub(crate) buffer: GpuBuffer,
+    alignment: u64,
+    // The distance between the copies of the blocks, aligned to the dynamic offset alignment
+    frame_stride: u64,
+    head: u64,
+    // Written through shared references, so that the instances in a ResourceMap can write it
+    data: RefCell<Vec<u8>>,
+    // The range of data written since the last flush of each copy
+    dirty: RefCell<Vec<Option<(u64, u64)>>>,
+    // The copy bound by the instances, the one flushed last
+    current_frame: Cell<usize>,
+}
+
+impl Resource for MaterialParameterArena {
+    fn get_description(&self) -> &str {
+        "Material Parameter Arena"
+    }
+}
+
+impl MaterialParameterArena {
+    pub fn new(gpu: &Gpu, capacity: usize, label: Option<&str>) -> anyhow::Result<Self> {
+        anyhow::ensure!(capacity > 0, "Cannot create an empty parameter arena");
+        let alignment = gpu.buffer_offset_alignment(BufferUsageFlags::UNIFORM_BUFFER);
+        let frame_stride = (capacity as u64).next_multiple_of(alignment);
+        let frames = gpu.frames_in_flight();
+        let buffer = gpu.create_buffer(
+            &BufferCreateInfo {
+                label: Some(label.unwrap_or("Material Parameter Arena")),
+                size: frame_stride as usize * frames,
+                usage: BufferUsageFlags::UNIFORM_BUFFER | BufferUsageFlags::TRANSFER_DST,
+                sharing_mode: Default::default(),
+            },
+            MemoryDomain::DeviceLocal,
+        )?;
+        Ok(Self {
+            buffer,
+            alignment,
+            frame_stride,
+            head: 0,
+            data: RefCell::new(vec![0; capacity]),
+            dirty: RefCell::new(vec![None; frames]),
+            current_frame: Cell::new(0),
+        })
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.data.borrow().len() as u64
+    }
+
+    // The bytes taken by the blocks allocated so far, including their alignment padding
+    pub fn used(&self) -> u64 {
+        self.head
+    }
+
+    pub(crate) fn allocate(&mut self, size: usize) -> anyhow::Result<u64> {
+        let offset = self.head.next_multiple_of(self.alignment);
+        let end = offset + size as u64;
+        anyhow::ensure!(
+            end <= self.capacity(),
+            "The parameter arena is full: cannot allocate {size} bytes, {} of {} bytes are used",
+            self.head,
+            self.capacity()
+        );
+        self.head = end;
+        Ok(offset)
+    }
+
+    // The range bound by the instances' descriptor sets, moved to their block by dynamic_offset
+    pub(crate) fn block_range(&self, size: usize) -> BufferRange<'_> {
+        BufferRange {
+            handle: &self.buffer,
+            offset: 0,
+            size: size as u64,
+        }
+    }
+
+    // The dynamic offset of the block at offset in the copy flushed last
+    pub(crate) fn dynamic_offset(&self, offset: u64) -> u32 {
+        (self.current_frame.get() as u64 * self.frame_stride + offset) as u32
+    }
+
+    pub(crate) fn write_bytes(&self, offset: u64, bytes: &[u8]) {
+        let start = offset as usize;
+        self.data.borrow_mut()[start..start + bytes.len()].copy_from_slice(bytes);
+        self.mark_dirty(offset, offset + bytes.len() as u64);
+    }
+
+    pub(crate) fn copy_block(&self, source: u64, dest: u64, size: usize) {
+        let source = source as usize;
+        self.data
+            .borrow_mut()
+            .copy_within(source..source + size, dest as usize);
+        self.mark_dirty(dest, dest + size as u64);
+    }
+
+    fn mark_dirty(&self, start: u64, end: u64) {
+        for dirty in self.dirty.borrow_mut().iter_mut() {
+            *dirty = Some(match *dirty {
+                Some((dirty_start, dirty_end)) => (dirty_start.min(start), dirty_end.max(end)),
+                None => (start, end),
+            });
+        }
+    }
+
+    /* Uploads the blocks written since the copy of frame was flushed last, and makes the
+     * instances bind that copy: the renderer flushes the arenas of the scene's instances
+     * before recording each frame, so the blocks must be written before rendering the frame */
+    pub fn flush(&self, gpu: &Gpu, frame: u64) -> anyhow::Result<()> {
+        let mut dirty = self.dirty.borrow_mut();
+        let copy = (frame % dirty.len() as u64) as usize;
+        if let Some((start, end)) = dirty[copy].take() {
+            gpu.write_buffer_data_with_offset(
+                &self.buffer,
+                copy as u64 * self.frame_stride + start,
+                &self.data.borrow()[start as usize..end as usize],
+            )?;
+        }
+        self.current_frame.set(copy);
+        Ok(())
+    }
+}
@@ -1,7 +1,12 @@
-use ash::{prelude::VkResult, vk::BufferUsageFlags};
-use nalgebra::{Vector2, Vector3};
+use std::borrow::Cow;
 
-use gpu::{BufferCreateInfo, Gpu, GpuBuffer, MemoryDomain};
+use ash::vk::{self, BufferUsageFlags};
+use nalgebra::{Matrix4, Point3, Vector2, Vector3};
+
+use gpu::{
+    BufferCreateInfo, Gpu, GpuBuffer, InputRate, MemoryDomain, PrimitiveTopology,
+    RenderPassCommand, Vertex, VertexBindingDescription,
+};
 use resource_map::Resource;
 
 pub struct MeshPrimitiveCreateInfo {
@@ -13,121 +18,528 @@ pub struct MeshPrimitiveCreateInfo {
     pub uvs: Vec<Vector2<f32>>,
 }
 
+// How normals and tangents are stored in the vertex buffers
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub enum NormalEncoding {
+    #[default]
+    Float,
+    // Packed in a single u32 as A2B10G10R10_SNORM_PACK32
+    Packed,
+}
+
+// How texture coordinates are stored in the vertex buffers
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub enum UvEncoding {
+    #[default]
+    Float,
+    Half,
+}
+
+/* The vertex attribute encodings of a mesh: the pipelines drawing the mesh
+ * must be created with the same encoding */
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub struct VertexEncoding {
+    pub normals: NormalEncoding,
+    pub uvs: UvEncoding,
+}
+
+impl NormalEncoding {
+    pub fn format(&self) -> vk::Format {
+        match self {
+            NormalEncoding::Float => vk::Format::R32G32B32_SFLOAT,
+            NormalEncoding::Packed => vk::Format::A2B10G10R10_SNORM_PACK32,
+        }
+    }
+
+    pub fn stride(&self) -> u32 {
+        match self {
+            NormalEncoding::Float => std::mem::size_of::<Vector3<f32>>() as u32,
+            NormalEncoding::Packed => std::mem::size_of::<u32>() as u32,
+        }
+    }
+}
+
+impl UvEncoding {
+    pub fn format(&self) -> vk::Format {
+        match self {
+            UvEncoding::Float => vk::Format::R32G32_SFLOAT,
+            UvEncoding::Half => vk::Format::R16G16_SFLOAT,
+        }
+    }
+
+    pub fn stride(&self) -> u32 {
+        match self {
+            UvEncoding::Float => std::mem::size_of::<Vector2<f32>>() as u32,
+            UvEncoding::Half => std::mem::size_of::<[u16; 2]>() as u32,
+        }
+    }
+}
+
+pub fn pack_snorm_1010102(v: &Vector3<f32>) -> u32 {
+    let snorm = |f: f32| ((f.clamp(-1.0, 1.0) * 511.0).round() as i32 as u32) & 0x3FF;
+    snorm(v.x) | snorm(v.y) << 10 | snorm(v.z) << 20
+}
+
+// Converts the float to an IEEE 754 half, rounding to the nearest representable value
+pub fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xFF) as i32;
+    let mantissa = bits & 0x7F_FFFF;
+
+    if exponent == 0xFF {
+        // Infinity or NaN
+        return sign | 0x7C00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1F {
+        return sign | 0x7C00;
+    }
+    if exponent <= 0 {
+        // The value can only be represented as a subnormal half
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let round = (mantissa >> (shift - 1)) & 1;
+        return sign | ((mantissa >> shift) + round) as u16;
+    }
+    let round = (mantissa >> 12) & 1;
+    // A carry from rounding correctly bumps the exponent
+    sign | ((((exponent as u32) << 10) | (mantissa >> 13)) + round) as u16
+}
+
 pub struct MeshCreateInfo<'a> {
     pub label: Option<&'a str>,
+    pub topology: PrimitiveTopology,
+    pub vertex_encoding: VertexEncoding,
     pub primitives: &'a [MeshPrimitiveCreateInfo],
+    // Keeps a copy of the positions and indices, see Mesh::cpu_positions
+    pub keep_cpu_data: bool,
 }
 
-pub struct MeshPrimitive {
+// An axis aligned bounding box
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingBox {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl BoundingBox {
+    pub fn from_points<'a>(points: impl IntoIterator<Item = &'a Vector3<f32>>) -> Self {
+        let mut points = points.into_iter();
+        let first = match points.next() {
+            Some(point) => Point3::from(*point),
+            None => return Self::default(),
+        };
+        points.fold(
+            Self {
+                min: first,
+                max: first,
+            },
+            |bounds, point| Self {
+                min: bounds.min.inf(&Point3::from(*point)),
+                max: bounds.max.sup(&Point3::from(*point)),
+            },
+        )
+    }
+
+    pub fn union(&self, other: &BoundingBox) -> Self {
+        Self {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        nalgebra::center(&self.min, &self.max)
+    }
+
+    pub fn extents(&self) -> Vector3<f32> {
+        (self.max - self.min) * 0.5
+    }
+
+    // The box containing the eight transformed corners of this box
+    pub fn transformed(&self, transform: &Matrix4<f32>) -> Self {
+        let corners: Vec<Vector3<f32>> = (0..8)
+            .map(|i| {
+                let corner = Point3::new(
+                    if i & 1 == 0 { self.min.x } else { self.max.x },
+                    if i & 2 == 0 { self.min.y } else { self.max.y },
+                    if i & 4 == 0 { self.min.z } else { self.max.z },
+                );
+                transform.transform_point(&corner).coords
+            })
+            .collect();
+        Self::from_points(&corners)
+    }
+}
+
+impl Default for BoundingBox {
+    fn default() -> Self {
+        Self {
+            min: Point3::origin(),
+            max: Point3::origin(),
+        }
+    }
+}
+
+/* The vertex and index buffers shared by all the primitives of a mesh (or of one of its lods).
+ * The vertex buffers are bound starting from binding 0: the surface meshes have a buffer for
+ * each of position, color, normal, tangent and uv, the meshes created from a Vertex format
+ * have a single interleaved buffer */
+pub struct MeshBuffers {
     pub index_buffer: GpuBuffer,
-    pub position_component: GpuBuffer,
-    pub color_component: GpuBuffer,
-    pub normal_component: GpuBuffer,
-    pub tangent_component: GpuBuffer,
-    pub uv_component: GpuBuffer,
+    pub vertex_buffers: Vec<GpuBuffer>,
+}
 
+impl MeshBuffers {
+    pub fn bind(&self, render_pass: &RenderPassCommand) {
+        render_pass.bind_index_buffer(&self.index_buffer, 0, vk::IndexType::UINT32);
+        let vertex_buffers: Vec<_> = self.vertex_buffers.iter().collect();
+        render_pass.bind_vertex_buffer(0, &vertex_buffers, &vec![0; vertex_buffers.len()]);
+    }
+}
+
+/* Where a primitive lives in its mesh's buffers: its indices start at first_index,
+ * and are relative to the primitive's first vertex, found at vertex_offset */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MeshPrimitive {
+    pub first_index: u32,
     pub index_count: u32,
+    pub vertex_offset: u32,
+    pub vertex_count: u32,
+}
+
+/* A simplified version of a mesh, drawn in place of the mesh's primitives when the mesh's
+ * projected height is smaller than max_screen_size (a fraction of the screen height).
+ * The lod's primitives use the same materials as the mesh's primitives */
+pub struct MeshLod {
+    pub max_screen_size: f32,
+    pub buffers: MeshBuffers,
+    pub primitives: Vec<MeshPrimitive>,
+}
+
+pub struct MeshLodCreateInfo<'a> {
+    pub max_screen_size: f32,
+    pub primitives: &'a [MeshPrimitiveCreateInfo],
+}
+
+/* The positions and indices of a mesh's primitives, laid out like in the mesh's buffers:
+ * the indices of each primitive are relative to the primitive's vertex_offset */
+pub struct MeshCpuData {
+    pub positions: Vec<Vector3<f32>>,
+    pub indices: Vec<u32>,
+}
+
+impl MeshCpuData {
+    fn pack(primitives: &[MeshPrimitiveCreateInfo]) -> Self {
+        Self {
+            positions: primitives
+                .iter()
+                .flat_map(|p| p.positions.iter().copied())
+                .collect(),
+            indices: primitives
+                .iter()
+                .flat_map(|p| p.indices.iter().copied())
+                .collect(),
+        }
+    }
 }
 
 pub struct Mesh {
+    pub topology: PrimitiveTopology,
+    pub vertex_encoding: VertexEncoding,
+    // The format of the interleaved vertex buffer of the meshes created with Mesh::from_vertices
+    pub vertex_input: Option<VertexBindingDescription<'static>>,
+    pub buffers: MeshBuffers,
     pub primitives: Vec<MeshPrimitive>,
+    pub bounds: BoundingBox,
+    // Sorted from the most detailed to the least detailed
+    pub lods: Vec<MeshLod>,
+    label: String,
+    cpu_data: Option<MeshCpuData>,
 }
 
 impl Mesh {
-    pub fn new(gpu: &Gpu, mesh_create_info: &MeshCreateInfo) -> VkResult<Self> {
-        let primitives: Vec<VkResult<MeshPrimitive>> = mesh_create_info
-            .primitives
+    /* Creates a DeviceLocal buffer for each (name, usage, data), e.g the indices or one of
+     * the vertex attributes: all the buffers are uploaded with a single submission */
+    fn create_buffers_with_data(
+        gpu: &Gpu,
+        label: &str,
+        buffers: &[(&str, BufferUsageFlags, &[u8])],
+    ) -> anyhow::Result<Vec<GpuBuffer>> {
+        let created = buffers
             .iter()
-            .enumerate()
-            .map(|(idx, create_info)| {
-                let label = mesh_create_info
-                    .label
-                    .map(|s| s.to_owned())
-                    .unwrap_or_else(|| "GPU Mesh".to_owned())
-                    + &format!(" - primitive {idx}");
-                let index_buffer = gpu.create_buffer(
+            .map(|(name, usage, data)| {
+                gpu.create_buffer(
                     &BufferCreateInfo {
-                        label: Some(&(label.clone() + ": Index buffer")),
-                        size: std::mem::size_of::<u32>() * create_info.indices.len().max(1),
-                        usage: BufferUsageFlags::INDEX_BUFFER,
+                        label: Some(&format!("{label}: {name}")),
+                        size: data.len().max(1),
+                        usage: *usage,
+                        sharing_mode: Default::default(),
                     },
                     MemoryDomain::DeviceLocal,
-                )?;
-                gpu.write_buffer_data(&index_buffer, &create_info.indices)?;
-                let position_component = gpu.create_buffer(
-                    &BufferCreateInfo {
-                        label: Some(&(label.clone() + ": Position buffer")),
-                        size: std::mem::size_of::<Vector3<f32>>()
-                            * create_info.positions.len().max(1),
-                        usage: BufferUsageFlags::VERTEX_BUFFER,
-                    },
-                    MemoryDomain::DeviceLocal,
-                )?;
-                gpu.write_buffer_data(&position_component, &create_info.positions)?;
-                let color_component = gpu.create_buffer(
-                    &BufferCreateInfo {
-                        label: Some(&(label.clone() + ": Color buffer")),
-                        size: std::mem::size_of::<Vector3<f32>>()
-                            * create_info.positions.len().max(1),
-                        usage: BufferUsageFlags::VERTEX_BUFFER,
-                    },
-                    MemoryDomain::DeviceLocal,
-                )?;
-                gpu.write_buffer_data(&color_component, &create_info.colors)?;
-                let normal_component = gpu.create_buffer(
-                    &BufferCreateInfo {
-                        label: Some(&(label.clone() + ": Normal buffer")),
-                        size: std::mem::size_of::<Vector3<f32>>()
-                            * create_info.normals.len().max(1),
-                        usage: BufferUsageFlags::VERTEX_BUFFER,
-                    },
-                    MemoryDomain::DeviceLocal,
-                )?;
-                gpu.write_buffer_data(&normal_component, &create_info.normals)?;
-                let tangent_component = gpu.create_buffer(
-                    &BufferCreateInfo {
-                        label: Some(&(label.clone() + ": Tangent buffer")),
-                        size: std::mem::size_of::<Vector3<f32>>()
-                            * create_info.tangents.len().max(1),
-                        usage: BufferUsageFlags::VERTEX_BUFFER,
-                    },
-                    MemoryDomain::DeviceLocal,
-                )?;
-                gpu.write_buffer_data(&tangent_component, &create_info.tangents)?;
-                let uv_component = gpu.create_buffer(
-                    &BufferCreateInfo {
-                        label: Some(&(label + ": TexCoord[0] buffer")),
-                        size: std::mem::size_of::<Vector2<f32>>() * create_info.uvs.len().max(1),
-                        usage: BufferUsageFlags::VERTEX_BUFFER,
-                    },
-                    MemoryDomain::DeviceLocal,
-                )?;
-                gpu.write_buffer_data(&uv_component, &create_info.uvs)?;
-                Ok(MeshPrimitive {
-                    index_buffer,
-                    position_component,
-                    color_component,
-                    normal_component,
-                    tangent_component,
-                    uv_component,
-                    index_count: create_info.indices.len() as _,
-                })
+                )
             })
+            .collect::<Result<Vec<_>, _>>()?;
+        let writes: Vec<_> = created
+            .iter()
+            .zip(buffers)
+            .map(|(buffer, (_, _, data))| (buffer, *data))
             .collect();
-        let mut generated_primitives = vec![];
-        generated_primitives.reserve(primitives.len());
-        for prim in primitives {
-            match prim {
-                Ok(prim) => generated_primitives.push(prim),
-                Err(e) => {
-                    return Err(e);
-                }
-            }
+        gpu.write_buffers_data(&writes)?;
+        Ok(created)
+    }
+
+    // The bytes of the indices or of a vertex attribute, as laid out in the buffers
+    fn as_bytes<T: Copy>(data: &[T]) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(data.as_ptr().cast(), std::mem::size_of_val(data)) }
+    }
+
+    fn encode_normals(normals: &[Vector3<f32>], encoding: NormalEncoding) -> Cow<'_, [u8]> {
+        match encoding {
+            NormalEncoding::Float => Cow::Borrowed(Self::as_bytes(normals)),
+            NormalEncoding::Packed => Cow::Owned(
+                normals
+                    .iter()
+                    .flat_map(|normal| pack_snorm_1010102(normal).to_ne_bytes())
+                    .collect(),
+            ),
+        }
+    }
+
+    fn encode_uvs(uvs: &[Vector2<f32>], encoding: UvEncoding) -> Cow<'_, [u8]> {
+        match encoding {
+            UvEncoding::Float => Cow::Borrowed(Self::as_bytes(uvs)),
+            UvEncoding::Half => Cow::Owned(
+                uvs.iter()
+                    .flat_map(|uv| [uv.x, uv.y])
+                    .flat_map(|value| f32_to_f16(value).to_ne_bytes())
+                    .collect(),
+            ),
         }
+    }
+
+    pub fn new(gpu: &Gpu, mesh_create_info: &MeshCreateInfo) -> anyhow::Result<Self> {
+        let label = mesh_create_info
+            .label
+            .map(|s| s.to_owned())
+            .unwrap_or_else(|| "GPU Mesh".to_owned());
+        let cpu_data = MeshCpuData::pack(mesh_create_info.primitives);
+        let (buffers, primitives) = Self::create_buffers(
+            gpu,
+            &label,
+            mesh_create_info.primitives,
+            &cpu_data,
+            mesh_create_info.vertex_encoding,
+        )?;
+        let bounds = BoundingBox::from_points(
+            mesh_create_info
+                .primitives
+                .iter()
+                .flat_map(|p| p.positions.iter()),
+        );
         Ok(Self {
-            primitives: generated_primitives,
+            topology: mesh_create_info.topology,
+            vertex_encoding: mesh_create_info.vertex_encoding,
+            vertex_input: None,
+            buffers,
+            primitives,
+            bounds,
+            lods: vec![],
+            label,
+            cpu_data: mesh_create_info.keep_cpu_data.then_some(cpu_data),
         })
     }
+
+    /* Creates a mesh with a single primitive, whose vertices are stored in a custom format:
+     * such meshes are only drawn by the materials whose MaterialDescription::vertex_input
+     * is VertexBindingDescription::of::<V>(0, InputRate::PerVertex).
+     * The bounds are used for culling */
+    pub fn from_vertices<V: Vertex>(
+        gpu: &Gpu,
+        label: Option<&str>,
+        topology: PrimitiveTopology,
+        vertices: &[V],
+        indices: &[u32],
+        bounds: BoundingBox,
+    ) -> anyhow::Result<Self> {
+        let label = label
+            .map(|s| s.to_owned())
+            .unwrap_or_else(|| "GPU Mesh".to_owned());
+        let mut buffers = Self::create_buffers_with_data(
+            gpu,
+            &label,
+            &[
+                (
+                    "Index buffer",
+                    BufferUsageFlags::INDEX_BUFFER,
+                    Self::as_bytes(indices),
+                ),
+                (
+                    "Vertex buffer",
+                    BufferUsageFlags::VERTEX_BUFFER,
+                    Self::as_bytes(vertices),
+                ),
+            ],
+        )?;
+        let vertex_buffer = buffers.pop().unwrap();
+        let index_buffer = buffers.pop().unwrap();
+        Ok(Self {
+            topology,
+            vertex_encoding: VertexEncoding::default(),
+            vertex_input: Some(VertexBindingDescription::of::<V>(0, InputRate::PerVertex)),
+            buffers: MeshBuffers {
+                index_buffer,
+                vertex_buffers: vec![vertex_buffer],
+            },
+            primitives: vec![MeshPrimitive {
+                first_index: 0,
+                index_count: indices.len() as _,
+                vertex_offset: 0,
+                vertex_count: vertices.len() as _,
+            }],
+            bounds,
+            lods: vec![],
+            label,
+            cpu_data: None,
+        })
+    }
+
+    /* The positions of the mesh's primitives, when created with keep_cpu_data,
+     * e.g to build collision shapes: the lods' geometry is never kept */
+    pub fn cpu_positions(&self) -> Option<&[Vector3<f32>]> {
+        self.cpu_data.as_ref().map(|data| data.positions.as_slice())
+    }
+
+    // The indices of the primitives, each relative to its primitive's vertex_offset
+    pub fn cpu_indices(&self) -> Option<&[u32]> {
+        self.cpu_data.as_ref().map(|data| data.indices.as_slice())
+    }
+
+    // The lod must have as many primitives as the mesh, since it shares the mesh's materials
+    pub fn add_lod(
+        &mut self,
+        gpu: &Gpu,
+        lod_create_info: &MeshLodCreateInfo,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            lod_create_info.primitives.len() == self.primitives.len(),
+            "Mesh '{}' has {} primitives, but the lod has {}",
+            self.label,
+            self.primitives.len(),
+            lod_create_info.primitives.len()
+        );
+        let label = format!("{} - lod {}", self.label, self.lods.len() + 1);
+        let (buffers, primitives) = Self::create_buffers(
+            gpu,
+            &label,
+            lod_create_info.primitives,
+            &MeshCpuData::pack(lod_create_info.primitives),
+            self.vertex_encoding,
+        )?;
+        self.lods.push(MeshLod {
+            max_screen_size: lod_create_info.max_screen_size,
+            buffers,
+            primitives,
+        });
+        self.lods
+            .sort_by(|a, b| b.max_screen_size.total_cmp(&a.max_screen_size));
+        Ok(())
+    }
+
+    // Picks the least detailed primitives that can be used for the given projected height
+    pub fn select_lod(&self, screen_size: f32) -> (&MeshBuffers, &[MeshPrimitive]) {
+        self.lods
+            .iter()
+            .rev()
+            .find(|lod| screen_size <= lod.max_screen_size)
+            .map(|lod| (&lod.buffers, lod.primitives.as_slice()))
+            .unwrap_or((&self.buffers, &self.primitives))
+    }
+
+    // Lays out the primitives one after the other in the mesh's buffers
+    fn primitive_ranges(primitives: &[MeshPrimitiveCreateInfo]) -> Vec<MeshPrimitive> {
+        let mut first_index = 0;
+        let mut vertex_offset = 0;
+        primitives
+            .iter()
+            .map(|create_info| {
+                let primitive = MeshPrimitive {
+                    first_index,
+                    index_count: create_info.indices.len() as _,
+                    vertex_offset,
+                    vertex_count: create_info.positions.len() as _,
+                };
+                first_index += primitive.index_count;
+                vertex_offset += primitive.vertex_count;
+                primitive
+            })
+            .collect()
+    }
+
+    /* Every vertex attribute of a primitive is padded (or truncated) to the primitive's
+     * vertex count, so that all the attributes share the primitive's vertex_offset */
+    fn packed_attribute<T: Copy>(
+        primitives: &[MeshPrimitiveCreateInfo],
+        attribute: impl Fn(&MeshPrimitiveCreateInfo) -> &[T],
+        fill: T,
+    ) -> Vec<T> {
+        primitives
+            .iter()
+            .flat_map(|create_info| {
+                let values = attribute(create_info);
+                (0..create_info.positions.len()).map(move |i| *values.get(i).unwrap_or(&fill))
+            })
+            .collect()
+    }
+
+    fn create_buffers(
+        gpu: &Gpu,
+        label: &str,
+        primitives: &[MeshPrimitiveCreateInfo],
+        geometry: &MeshCpuData,
+        encoding: VertexEncoding,
+    ) -> anyhow::Result<(MeshBuffers, Vec<MeshPrimitive>)> {
+        let ranges = Self::primitive_ranges(primitives);
+        let MeshCpuData { positions, indices } = geometry;
+        let colors = Self::packed_attribute(primitives, |p| &p.colors, Vector3::zeros());
+        let normals = Self::packed_attribute(primitives, |p| &p.normals, Vector3::zeros());
+        let tangents = Self::packed_attribute(primitives, |p| &p.tangents, Vector3::zeros());
+        let uvs = Self::packed_attribute(primitives, |p| &p.uvs, Vector2::zeros());
+
+        let normals = Self::encode_normals(&normals, encoding.normals);
+        let tangents = Self::encode_normals(&tangents, encoding.normals);
+        let uvs = Self::encode_uvs(&uvs, encoding.uvs);
+        let vertex = BufferUsageFlags::VERTEX_BUFFER;
+        // The vertex buffers are in the binding order of MeshBuffers
+        let mut buffers = Self::create_buffers_with_data(
+            gpu,
+            label,
+            &[
+                (
+                    "Index buffer",
+                    BufferUsageFlags::INDEX_BUFFER,
+                    Self::as_bytes(indices),
+                ),
+                ("Position buffer", vertex, Self::as_bytes(positions)),
+                ("Color buffer", vertex, Self::as_bytes(&colors)),
+                ("Normal buffer", vertex, &normals),
+                ("Tangent buffer", vertex, &tangents),
+                ("TexCoord[0] buffer", vertex, &uvs),
+            ],
+        )?;
+        let index_buffer = buffers.remove(0);
+        Ok((
+            MeshBuffers {
+                index_buffer,
+                vertex_buffers: buffers,
+            },
+            ranges,
+        ))
+    }
 }
 
 impl Resource for Mesh {
@@ -135,3 +547,123 @@ impl Resource for Mesh {
         "Mesh"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{point, vector, Matrix4};
+
+    use super::{
+        f32_to_f16, pack_snorm_1010102, BoundingBox, Mesh, MeshCpuData, MeshPrimitive,
+        MeshPrimitiveCreateInfo,
+    };
+
+    fn primitive(indices: Vec<u32>, vertex_count: usize) -> MeshPrimitiveCreateInfo {
+        MeshPrimitiveCreateInfo {
+            indices,
+            positions: vec![vector![0.0, 0.0, 0.0]; vertex_count],
+            colors: vec![],
+            normals: vec![],
+            tangents: vec![],
+            uvs: vec![],
+        }
+    }
+
+    #[test]
+    fn halves() {
+        assert_eq!(f32_to_f16(0.0), 0x0000);
+        assert_eq!(f32_to_f16(-0.0), 0x8000);
+        assert_eq!(f32_to_f16(1.0), 0x3C00);
+        assert_eq!(f32_to_f16(0.5), 0x3800);
+        assert_eq!(f32_to_f16(-2.0), 0xC000);
+        assert_eq!(f32_to_f16(65504.0), 0x7BFF);
+        assert_eq!(f32_to_f16(1.0e6), 0x7C00);
+        assert_eq!(f32_to_f16(f32::INFINITY), 0x7C00);
+        assert_eq!(f32_to_f16(5.960_464_5e-8), 0x0001);
+        assert_eq!(f32_to_f16(1.0e-10), 0x0000);
+    }
+
+    #[test]
+    fn packed_normals() {
+        assert_eq!(pack_snorm_1010102(&vector![0.0, 0.0, 0.0]), 0);
+        assert_eq!(pack_snorm_1010102(&vector![1.0, 0.0, 0.0]), 511);
+        assert_eq!(pack_snorm_1010102(&vector![0.0, -1.0, 0.0]), 0x201 << 10);
+        assert_eq!(pack_snorm_1010102(&vector![0.0, 0.0, 2.0]), 511 << 20);
+    }
+
+    #[test]
+    fn bounds() {
+        let bounds = BoundingBox::from_points(&[
+            vector![1.0, -1.0, 0.0],
+            vector![-2.0, 3.0, 1.0],
+            vector![0.0, 0.0, -1.0],
+        ]);
+        assert_eq!(bounds.min, point![-2.0, -1.0, -1.0]);
+        assert_eq!(bounds.max, point![1.0, 3.0, 1.0]);
+
+        let moved = bounds.transformed(&Matrix4::new_translation(&vector![1.0, 0.0, 0.0]));
+        assert_eq!(moved.min, point![-1.0, -1.0, -1.0]);
+        assert_eq!(moved.max, point![2.0, 3.0, 1.0]);
+    }
+
+    #[test]
+    fn primitives_share_the_buffers() {
+        let primitives = [
+            primitive(vec![0, 1, 2], 3),
+            primitive(vec![], 4),
+            primitive(vec![0, 1, 2, 2, 1, 3], 4),
+        ];
+        assert_eq!(
+            Mesh::primitive_ranges(&primitives),
+            vec![
+                MeshPrimitive {
+                    first_index: 0,
+                    index_count: 3,
+                    vertex_offset: 0,
+                    vertex_count: 3,
+                },
+                MeshPrimitive {
+                    first_index: 3,
+                    index_count: 0,
+                    vertex_offset: 3,
+                    vertex_count: 4,
+                },
+                MeshPrimitive {
+                    first_index: 3,
+                    index_count: 6,
+                    vertex_offset: 7,
+                    vertex_count: 4,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn cpu_data_matches_the_primitive_ranges() {
+        let primitives = [primitive(vec![0, 1, 2], 3), primitive(vec![1, 0], 2)];
+        let cpu_data = MeshCpuData::pack(&primitives);
+        assert_eq!(cpu_data.positions.len(), 5);
+        assert_eq!(cpu_data.indices, vec![0, 1, 2, 1, 0]);
+
+        let second = Mesh::primitive_ranges(&primitives)[1];
+        let first_index = second.first_index as usize;
+        assert_eq!(cpu_data.indices[first_index..], [1, 0]);
+    }
+
+    #[test]
+    fn attributes_are_padded_to_the_vertex_count() {
+        let mut first = primitive(vec![], 2);
+        first.colors = vec![vector![1.0, 0.0, 0.0]];
+        let mut second = primitive(vec![], 1);
+        second.colors = vec![vector![0.0, 1.0, 0.0], vector![0.0, 0.0, 1.0]];
+        let colors =
+            Mesh::packed_attribute(&[first, second], |p| &p.colors, vector![0.0, 0.0, 0.0]);
+        assert_eq!(
+            colors,
+            vec![
+                vector![1.0, 0.0, 0.0],
+                vector![0.0, 0.0, 0.0],
+                vector![0.0, 1.0, 0.0]
+            ]
+        );
+    }
+}
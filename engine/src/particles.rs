@@ -0,0 +1,269 @@
+use nalgebra::{vector, Vector3, Vector4};
+
+/*
+The parameters of a particle emitter: the initial velocity of each particle
+is velocity, with each component offset randomly by at most velocity_spread
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EmitterSettings {
+    pub position: Vector3<f32>,
+    // Particles spawned each second
+    pub rate: f32,
+    // How many seconds each particle lives for
+    pub lifetime: f32,
+    pub velocity: Vector3<f32>,
+    pub velocity_spread: f32,
+    pub acceleration: Vector3<f32>,
+    pub size: f32,
+    pub color: Vector4<f32>,
+    // The slots reserved in the particle buffer: once they're all taken, the oldest are reused
+    pub max_particles: usize,
+}
+
+impl Default for EmitterSettings {
+    fn default() -> Self {
+        Self {
+            position: Vector3::zeros(),
+            rate: 10.0,
+            lifetime: 2.0,
+            velocity: vector![0.0, 1.0, 0.0],
+            velocity_spread: 0.25,
+            acceleration: Vector3::zeros(),
+            size: 0.1,
+            color: vector![1.0, 1.0, 1.0, 1.0],
+            max_particles: 1000,
+        }
+    }
+}
+
+// The layout of a particle in the renderer's particle buffer, written by the simulation shader
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct GpuParticle {
+    pub position_size: Vector4<f32>,
+    // w is how many seconds the particle has left to live: the dead particles have none
+    pub velocity_life: Vector4<f32>,
+    pub color: Vector4<f32>,
+}
+
+/*
+The push constants of the simulation shader, which advances the slot_count particles
+starting at first_slot by delta_seconds, then spawns spawn_count particles in the slots
+following spawn_start (wrapping around slot_count)
+ */
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ParticleSimulationStep {
+    pub position_size: Vector4<f32>,
+    pub velocity_spread: Vector4<f32>,
+    pub acceleration_lifetime: Vector4<f32>,
+    pub color: Vector4<f32>,
+    pub first_slot: u32,
+    pub slot_count: u32,
+    pub spawn_start: u32,
+    pub spawn_count: u32,
+    pub delta_seconds: f32,
+    pub seed: u32,
+    // When not zero every particle is killed before spawning, see ParticleSystem::clear
+    pub clear: u32,
+    pub _padding: u32,
+}
+
+// How far the renderer has simulated a ParticleSystem
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct ParticleSimulationState {
+    elapsed: f64,
+    spawned: u64,
+    clears: u32,
+}
+
+/*
+A particle system simulated on the GPU: update() only accounts for the time passed
+and the particles to spawn, the renderer then advances the particles in a compute
+dispatch and draws them as instanced billboards straight from the particle buffer.
+Call update() (or Scene::update_particle_systems) once per frame to advance the simulation
+ */
+pub struct ParticleSystem {
+    pub settings: EmitterSettings,
+    pub enabled: bool,
+    // The seconds passed and the particles spawned since the system was created
+    elapsed: f64,
+    spawned: u64,
+    spawn_accumulator: f32,
+    // The value of spawned when clear() was last called
+    cleared_at: u64,
+    clears: u32,
+}
+
+impl ParticleSystem {
+    pub fn new(settings: EmitterSettings) -> Self {
+        Self {
+            settings,
+            enabled: true,
+            elapsed: 0.0,
+            spawned: 0,
+            spawn_accumulator: 0.0,
+            cleared_at: 0,
+            clears: 0,
+        }
+    }
+
+    pub fn update(&mut self, delta_seconds: f32) {
+        self.elapsed += delta_seconds as f64;
+        if !self.enabled {
+            self.spawn_accumulator = 0.0;
+            return;
+        }
+        self.spawn_accumulator += self.settings.rate * delta_seconds;
+        let spawned = self.spawn_accumulator.floor();
+        self.spawn_accumulator -= spawned;
+        self.spawned += spawned as u64;
+    }
+
+    // How many particles were spawned since the system was created
+    pub fn spawned_count(&self) -> u64 {
+        self.spawned
+    }
+
+    // The particles are killed the next time the system is simulated
+    pub fn clear(&mut self) {
+        self.cleared_at = self.spawned;
+        self.clears += 1;
+        self.spawn_accumulator = 0.0;
+    }
+
+    /*
+    The step bringing the particles in [first_slot, first_slot + slot_count) from simulated
+    to the current state of the system, which is then stored in simulated.
+    Returns None when nothing happened since the last step
+     */
+    pub(crate) fn simulation_step(
+        &self,
+        simulated: &mut ParticleSimulationState,
+        first_slot: u32,
+        slot_count: u32,
+    ) -> Option<ParticleSimulationStep> {
+        let clear = simulated.clears != self.clears;
+        let spawn_from = if clear {
+            simulated.spawned.max(self.cleared_at)
+        } else {
+            simulated.spawned
+        };
+        let delta_seconds = (self.elapsed - simulated.elapsed) as f32;
+        let spawn_count = (self.spawned - spawn_from).min(slot_count as u64) as u32;
+        let spawn_start = if slot_count > 0 {
+            (spawn_from % slot_count as u64) as u32
+        } else {
+            0
+        };
+        *simulated = ParticleSimulationState {
+            elapsed: self.elapsed,
+            spawned: self.spawned,
+            clears: self.clears,
+        };
+        if slot_count == 0 || (delta_seconds <= 0.0 && spawn_count == 0 && !clear) {
+            return None;
+        }
+
+        let settings = &self.settings;
+        Some(ParticleSimulationStep {
+            position_size: settings.position.push(settings.size),
+            velocity_spread: settings.velocity.push(settings.velocity_spread),
+            acceleration_lifetime: settings.acceleration.push(settings.lifetime),
+            color: settings.color,
+            first_slot,
+            slot_count,
+            spawn_start,
+            spawn_count,
+            delta_seconds,
+            seed: (spawn_from as u32).wrapping_mul(0x9E37_79B9) ^ first_slot,
+            clear: clear as u32,
+            _padding: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmitterSettings, ParticleSimulationState, ParticleSystem};
+
+    #[test]
+    fn spawns_at_rate() {
+        let mut system = ParticleSystem::new(EmitterSettings {
+            rate: 10.0,
+            lifetime: 100.0,
+            ..Default::default()
+        });
+        for _ in 0..10 {
+            system.update(0.05);
+        }
+        assert_eq!(system.spawned_count(), 5);
+        let step = system
+            .simulation_step(&mut ParticleSimulationState::default(), 0, 16)
+            .unwrap();
+        assert_eq!(step.spawn_count, 5);
+        assert!((step.delta_seconds - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn steps_are_simulated_once() {
+        let mut system = ParticleSystem::new(EmitterSettings::default());
+        let mut simulated = ParticleSimulationState::default();
+        system.update(1.0);
+        assert!(system.simulation_step(&mut simulated, 0, 16).is_some());
+        assert!(system.simulation_step(&mut simulated, 0, 16).is_none());
+    }
+
+    #[test]
+    fn respects_max_particles() {
+        let mut system = ParticleSystem::new(EmitterSettings {
+            rate: 1000.0,
+            max_particles: 16,
+            ..Default::default()
+        });
+        system.update(1.0);
+        let step = system
+            .simulation_step(&mut ParticleSimulationState::default(), 32, 16)
+            .unwrap();
+        assert_eq!(step.first_slot, 32);
+        assert_eq!(step.spawn_count, 16);
+    }
+
+    #[test]
+    fn spawns_wrap_around_the_slots() {
+        let mut system = ParticleSystem::new(EmitterSettings {
+            rate: 10.0,
+            ..Default::default()
+        });
+        let mut simulated = ParticleSimulationState::default();
+        system.update(1.0);
+        let step = system.simulation_step(&mut simulated, 0, 16).unwrap();
+        assert_eq!((step.spawn_start, step.spawn_count), (0, 10));
+        system.update(1.0);
+        let step = system.simulation_step(&mut simulated, 0, 16).unwrap();
+        assert_eq!((step.spawn_start, step.spawn_count), (10, 10));
+    }
+
+    #[test]
+    fn disabled_systems_only_advance() {
+        let mut system = ParticleSystem::new(EmitterSettings::default());
+        system.enabled = false;
+        system.update(1.0);
+        let step = system
+            .simulation_step(&mut ParticleSimulationState::default(), 0, 16)
+            .unwrap();
+        assert_eq!(step.spawn_count, 0);
+        assert!((step.delta_seconds - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn clear_kills_the_particles() {
+        let mut system = ParticleSystem::new(EmitterSettings::default());
+        let mut simulated = ParticleSimulationState::default();
+        system.update(1.0);
+        system.simulation_step(&mut simulated, 0, 16);
+        system.clear();
+        let step = system.simulation_step(&mut simulated, 0, 16).unwrap();
+        assert_eq!((step.clear, step.spawn_count), (1, 0));
+    }
+}
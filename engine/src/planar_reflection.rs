@@ -0,0 +1,180 @@
+use anyhow::Context;
+use ash::vk::{AccessFlags, Extent2D, ImageLayout, PipelineStageFlags};
+use gpu::{CommandBuffer, Gpu, ImageTransition, ToVk, TransitionInfo};
+use nalgebra::{Matrix4, Point3, Vector3};
+use resource_map::{ResourceHandle, ResourceMap};
+
+use crate::{
+    utils::constants::MATRIX_COORDINATE_X_FLIP, Backbuffer, Camera, DeferredRenderingPipeline,
+    MaterialInstance, RenderingPipeline, SamplerSettings, Scene, Texture,
+};
+
+// The points p where normal.dot(p) == distance, the normal is unit length
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReflectionPlane {
+    pub normal: Vector3<f32>,
+    pub distance: f32,
+}
+
+impl ReflectionPlane {
+    pub fn new(point: Point3<f32>, normal: Vector3<f32>) -> Self {
+        let normal = normal.normalize();
+        Self {
+            normal,
+            distance: normal.dot(&point.coords),
+        }
+    }
+
+    pub fn reflect_point(&self, point: &Point3<f32>) -> Point3<f32> {
+        point - self.normal * 2.0 * (self.normal.dot(&point.coords) - self.distance)
+    }
+
+    pub fn reflect_vector(&self, vector: &Vector3<f32>) -> Vector3<f32> {
+        vector - self.normal * 2.0 * self.normal.dot(vector)
+    }
+
+    /* The camera looking at the scene from behind the plane: seen through the plane,
+     * its image is the reflection of what pov sees on the plane */
+    pub fn mirror_camera(&self, pov: &Camera) -> Camera {
+        Camera {
+            location: self.reflect_point(&pov.location),
+            forward: self.reflect_vector(&pov.forward),
+            ..*pov
+        }
+    }
+}
+
+/*
+Renders the scene mirrored across the reflection plane of a material instance into a texture,
+which the instance samples projectively: a surface point p on the plane is found in the texture at
+    clip = view_projection() * p, uv = clip.xy / clip.w * 0.5 + 0.5
+The reflection is rendered by its own renderer, which keeps its own targets and shadow maps.
+The scene is not clipped by the plane: the objects behind the plane show up in the reflection
+ */
+pub struct PlanarReflection {
+    renderer: DeferredRenderingPipeline,
+    texture: ResourceHandle<Texture>,
+    view_projection: Matrix4<f32>,
+}
+
+impl PlanarReflection {
+    pub fn new(
+        gpu: &Gpu,
+        resource_map: &mut ResourceMap,
+        renderer: DeferredRenderingPipeline,
+        size: Extent2D,
+    ) -> anyhow::Result<Self> {
+        let texture = Texture::new_render_target(
+            gpu,
+            resource_map,
+            size.width,
+            size.height,
+            &SamplerSettings::default().with_max_anisotropy(None),
+            Some("Planar reflection"),
+        )?;
+        Ok(Self {
+            renderer,
+            texture: resource_map.add(texture),
+            view_projection: Matrix4::identity(),
+        })
+    }
+
+    // Used as a texture input of the reflective material
+    pub fn texture(&self) -> &ResourceHandle<Texture> {
+        &self.texture
+    }
+
+    // The mirror camera's view projection in the last render, written to the material's parameters
+    pub fn view_projection(&self) -> Matrix4<f32> {
+        self.view_projection
+    }
+
+    pub fn renderer(&self) -> &DeferredRenderingPipeline {
+        &self.renderer
+    }
+    pub fn renderer_mut(&mut self) -> &mut DeferredRenderingPipeline {
+        &mut self.renderer
+    }
+
+    /* Records the reflection of the scene seen from pov across the reflective material's plane:
+     * the surfaces drawn with the reflective material are skipped. The returned commands must be
+     * submitted before the frame's commands, which sample the texture they leave readable */
+    pub fn render(
+        &mut self,
+        pov: &Camera,
+        scene: &Scene,
+        resource_map: &ResourceMap,
+        reflective_material: &ResourceHandle<MaterialInstance>,
+    ) -> anyhow::Result<CommandBuffer<'_>> {
+        let material = resource_map.get(reflective_material);
+        let plane = material.reflection_plane().with_context(|| {
+            format!(
+                "Material instance '{}' has no reflection plane",
+                material.name
+            )
+        })?;
+        let camera = plane.mirror_camera(pov);
+        self.view_projection =
+            camera.projection() * MATRIX_COORDINATE_X_FLIP * camera.view_matrix();
+        self.renderer.hidden_material = Some(reflective_material.clone());
+
+        let texture = resource_map.get(&self.texture);
+        let view = resource_map.get(&texture.image_view);
+        let image = &resource_map.get(&view.image).0;
+        let mut command_buffer = self.renderer.render(
+            &camera,
+            scene,
+            Backbuffer {
+                size: image.extents(),
+                format: image.format().to_vk(),
+                image,
+                image_view: &view.view,
+            },
+            resource_map,
+        )?;
+        // The renderer leaves its backbuffer ready to be presented
+        command_buffer.transition_images(&[(
+            image,
+            TransitionInfo {
+                layout: ImageLayout::PRESENT_SRC_KHR,
+                access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
+                stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            },
+            ImageTransition::ColorAttachmentToShaderRead
+                .transition_infos()
+                .1,
+        )]);
+        Ok(command_buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{point, vector};
+
+    use super::ReflectionPlane;
+    use crate::Camera;
+
+    #[test]
+    fn the_mirror_camera_sees_the_reflection_of_the_plane() {
+        let floor = ReflectionPlane::new(point![0.0, 1.0, 0.0], vector![0.0, 2.0, 0.0]);
+        assert_eq!(floor.distance, 1.0);
+
+        let pov = Camera {
+            location: point![1.0, 3.0, 2.0],
+            forward: vector![0.0, -1.0, 1.0].normalize(),
+            ..Default::default()
+        };
+        let mirror = floor.mirror_camera(&pov);
+        assert_eq!(mirror.location, point![1.0, -1.0, 2.0]);
+        assert_eq!(mirror.forward, vector![0.0, 1.0, 1.0].normalize());
+        assert_eq!(mirror.fov, pov.fov);
+
+        // A point on the plane is seen along the reflected ray
+        let on_plane = point![1.0, 1.0, 4.0];
+        let to_point = (on_plane - pov.location).normalize();
+        let reflected = floor.reflect_vector(&to_point);
+        assert!(((on_plane - mirror.location).normalize() - reflected).norm() < 1e-6);
+        assert_eq!(floor.reflect_point(&on_plane), on_plane);
+    }
+}
@@ -0,0 +1,891 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use ash::vk::PushConstantRange;
+use gpu::{BindingElement, BindingType, ShaderStage, ToVk};
+
+use crate::{MasterMaterial, MaterialParameterOffsetSize};
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+const HEADER_LENGTH: usize = 5;
+// Deeper types are rejected: a malformed module can declare a struct containing itself
+const MAX_TYPE_DEPTH: u32 = 64;
+
+mod op {
+    pub const NAME: u32 = 5;
+    pub const MEMBER_NAME: u32 = 6;
+    pub const ENTRY_POINT: u32 = 15;
+    pub const TYPE_INT: u32 = 21;
+    pub const TYPE_FLOAT: u32 = 22;
+    pub const TYPE_VECTOR: u32 = 23;
+    pub const TYPE_MATRIX: u32 = 24;
+    pub const TYPE_IMAGE: u32 = 25;
+    pub const TYPE_SAMPLER: u32 = 26;
+    pub const TYPE_SAMPLED_IMAGE: u32 = 27;
+    pub const TYPE_ARRAY: u32 = 28;
+    pub const TYPE_RUNTIME_ARRAY: u32 = 29;
+    pub const TYPE_STRUCT: u32 = 30;
+    pub const TYPE_POINTER: u32 = 32;
+    pub const CONSTANT: u32 = 43;
+    pub const VARIABLE: u32 = 59;
+    pub const DECORATE: u32 = 71;
+    pub const MEMBER_DECORATE: u32 = 72;
+}
+
+mod decoration {
+    pub const BUFFER_BLOCK: u32 = 3;
+    pub const ARRAY_STRIDE: u32 = 6;
+    pub const MATRIX_STRIDE: u32 = 7;
+    pub const BINDING: u32 = 33;
+    pub const DESCRIPTOR_SET: u32 = 34;
+    pub const OFFSET: u32 = 35;
+}
+
+mod storage_class {
+    pub const UNIFORM_CONSTANT: u32 = 0;
+    pub const UNIFORM: u32 = 2;
+    pub const PUSH_CONSTANT: u32 = 9;
+    pub const STORAGE_BUFFER: u32 = 12;
+}
+
+mod execution_model {
+    pub const VERTEX: u32 = 0;
+    pub const FRAGMENT: u32 = 4;
+    pub const GL_COMPUTE: u32 = 5;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ReflectedDescriptorType {
+    UniformBuffer,
+    StorageBuffer,
+    Sampler,
+    SampledImage,
+    StorageImage,
+    CombinedImageSampler,
+}
+
+impl ReflectedDescriptorType {
+    // Whether a BindingType can be bound where the shader expects this descriptor type
+    pub fn matches(self, binding_type: BindingType) -> bool {
+        matches!(
+            (self, binding_type),
+            (Self::UniformBuffer, BindingType::Uniform)
+                | (Self::UniformBuffer, BindingType::UniformDynamic)
+                | (Self::StorageBuffer, BindingType::Storage)
+                | (
+                    Self::Sampler,
+                    BindingType::Sampler | BindingType::ImmutableSampler(_)
+                )
+                | (
+                    Self::CombinedImageSampler,
+                    BindingType::CombinedImageSampler
+                        | BindingType::CombinedImageSamplerArray { .. }
+                        | BindingType::CombinedImageImmutableSampler(_)
+                )
+        )
+    }
+
+    // The BindingType declaring count descriptors of this type, if the set layouts support it
+    pub fn binding_type(self, count: u32) -> Option<BindingType> {
+        match (self, count) {
+            (_, 0) => None,
+            (Self::UniformBuffer, 1) => Some(BindingType::Uniform),
+            (Self::StorageBuffer, 1) => Some(BindingType::Storage),
+            (Self::Sampler, 1) => Some(BindingType::Sampler),
+            (Self::CombinedImageSampler, 1) => Some(BindingType::CombinedImageSampler),
+            (Self::CombinedImageSampler, count) => {
+                Some(BindingType::CombinedImageSamplerArray { count })
+            }
+            _ => None,
+        }
+    }
+}
+
+// A member of a uniform or storage block, nested structs are named after their path e.g "a.b"
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReflectedMember {
+    pub name: String,
+    pub offset: u32,
+    // 0 for runtime sized arrays
+    pub size: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReflectedBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub ty: ReflectedDescriptorType,
+    // 1 for single descriptors, 0 for runtime sized arrays
+    pub count: u32,
+    // The name of the variable, when the module has debug names
+    pub name: Option<String>,
+    // The stages of the module's entry points
+    pub stage: ShaderStage,
+    // The members of uniform and storage blocks, empty for the other descriptors
+    pub members: Vec<ReflectedMember>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReflectedPushConstants {
+    pub stage: ShaderStage,
+    pub offset: u32,
+    pub size: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShaderReflection {
+    pub bindings: Vec<ReflectedBinding>,
+    pub push_constants: Vec<ReflectedPushConstants>,
+}
+
+impl ShaderReflection {
+    pub fn binding(&self, set: u32, binding: u32) -> Option<&ReflectedBinding> {
+        self.bindings
+            .iter()
+            .find(|b| b.set == set && b.binding == binding)
+    }
+
+    pub fn bindings_in_set(&self, set: u32) -> impl Iterator<Item = &ReflectedBinding> {
+        self.bindings.iter().filter(move |b| b.set == set)
+    }
+
+    // The layout of a set as declared by the shaders, e.g the elements of a GlobalBinding
+    pub fn binding_elements(&self, set: u32) -> anyhow::Result<Vec<BindingElement>> {
+        self.bindings_in_set(set)
+            .map(|b| {
+                let binding_type = b.ty.binding_type(b.count).with_context(|| {
+                    format!(
+                        "Set {set}, binding {}: {} {:?} descriptors can't be declared in a layout",
+                        b.binding, b.count, b.ty
+                    )
+                })?;
+                Ok(BindingElement {
+                    binding_type,
+                    index: b.binding,
+                    stage: b.stage,
+                })
+            })
+            .collect()
+    }
+
+    pub fn push_constant_ranges(&self) -> Vec<PushConstantRange> {
+        self.push_constants
+            .iter()
+            .map(|range| PushConstantRange {
+                stage_flags: range.stage.to_vk(),
+                offset: range.offset,
+                size: range.size,
+            })
+            .collect()
+    }
+
+    /* The members of the uniform block in the material's user set, e.g to fill
+     * MaterialDescription::material_parameters from the fragment shader */
+    pub fn material_parameters(&self) -> HashMap<String, MaterialParameterOffsetSize> {
+        self.bindings_in_set(MasterMaterial::USER_SET_INDEX)
+            .filter(|b| b.ty == ReflectedDescriptorType::UniformBuffer)
+            .flat_map(|b| &b.members)
+            .map(|member| {
+                let parameter = MaterialParameterOffsetSize {
+                    offset: member.offset as usize,
+                    size: member.size as usize,
+                };
+                (member.name.clone(), parameter)
+            })
+            .collect()
+    }
+
+    /* Adds the bindings and push constants of another stage, e.g the fragment shader's
+     * to the vertex shader's: the bindings declared by both must be the same descriptors */
+    pub fn merge(&mut self, other: &ShaderReflection) -> anyhow::Result<()> {
+        for binding in &other.bindings {
+            let Some(existing) = self
+                .bindings
+                .iter_mut()
+                .find(|b| b.set == binding.set && b.binding == binding.binding)
+            else {
+                self.bindings.push(binding.clone());
+                continue;
+            };
+            anyhow::ensure!(
+                existing.ty == binding.ty && existing.count == binding.count,
+                "Set {}, binding {} is declared as {} {:?} and as {} {:?}",
+                binding.set,
+                binding.binding,
+                existing.count,
+                existing.ty,
+                binding.count,
+                binding.ty
+            );
+            existing.stage |= binding.stage;
+        }
+        self.bindings.sort_by_key(|b| (b.set, b.binding));
+
+        for range in &other.push_constants {
+            match self
+                .push_constants
+                .iter_mut()
+                .find(|r| r.offset == range.offset && r.size == range.size)
+            {
+                Some(existing) => existing.stage |= range.stage,
+                None => self.push_constants.push(*range),
+            }
+        }
+        Ok(())
+    }
+}
+
+enum SpirvType {
+    // The width is in bits
+    Scalar { width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray { element: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+}
+
+// The instructions of a module describing its resource variables and their types
+#[derive(Default)]
+struct Module {
+    names: HashMap<u32, String>,
+    member_names: HashMap<(u32, u32), String>,
+    bindings: HashMap<u32, u32>,
+    sets: HashMap<u32, u32>,
+    buffer_blocks: Vec<u32>,
+    array_strides: HashMap<u32, u32>,
+    member_offsets: HashMap<(u32, u32), u32>,
+    matrix_strides: HashMap<(u32, u32), u32>,
+    types: HashMap<u32, SpirvType>,
+    constants: HashMap<u32, u32>,
+    // The id, pointer type and storage class of each variable
+    variables: Vec<(u32, u32, u32)>,
+}
+
+impl Module {
+    fn array_length(&self, length: u32) -> u32 {
+        *self.constants.get(&length).unwrap_or(&1)
+    }
+
+    fn member_offset(&self, struct_id: u32, index: u32) -> anyhow::Result<u32> {
+        self.member_offsets
+            .get(&(struct_id, index))
+            .copied()
+            .with_context(|| format!("Member {index} of struct {struct_id} has no offset"))
+    }
+
+    // The size of a type laid out in a block, matrices are laid out with the member's stride
+    fn size_of(&self, type_id: u32, matrix_stride: Option<u32>, depth: u32) -> anyhow::Result<u32> {
+        anyhow::ensure!(
+            depth < MAX_TYPE_DEPTH,
+            "Type {type_id} is nested too deeply"
+        );
+        Ok(match self.types.get(&type_id) {
+            Some(SpirvType::Scalar { width }) => width / 8,
+            Some(SpirvType::Vector { component, count }) => {
+                count * self.size_of(*component, None, depth + 1)?
+            }
+            Some(SpirvType::Matrix { column, count }) => match matrix_stride {
+                Some(stride) => count * stride,
+                None => count * self.size_of(*column, None, depth + 1)?,
+            },
+            Some(SpirvType::Array { element, length }) => {
+                let length = self.array_length(*length);
+                match self.array_strides.get(&type_id) {
+                    Some(stride) => length * stride,
+                    None => length * self.size_of(*element, matrix_stride, depth + 1)?,
+                }
+            }
+            Some(SpirvType::RuntimeArray { .. }) => 0,
+            Some(SpirvType::Struct { members }) => {
+                let mut size = 0;
+                for (index, member) in members.iter().enumerate() {
+                    let index = index as u32;
+                    let offset = self.member_offset(type_id, index)?;
+                    let stride = self.matrix_strides.get(&(type_id, index)).copied();
+                    size = size.max(offset + self.size_of(*member, stride, depth + 1)?);
+                }
+                size
+            }
+            _ => anyhow::bail!("Type {type_id} can't be laid out in a block"),
+        })
+    }
+
+    // Flattens the members of a block, the members of nested structs are prefixed by their path
+    fn collect_members(
+        &self,
+        struct_id: u32,
+        prefix: &str,
+        base_offset: u32,
+        depth: u32,
+        members: &mut Vec<ReflectedMember>,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            depth < MAX_TYPE_DEPTH,
+            "Type {struct_id} is nested too deeply"
+        );
+        let Some(SpirvType::Struct {
+            members: member_types,
+        }) = self.types.get(&struct_id)
+        else {
+            anyhow::bail!("Type {struct_id} is not a struct");
+        };
+        for (index, &member_type) in member_types.iter().enumerate() {
+            let index = index as u32;
+            let name = self
+                .member_names
+                .get(&(struct_id, index))
+                .filter(|name| !name.is_empty())
+                .cloned()
+                .unwrap_or_else(|| index.to_string());
+            let name = format!("{prefix}{name}");
+            let offset = base_offset + self.member_offset(struct_id, index)?;
+            if let Some(SpirvType::Struct { .. }) = self.types.get(&member_type) {
+                let prefix = format!("{name}.");
+                self.collect_members(member_type, &prefix, offset, depth + 1, members)?;
+            } else {
+                let stride = self.matrix_strides.get(&(struct_id, index)).copied();
+                let size = self.size_of(member_type, stride, depth + 1)?;
+                members.push(ReflectedMember { name, offset, size });
+            }
+        }
+        Ok(())
+    }
+}
+
+/* Collects the descriptors and push constants declared by a SPIR-V module: only the instructions
+ * describing the resource variables and their layout are parsed, the rest of the module is skipped */
+pub fn reflect_shader(code: &[u32]) -> anyhow::Result<ShaderReflection> {
+    anyhow::ensure!(
+        code.len() >= HEADER_LENGTH && code[0] == SPIRV_MAGIC,
+        "The shader code is not a SPIR-V module"
+    );
+
+    let mut module = Module::default();
+    let mut stage = ShaderStage::empty();
+
+    let mut words = &code[HEADER_LENGTH..];
+    while !words.is_empty() {
+        let length = (words[0] >> 16) as usize;
+        let opcode = words[0] & 0xFFFF;
+        anyhow::ensure!(
+            length > 0 && length <= words.len(),
+            "Malformed SPIR-V instruction with opcode {opcode}"
+        );
+        let operands = &words[1..length];
+        words = &words[length..];
+
+        let operand = |index: usize| {
+            operands
+                .get(index)
+                .copied()
+                .ok_or_else(|| anyhow::anyhow!("Missing operand of SPIR-V opcode {opcode}"))
+        };
+        match opcode {
+            op::NAME => {
+                module
+                    .names
+                    .insert(operand(0)?, decode_string(&operands[1..]));
+            }
+            op::MEMBER_NAME => {
+                let member = (operand(0)?, operand(1)?);
+                module
+                    .member_names
+                    .insert(member, decode_string(&operands[2..]));
+            }
+            op::ENTRY_POINT => {
+                stage |= match operand(0)? {
+                    execution_model::VERTEX => ShaderStage::Vertex,
+                    execution_model::FRAGMENT => ShaderStage::Fragment,
+                    execution_model::GL_COMPUTE => ShaderStage::Compute,
+                    model => anyhow::bail!("Unsupported SPIR-V execution model {model}"),
+                }
+            }
+            op::DECORATE => match operand(1)? {
+                decoration::BINDING => {
+                    module.bindings.insert(operand(0)?, operand(2)?);
+                }
+                decoration::DESCRIPTOR_SET => {
+                    module.sets.insert(operand(0)?, operand(2)?);
+                }
+                decoration::ARRAY_STRIDE => {
+                    module.array_strides.insert(operand(0)?, operand(2)?);
+                }
+                decoration::BUFFER_BLOCK => module.buffer_blocks.push(operand(0)?),
+                _ => {}
+            },
+            op::MEMBER_DECORATE => {
+                let member = (operand(0)?, operand(1)?);
+                match operand(2)? {
+                    decoration::OFFSET => {
+                        module.member_offsets.insert(member, operand(3)?);
+                    }
+                    decoration::MATRIX_STRIDE => {
+                        module.matrix_strides.insert(member, operand(3)?);
+                    }
+                    _ => {}
+                }
+            }
+            op::TYPE_INT | op::TYPE_FLOAT => {
+                module
+                    .types
+                    .insert(operand(0)?, SpirvType::Scalar { width: operand(1)? });
+            }
+            op::TYPE_VECTOR => {
+                module.types.insert(
+                    operand(0)?,
+                    SpirvType::Vector {
+                        component: operand(1)?,
+                        count: operand(2)?,
+                    },
+                );
+            }
+            op::TYPE_MATRIX => {
+                module.types.insert(
+                    operand(0)?,
+                    SpirvType::Matrix {
+                        column: operand(1)?,
+                        count: operand(2)?,
+                    },
+                );
+            }
+            op::TYPE_IMAGE => {
+                module.types.insert(
+                    operand(0)?,
+                    SpirvType::Image {
+                        sampled: operand(6)?,
+                    },
+                );
+            }
+            op::TYPE_SAMPLER => {
+                module.types.insert(operand(0)?, SpirvType::Sampler);
+            }
+            op::TYPE_SAMPLED_IMAGE => {
+                module.types.insert(operand(0)?, SpirvType::SampledImage);
+            }
+            op::TYPE_ARRAY => {
+                module.types.insert(
+                    operand(0)?,
+                    SpirvType::Array {
+                        element: operand(1)?,
+                        length: operand(2)?,
+                    },
+                );
+            }
+            op::TYPE_RUNTIME_ARRAY => {
+                module.types.insert(
+                    operand(0)?,
+                    SpirvType::RuntimeArray {
+                        element: operand(1)?,
+                    },
+                );
+            }
+            op::TYPE_STRUCT => {
+                let id = operand(0)?;
+                let members = operands[1..].to_vec();
+                module.types.insert(id, SpirvType::Struct { members });
+            }
+            op::TYPE_POINTER => {
+                module.types.insert(
+                    operand(0)?,
+                    SpirvType::Pointer {
+                        pointee: operand(2)?,
+                    },
+                );
+            }
+            op::CONSTANT => {
+                module.constants.insert(operand(1)?, operand(2)?);
+            }
+            op::VARIABLE => module
+                .variables
+                .push((operand(1)?, operand(0)?, operand(2)?)),
+            _ => {}
+        }
+    }
+
+    let mut reflected = vec![];
+    let mut push_constants = vec![];
+    for &(id, pointer_type, storage_class) in &module.variables {
+        if storage_class == storage_class::PUSH_CONSTANT {
+            let Some(SpirvType::Pointer { pointee }) = module.types.get(&pointer_type) else {
+                anyhow::bail!("The type of the push constants is not a pointer");
+            };
+            let mut members = vec![];
+            module.collect_members(*pointee, "", 0, 0, &mut members)?;
+            let offset = members.iter().map(|m| m.offset).min().unwrap_or(0);
+            let end = members.iter().map(|m| m.offset + m.size).max().unwrap_or(0);
+            if end > offset {
+                push_constants.push(ReflectedPushConstants {
+                    stage,
+                    offset,
+                    size: end - offset,
+                });
+            }
+            continue;
+        }
+
+        let (Some(&set), Some(&binding)) = (module.sets.get(&id), module.bindings.get(&id)) else {
+            continue;
+        };
+        let Some(SpirvType::Pointer { pointee }) = module.types.get(&pointer_type) else {
+            anyhow::bail!(
+                "The type of the variable at set {set}, binding {binding} is not a pointer"
+            );
+        };
+
+        let (mut type_id, mut count) = (*pointee, 1);
+        match module.types.get(&type_id) {
+            Some(SpirvType::Array { element, length }) => {
+                count = module.array_length(*length);
+                type_id = *element;
+            }
+            Some(SpirvType::RuntimeArray { element }) => {
+                count = 0;
+                type_id = *element;
+            }
+            _ => {}
+        }
+        let ty = match (storage_class, module.types.get(&type_id)) {
+            (storage_class::UNIFORM_CONSTANT, Some(SpirvType::SampledImage)) => {
+                ReflectedDescriptorType::CombinedImageSampler
+            }
+            (storage_class::UNIFORM_CONSTANT, Some(SpirvType::Sampler)) => {
+                ReflectedDescriptorType::Sampler
+            }
+            (storage_class::UNIFORM_CONSTANT, Some(SpirvType::Image { sampled: 2 })) => {
+                ReflectedDescriptorType::StorageImage
+            }
+            (storage_class::UNIFORM_CONSTANT, Some(SpirvType::Image { .. })) => {
+                ReflectedDescriptorType::SampledImage
+            }
+            (storage_class::UNIFORM, Some(SpirvType::Struct { .. })) => {
+                if module.buffer_blocks.contains(&type_id) {
+                    ReflectedDescriptorType::StorageBuffer
+                } else {
+                    ReflectedDescriptorType::UniformBuffer
+                }
+            }
+            (storage_class::STORAGE_BUFFER, Some(SpirvType::Struct { .. })) => {
+                ReflectedDescriptorType::StorageBuffer
+            }
+            _ => anyhow::bail!(
+                "Unsupported descriptor at set {set}, binding {binding} (storage class {storage_class})"
+            ),
+        };
+        let mut members = vec![];
+        if matches!(
+            ty,
+            ReflectedDescriptorType::UniformBuffer | ReflectedDescriptorType::StorageBuffer
+        ) {
+            module.collect_members(type_id, "", 0, 0, &mut members)?;
+        }
+        reflected.push(ReflectedBinding {
+            set,
+            binding,
+            ty,
+            count,
+            // Blocks are usually anonymous: the name of their type is more descriptive
+            name: module
+                .names
+                .get(&id)
+                .filter(|name| !name.is_empty())
+                .or_else(|| module.names.get(&type_id))
+                .cloned(),
+            stage,
+            members,
+        });
+    }
+    reflected.sort_by_key(|b| (b.set, b.binding));
+    Ok(ShaderReflection {
+        bindings: reflected,
+        push_constants,
+    })
+}
+
+// Literal strings are nul terminated and packed in little endian words
+fn decode_string(words: &[u32]) -> String {
+    let bytes: Vec<u8> = words
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .take_while(|&byte| byte != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use engine_macros::glsl;
+    use gpu::{BindingType, ShaderStage};
+
+    use super::{
+        decoration, execution_model, op, reflect_shader, storage_class, ReflectedBinding,
+        ReflectedDescriptorType, ReflectedMember, ReflectedPushConstants, SPIRV_MAGIC,
+    };
+
+    const SSR_FS: &[u32] = glsl!(
+        kind = fragment,
+        path = "src/shaders/ssr_fs.frag",
+        entry_point = "main"
+    );
+
+    fn instruction(module: &mut Vec<u32>, opcode: u32, operands: &[u32]) {
+        module.push(((operands.len() as u32 + 1) << 16) | opcode);
+        module.extend_from_slice(operands);
+    }
+
+    fn string(operands: &mut Vec<u32>, string: &str) {
+        let mut bytes = string.as_bytes().to_vec();
+        bytes.resize((bytes.len() / 4 + 1) * 4, 0);
+        operands.extend(
+            bytes
+                .chunks(4)
+                .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap())),
+        );
+    }
+
+    fn name(module: &mut Vec<u32>, id: u32, name: &str) {
+        let mut operands = vec![id];
+        string(&mut operands, name);
+        instruction(module, op::NAME, &operands);
+    }
+
+    fn member_name(module: &mut Vec<u32>, id: u32, member: u32, name: &str) {
+        let mut operands = vec![id, member];
+        string(&mut operands, name);
+        instruction(module, op::MEMBER_NAME, &operands);
+    }
+
+    fn binding(module: &mut Vec<u32>, id: u32, set: u32, binding: u32) {
+        instruction(module, op::DECORATE, &[id, decoration::DESCRIPTOR_SET, set]);
+        instruction(module, op::DECORATE, &[id, decoration::BINDING, binding]);
+    }
+
+    // A module with a single entry point, its ids start from 100
+    fn module(execution_model: u32) -> Vec<u32> {
+        let mut module = vec![SPIRV_MAGIC, 0x0001_0000, 0, 200, 0];
+        let mut operands = vec![execution_model, 100];
+        string(&mut operands, "main");
+        instruction(&mut module, op::ENTRY_POINT, &operands);
+        module
+    }
+
+    // A uniform block holding a single float, at set 0 and binding 0
+    fn uniform_block(module: &mut Vec<u32>) {
+        binding(module, 103, 0, 0);
+        instruction(
+            module,
+            op::MEMBER_DECORATE,
+            &[101, 0, decoration::OFFSET, 0],
+        );
+        instruction(module, op::TYPE_FLOAT, &[110, 32]);
+        instruction(module, op::TYPE_STRUCT, &[101, 110]);
+        instruction(
+            module,
+            op::TYPE_POINTER,
+            &[102, storage_class::UNIFORM, 101],
+        );
+        instruction(module, op::VARIABLE, &[102, 103, storage_class::UNIFORM]);
+    }
+
+    // A sampler at set 0
+    fn sampler(module: &mut Vec<u32>, binding_index: u32) {
+        binding(module, 123, 0, binding_index);
+        instruction(module, op::TYPE_SAMPLER, &[120]);
+        instruction(
+            module,
+            op::TYPE_POINTER,
+            &[121, storage_class::UNIFORM_CONSTANT, 120],
+        );
+        instruction(
+            module,
+            op::VARIABLE,
+            &[121, 123, storage_class::UNIFORM_CONSTANT],
+        );
+    }
+
+    #[test]
+    fn reflects_samplers_and_uniform_blocks() {
+        let mut module = vec![SPIRV_MAGIC, 0x0001_0000, 0, 100, 0];
+        name(&mut module, 10, "baseColorSampler");
+        name(&mut module, 20, "Params");
+        name(&mut module, 21, "");
+        member_name(&mut module, 20, 0, "roughness");
+        instruction(
+            &mut module,
+            op::MEMBER_DECORATE,
+            &[20, 0, decoration::OFFSET, 0],
+        );
+        name(&mut module, 30, "cascades");
+        binding(&mut module, 10, 1, 0);
+        binding(&mut module, 21, 1, 1);
+        binding(&mut module, 30, 0, 3);
+        // float, image, sampled image, pointer, variable
+        instruction(&mut module, 22, &[1, 32]);
+        instruction(&mut module, op::TYPE_IMAGE, &[2, 1, 1, 0, 0, 0, 1, 0]);
+        instruction(&mut module, op::TYPE_SAMPLED_IMAGE, &[3, 2]);
+        instruction(
+            &mut module,
+            op::TYPE_POINTER,
+            &[4, storage_class::UNIFORM_CONSTANT, 3],
+        );
+        instruction(
+            &mut module,
+            op::VARIABLE,
+            &[4, 10, storage_class::UNIFORM_CONSTANT],
+        );
+        // An anonymous uniform block
+        instruction(&mut module, op::TYPE_STRUCT, &[20, 1]);
+        instruction(
+            &mut module,
+            op::TYPE_POINTER,
+            &[5, storage_class::UNIFORM, 20],
+        );
+        instruction(&mut module, op::VARIABLE, &[5, 21, storage_class::UNIFORM]);
+        // An array of 4 sampled images
+        instruction(&mut module, op::CONSTANT, &[1, 6, 4]);
+        instruction(&mut module, op::TYPE_ARRAY, &[7, 3, 6]);
+        instruction(
+            &mut module,
+            op::TYPE_POINTER,
+            &[8, storage_class::UNIFORM_CONSTANT, 7],
+        );
+        instruction(
+            &mut module,
+            op::VARIABLE,
+            &[8, 30, storage_class::UNIFORM_CONSTANT],
+        );
+
+        let reflection = reflect_shader(&module).unwrap();
+        assert_eq!(
+            reflection.bindings,
+            vec![
+                ReflectedBinding {
+                    set: 0,
+                    binding: 3,
+                    ty: ReflectedDescriptorType::CombinedImageSampler,
+                    count: 4,
+                    name: Some("cascades".to_owned()),
+                    stage: ShaderStage::empty(),
+                    members: vec![],
+                },
+                ReflectedBinding {
+                    set: 1,
+                    binding: 0,
+                    ty: ReflectedDescriptorType::CombinedImageSampler,
+                    count: 1,
+                    name: Some("baseColorSampler".to_owned()),
+                    stage: ShaderStage::empty(),
+                    members: vec![],
+                },
+                ReflectedBinding {
+                    set: 1,
+                    binding: 1,
+                    ty: ReflectedDescriptorType::UniformBuffer,
+                    count: 1,
+                    name: Some("Params".to_owned()),
+                    stage: ShaderStage::empty(),
+                    members: vec![ReflectedMember {
+                        name: "roughness".to_owned(),
+                        offset: 0,
+                        size: 4,
+                    }],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reflects_the_layout_of_a_compiled_shader() {
+        let reflection = reflect_shader(SSR_FS).unwrap();
+        let samplers: Vec<_> = reflection
+            .bindings_in_set(0)
+            .filter(|b| b.ty == ReflectedDescriptorType::CombinedImageSampler)
+            .map(|b| b.binding)
+            .collect();
+        assert_eq!(samplers, vec![0, 1, 2, 3, 4, 5]);
+
+        let camera = reflection.binding(0, 6).unwrap();
+        assert_eq!(camera.ty, ReflectedDescriptorType::UniformBuffer);
+        assert_eq!(camera.stage, ShaderStage::Fragment);
+        let members: Vec<_> = camera
+            .members
+            .iter()
+            .map(|m| (m.name.as_str(), m.offset, m.size))
+            .collect();
+        assert_eq!(
+            members,
+            vec![("eye", 0, 16), ("view", 16, 64), ("proj", 80, 64)]
+        );
+
+        assert_eq!(
+            reflection.push_constants,
+            vec![ReflectedPushConstants {
+                stage: ShaderStage::Fragment,
+                offset: 0,
+                size: 24,
+            }]
+        );
+        let elements = reflection.binding_elements(0).unwrap();
+        assert_eq!(elements.len(), 7);
+        assert!(matches!(elements[6].binding_type, BindingType::Uniform));
+    }
+
+    #[test]
+    fn merged_stages_share_their_bindings() {
+        let mut vertex = module(execution_model::VERTEX);
+        uniform_block(&mut vertex);
+        let mut fragment = module(execution_model::FRAGMENT);
+        uniform_block(&mut fragment);
+        sampler(&mut fragment, 1);
+
+        let mut reflection = reflect_shader(&vertex).unwrap();
+        let fragment = reflect_shader(&fragment).unwrap();
+        reflection.merge(&fragment).unwrap();
+        let stages: Vec<_> = reflection.bindings.iter().map(|b| b.stage).collect();
+        assert_eq!(
+            stages,
+            vec![ShaderStage::VertexFragment, ShaderStage::Fragment]
+        );
+
+        // The same binding can't hold different descriptors in different stages
+        let mut sampler_at_zero = module(execution_model::FRAGMENT);
+        sampler(&mut sampler_at_zero, 0);
+        let sampler_at_zero = reflect_shader(&sampler_at_zero).unwrap();
+        assert!(reflection.merge(&sampler_at_zero).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_modules() {
+        assert!(reflect_shader(&[0]).is_err());
+        assert!(reflect_shader(&[SPIRV_MAGIC, 0, 0, 0, 0, 5 << 16]).is_err());
+
+        let mut empty_struct = module(execution_model::FRAGMENT);
+        instruction(&mut empty_struct, op::TYPE_STRUCT, &[]);
+        assert!(reflect_shader(&empty_struct).is_err());
+
+        // A uniform block containing itself
+        let mut cyclic = module(execution_model::FRAGMENT);
+        binding(&mut cyclic, 103, 0, 0);
+        instruction(
+            &mut cyclic,
+            op::MEMBER_DECORATE,
+            &[101, 0, decoration::OFFSET, 0],
+        );
+        instruction(&mut cyclic, op::TYPE_STRUCT, &[101, 101]);
+        instruction(
+            &mut cyclic,
+            op::TYPE_POINTER,
+            &[102, storage_class::UNIFORM, 101],
+        );
+        instruction(
+            &mut cyclic,
+            op::VARIABLE,
+            &[102, 103, storage_class::UNIFORM],
+        );
+        assert!(reflect_shader(&cyclic).is_err());
+    }
+}
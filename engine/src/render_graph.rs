@@ -6,8 +6,8 @@ use std::{
     hash::{Hash, Hasher},
 };
 
-use ash::vk::{self, AccessFlags, AttachmentLoadOp, AttachmentReference, AttachmentStoreOp, BlendFactor, BlendOp, BorderColor, BufferUsageFlags, ColorComponentFlags, CompareOp, ComponentMapping, DependencyFlags, Extent2D, Filter, ImageAspectFlags, ImageLayout, ImageSubresourceRange, ImageUsageFlags, ImageViewType, Offset2D, PipelineBindPoint, PipelineStageFlags, Rect2D, SampleCountFlags, SamplerAddressMode, SamplerCreateFlags, SamplerCreateInfo, SamplerMipmapMode, StructureType, SubpassDependency, SubpassDescriptionFlags};
-use gpu::{BeginRenderPassInfo, BindingType, BlendState, BufferCreateInfo, BufferRange, ColorAttachment, ColorLoadOp, CommandBuffer, DepthAttachment, DepthLoadOp, DescriptorInfo, DescriptorSetInfo, FramebufferCreateInfo, Gpu, GpuBuffer, GpuDescriptorSet, GpuFramebuffer, GpuImage, GpuImageView, GpuSampler, ImageCreateInfo, ImageFormat, ImageMemoryBarrier, ImageViewCreateInfo, MemoryDomain, Pipeline, PipelineBarrierInfo, RenderPass, RenderPassAttachment, RenderPassCommand, RenderPassDescription, StencilAttachment, StencilLoadOp, SubpassDescription, ToVk, TransitionInfo};
+use ash::vk::{self, AccessFlags, AttachmentLoadOp, AttachmentReference, AttachmentStoreOp, BlendFactor, BlendOp, BorderColor, BufferUsageFlags, ColorComponentFlags, CompareOp, DependencyFlags, Extent2D, Filter, ImageLayout, ImageUsageFlags, Offset2D, PipelineBindPoint, PipelineStageFlags, Rect2D, SamplerAddressMode, SamplerCreateFlags, SamplerCreateInfo, SamplerMipmapMode, StructureType, SubpassDependency, SubpassDescriptionFlags};
+use gpu::{BeginRenderPassInfo, BindingType, BlendState, BufferCreateInfo, ColorAttachment, ColorLoadOp, ColorResolve, CommandBuffer, DepthAttachment, DepthLoadOp, DepthResolve, DepthResolveMode, DescriptorInfo, DescriptorSetInfo, FramebufferCreateInfo, Gpu, GpuBuffer, GpuDescriptorSet, GpuFramebuffer, GpuImage, GpuImageView, GpuSampler, ImageCreateInfo, ImageFormat, MemoryDomain, Pipeline, RenderPass, RenderPassAttachment, RenderPassCommand, RenderPassDescription, SampleCount, StencilAttachment, StencilLoadOp, SubpassDescription, ToVk, TransitionInfo};
 
 use ash::vk::PushConstantRange;
 use gpu::{
@@ -18,6 +18,8 @@ use gpu::{
 use indexmap::IndexSet;
 use log::trace;
 
+use crate::PassTimer;
+
 /*
  How to add another resource to DefaultResourceAllocator?
     let ResT the resource you want to add
@@ -236,6 +238,10 @@ impl<'a> ExternalShaderResource<'a> {
 #[derive(Default)]
 struct ExternalResources<'a> {
     external_images: HashMap<ResourceId, &'a GpuImage>,
+    // The states of the external images whose contents must be preserved
+    external_image_states: HashMap<ResourceId, TransitionInfo>,
+    // Samplers replacing the graph allocated ones for the external images read by shaders
+    external_samplers: HashMap<ResourceId, &'a GpuSampler>,
     external_shader_resources: HashMap<ResourceId, ExternalShaderResource<'a>>,
     external_render_passes: HashMap<RenderPassHandle, &'a RenderPass>,
 }
@@ -260,6 +266,14 @@ impl<'a> ExternalResources<'a> {
             .insert(*id, ExternalShaderResource::ImageView(view));
     }
 
+    pub fn set_external_image_state(&mut self, id: &ResourceId, state: TransitionInfo) {
+        self.external_image_states.insert(*id, state);
+    }
+
+    pub fn inject_external_sampler(&mut self, id: &ResourceId, sampler: &'a GpuSampler) {
+        self.external_samplers.insert(*id, sampler);
+    }
+
     pub fn inject_external_buffer(&mut self, id: &ResourceId, buffer: &'a GpuBuffer) {
         self.external_shader_resources
             .insert(*id, ExternalShaderResource::Buffer(buffer));
@@ -311,6 +325,9 @@ impl<'a> CreateFrom<'a, ImageDescription> for GraphImage {
                     usage: desc.format.default_usage_flags()
                         | ImageUsageFlags::INPUT_ATTACHMENT
                         | ImageUsageFlags::SAMPLED,
+                    mip_levels: 1,
+                    sharing_mode: Default::default(),
+                    samples: desc.sample_count(),
                 },
                 MemoryDomain::DeviceLocal,
                 None,
@@ -387,9 +404,18 @@ impl<'a> CreateFrom<'a, NoDesc> for GraphSampler {
 
 pub struct GraphImageView {
     image: GpuImageView,
+    // Only the depth of the depth-stencil images can be sampled, see Gpu::create_sampled_view
+    sampled: Option<GpuImageView>,
     desc: ImageDescription,
 }
 
+impl GraphImageView {
+    // The view bound to the descriptors of the passes reading the image
+    fn sampled_view(&self) -> &GpuImageView {
+        self.sampled.as_ref().unwrap_or(&self.image)
+    }
+}
+
 impl GraphResource for GraphImageView {
     type Inner = GpuImageView;
     type Desc = ImageDescription;
@@ -398,7 +424,11 @@ impl GraphResource for GraphImageView {
     where
         Self: Sized,
     {
-        Self { image, desc }
+        Self {
+            image,
+            sampled: None,
+            desc,
+        }
     }
 
     fn matches_description(&self, new_desc: &Self::Desc) -> bool {
@@ -422,21 +452,13 @@ impl<'a> AsRef<ImageDescription> for GraphImageViewCreateInfo<'a> {
 impl<'a> CreateFrom<'a, GraphImageViewCreateInfo<'_>> for GraphImageView {
     fn create(gpu: &Gpu, desc: &'a GraphImageViewCreateInfo) -> anyhow::Result<Self> {
         let view = gpu
-            .create_image_view(&ImageViewCreateInfo {
-                image: desc.image,
-                view_type: ImageViewType::TYPE_2D,
-                format: desc.desc.format.to_vk(),
-                components: ComponentMapping::default(),
-                subresource_range: ImageSubresourceRange {
-                    aspect_mask: desc.desc.format.aspect_mask(),
-                    base_mip_level: 0,
-                    level_count: 1,
-                    base_array_layer: 0,
-                    layer_count: 1,
-                },
-            })
+            .create_default_view(desc.image)
             .expect("Failed to create image resource");
-        Ok(GraphImageView::construct(view, *desc.desc))
+        let mut image_view = GraphImageView::construct(view, *desc.desc);
+        if desc.desc.format.has_stencil() {
+            image_view.sampled = Some(gpu.create_sampled_view(desc.image)?);
+        }
+        Ok(image_view)
     }
 }
 
@@ -478,6 +500,7 @@ impl<'a> CreateFrom<'a, BufferDescription> for GraphBuffer {
                 label: None,
                 size: desc.length as _,
                 usage: BufferUsageFlags::UNIFORM_BUFFER | BufferUsageFlags::STORAGE_BUFFER,
+                sharing_mode: Default::default(),
             },
             MemoryDomain::DeviceLocal,
         )?;
@@ -555,7 +578,7 @@ impl<'a> CreateFrom<'a, RenderGraphPassCreateInfo<'_>> for GraphPass {
             };
             let attachment = RenderPassAttachment {
                 format: image_desc.format.to_vk(),
-                samples: SampleCountFlags::TYPE_1,
+                samples: image_desc.sample_count().to_vk(),
                 load_op: match resource_usage.input {
                     ResourceLayout::Unknown => AttachmentLoadOp::DONT_CARE,
 
@@ -620,7 +643,7 @@ impl<'a> CreateFrom<'a, RenderGraphPassCreateInfo<'_>> for GraphPass {
             let resource_usage = create_info.pass_info.resource_usage(read);
             let attachment = RenderPassAttachment {
                 format: image_desc.format.to_vk(),
-                samples: SampleCountFlags::TYPE_1,
+                samples: image_desc.sample_count().to_vk(),
                 load_op: AttachmentLoadOp::LOAD,
                 store_op: AttachmentStoreOp::NONE,
                 stencil_load_op: AttachmentLoadOp::DONT_CARE,
@@ -857,6 +880,7 @@ pub struct GraphRunContext<'a, 'e> {
     callbacks: Callbacks<'e>,
     external_resources: ExternalResources<'e>,
     command_buffer: &'e mut CommandBuffer<'a>,
+    pass_timer: Option<&'e mut PassTimer>,
 }
 
 impl<'a, 'e> GraphRunContext<'a, 'e> {
@@ -871,9 +895,15 @@ impl<'a, 'e> GraphRunContext<'a, 'e> {
             command_buffer,
             callbacks: Callbacks::default(),
             external_resources: ExternalResources::default(),
+            pass_timer: None,
         }
     }
 
+    // Each render pass is measured by the timer, which must have been reset
+    pub(crate) fn set_pass_timer(&mut self, pass_timer: &'e mut PassTimer) {
+        self.pass_timer = Some(pass_timer);
+    }
+
     pub(crate) fn register_callback<F: FnMut(&Gpu, &mut RenderPassContext) + 'e>(
         &mut self,
         handle: &RenderPassHandle,
@@ -904,6 +934,17 @@ impl<'a, 'e> GraphRunContext<'a, 'e> {
         self.external_resources
             .inject_external_image(handle, image, view);
     }
+    /* By default external images are transitioned from UNDEFINED, discarding their contents:
+     * use this to tell the graph which state the image is in when the graph runs */
+    pub(crate) fn set_external_image_state(&mut self, handle: &ResourceId, state: TransitionInfo) {
+        self.external_resources
+            .set_external_image_state(handle, state);
+    }
+    // Samples the image with sampler instead of the graph's default one (e.g a comparison sampler)
+    pub(crate) fn inject_external_sampler(&mut self, handle: &ResourceId, sampler: &'e GpuSampler) {
+        self.external_resources
+            .inject_external_sampler(handle, sampler);
+    }
     pub(crate) fn injext_external_buffer(&mut self, handle: &ResourceId, buffer: &'e GpuBuffer) {
         self.external_resources
             .inject_external_buffer(handle, buffer);
@@ -980,6 +1021,8 @@ impl ClearValue {
         match self {
             ClearValue::DontCare => StencilLoadOp::DontCare,
             ClearValue::Stencil(s) => StencilLoadOp::Clear(*s),
+            // Depth stencil images cleared with a depth value clear the stencil to 0
+            ClearValue::Depth(_) => StencilLoadOp::Clear(0),
             _ => unreachable!()
         }
     }
@@ -990,11 +1033,24 @@ pub struct ImageDescription {
     pub width: u32,
     pub height: u32,
     pub format: ImageFormat,
+    // 1, 2, 4 or 8: multisampled images are resolved by the passes writing them, see RenderPassBuilder::resolves_attachments
     pub samples: u32,
     pub present: bool,
     pub clear_value: ClearValue
 }
 
+impl ImageDescription {
+    fn sample_count(&self) -> SampleCount {
+        match self.samples {
+            1 => SampleCount::One,
+            2 => SampleCount::Two,
+            4 => SampleCount::Four,
+            8 => SampleCount::Eight,
+            _ => panic!("Invalid sample count! {}", self.samples),
+        }
+    }
+}
+
 #[derive(Ord, PartialOrd, Eq, PartialEq, Debug, Clone, Copy, Hash)]
 pub enum BufferType {
     Storage,
@@ -1106,6 +1162,9 @@ pub(crate) fn create_pipeline_for_graph_renderpass(
     }
 
     let (mut color_attachments, mut depth_stencil_attachments) = (vec![], vec![]);
+    let mut depth_stencil_format = None;
+    // The pipeline rasterizes with the samples of the pass's attachments
+    let mut samples = SampleCount::One;
 
     for (_, write) in pass_info.attachment_writes.iter().enumerate() {
         let resource = graph.get_resource_info(write)?;
@@ -1113,20 +1172,11 @@ pub(crate) fn create_pipeline_for_graph_renderpass(
         match resource.ty {
             AllocationType::Image(desc) => {
                 let format = desc.format.to_vk();
-                let samples = match desc.samples {
-                    1 => SampleCountFlags::TYPE_1,
-                    2 => SampleCountFlags::TYPE_2,
-                    4 => SampleCountFlags::TYPE_4,
-                    8 => SampleCountFlags::TYPE_8,
-                    16 => SampleCountFlags::TYPE_16,
-                    32 => SampleCountFlags::TYPE_32,
-                    64 => SampleCountFlags::TYPE_64,
-                    _ => panic!("Invalid sample count! {}", desc.samples),
-                };
+                samples = desc.sample_count();
                 if desc.format.is_color() {
                     color_attachments.push(RenderPassAttachment {
                         format,
-                        samples,
+                        samples: samples.to_vk(),
                         load_op: AttachmentLoadOp::DONT_CARE,
                         store_op: AttachmentStoreOp::STORE,
                         stencil_load_op: AttachmentLoadOp::DONT_CARE,
@@ -1141,6 +1191,7 @@ pub(crate) fn create_pipeline_for_graph_renderpass(
                     });
                 } else {
                     depth_stencil_attachments.push(DepthStencilAttachment {});
+                    depth_stencil_format = Some(desc.format);
                 }
             }
             AllocationType::Buffer { .. } => {
@@ -1149,10 +1200,21 @@ pub(crate) fn create_pipeline_for_graph_renderpass(
         }
     }
 
+    for read in pass_info.attachment_reads.iter() {
+        let resource = graph.get_resource_info(read)?;
+        if let AllocationType::Image(desc) = resource.ty {
+            samples = desc.sample_count();
+            if desc.format.is_depth() {
+                depth_stencil_format = Some(desc.format);
+            }
+        }
+    }
+
     let description = PipelineDescription {
         global_bindings: &[GlobalBinding {
             set_index: 0,
             elements: &set_zero_bindings,
+            push_descriptor: false,
         }],
         vertex_inputs: description.vertex_inputs,
         vertex_stage: if let RenderStage::Graphics { vertex, .. } = &description.stage {
@@ -1184,8 +1246,11 @@ pub(crate) fn create_pipeline_for_graph_renderpass(
         cull_mode: description.fragment_state.cull_mode,
         front_face: description.fragment_state.front_face,
         depth_stencil_state: description.fragment_state.depth_stencil_state,
+        depth_stencil_format,
         logic_op: description.fragment_state.logic_op,
         push_constant_ranges: description.fragment_state.push_constant_ranges,
+        conservative_rasterization: false,
+        samples,
     };
 
     Ok(Pipeline::new(gpu, &description)?)
@@ -1247,6 +1312,8 @@ pub struct RenderPassInfo {
     pub attachment_writes: IndexSet<ResourceId>,
     pub shader_reads: IndexSet<ResourceId>,
     pub attachment_reads: IndexSet<ResourceId>,
+    // Written at the end of the pass with the samples of its multisampled attachments
+    pub attachment_resolves: IndexSet<ResourceId>,
     pub resource_usages: HashMap<ResourceId, ResourceUsage>,
     pub extents: Extent2D,
     pub blend_state: Option<BlendState>,
@@ -1257,7 +1324,7 @@ pub struct RenderPassInfo {
 
 impl RenderPassInfo {
     fn uses_as_write_attachment(&self, resource: &ResourceId) -> bool {
-        self.attachment_writes.contains(resource)
+        self.attachment_writes.contains(resource) || self.attachment_resolves.contains(resource)
     }
 
     fn has_any_as_write_attachment<'s, R: IntoIterator<Item = &'s ResourceId>>(
@@ -1307,6 +1374,11 @@ impl PartialEq for RenderPassInfo {
                 return false;
             }
         }
+        for r in &self.attachment_resolves {
+            if !other.attachment_resolves.contains(r) {
+                return false;
+            }
+        }
 
         for (r, u) in &self.resource_usages {
             if !other.resource_usages.get(r).is_some_and(|ou| *u == *ou) {
@@ -1328,6 +1400,9 @@ impl Hash for RenderPassInfo {
         for read in &self.shader_reads {
             read.hash(state);
         }
+        for resolve in &self.attachment_resolves {
+            resolve.hash(state);
+        }
     }
 }
 
@@ -1359,6 +1434,17 @@ impl<'g> RenderPassBuilder<'g> {
         self.pass.attachment_reads.extend(handles.iter());
         self
     }
+    /* The i-th color resolve receives the averaged samples of the pass's i-th color attachment,
+     * in the order they're written then read; a depth resolve receives the first sample
+     * of the depth attachment */
+    pub fn resolves_attachments(mut self, handles: &[ResourceId]) -> Self {
+        for handle in handles {
+            assert!(!self.pass.attachment_resolves.contains(handle));
+        }
+
+        self.pass.attachment_resolves.extend(handles.iter());
+        self
+    }
 
     pub fn read(mut self, handle: ResourceId) -> Self {
         assert!(!self.pass.shader_reads.contains(&handle));
@@ -1526,6 +1612,7 @@ impl RenderGraph {
                 attachment_writes: Default::default(),
                 shader_reads: Default::default(),
                 attachment_reads: Default::default(),
+                attachment_resolves: Default::default(),
                 resource_usages: Default::default(),
                 extents,
                 is_external: false,
@@ -1618,7 +1705,11 @@ impl RenderGraph {
                 for read in writing_pass.shader_reads {
                     compiled.resources_used.insert(read);
                 }
-                for write in writing_pass.attachment_writes {
+                for write in writing_pass
+                    .attachment_writes
+                    .into_iter()
+                    .chain(writing_pass.attachment_resolves)
+                {
                     compiled.resources_used.insert(write);
                 }
             } else {
@@ -1691,7 +1782,10 @@ impl RenderGraph {
             compiled
                 .graph_operations
                 .push(GraphOperation::TransitionAttachmentWrite(
-                    pass.attachment_writes.clone(),
+                    pass.attachment_writes
+                        .union(&pass.attachment_resolves)
+                        .cloned()
+                        .collect(),
                 ));
             compiled
                 .graph_operations
@@ -1769,7 +1863,11 @@ impl RenderGraph {
         let mut resource_usages = HashMap::new();
         for pass_id in compiled.pass_sequence.iter() {
             let pass_info = self.passes.get_mut(pass_id).expect("Failed to find pass");
-            for write in &pass_info.attachment_writes {
+            for write in pass_info
+                .attachment_writes
+                .iter()
+                .chain(&pass_info.attachment_resolves)
+            {
                 resource_usages.insert(*write, ResourceLayout::AttachmentWrite);
             }
             for read in &pass_info.attachment_reads {
@@ -1790,7 +1888,11 @@ impl RenderGraph {
         }
         for pass_id in compiled.pass_sequence.iter().rev() {
             let pass_info = self.passes.get_mut(pass_id).expect("Failed to find pass");
-            for write in &pass_info.attachment_writes {
+            for write in pass_info
+                .attachment_writes
+                .iter()
+                .chain(&pass_info.attachment_resolves)
+            {
                 pass_info.resource_usages.entry(*write).or_default().output =
                     *resource_usages.entry(*write).or_default();
             }
@@ -1865,6 +1967,8 @@ impl RenderGraphRunner for GpuRunner {
         resource_allocator: &mut DefaultResourceAllocator,
     ) -> anyhow::Result<()> {
         self.resource_states.clear();
+        self.resource_states
+            .extend(ctx.external_resources.external_image_states.iter());
         resource_allocator.update(ctx.current_iteration);
 
         let label = ctx.command_buffer.begin_debug_region(
@@ -1879,8 +1983,7 @@ impl RenderGraphRunner for GpuRunner {
 
                 // Transition shader reads
                 {
-                    let mut color_transitions = vec![];
-                    let mut depth_stencil_transitions = vec![];
+                    let mut transitions = vec![];
                     for read in &info.shader_reads {
                         let info = graph.get_resource_info(read)?;
                         let image_desc = if let AllocationType::Image(d) = info.ty { d } else { continue; };
@@ -1903,70 +2006,16 @@ impl RenderGraphRunner for GpuRunner {
                         self.resource_states.insert(*read, new_layout);
 
                         let image = Self::get_image_unchecked(&ctx.external_resources, read, resource_allocator);
-                        if image_desc.format.is_color() {
-                            color_transitions.push(ImageMemoryBarrier {
-                                src_access_mask: old_layout.access_mask,
-                                dst_access_mask: new_layout.access_mask,
-                                old_layout: old_layout.layout,
-                                new_layout: new_layout.layout,
-                                src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-                                dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-                                image,
-                                subresource_range: vk::ImageSubresourceRange {
-                                    aspect_mask: ImageAspectFlags::COLOR,
-                                    base_mip_level: 0,
-                                    level_count: 1,
-                                    base_array_layer: 0,
-                                    layer_count: 1,
-                                },
-                            })
-                        } else {
-                            depth_stencil_transitions.push(ImageMemoryBarrier {
-                                src_access_mask: old_layout.access_mask,
-                                dst_access_mask: new_layout.access_mask,
-                                old_layout: old_layout.layout,
-                                new_layout: new_layout.layout,
-                                src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-                                dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-                                image,
-                                subresource_range: vk::ImageSubresourceRange {
-                                    aspect_mask: ImageAspectFlags::DEPTH,
-                                    base_mip_level: 0,
-                                    level_count: 1,
-                                    base_array_layer: 0,
-                                    layer_count: 1,
-                                },
-                            })
-                        }
+                        transitions.push((image, old_layout, new_layout));
                     }
 
-                    if !color_transitions.is_empty() {
-                        ctx.command_buffer.pipeline_barrier(&PipelineBarrierInfo {
-                            src_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-                            dst_stage_mask: PipelineStageFlags::VERTEX_SHADER | PipelineStageFlags::FRAGMENT_SHADER,
-                            dependency_flags: Default::default(),
-                            memory_barriers: &[],
-                            buffer_memory_barriers: &[],
-                            image_memory_barriers: &color_transitions,
-                        })
-                    }
-                    if !depth_stencil_transitions.is_empty() {
-                        ctx.command_buffer.pipeline_barrier(&PipelineBarrierInfo {
-                            src_stage_mask: PipelineStageFlags::EARLY_FRAGMENT_TESTS,
-                            dst_stage_mask: PipelineStageFlags::VERTEX_SHADER | PipelineStageFlags::FRAGMENT_SHADER,
-                            dependency_flags: Default::default(),
-                            memory_barriers: &[],
-                            buffer_memory_barriers: &[],
-                            image_memory_barriers: &color_transitions,
-                        })
-                    }
+                    ctx.command_buffer.transition_images(&transitions);
                 }
                 
                 // Transition attach write 
                 {
-                    let mut color_transitions = vec![];
-                    let mut depth_stencil_transitions = vec![];
-                    for read in &info.attachment_writes {
+                    let mut transitions = vec![];
+                    for read in info.attachment_writes.iter().chain(&info.attachment_resolves) {
                         let info = graph.get_resource_info(read)?;
                         let image_desc = if let AllocationType::Image(d) = info.ty { d } else { continue; };
                         let old_layout = *self.resource_states.entry(*read).or_insert(TransitionInfo {
@@ -2001,70 +2050,16 @@ impl RenderGraphRunner for GpuRunner {
                         self.resource_states.insert(*read, new_layout);
 
                         let image = Self::get_image_unchecked(&ctx.external_resources, read, resource_allocator);
-                        if image_desc.format.is_color() {
-                            color_transitions.push(ImageMemoryBarrier {
-                                src_access_mask: old_layout.access_mask,
-                                dst_access_mask: new_layout.access_mask,
-                                old_layout: old_layout.layout,
-                                new_layout: new_layout.layout,
-                                src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-                                dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-                                image,
-                                subresource_range: vk::ImageSubresourceRange {
-                                    aspect_mask: ImageAspectFlags::COLOR,
-                                    base_mip_level: 0,
-                                    level_count: 1,
-                                    base_array_layer: 0,
-                                    layer_count: 1,
-                                },
-                            })
-                        } else {
-                            depth_stencil_transitions.push(ImageMemoryBarrier {
-                                src_access_mask: old_layout.access_mask,
-                                dst_access_mask: new_layout.access_mask,
-                                old_layout: old_layout.layout,
-                                new_layout: new_layout.layout,
-                                src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-                                dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-                                image,
-                                subresource_range: vk::ImageSubresourceRange {
-                                    aspect_mask: ImageAspectFlags::DEPTH,
-                                    base_mip_level: 0,
-                                    level_count: 1,
-                                    base_array_layer: 0,
-                                    layer_count: 1,
-                                },
-                            })
-                        }
+                        transitions.push((image, old_layout, new_layout));
                     }
 
-                    if !color_transitions.is_empty() {
-                        ctx.command_buffer.pipeline_barrier(&PipelineBarrierInfo {
-                            src_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-                            dst_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-                            dependency_flags: Default::default(),
-                            memory_barriers: &[],
-                            buffer_memory_barriers: &[],
-                            image_memory_barriers: &color_transitions,
-                        })
-                    }
-                    if !depth_stencil_transitions.is_empty() {
-                        ctx.command_buffer.pipeline_barrier(&PipelineBarrierInfo {
-                            src_stage_mask: PipelineStageFlags::EARLY_FRAGMENT_TESTS,
-                            dst_stage_mask: PipelineStageFlags::LATE_FRAGMENT_TESTS,
-                            dependency_flags: Default::default(),
-                            memory_barriers: &[],
-                            buffer_memory_barriers: &[],
-                            image_memory_barriers: &color_transitions,
-                        })
-                    }
+                    ctx.command_buffer.transition_images(&transitions);
                 }
                 
                 // Transition attach read
                 {
 
-                    let mut color_transitions = vec![];
-                    let mut depth_stencil_transitions = vec![];
+                    let mut transitions = vec![];
                     for read in &info.attachment_reads {
                         let info = graph.get_resource_info(read)?;
                         let image_desc = if let AllocationType::Image(d) = info.ty { d } else { continue; };
@@ -2098,63 +2093,10 @@ impl RenderGraphRunner for GpuRunner {
                         self.resource_states.insert(*read, new_layout);
 
                         let image = Self::get_image_unchecked(&ctx.external_resources, read, resource_allocator);
-                        if image_desc.format.is_color() {
-                            color_transitions.push(ImageMemoryBarrier {
-                                src_access_mask: old_layout.access_mask,
-                                dst_access_mask: new_layout.access_mask,
-                                old_layout: old_layout.layout,
-                                new_layout: new_layout.layout,
-                                src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-                                dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-                                image,
-                                subresource_range: vk::ImageSubresourceRange {
-                                    aspect_mask: ImageAspectFlags::COLOR,
-                                    base_mip_level: 0,
-                                    level_count: 1,
-                                    base_array_layer: 0,
-                                    layer_count: 1,
-                                },
-                            })
-                        } else {
-                            depth_stencil_transitions.push(ImageMemoryBarrier {
-                                src_access_mask: old_layout.access_mask,
-                                dst_access_mask: new_layout.access_mask,
-                                old_layout: old_layout.layout,
-                                new_layout: new_layout.layout,
-                                src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-                                dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
-                                image,
-                                subresource_range: vk::ImageSubresourceRange {
-                                    aspect_mask: ImageAspectFlags::DEPTH,
-                                    base_mip_level: 0,
-                                    level_count: 1,
-                                    base_array_layer: 0,
-                                    layer_count: 1,
-                                },
-                            })
-                        }
+                        transitions.push((image, old_layout, new_layout));
                     }
 
-                    if !color_transitions.is_empty() {
-                        ctx.command_buffer.pipeline_barrier(&PipelineBarrierInfo {
-                            src_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-                            dst_stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-                            dependency_flags: Default::default(),
-                            memory_barriers: &[],
-                            buffer_memory_barriers: &[],
-                            image_memory_barriers: &color_transitions,
-                        })
-                    }
-                    if !depth_stencil_transitions.is_empty() {
-                        ctx. command_buffer.pipeline_barrier(&PipelineBarrierInfo {
-                            src_stage_mask: PipelineStageFlags::EARLY_FRAGMENT_TESTS,
-                            dst_stage_mask: PipelineStageFlags::ALL_GRAPHICS,
-                            dependency_flags: Default::default(),
-                            memory_barriers: &[],
-                            buffer_memory_barriers: &[],
-                            image_memory_barriers: &color_transitions,
-                        })
-                    }
+                    ctx.command_buffer.transition_images(&transitions);
                 }
                 let (color_views, depth_view, stencil_view) = resolve_render_image_views_unchecked(
                     info,
@@ -2174,6 +2116,9 @@ impl RenderGraphRunner for GpuRunner {
                 );
 
                 let cb = ctx.callbacks.callbacks.get_mut(rp);
+                if let Some(timer) = ctx.pass_timer.as_deref_mut() {
+                    timer.begin_pass(ctx.command_buffer, rp.label);
+                }
                 let render_pass_label = ctx.command_buffer.begin_debug_region(
 
                     &format!("Begin Render Pass: {}", rp.label),
@@ -2214,6 +2159,10 @@ impl RenderGraphRunner for GpuRunner {
                     cb(ctx.gpu, &mut context);
                 }
                 render_pass_label.end();
+                drop(context);
+                if let Some(timer) = ctx.pass_timer.as_deref_mut() {
+                    timer.end_pass(ctx.command_buffer);
+                }
             }
         }
         if let Some(end_cb) = &mut ctx.callbacks.end_callback {
@@ -2235,7 +2184,7 @@ fn ensure_graph_allocated_resources_exist(
     graph: &RenderGraph,
     resource_allocator: &mut DefaultResourceAllocator,
 ) -> Result<(), anyhow::Error> {
-    for writes in &info.attachment_writes {
+    for writes in info.attachment_writes.iter().chain(&info.attachment_resolves) {
         if !ctx
             .external_resources
             .external_shader_resources
@@ -2306,16 +2255,26 @@ fn ensure_graph_allocated_samplers_exists(
     resource_allocator: &mut DefaultResourceAllocator,
 ) -> Result<(), anyhow::Error> {
     for writes in &info.shader_reads {
-        if !ctx
-            .external_resources
-            .external_shader_resources
-            .contains_key(writes)
-        {
-            match &graph.allocations[writes].ty {
-                AllocationType::Image(d) => *d,
-                AllocationType::Buffer { .. } => panic!("A buffer cannot have a sampler! Bug?"),
-            };
-            resource_allocator.samplers.get(ctx, &NoDesc, writes)?;
+        match &graph.allocations[writes].ty {
+            // External images are sampled through a graph allocated sampler too
+            AllocationType::Image(_) => {
+                if !ctx
+                    .external_resources
+                    .external_samplers
+                    .contains_key(writes)
+                {
+                    resource_allocator.samplers.get(ctx, &NoDesc, writes)?;
+                }
+            }
+            AllocationType::Buffer { .. } => {
+                if !ctx
+                    .external_resources
+                    .external_shader_resources
+                    .contains_key(writes)
+                {
+                    panic!("A buffer cannot have a sampler! Bug?")
+                }
+            }
         };
     }
     Ok(())
@@ -2356,6 +2315,7 @@ where
                 load_op: image_desc.clear_value.color_op(),
                 store_op: gpu::AttachmentStoreOp::Store,
                 initial_layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                resolve: None,
             });
         } else if view.format().is_depth() {
             depth = Some(DepthAttachment {
@@ -2363,13 +2323,24 @@ where
                 load_op: image_desc.clear_value.depth_op(),
                 store_op: gpu::AttachmentStoreOp::Store,
                 initial_layout: ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                resolve: None,
             });
+            if view.format().has_stencil() {
+                stencil = Some(StencilAttachment {
+                    image_view: view,
+                    load_op: image_desc.clear_value.stencil_op(),
+                    store_op: gpu::AttachmentStoreOp::Store,
+                    initial_layout: ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                    resolve: None,
+                });
+            }
         } else {
             stencil = Some(StencilAttachment {
                 image_view: view,
                 load_op: image_desc.clear_value.stencil_op(),
                 store_op: gpu::AttachmentStoreOp::Store,
                 initial_layout: ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                resolve: None,
             });
         }
     }
@@ -2391,6 +2362,7 @@ where
                 load_op: ColorLoadOp::Load,
                 store_op: gpu::AttachmentStoreOp::Store,
                 initial_layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                resolve: None,
             });
         } else if view.format().is_depth() {
             depth = Some(DepthAttachment {
@@ -2398,14 +2370,64 @@ where
                 load_op: DepthLoadOp::Load,
                 store_op: gpu::AttachmentStoreOp::Store,
                 initial_layout: ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                resolve: None,
             });
+            if view.format().has_stencil() {
+                stencil = Some(StencilAttachment {
+                    image_view: view,
+                    load_op: StencilLoadOp::Load,
+                    store_op: gpu::AttachmentStoreOp::Store,
+                    initial_layout: ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                    resolve: None,
+                });
+            }
         } else {
             stencil = Some( StencilAttachment {
                 image_view: view,
                 load_op: StencilLoadOp::Load,
                 store_op: gpu::AttachmentStoreOp::Store,
                 initial_layout: ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                resolve: None,
+            });
+        }
+    }
+    let mut resolved_colors = colors.iter_mut();
+    for resolves in &info.attachment_resolves {
+        let resource_info = graph
+            .get_resource_info(resolves)
+            .expect("Resource not found!");
+
+        let view = if resource_info.external {
+            external_resources
+                .get_shader_resource(resolves)
+                .as_image_view()
+        } else {
+            image_views_allocator.get_unchecked(resolves).resource()
+        };
+
+        if view.format().is_color() {
+            let color = resolved_colors
+                .next()
+                .expect("A color resolve has no color attachment to resolve");
+            color.resolve = Some(ColorResolve {
+                image_view: view,
+                layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
             });
+        } else {
+            let depth = depth
+                .as_mut()
+                .expect("A depth resolve has no depth attachment to resolve");
+            // The only mode every device supports, see DepthResolveMode
+            let resolve = DepthResolve {
+                mode: DepthResolveMode::SampleZero,
+                image_view: view,
+                layout: ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            };
+            depth.resolve = Some(resolve);
+            // The stencil materials test the resolved stencil too
+            if let Some(stencil) = stencil.as_mut().filter(|_| view.format().has_stencil()) {
+                stencil.resolve = Some(resolve);
+            }
         }
     }
     (colors, depth, stencil)
@@ -2433,18 +2455,19 @@ fn resolve_input_descriptor_set<'a>(
                 let view = if resource_info.external {
                     ctx.external_resources.external_shader_resources[read].as_image_view()
                 } else {
-                    image_view_allocator.get_unchecked(read).resource()
+                    image_view_allocator.get_unchecked(read).sampled_view()
+                };
+                let sampler = match ctx.external_resources.external_samplers.get(read) {
+                    Some(sampler) => *sampler,
+                    None => sampler_allocator.get_unchecked(read).resource(),
                 };
                 view.hash(&mut hasher);
-                descriptors.push(DescriptorInfo {
-                    binding: idx as _,
-                    element_type: gpu::DescriptorType::CombinedImageSampler(gpu::SamplerState {
-                        sampler: sampler_allocator.get_unchecked(read).resource(),
-                        image_view: view,
-                        image_layout: ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-                    }),
-                    binding_stage: gpu::ShaderStage::VertexFragment,
-                })
+                descriptors.push(DescriptorInfo::combined_image_sampler(
+                    idx as _,
+                    sampler,
+                    view,
+                    gpu::ShaderStage::VertexFragment,
+                ))
             }
             AllocationType::Buffer(desc) => {
                 let buffer = if resource_info.external {
@@ -2453,19 +2476,18 @@ fn resolve_input_descriptor_set<'a>(
                     buffer_allocator.get_unchecked(read).resource()
                 };
                 buffer.hash(&mut hasher);
-                let range = BufferRange {
-                    handle: buffer,
-                    offset: 0,
-                    size: vk::WHOLE_SIZE,
-                };
-                descriptors.push(DescriptorInfo {
-                    binding: idx as _,
-                    element_type: if desc.ty == BufferType::Uniform {
-                        gpu::DescriptorType::UniformBuffer(range)
-                    } else {
-                        gpu::DescriptorType::StorageBuffer(range)
-                    },
-                    binding_stage: gpu::ShaderStage::VertexFragment,
+                descriptors.push(if desc.ty == BufferType::Uniform {
+                    DescriptorInfo::uniform_buffer(
+                        idx as _,
+                        buffer,
+                        gpu::ShaderStage::VertexFragment,
+                    )
+                } else {
+                    DescriptorInfo::storage_buffer(
+                        idx as _,
+                        buffer,
+                        gpu::ShaderStage::VertexFragment,
+                    )
                 })
             }
         }
@@ -1,5 +1,5 @@
 use ash::vk::{Extent2D, Format};
-use gpu::{CommandBuffer, Gpu, GpuImage, GpuImageView};
+use gpu::{CommandBuffer, Gpu, GpuImage, GpuImageView, ToVk};
 use nalgebra::{Matrix4, Vector3};
 use resource_map::{ResourceHandle, ResourceMap};
 
@@ -10,7 +10,9 @@ struct PerFrameData {
     projection: nalgebra::Matrix4<f32>,
 }
 
-use crate::{mesh::Mesh, Camera, MasterMaterial, MaterialDescription, MaterialInstance};
+use crate::{
+    mesh::Mesh, Camera, MasterMaterial, MaterialDescription, MaterialInstance, ParticleSystem,
+};
 
 #[derive(Clone)]
 pub struct ScenePrimitive {
@@ -37,6 +39,18 @@ pub enum LightType {
     },
 }
 
+/* How often the shadow map of a shadow casting light is rendered again:
+ * Static shadow maps are rendered once and kept until the light changes,
+ * or for directional lights until the camera moves out of their cascades.
+ * Any change to the light marks its shadow map as dirty, see Light::mark_shadow_dirty */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShadowUpdatePolicy {
+    Static,
+    #[default]
+    EveryFrame,
+    EveryNFrames(u32),
+}
+
 #[derive(Clone, Copy, PartialEq)]
 pub struct Light {
     pub ty: LightType,
@@ -46,15 +60,81 @@ pub struct Light {
     pub intensity: f32,
 
     pub enabled: bool,
+
+    // Only directional and spot lights can cast shadows
+    pub casts_shadows: bool,
+    pub shadow_update_policy: ShadowUpdatePolicy,
+    // Bumped by mark_shadow_dirty, forcing the renderer to render the shadow map again
+    pub shadow_version: u32,
+}
+
+impl Default for Light {
+    fn default() -> Self {
+        Self {
+            ty: LightType::Point,
+            position: Vector3::zeros(),
+            radius: 1.0,
+            color: Vector3::repeat(1.0),
+            intensity: 1.0,
+            enabled: true,
+            casts_shadows: false,
+            shadow_update_policy: ShadowUpdatePolicy::default(),
+            shadow_version: 0,
+        }
+    }
 }
 
-#[derive(Clone, Copy, Eq, Ord, PartialOrd, PartialEq)]
-pub struct LightHandle(usize);
+impl Light {
+    pub fn set_shadow_update_policy(&mut self, policy: ShadowUpdatePolicy) {
+        self.shadow_update_policy = policy;
+    }
+
+    /* Cached shadow maps are only rendered again when the light itself changes:
+     * call this when the geometry lit by a Static light moves */
+    pub fn mark_shadow_dirty(&mut self) {
+        self.shadow_version = self.shadow_version.wrapping_add(1);
+    }
+}
+
+/* Identifies a light added to a scene: the slots of the removed lights are reused,
+ * the generation tells a handle to a removed light apart from the light now in its slot */
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub struct LightHandle {
+    index: usize,
+    generation: u32,
+}
 
+#[derive(Default)]
+struct LightSlot {
+    light: Option<Light>,
+    generation: u32,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub struct SceneNodeHandle(usize);
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub struct ParticleSystemHandle(usize);
+
+/* A node of the scene hierarchy: the transform of the primitives attached to a node
+ * is the node's world transform, which gets updated when any of its ancestors moves */
+#[derive(Clone)]
+pub struct SceneNode {
+    pub parent: Option<SceneNodeHandle>,
+    pub children: Vec<SceneNodeHandle>,
+    pub local_transform: Matrix4<f32>,
+    pub primitives: Vec<usize>,
+}
+
+/* The renderer draws the primitives grouped by master material, sorted by the material's name,
+ * and in insertion order inside each group: the order is stable from frame to frame */
 #[derive(Default)]
 pub struct Scene {
     pub primitives: Vec<ScenePrimitive>,
-    pub lights: Vec<Light>,
+    lights: Vec<LightSlot>,
+    free_light_slots: Vec<usize>,
+    nodes: Vec<SceneNode>,
+    particle_systems: Vec<ParticleSystem>,
 }
 
 impl Scene {
@@ -62,6 +142,70 @@ impl Scene {
         Self {
             primitives: vec![],
             lights: vec![],
+            free_light_slots: vec![],
+            nodes: vec![],
+            particle_systems: vec![],
+        }
+    }
+
+    pub fn add_node(
+        &mut self,
+        parent: Option<SceneNodeHandle>,
+        local_transform: Matrix4<f32>,
+    ) -> SceneNodeHandle {
+        let handle = SceneNodeHandle(self.nodes.len());
+        self.nodes.push(SceneNode {
+            parent,
+            children: vec![],
+            local_transform,
+            primitives: vec![],
+        });
+        if let Some(parent) = parent {
+            self.nodes[parent.0].children.push(handle);
+        }
+        handle
+    }
+
+    // The primitive's transform is replaced by the node's world transform
+    pub fn add_to_node(&mut self, node: SceneNodeHandle, mut primitive: ScenePrimitive) -> usize {
+        primitive.transform = self.node_world_transform(node);
+        let idx = self.add(primitive);
+        self.nodes[node.0].primitives.push(idx);
+        idx
+    }
+
+    pub fn node(&self, node: SceneNodeHandle) -> &SceneNode {
+        &self.nodes[node.0]
+    }
+
+    pub fn all_nodes(&self) -> &[SceneNode] {
+        &self.nodes
+    }
+
+    pub fn node_world_transform(&self, node: SceneNodeHandle) -> Matrix4<f32> {
+        let node = &self.nodes[node.0];
+        match node.parent {
+            Some(parent) => self.node_world_transform(parent) * node.local_transform,
+            None => node.local_transform,
+        }
+    }
+
+    pub fn set_node_transform(&mut self, node: SceneNodeHandle, local_transform: Matrix4<f32>) {
+        self.nodes[node.0].local_transform = local_transform;
+        let parent_transform = match self.nodes[node.0].parent {
+            Some(parent) => self.node_world_transform(parent),
+            None => Matrix4::identity(),
+        };
+        self.update_world_transforms(node, parent_transform);
+    }
+
+    fn update_world_transforms(&mut self, node: SceneNodeHandle, parent_transform: Matrix4<f32>) {
+        let world_transform = parent_transform * self.nodes[node.0].local_transform;
+        for &primitive in &self.nodes[node.0].primitives {
+            self.primitives[primitive].transform = world_transform;
+        }
+        for child in self.nodes[node.0].children.clone() {
+            self.update_world_transforms(child, world_transform);
         }
     }
 
@@ -72,26 +216,86 @@ impl Scene {
     }
 
     pub fn add_light(&mut self, light: Light) -> LightHandle {
-        let idx = self.lights.len();
-        self.lights.push(light);
-        LightHandle(idx)
+        let index = self.free_light_slots.pop().unwrap_or_else(|| {
+            self.lights.push(LightSlot::default());
+            self.lights.len() - 1
+        });
+        let slot = &mut self.lights[index];
+        slot.light = Some(light);
+        LightHandle {
+            index,
+            generation: slot.generation,
+        }
+    }
+
+    // Returns the removed light, None if the handle's light was already removed
+    pub fn remove_light(&mut self, handle: &LightHandle) -> Option<Light> {
+        let slot = self.lights.get_mut(handle.index)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        let light = slot.light.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free_light_slots.push(handle.index);
+        Some(light)
+    }
+
+    // None if the handle's light was removed
+    pub fn light(&self, handle: &LightHandle) -> Option<&Light> {
+        self.lights
+            .get(handle.index)
+            .filter(|slot| slot.generation == handle.generation)
+            .and_then(|slot| slot.light.as_ref())
+    }
+
+    pub fn set_light(&mut self, handle: &LightHandle, light: Light) {
+        *self.edit_light(handle) = light;
+    }
+
+    pub fn set_light_enabled(&mut self, handle: &LightHandle, enabled: bool) {
+        self.edit_light(handle).enabled = enabled;
+    }
+
+    pub fn add_particle_system(&mut self, particle_system: ParticleSystem) -> ParticleSystemHandle {
+        let idx = self.particle_systems.len();
+        self.particle_systems.push(particle_system);
+        ParticleSystemHandle(idx)
+    }
+
+    pub fn edit_particle_system(&mut self, handle: &ParticleSystemHandle) -> &mut ParticleSystem {
+        &mut self.particle_systems[handle.0]
+    }
+
+    pub fn all_particle_systems(&self) -> &[ParticleSystem] {
+        &self.particle_systems
+    }
+
+    pub fn update_particle_systems(&mut self, delta_seconds: f32) {
+        for particle_system in &mut self.particle_systems {
+            particle_system.update(delta_seconds);
+        }
     }
 
     pub fn edit(&mut self, idx: usize) -> &mut ScenePrimitive {
         &mut self.primitives[idx]
     }
+    // Panics if the handle's light was removed
     pub fn edit_light(&mut self, handle: &LightHandle) -> &mut Light {
-        &mut self.lights[handle.0]
+        self.lights
+            .get_mut(handle.index)
+            .filter(|slot| slot.generation == handle.generation)
+            .and_then(|slot| slot.light.as_mut())
+            .expect("The light was removed from the scene")
     }
 
     pub fn all_primitives(&self) -> &[ScenePrimitive] {
         &self.primitives
     }
-    pub fn all_lights(&self) -> &[Light] {
-        &self.lights
+    pub fn all_lights(&self) -> impl Iterator<Item = &Light> {
+        self.lights.iter().filter_map(|slot| slot.light.as_ref())
     }
     pub fn all_enabled_lights(&self) -> impl Iterator<Item = &Light> {
-        self.lights.iter().filter(|l| l.enabled)
+        self.all_lights().filter(|l| l.enabled)
     }
 
     pub fn edit_all_primitives(&mut self) -> &mut [ScenePrimitive] {
@@ -106,6 +310,46 @@ pub struct Backbuffer<'a> {
     pub image_view: &'a GpuImageView,
 }
 
+impl Backbuffer<'_> {
+    /* Checks that the image and the view match the backbuffer's size and format:
+     * they can get out of sync when the window is resized before the swapchain is recreated */
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let Extent2D { width, height } = self.size;
+        anyhow::ensure!(
+            width > 0 && height > 0,
+            "The backbuffer has an empty size: {width}x{height}"
+        );
+        for (what, extents, format) in [
+            ("image", self.image.extents(), self.image.format()),
+            (
+                "image view",
+                self.image_view.extents(),
+                self.image_view.format(),
+            ),
+        ] {
+            anyhow::ensure!(
+                extents == self.size,
+                "The backbuffer is {width}x{height}, but its {what} is {}x{}",
+                extents.width,
+                extents.height
+            );
+            anyhow::ensure!(
+                format.to_vk() == self.format,
+                "The backbuffer's format is {:?}, but its {what}'s format is {:?}",
+                self.format,
+                format.to_vk()
+            );
+        }
+        Ok(())
+    }
+
+    /* When the backbuffer is sRGB the hardware encodes the colors written to it,
+     * otherwise the linear colors must be encoded by the shaders */
+    pub fn is_srgb(&self) -> bool {
+        gpu::ImageFormat::from(self.format).is_srgb()
+    }
+}
+
 pub trait RenderingPipeline {
     fn render(
         &mut self,
@@ -143,6 +387,7 @@ impl ForwardRenderingPipeline {
                 label: Some("Forward Renderer - Camera buffer"),
                 size: std::mem::size_of::<PerFrameData>(),
                 usage: BufferUsageFlags::UNIFORM_BUFFER | BufferUsageFlags::TRANSFER_DST,
+                sharing_mode: Default::default(),
             };
             let buffer = gpu.create_buffer(
                 &create_info,
@@ -447,7 +692,7 @@ impl RenderingPipeline for ForwardRenderingPipeline {
             .write_buffer_data(
                 &self.camera_buffer,
                 &[PerFrameData {
-                    view: pov.view(),
+                    view: pov.view_matrix(),
                     projection: pov.projection(),
                 }],
             )
@@ -525,6 +770,7 @@ impl RenderingPipeline for ForwardRenderingPipeline {
                         );
                         let mesh = self.resource_map.get(&primitive.mesh);
 
+                        mesh.buffers.bind(&ctx.render_pass_command);
                         for (idx, mesh_prim) in mesh.primitives.iter().enumerate() {
                             let material = &primitive.materials[idx];
                             let material = self.resource_map.get(material);
@@ -536,30 +782,18 @@ impl RenderingPipeline for ForwardRenderingPipeline {
                                 1,
                                 &[&material.resources_descriptor_set],
                             );
-
-                            ctx.render_pass_command.bind_index_buffer(
-                                &mesh_prim.index_buffer,
-                                0,
-                                IndexType::UINT32,
-                            );
-                            ctx.render_pass_command.bind_vertex_buffer(
-                                0,
-                                &[
-                                    &mesh_prim.position_component,
-                                    &mesh_prim.color_component,
-                                    &mesh_prim.normal_component,
-                                    &mesh_prim.tangent_component,
-                                    &mesh_prim.uv_component,
-                                ],
-                                &[0, 0, 0, 0, 0],
-                            );
                             ctx.render_pass_command.push_constant(
                                 &pipeline,
                                 &primitive.transform,
                                 0,
                             );
-                            ctx.render_pass_command
-                                .draw_indexed(mesh_prim.index_count, 1, 0, 0, 0);
+                            ctx.render_pass_command.draw_indexed(
+                                mesh_prim.index_count,
+                                1,
+                                mesh_prim.first_index,
+                                mesh_prim.vertex_offset as i32,
+                                0,
+                            );
                         }
                         primitive_label.end();
                     }
@@ -583,3 +817,33 @@ impl RenderingPipeline for ForwardRenderingPipeline {
     }
 }
  */
+
+#[cfg(test)]
+mod tests {
+    use super::{Light, Scene};
+
+    #[test]
+    fn removed_light_handles_are_not_reused() {
+        let mut scene = Scene::new();
+        let first = scene.add_light(Light::default());
+        let second = scene.add_light(Light {
+            intensity: 2.0,
+            ..Default::default()
+        });
+        assert!(scene.remove_light(&first).is_some());
+        assert!(scene.remove_light(&first).is_none());
+
+        // The new light takes the removed light's slot
+        let third = scene.add_light(Light {
+            intensity: 3.0,
+            ..Default::default()
+        });
+        assert!(scene.light(&first).is_none());
+        assert_eq!(scene.light(&third).map(|l| l.intensity), Some(3.0));
+
+        scene.set_light_enabled(&second, false);
+        let enabled: Vec<_> = scene.all_enabled_lights().map(|l| l.intensity).collect();
+        assert_eq!(enabled, vec![3.0]);
+        assert_eq!(scene.all_lights().count(), 2);
+    }
+}
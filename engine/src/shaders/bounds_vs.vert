@@ -0,0 +1,33 @@
+#version 460
+
+struct PerFrameData {
+    vec4 eye;
+    mat4 view;
+    mat4 proj;
+};
+
+layout(set = 0, binding = 5) uniform PerFrameDataBlock {
+    PerFrameData pfd;
+} per_frame_data;
+
+layout(push_constant) uniform Bounds {
+    vec4 min;
+    vec4 max;
+} bounds;
+
+// The 12 edges of the box, the corners' bits select max over min on the x, y and z axes
+const uint EDGES[24] = uint[24](
+    0, 1, 2, 3, 4, 5, 6, 7,
+    0, 2, 1, 3, 4, 6, 5, 7,
+    0, 4, 1, 5, 2, 6, 3, 7
+);
+
+void main() {
+    uint corner = EDGES[gl_VertexIndex];
+    vec3 position = vec3(
+        (corner & 1) != 0 ? bounds.max.x : bounds.min.x,
+        (corner & 2) != 0 ? bounds.max.y : bounds.min.y,
+        (corner & 4) != 0 ? bounds.max.z : bounds.min.z
+    );
+    gl_Position = per_frame_data.pfd.proj * per_frame_data.pfd.view * vec4(position, 1.0);
+}
@@ -0,0 +1,63 @@
+#version 460
+
+// The distance between two samples of the spiral grows by RADIUS_STEP / radius
+#define RADIUS_STEP 1.0
+#define GOLDEN_ANGLE 2.39996323
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+layout(binding = 0) uniform sampler2D tex;
+layout(binding = 1) uniform sampler2D depthTex;
+layout(push_constant) uniform DofInput {
+    vec2 rcp_frame;
+    float near;
+    float far;
+    float focus_distance;
+    float aperture;
+    float max_blur_radius;
+} dof_input;
+
+// The camera uses an OpenGL style projection, whose depth goes from -1 to 1
+float linearDepth(vec2 coords) {
+    ivec2 size = textureSize(depthTex, 0);
+    ivec2 texel = clamp(ivec2(coords * vec2(size)), ivec2(0), size - 1);
+    float depth = texelFetch(depthTex, texel, 0).r;
+    float near = dof_input.near;
+    float far = dof_input.far;
+    return 2.0 * near * far / (far + near - depth * (far - near));
+}
+
+// The radius in pixels of the circle of confusion of a point at depth
+float circleOfConfusion(float depth) {
+    float coc = dof_input.aperture * abs(depth - dof_input.focus_distance) / depth;
+    return clamp(coc, 0.0, 1.0) * dof_input.max_blur_radius;
+}
+
+/* Gathers the samples on a golden angle spiral around the pixel: each sample contributes
+ * when its circle of confusion covers the pixel, the samples behind the pixel are limited
+ * by the pixel's own blur so that the background doesn't bleed over the focused surfaces */
+void main() {
+    vec4 center = texture(tex, uv);
+    float centerDepth = linearDepth(uv);
+    float centerCoc = circleOfConfusion(centerDepth);
+
+    vec3 sum = center.rgb;
+    float count = 1.0;
+    float radius = RADIUS_STEP;
+    for (float angle = 0.0; radius < dof_input.max_blur_radius; angle += GOLDEN_ANGLE) {
+        vec2 coords = uv + vec2(cos(angle), sin(angle)) * dof_input.rcp_frame * radius;
+        vec3 sampleColor = texture(tex, coords).rgb;
+        float sampleDepth = linearDepth(coords);
+        float sampleCoc = circleOfConfusion(sampleDepth);
+        if (sampleDepth > centerDepth) {
+            sampleCoc = min(sampleCoc, centerCoc * 2.0);
+        }
+        float weight = smoothstep(radius - 0.5, radius + 0.5, sampleCoc);
+        sum += mix(sum / count, sampleColor, weight);
+        count += 1.0;
+        radius += RADIUS_STEP / radius;
+    }
+    color = vec4(sum / count, center.a);
+}
@@ -0,0 +1,12 @@
+#version 460
+
+layout(location = 0) in vec2 uv;
+layout(location = 1) in vec4 color;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    float distance = length(uv * 2.0 - 1.0);
+    float alpha = color.a * (1.0 - smoothstep(0.5, 1.0, distance));
+    out_color = vec4(color.rgb * alpha, 0.0);
+}
@@ -0,0 +1,43 @@
+#version 460
+
+struct PerFrameData {
+    vec4 eye;
+    mat4 view;
+    mat4 proj;
+};
+
+struct Particle {
+    vec4 position_size;
+    vec4 velocity_life;
+    vec4 color;
+};
+
+layout(set = 0, binding = 0) uniform PerFrameDataBlock {
+    PerFrameData pfd;
+} per_frame_data;
+
+layout(set = 0, binding = 1) readonly buffer ParticleData {
+    Particle particles[];
+} particle_data;
+
+layout(location = 0) out vec2 uv;
+layout(location = 1) out vec4 color;
+
+void main() {
+    vec2[] corners = vec2[4](vec2(-1.0, -1.0), vec2(1.0, -1.0), vec2(-1.0, 1.0), vec2(1.0, 1.0));
+    Particle particle = particle_data.particles[gl_InstanceIndex];
+    mat4 view = per_frame_data.pfd.view;
+
+    // The dead particles are collapsed into a point, which isn't rasterized
+    float size = particle.velocity_life.w > 0.0 ? particle.position_size.w : 0.0;
+
+    // Orient the quad towards the camera using the view's right and up axes
+    vec3 right = vec3(view[0][0], view[1][0], view[2][0]);
+    vec3 up = vec3(view[0][1], view[1][1], view[2][1]);
+    vec2 corner = corners[gl_VertexIndex];
+    vec3 position = particle.position_size.xyz + (right * corner.x + up * corner.y) * size;
+
+    uv = corner * 0.5 + 0.5;
+    color = particle.color;
+    gl_Position = per_frame_data.pfd.proj * view * vec4(position, 1.0);
+}
@@ -0,0 +1,127 @@
+#version 460
+
+// The bisection steps refining a hit between the last two steps of the ray
+#define REFINEMENT_STEPS 4
+// The fraction of the screen over which the reflections fade out near its borders
+#define EDGE_FADE 0.1
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+layout(binding = 0) uniform sampler2D tex;
+layout(binding = 1) uniform sampler2D posSampler;
+layout(binding = 2) uniform sampler2D normSampler;
+layout(binding = 3) uniform sampler2D difSampler;
+layout(binding = 4) uniform sampler2D pbrSampler;
+layout(binding = 5) uniform sampler2D depthTex;
+layout(binding = 6) uniform PerFrameDataBlock {
+    vec4 eye;
+    mat4 view;
+    mat4 proj;
+} pfd;
+layout(push_constant) uniform SsrInput {
+    float near;
+    float far;
+    uint max_steps;
+    float step_size;
+    float thickness;
+    float max_roughness;
+} ssr_input;
+
+// Fetched without filtering, not every device can filter depth images
+float rawDepth(vec2 coords) {
+    ivec2 size = textureSize(depthTex, 0);
+    ivec2 texel = clamp(ivec2(coords * vec2(size)), ivec2(0), size - 1);
+    return texelFetch(depthTex, texel, 0).r;
+}
+
+// The camera uses an OpenGL style projection, whose depth goes from -1 to 1
+float linearDepth(vec2 coords) {
+    float depth = rawDepth(coords);
+    float near = ssr_input.near;
+    float far = ssr_input.far;
+    return 2.0 * near * far / (far + near - depth * (far - near));
+}
+
+// The screen coordinates of a world space position, with its view depth in z
+vec3 project(vec3 position) {
+    vec4 viewPosition = pfd.view * vec4(position, 1.0);
+    vec4 clip = pfd.proj * viewPosition;
+    return vec3(clip.xy / clip.w * 0.5 + 0.5, -viewPosition.z);
+}
+
+bool onScreen(vec3 projected) {
+    return projected.z > ssr_input.near
+        && all(greaterThanEqual(projected.xy, vec2(0.0)))
+        && all(lessThanEqual(projected.xy, vec2(1.0)));
+}
+
+/* Marches the ray in world space, a step hits when it ends behind the depth buffer
+ * by less than the thickness given to the surfaces */
+bool traceRay(vec3 origin, vec3 direction, out vec2 hitCoords) {
+    vec3 previous = origin;
+    for (uint i = 0; i < ssr_input.max_steps; i++) {
+        vec3 current = previous + direction * ssr_input.step_size;
+        vec3 projected = project(current);
+        if (!onScreen(projected)) {
+            return false;
+        }
+        float delta = projected.z - linearDepth(projected.xy);
+        if (delta > 0.0 && delta < ssr_input.thickness) {
+            vec3 front = previous;
+            vec3 back = current;
+            for (int j = 0; j < REFINEMENT_STEPS; j++) {
+                vec3 middle = (front + back) * 0.5;
+                vec3 projectedMiddle = project(middle);
+                if (projectedMiddle.z > linearDepth(projectedMiddle.xy)) {
+                    back = middle;
+                } else {
+                    front = middle;
+                }
+            }
+            hitCoords = project(back).xy;
+            return true;
+        }
+        previous = current;
+    }
+    return false;
+}
+
+/* Adds the reflected lit frame to the glossy surfaces, weighted by their fresnel term.
+ * The pixels without a surface and the rays that miss keep the lit color */
+void main() {
+    vec4 lit = texture(tex, uv);
+    color = lit;
+    if (rawDepth(uv) >= 1.0) {
+        return;
+    }
+
+    vec4 pbr = texture(pbrSampler, uv);
+    float metalness = pbr.x;
+    float roughness = pbr.y;
+    if (roughness >= ssr_input.max_roughness) {
+        return;
+    }
+
+    vec3 position = texture(posSampler, uv).xyz;
+    vec3 normal = normalize(texture(normSampler, uv).xyz * 2.0 - 1.0);
+    vec3 view = normalize(position - pfd.eye.xyz);
+    vec3 direction = reflect(view, normal);
+
+    // Starting above the surface keeps the ray from hitting the surface it leaves from
+    vec2 hitCoords;
+    if (!traceRay(position + normal * ssr_input.thickness, direction, hitCoords)) {
+        return;
+    }
+
+    vec3 reflection = texture(tex, hitCoords).rgb;
+    vec3 f0 = mix(vec3(0.04), texture(difSampler, uv).rgb, metalness);
+    float cosTheta = clamp(dot(-view, normal), 0.0, 1.0);
+    vec3 fresnel = f0 + (1.0 - f0) * pow(1.0 - cosTheta, 5.0);
+    float glossiness = 1.0 - roughness / ssr_input.max_roughness;
+    vec2 edges = smoothstep(0.0, EDGE_FADE, hitCoords)
+        * (1.0 - smoothstep(1.0 - EDGE_FADE, 1.0, hitCoords));
+    float fade = glossiness * glossiness * edges.x * edges.y;
+    color = vec4(lit.rgb + reflection * fresnel * fade, lit.a);
+}
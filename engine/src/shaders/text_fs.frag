@@ -0,0 +1,25 @@
+#version 460
+
+layout(set = 0, binding = 1) uniform sampler2D font_atlas;
+
+layout(push_constant) uniform TextParams {
+    vec2 target_size;
+    uint is_sdf;
+} params;
+
+layout(location = 0) in vec2 uv;
+layout(location = 1) in vec4 color;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    vec4 texel = texture(font_atlas, uv);
+    float coverage = texel.a;
+    if (params.is_sdf != 0) {
+        // The edge of the glyph is at 0.5, antialiased over about a pixel
+        float width = max(fwidth(texel.a) * 0.5, 1e-4);
+        coverage = smoothstep(0.5 - width, 0.5 + width, texel.a);
+        texel.rgb = vec3(1.0);
+    }
+    out_color = vec4(color.rgb * texel.rgb, color.a * coverage);
+}
@@ -0,0 +1,31 @@
+#version 460
+
+struct Glyph {
+    vec4 rect;
+    vec4 uv_rect;
+    vec4 color;
+};
+
+layout(set = 0, binding = 0) readonly buffer GlyphData {
+    Glyph glyphs[];
+} glyph_data;
+
+layout(push_constant) uniform TextParams {
+    vec2 target_size;
+    uint is_sdf;
+} params;
+
+layout(location = 0) out vec2 uv;
+layout(location = 1) out vec4 color;
+
+void main() {
+    vec2[] corners = vec2[4](vec2(0.0, 0.0), vec2(1.0, 0.0), vec2(0.0, 1.0), vec2(1.0, 1.0));
+    Glyph glyph = glyph_data.glyphs[gl_InstanceIndex];
+    vec2 corner = corners[gl_VertexIndex];
+
+    // The glyphs are placed in pixels, from the target's top left corner
+    vec2 position = glyph.rect.xy + corner * glyph.rect.zw;
+    uv = mix(glyph.uv_rect.xy, glyph.uv_rect.zw, corner);
+    color = glyph.color;
+    gl_Position = vec4(position / params.target_size * 2.0 - 1.0, 0.0, 1.0);
+}
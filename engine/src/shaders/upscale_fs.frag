@@ -0,0 +1,71 @@
+#version 460
+
+#define UPSCALER_BILINEAR 0
+#define UPSCALER_LANCZOS 1
+
+#define PI 3.14159265359
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 color;
+
+layout(binding = 0) uniform sampler2D tex;
+layout(push_constant) uniform UpscaleInput {
+    uint mode;
+    float sharpness;
+} upscale_input;
+
+float lanczos2(float x) {
+    x = abs(x);
+    if (x < 1e-5) {
+        return 1.0;
+    }
+    if (x >= 2.0) {
+        return 0.0;
+    }
+    float px = PI * x;
+    return 2.0 * sin(px) * sin(px * 0.5) / (px * px);
+}
+
+// A 4x4 Lanczos 2 filter, minColor and maxColor are the bounds of the 2x2 texels nearest to coords
+vec3 lanczos(vec2 coords, out vec3 minColor, out vec3 maxColor) {
+    ivec2 size = textureSize(tex, 0);
+    vec2 pos = coords * vec2(size) - 0.5;
+    vec2 base = floor(pos);
+    vec2 f = pos - base;
+
+    vec3 sum = vec3(0.0);
+    float weights = 0.0;
+    minColor = vec3(1.0e9);
+    maxColor = vec3(-1.0e9);
+    for (int y = -1; y <= 2; y++) {
+        for (int x = -1; x <= 2; x++) {
+            ivec2 texel = clamp(ivec2(base) + ivec2(x, y), ivec2(0), size - 1);
+            vec3 texelColor = texelFetch(tex, texel, 0).rgb;
+            float weight = lanczos2(float(x) - f.x) * lanczos2(float(y) - f.y);
+            sum += texelColor * weight;
+            weights += weight;
+            if (x >= 0 && x <= 1 && y >= 0 && y <= 1) {
+                minColor = min(minColor, texelColor);
+                maxColor = max(maxColor, texelColor);
+            }
+        }
+    }
+    return sum / weights;
+}
+
+void main() {
+    vec4 bilinear = texture(tex, uv);
+    if (upscale_input.mode == UPSCALER_BILINEAR) {
+        color = bilinear;
+        return;
+    }
+
+    vec3 minColor;
+    vec3 maxColor;
+    vec3 filtered = lanczos(uv, minColor, maxColor);
+    // Pushes the result away from the blurrier bilinear sample
+    vec3 sharpened = filtered + upscale_input.sharpness * (filtered - bilinear.rgb);
+    // The negative lobes of the filter ring around the edges: clamping to the nearest texels hides it
+    color = vec4(clamp(sharpened, minColor, maxColor), bilinear.a);
+}
@@ -0,0 +1,465 @@
+use ash::vk::{Extent2D, Offset2D, Rect2D};
+use nalgebra::{vector, Matrix4, Point3, Vector3, Vector4};
+
+use crate::{BoundingBox, Camera, Light, LightType, ShadowUpdatePolicy};
+
+// The shadow maps are tiles of a single depth atlas, one tile per shadow casting light
+pub(crate) const SHADOW_ATLAS_SIZE: u32 = 4096;
+pub(crate) const SHADOW_MAP_SIZE: u32 = 1024;
+const SHADOW_MAPS_PER_ROW: u32 = SHADOW_ATLAS_SIZE / SHADOW_MAP_SIZE;
+pub(crate) const MAX_SHADOW_MAPS: usize = (SHADOW_MAPS_PER_ROW * SHADOW_MAPS_PER_ROW) as usize;
+pub(crate) const MAX_CASCADES: usize = 4;
+
+const SPOT_SHADOW_NEAR: f32 = 0.1;
+/* The cascades of the lights that aren't rendered every frame cover a larger sphere than their
+ * frustum slice, so that the camera can move a bit before they need to be fitted again */
+const CACHED_CASCADE_MARGIN: f32 = 1.25;
+
+// Maps the clip space depth from [-1, 1] to [0, 1]
+const DEPTH_CORRECTION: Matrix4<f32> = Matrix4::new(
+    1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.5, 0.5, 0.0, 0.0, 0.0, 1.0,
+);
+
+pub(crate) fn casts_shadows(light: &Light) -> bool {
+    light.casts_shadows
+        && matches!(
+            light.ty,
+            LightType::Directional { .. } | LightType::Spotlight { .. }
+        )
+}
+
+/* The view depths where each cascade ends, splitting [near, far] in count slices:
+ * lambda blends between uniform (0.0) and logarithmic (1.0) splits */
+pub(crate) fn cascade_splits(near: f32, far: f32, count: usize, lambda: f32) -> Vec<f32> {
+    (1..=count)
+        .map(|i| {
+            let fraction = i as f32 / count as f32;
+            let logarithmic = near * (far / near).powf(fraction);
+            let uniform = near + (far - near) * fraction;
+            lambda * logarithmic + (1.0 - lambda) * uniform
+        })
+        .collect()
+}
+
+// A shadow map stored in the atlas: directional lights have one for each cascade
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct ShadowMapSource {
+    pub light: Light,
+    // The view depth range covered by a directional light's cascade
+    pub cascade: Option<(f32, f32)>,
+    // The bounding sphere of the camera's frustum slice between the cascade's depths
+    pub view_slice: Option<(Point3<f32>, f32)>,
+}
+
+pub(crate) fn shadow_map_sources(
+    light: &Light,
+    pov: &Camera,
+    splits: &[f32],
+) -> Vec<ShadowMapSource> {
+    match light.ty {
+        LightType::Directional { .. } => std::iter::once(pov.near)
+            .chain(splits.iter().copied())
+            .zip(splits.iter().copied())
+            .map(|(cascade_near, cascade_far)| ShadowMapSource {
+                light: *light,
+                cascade: Some((cascade_near, cascade_far)),
+                view_slice: Some(frustum_slice_sphere(pov, cascade_near, cascade_far)),
+            })
+            .collect(),
+        _ => vec![ShadowMapSource {
+            light: *light,
+            cascade: None,
+            view_slice: None,
+        }],
+    }
+}
+
+// The area of the atlas the shadow map in slot is rendered to
+pub(crate) fn shadow_map_rect(slot: usize) -> Rect2D {
+    let slot = slot as u32;
+    Rect2D {
+        offset: Offset2D {
+            x: ((slot % SHADOW_MAPS_PER_ROW) * SHADOW_MAP_SIZE) as i32,
+            y: ((slot / SHADOW_MAPS_PER_ROW) * SHADOW_MAP_SIZE) as i32,
+        },
+        extent: Extent2D {
+            width: SHADOW_MAP_SIZE,
+            height: SHADOW_MAP_SIZE,
+        },
+    }
+}
+
+// The shadow map rect in atlas uv coordinates, as (offset, size)
+pub(crate) fn shadow_map_uv_rect(slot: usize) -> Vector4<f32> {
+    let rect = shadow_map_rect(slot);
+    let atlas_size = SHADOW_ATLAS_SIZE as f32;
+    let size = SHADOW_MAP_SIZE as f32 / atlas_size;
+    vector![
+        rect.offset.x as f32 / atlas_size,
+        rect.offset.y as f32 / atlas_size,
+        size,
+        size
+    ]
+}
+
+pub(crate) struct ShadowCamera {
+    pub eye: Point3<f32>,
+    pub view: Matrix4<f32>,
+    pub projection: Matrix4<f32>,
+    // The sphere a directional light's cascade was fitted to, None when it covers the whole scene
+    pub covered: Option<(Point3<f32>, f32)>,
+}
+
+// The bounding sphere of the slice of the camera's frustum between near and far
+fn frustum_slice_sphere(pov: &Camera, near: f32, far: f32) -> (Point3<f32>, f32) {
+    let forward = pov
+        .forward
+        .try_normalize(f32::EPSILON)
+        .unwrap_or(-Vector3::z());
+    let right = forward
+        .cross(&Vector3::y())
+        .try_normalize(f32::EPSILON)
+        .unwrap_or(Vector3::x());
+    let up = right.cross(&forward);
+    let tan_half_fov = (pov.fov * 0.5).tan();
+    let aspect = pov.width / pov.height;
+    let corners: Vec<Point3<f32>> = [near, far]
+        .iter()
+        .flat_map(|&depth| {
+            let center = pov.location + forward * depth;
+            let up = up * depth * tan_half_fov;
+            let right = right * depth * tan_half_fov * aspect;
+            [
+                center + right + up,
+                center + right - up,
+                center - right + up,
+                center - right - up,
+            ]
+        })
+        .collect();
+    let center = Point3::from(
+        corners
+            .iter()
+            .fold(Vector3::zeros(), |sum, corner| sum + corner.coords)
+            / corners.len() as f32,
+    );
+    let radius = corners
+        .iter()
+        .map(|corner| (corner - center).norm())
+        .fold(0.0, f32::max);
+    (center, radius)
+}
+
+/* The camera a shadow map is rendered from: directional lights use an orthographic
+ * projection fitted to their cascade (or to the whole scene when it's smaller),
+ * spot lights a perspective projection covering their outer cone.
+ * Other lights don't cast shadows */
+pub(crate) fn shadow_camera(
+    source: &ShadowMapSource,
+    scene_bounds: &BoundingBox,
+) -> Option<ShadowCamera> {
+    let light = &source.light;
+    let mut covered = None;
+    let (eye, direction, projection) = match light.ty {
+        LightType::Directional { direction } => {
+            let direction = direction
+                .try_normalize(f32::EPSILON)
+                .unwrap_or(-Vector3::y());
+            let scene_center = scene_bounds.center();
+            let scene_radius = scene_bounds.extents().norm().max(0.01);
+            let margin = match light.shadow_update_policy {
+                ShadowUpdatePolicy::EveryFrame => 1.0,
+                _ => CACHED_CASCADE_MARGIN,
+            };
+            covered = source
+                .view_slice
+                .map(|(center, radius)| (center, radius * margin))
+                .filter(|(_, radius)| *radius < scene_radius);
+            let (center, radius) = covered.unwrap_or((scene_center, scene_radius));
+            // Move the eye back enough to catch every caster in the scene
+            let distance = ((center - scene_center).norm() + scene_radius).max(radius);
+            let eye = center - direction * distance;
+            let projection =
+                Matrix4::new_orthographic(-radius, radius, -radius, radius, 0.0, distance + radius);
+            (eye, direction, projection)
+        }
+        LightType::Spotlight {
+            direction,
+            outer_cone_degrees,
+            ..
+        } => {
+            let direction = direction
+                .try_normalize(f32::EPSILON)
+                .unwrap_or(-Vector3::y());
+            let fov = (outer_cone_degrees * 2.0).clamp(1.0, 170.0).to_radians();
+            let projection = Matrix4::new_perspective(
+                1.0,
+                fov,
+                SPOT_SHADOW_NEAR,
+                light.radius.max(SPOT_SHADOW_NEAR * 2.0),
+            );
+            (Point3::from(light.position), direction, projection)
+        }
+        _ => return None,
+    };
+    // look_at_rh can't handle a direction parallel to the up vector
+    let up = if direction.y.abs() > 0.99 {
+        Vector3::x()
+    } else {
+        Vector3::y()
+    };
+    Some(ShadowCamera {
+        eye,
+        view: Matrix4::look_at_rh(&eye, &(eye + direction), &up),
+        projection: DEPTH_CORRECTION * projection,
+        covered,
+    })
+}
+
+#[derive(Clone, Copy)]
+struct CachedShadowMap {
+    // The source as it was when the shadow map was rendered, used to detect changes
+    source: ShadowMapSource,
+    view_projection: Matrix4<f32>,
+    // See ShadowCamera::covered
+    covered: Option<(Point3<f32>, f32)>,
+    rendered_frame: u64,
+}
+
+/* Keeps track of the shadow maps stored in the atlas, deciding which ones are rendered
+ * each frame: the i-th shadow map source owns the i-th slot of the atlas.
+ * Whatever their policy, cascades are fitted again once the camera's frustum slice
+ * leaves the sphere they were rendered for */
+#[derive(Default)]
+pub(crate) struct ShadowMapCache {
+    slots: Vec<Option<CachedShadowMap>>,
+    // Where the round robin starts from on the next frame
+    next_slot: usize,
+}
+
+impl ShadowMapCache {
+    /* Picks at most max_updates of the slots that need to be rendered this frame,
+     * in round robin order so that every light eventually gets its turn.
+     * A slot needs to be rendered when its source changed or when its policy asks for it */
+    pub fn schedule(
+        &mut self,
+        sources: &[ShadowMapSource],
+        frame: u64,
+        max_updates: usize,
+    ) -> Vec<usize> {
+        self.slots.resize(sources.len(), None);
+        let mut updates = vec![];
+        if sources.is_empty() {
+            return updates;
+        }
+        let start = self.next_slot % sources.len();
+        for offset in 0..sources.len() {
+            if updates.len() >= max_updates {
+                break;
+            }
+            let slot = (start + offset) % sources.len();
+            if self.needs_update(slot, &sources[slot], frame) {
+                updates.push(slot);
+                self.next_slot = slot + 1;
+            }
+        }
+        updates
+    }
+
+    pub fn mark_rendered(
+        &mut self,
+        slot: usize,
+        source: &ShadowMapSource,
+        view_projection: Matrix4<f32>,
+        covered: Option<(Point3<f32>, f32)>,
+        frame: u64,
+    ) {
+        self.slots[slot] = Some(CachedShadowMap {
+            source: *source,
+            view_projection,
+            covered,
+            rendered_frame: frame,
+        });
+    }
+
+    // The view projection the slot's shadow map was rendered with, None if it was never rendered
+    pub fn view_projection(&self, slot: usize) -> Option<Matrix4<f32>> {
+        self.slots
+            .get(slot)
+            .copied()
+            .flatten()
+            .map(|cached| cached.view_projection)
+    }
+
+    fn needs_update(&self, slot: usize, source: &ShadowMapSource, frame: u64) -> bool {
+        let Some(cached) = &self.slots[slot] else {
+            return true;
+        };
+        // The view slice moves with the camera, it only matters when it leaves the cascade
+        let changed = cached.source.light != source.light
+            || cached.source.cascade != source.cascade
+            || Self::left_cascade(cached.covered, source.view_slice);
+        changed
+            || match source.light.shadow_update_policy {
+                ShadowUpdatePolicy::Static => false,
+                ShadowUpdatePolicy::EveryFrame => true,
+                ShadowUpdatePolicy::EveryNFrames(n) => {
+                    frame.saturating_sub(cached.rendered_frame) >= n.max(1) as u64
+                }
+            }
+    }
+
+    // Whether the view slice isn't contained in the sphere covered by a cascade anymore
+    fn left_cascade(
+        covered: Option<(Point3<f32>, f32)>,
+        view_slice: Option<(Point3<f32>, f32)>,
+    ) -> bool {
+        match (covered, view_slice) {
+            (Some((center, radius)), Some((slice_center, slice_radius))) => {
+                (slice_center - center).norm() + slice_radius > radius
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{point, vector, Matrix4};
+
+    use super::{
+        cascade_splits, shadow_camera, shadow_map_sources, ShadowMapCache, ShadowMapSource,
+    };
+    use crate::{BoundingBox, Camera, Light, LightType, ShadowUpdatePolicy};
+
+    fn caster(policy: ShadowUpdatePolicy) -> Light {
+        Light {
+            ty: LightType::Directional {
+                direction: vector![0.0, -1.0, 0.0],
+            },
+            casts_shadows: true,
+            shadow_update_policy: policy,
+            ..Default::default()
+        }
+    }
+
+    fn run_frame(
+        cache: &mut ShadowMapCache,
+        casters: &[Light],
+        frame: u64,
+        max: usize,
+    ) -> Vec<usize> {
+        let sources: Vec<ShadowMapSource> = casters
+            .iter()
+            .map(|light| ShadowMapSource {
+                light: *light,
+                cascade: None,
+                view_slice: None,
+            })
+            .collect();
+        let updates = cache.schedule(&sources, frame, max);
+        for &slot in &updates {
+            cache.mark_rendered(slot, &sources[slot], Matrix4::identity(), None, frame);
+        }
+        updates
+    }
+
+    #[test]
+    fn static_shadows_are_cached() {
+        let mut cache = ShadowMapCache::default();
+        let mut casters = [caster(ShadowUpdatePolicy::Static)];
+        assert_eq!(run_frame(&mut cache, &casters, 0, 4), vec![0]);
+        assert!(run_frame(&mut cache, &casters, 1, 4).is_empty());
+
+        casters[0].mark_shadow_dirty();
+        assert_eq!(run_frame(&mut cache, &casters, 2, 4), vec![0]);
+        casters[0].position.x += 1.0;
+        assert_eq!(run_frame(&mut cache, &casters, 3, 4), vec![0]);
+        assert!(run_frame(&mut cache, &casters, 4, 4).is_empty());
+    }
+
+    #[test]
+    fn every_n_frames() {
+        let mut cache = ShadowMapCache::default();
+        let casters = [caster(ShadowUpdatePolicy::EveryNFrames(3))];
+        let rendered: Vec<u64> = (0..7)
+            .filter(|&frame| !run_frame(&mut cache, &casters, frame, 4).is_empty())
+            .collect();
+        assert_eq!(rendered, vec![0, 3, 6]);
+    }
+
+    #[test]
+    fn round_robin_respects_budget() {
+        let mut cache = ShadowMapCache::default();
+        let casters = [
+            caster(ShadowUpdatePolicy::EveryFrame),
+            caster(ShadowUpdatePolicy::EveryFrame),
+            caster(ShadowUpdatePolicy::EveryFrame),
+        ];
+        assert_eq!(run_frame(&mut cache, &casters, 0, 2), vec![0, 1]);
+        assert_eq!(run_frame(&mut cache, &casters, 1, 2), vec![2, 0]);
+        assert_eq!(run_frame(&mut cache, &casters, 2, 2), vec![1, 2]);
+        assert!(cache.view_projection(2).is_some());
+        assert!(cache.view_projection(3).is_none());
+    }
+
+    #[test]
+    fn cascade_split_schemes() {
+        let uniform = cascade_splits(1.0, 100.0, 4, 0.0);
+        assert_eq!(uniform, vec![25.75, 50.5, 75.25, 100.0]);
+
+        let logarithmic = cascade_splits(1.0, 100.0, 2, 1.0);
+        assert!((logarithmic[0] - 10.0).abs() < 1e-4);
+        assert!((logarithmic[1] - 100.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn directional_lights_have_a_shadow_map_per_cascade() {
+        let light = caster(ShadowUpdatePolicy::EveryFrame);
+        let sources = shadow_map_sources(&light, &Camera::default(), &[10.0, 50.0, 100.0]);
+        let cascades: Vec<_> = sources.iter().map(|source| source.cascade).collect();
+        assert_eq!(
+            cascades,
+            vec![Some((0.1, 10.0)), Some((10.0, 50.0)), Some((50.0, 100.0))]
+        );
+
+        let spot = Light {
+            ty: LightType::Spotlight {
+                direction: vector![0.0, -1.0, 0.0],
+                inner_cone_degrees: 15.0,
+                outer_cone_degrees: 30.0,
+            },
+            ..light
+        };
+        let spot_sources = shadow_map_sources(&spot, &Camera::default(), &[10.0]);
+        assert_eq!(spot_sources.len(), 1);
+    }
+
+    #[test]
+    fn cached_cascades_follow_the_camera() {
+        let mut cache = ShadowMapCache::default();
+        let light = caster(ShadowUpdatePolicy::Static);
+        let scene_bounds = BoundingBox {
+            min: point![-1000.0, -1000.0, -1000.0],
+            max: point![1000.0, 1000.0, 1000.0],
+        };
+        let mut render = |pov: &Camera, frame| {
+            let sources = shadow_map_sources(&light, pov, &[10.0]);
+            let updates = cache.schedule(&sources, frame, 4);
+            for &slot in &updates {
+                let camera = shadow_camera(&sources[slot], &scene_bounds).unwrap();
+                let view_projection = camera.projection * camera.view;
+                cache.mark_rendered(slot, &sources[slot], view_projection, camera.covered, frame);
+            }
+            updates
+        };
+        let mut pov = Camera::default();
+        assert_eq!(render(&pov, 0), vec![0]);
+        // Small movements stay within the cascade's margin
+        pov.location.x += 0.1;
+        assert!(render(&pov, 1).is_empty());
+        pov.location.x += 100.0;
+        assert_eq!(render(&pov, 2), vec![0]);
+        assert!(render(&pov, 3).is_empty());
+    }
+}
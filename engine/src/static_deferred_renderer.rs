@@ -1,20 +1,27 @@
 use engine_macros::glsl;
-use std::{collections::HashMap, mem::size_of};
+use log::warn;
+use std::{collections::HashMap, mem::size_of, ops::Range};
 
 use ash::{
     prelude::VkResult,
     vk::{
-        BufferUsageFlags, CompareOp, IndexType, PipelineBindPoint,
-        PipelineStageFlags, PushConstantRange, ShaderModuleCreateFlags, ShaderStageFlags,
+        BorderColor, BufferUsageFlags, CompareOp, Extent2D, Filter, Format, ImageAspectFlags,
+        ImageSubresourceLayers, ImageUsageFlags, Offset2D, Offset3D, PipelineBindPoint,
+        PipelineStageFlags, PushConstantRange, Rect2D, ShaderModuleCreateFlags, ShaderStageFlags,
         StencilOpState,
     },
 };
 use gpu::{
-    BindingType, BufferCreateInfo, CommandBuffer, DepthStencilState, FragmentStageInfo, Gpu,
-    GpuBuffer, GpuShaderModule, ImageFormat, MemoryDomain,
-    ShaderModuleCreateInfo, Swapchain, ToVk, VertexStageInfo,
+    BeginRenderPassInfo, BindingElement, BindingType, BufferCreateInfo, BufferMemoryBarrier,
+    BufferRange, CommandBuffer, ComputePipelineDescription, ComputeStageInfo, DepthAttachment,
+    DepthLoadOp, DepthResolveMode, DepthStencilAttachment, DepthStencilState, DescriptorInfo,
+    DescriptorSetInfo, DescriptorType, FragmentStageInfo, GlobalBinding, Gpu, GpuBuffer,
+    GpuDescriptorSet, GpuImage, GpuImageView, GpuSampler, GpuShaderModule, ImageCreateInfo,
+    ImageFormat, ImageTransition, MemoryDomain, Pipeline, PipelineBarrierInfo, PipelineDescription,
+    RenderPassCommand, SampleCount, ShaderModuleCreateInfo, StencilAttachment, StencilLoadOp, ToVk,
+    TransitionInfo, VertexStageInfo,
 };
-use nalgebra::{vector, Matrix4, Vector2, Vector4};
+use nalgebra::{vector, Matrix3, Matrix4, Vector2, Vector4};
 use resource_map::{ResourceHandle, ResourceMap};
 
 const FXAA_FS: &[u32] = glsl!(
@@ -28,6 +35,54 @@ const FXAA_VS: &[u32] = glsl!(
     path = "src/shaders/fxaa_vs.vert",
     entry_point = "main"
 );
+
+const UPSCALE_FS: &[u32] = glsl!(
+    kind = fragment,
+    path = "src/shaders/upscale_fs.frag",
+    entry_point = "main"
+);
+
+const DOF_FS: &[u32] = glsl!(
+    kind = fragment,
+    path = "src/shaders/dof_fs.frag",
+    entry_point = "main"
+);
+
+const SSR_FS: &[u32] = glsl!(
+    kind = fragment,
+    path = "src/shaders/ssr_fs.frag",
+    entry_point = "main"
+);
+
+const PARTICLE_CS: &[u32] = glsl!(
+    kind = compute,
+    path = "src/shaders/particle_cs.comp",
+    entry_point = "main"
+);
+
+const PARTICLE_VS: &[u32] = glsl!(
+    kind = vertex,
+    path = "src/shaders/particle_vs.vert",
+    entry_point = "main"
+);
+
+const PARTICLE_FS: &[u32] = glsl!(
+    kind = fragment,
+    path = "src/shaders/particle_fs.frag",
+    entry_point = "main"
+);
+
+const BOUNDS_VS: &[u32] = glsl!(
+    kind = vertex,
+    path = "src/shaders/bounds_vs.vert",
+    entry_point = "main"
+);
+
+const BOUNDS_FS: &[u32] = glsl!(
+    kind = fragment,
+    path = "src/shaders/bounds_fs.frag",
+    entry_point = "main"
+);
 #[repr(C)]
 #[derive(Clone, Copy)]
 struct FxaaShaderParams {
@@ -54,6 +109,234 @@ impl Default for FxaaSettings {
     }
 }
 
+/* How the shadow maps are filtered when lighting the scene: Hard takes a single sample,
+ * Pcf averages a kernel_size x kernel_size grid of samples (odd, up to 7x7) and
+ * Poisson takes up to 16 samples from a poisson disk, with the radius in shadow map texels.
+ * Every sample is compared by the sampler, which filters the result of the comparison */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShadowQuality {
+    Hard,
+    Pcf { kernel_size: u32 },
+    Poisson { samples: u32, radius: f32 },
+}
+
+impl Default for ShadowQuality {
+    fn default() -> Self {
+        Self::Pcf { kernel_size: 3 }
+    }
+}
+
+/* How the frame is upscaled to the backbuffer when rendering below its resolution:
+ * Lanczos keeps the edges crisper than Bilinear, and sharpness (from 0 to 1)
+ * sharpens them further. At a render scale of 1 both leave the frame untouched */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Upscaler {
+    Bilinear,
+    Lanczos { sharpness: f32 },
+}
+
+impl Default for Upscaler {
+    fn default() -> Self {
+        Self::Lanczos { sharpness: 0.25 }
+    }
+}
+
+/* Replaces the lit frame with a visualization helping to debug the renderer:
+ * NanCheck paints magenta the pixels whose lighting is NaN or infinite */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DebugView {
+    #[default]
+    None,
+    NanCheck,
+}
+
+/* Screen space reflections, traced against the depth buffer: each ray takes up to max_steps
+ * steps of step_size world units, and a step hits when it ends behind the depth buffer by less
+ * than thickness. The surfaces reflect less the rougher they are, and don't reflect from
+ * max_roughness on. The rays that leave the screen or miss leave the lit frame untouched */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SsrSettings {
+    pub max_steps: u32,
+    pub step_size: f32,
+    pub thickness: f32,
+    pub max_roughness: f32,
+}
+
+impl SsrSettings {
+    pub const MAX_STEPS: u32 = 256;
+}
+
+impl Default for SsrSettings {
+    fn default() -> Self {
+        Self {
+            max_steps: 64,
+            step_size: 0.1,
+            thickness: 0.2,
+            max_roughness: 0.6,
+        }
+    }
+}
+
+// Pushed to the ScreenSpaceReflections pass, the layout must match ssr_fs.frag
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SsrShaderParams {
+    near: f32,
+    far: f32,
+    max_steps: u32,
+    step_size: f32,
+    thickness: f32,
+    max_roughness: f32,
+}
+
+impl SsrShaderParams {
+    fn new(settings: SsrSettings, pov: &Camera) -> Self {
+        Self {
+            near: pov.near,
+            far: pov.far,
+            max_steps: settings.max_steps.min(SsrSettings::MAX_STEPS),
+            step_size: settings.step_size.max(f32::EPSILON),
+            thickness: settings.thickness.max(0.0),
+            max_roughness: settings.max_roughness.clamp(0.0, 1.0),
+        }
+    }
+}
+
+/* The depth of field applied to the lit frame before tonemapping: the circle of confusion
+ * of a pixel grows with aperture and with its distance from focus_distance, relative to the
+ * pixel's depth, and the blur is capped at max_blur_radius pixels of the render size */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DofParams {
+    pub focus_distance: f32,
+    pub aperture: f32,
+    pub max_blur_radius: f32,
+}
+
+impl DofParams {
+    // The number of samples taken by dof_fs.frag grows with the square of the radius
+    pub const MAX_BLUR_RADIUS: f32 = 16.0;
+}
+
+impl Default for DofParams {
+    fn default() -> Self {
+        Self {
+            focus_distance: 10.0,
+            aperture: 0.5,
+            max_blur_radius: 8.0,
+        }
+    }
+}
+
+// Pushed to the DepthOfField pass, the layout must match dof_fs.frag
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DofShaderParams {
+    rcp_frame: Vector2<f32>,
+    near: f32,
+    far: f32,
+    focus_distance: f32,
+    aperture: f32,
+    max_blur_radius: f32,
+}
+
+impl DofShaderParams {
+    fn new(params: DofParams, pov: &Camera, render_size: Extent2D) -> Self {
+        let max_blur_radius = params.max_blur_radius;
+        Self {
+            rcp_frame: vector![
+                1.0 / render_size.width as f32,
+                1.0 / render_size.height as f32
+            ],
+            near: pov.near,
+            far: pov.far,
+            focus_distance: params.focus_distance.max(pov.near),
+            aperture: params.aperture.max(0.0),
+            max_blur_radius: max_blur_radius.clamp(0.0, DofParams::MAX_BLUR_RADIUS),
+        }
+    }
+}
+
+// Pushed to the Upscale pass, the modes must match upscale_fs.frag
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UpscaleParams {
+    mode: u32,
+    sharpness: f32,
+}
+
+impl From<Upscaler> for UpscaleParams {
+    fn from(upscaler: Upscaler) -> Self {
+        match upscaler {
+            Upscaler::Bilinear => Self {
+                mode: 0,
+                sharpness: 0.0,
+            },
+            Upscaler::Lanczos { sharpness } => Self {
+                mode: 1,
+                sharpness: sharpness.clamp(0.0, 1.0),
+            },
+        }
+    }
+}
+
+/* Pushed to the Tonemapping pass: when the backbuffer isn't sRGB the tonemapper applies
+ * the sRGB transfer function itself, and the following passes work on the encoded colors */
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TonemapParams {
+    encode_srgb: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ShadowFilterParams {
+    mode: u32,
+    samples: u32,
+    radius: f32,
+}
+
+impl From<ShadowQuality> for ShadowFilterParams {
+    fn from(quality: ShadowQuality) -> Self {
+        match quality {
+            ShadowQuality::Hard => Self {
+                mode: 0,
+                samples: 1,
+                radius: 0.0,
+            },
+            ShadowQuality::Pcf { kernel_size } => Self {
+                mode: 1,
+                samples: kernel_size.clamp(1, 7),
+                radius: 0.0,
+            },
+            ShadowQuality::Poisson { samples, radius } => Self {
+                mode: 2,
+                samples: samples.clamp(1, 16),
+                radius,
+            },
+        }
+    }
+}
+
+// Pushed to the GBufferCombine pass, the values must match gbuffer_combine.frag
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CombineParams {
+    shadow_filter: ShadowFilterParams,
+    debug_view: u32,
+}
+
+impl CombineParams {
+    fn new(quality: ShadowQuality, debug_view: DebugView) -> Self {
+        Self {
+            shadow_filter: quality.into(),
+            debug_view: match debug_view {
+                DebugView::None => 0,
+                DebugView::NanCheck => 1,
+            },
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 struct PerFrameData {
@@ -70,6 +353,12 @@ struct GpuLightInfo {
     color: Vector4<f32>,
     extras: Vector4<f32>,
     ty: [u32; 4],
+    // One shadow map for each cascade of a directional light, ty[1] tells how many are in use
+    shadow_view_projections: [Matrix4<f32>; shadows::MAX_CASCADES],
+    // The shadow maps in atlas uv coordinates
+    shadow_rects: [Vector4<f32>; shadows::MAX_CASCADES],
+    // The view depth where each cascade ends
+    cascade_splits: Vector4<f32>,
 }
 
 impl From<&Light> for GpuLightInfo {
@@ -111,11 +400,14 @@ impl From<&Light> for GpuLightInfo {
             direction,
             extras,
             ty: [ty, 0, 0, 0],
+            shadow_view_projections: [Matrix4::identity(); shadows::MAX_CASCADES],
+            shadow_rects: [Vector4::zeros(); shadows::MAX_CASCADES],
+            cascade_splits: Vector4::zeros(),
         }
     }
 }
 
-use crate::{app_state, camera::Camera, material::{MasterMaterial, MasterMaterialDescription}, BufferDescription, BufferType, ClearValue, FragmentState, GpuRunner, GraphRunContext, Light, LightType, MaterialDescription, MaterialDomain, MaterialInstance, MeshPrimitive, ModuleInfo, PipelineTarget, RenderGraph, RenderGraphPipelineDescription, RenderPassContext, RenderStage, RenderingPipeline, Scene, Backbuffer};
+use crate::{app_state, camera::Camera, material::{MasterMaterial, MasterMaterialDescription}, shadows::{self, ShadowMapCache}, BoundingBox, BufferDescription, BufferType, ClearValue, FragmentState, GpuParticle, GpuRunner, ParticleSimulationState, ParticleSimulationStep, GraphRunContext, Light, LightType, PassTimer, PassTimings, MaterialDescription, MaterialDomain, MaterialInstance, MeshBuffers, MeshPrimitive, ModuleInfo, PipelineTarget, RenderGraph, RenderGraphPipelineDescription, RenderStage, RenderingPipeline, ResourceId, SamplerSettings, Scene, Texture, Backbuffer};
 
 use ash::vk::{
     AccessFlags, AttachmentLoadOp, AttachmentReference, AttachmentStoreOp, BlendFactor, BlendOp,
@@ -126,15 +418,124 @@ use gpu::{
     BlendState, RenderPass, RenderPassAttachment, RenderPassDescription, SubpassDescription,
 };
 
+/* The per frame data bound at set 0 by the surface passes, in binding order:
+ * every surface material shares this layout, so the set is bound once per pass */
+const SURFACE_GLOBAL_INPUTS: &[BindingType] = &[
+    BindingType::Uniform, // Camera buffer
+    BindingType::Storage, // Instances
+    BindingType::Storage, // Lights
+];
+
 struct FrameBuffers {
     camera_buffer: GpuBuffer,
     light_buffer: GpuBuffer,
+    instance_buffer: GpuBuffer,
+    // The camera buffer and the particle buffer, read by the particle pipeline
+    particle_descriptor_set: GpuDescriptorSet,
+    // The cameras of the shadow maps, one slot for each shadow map
+    shadow_camera_buffer: GpuBuffer,
+    // The SURFACE_GLOBAL_INPUTS set used when rendering each shadow map
+    shadow_descriptor_sets: Vec<GpuDescriptorSet>,
+    // Used by render_depth_only, so that it doesn't overwrite the frame's camera and instances
+    depth_only_camera_buffer: GpuBuffer,
+    depth_only_instance_buffer: GpuBuffer,
+    depth_only_descriptor_set: GpuDescriptorSet,
+}
+
+// The image the final frame is rendered to, before being copied to the backbuffer
+struct OutputImage {
+    // Declared first, so that the view is dropped before the image
+    view: GpuImageView,
+    image: GpuImage,
+}
+
+impl OutputImage {
+    fn new(gpu: &Gpu, size: Extent2D, format: Format) -> anyhow::Result<Self> {
+        let image = gpu.create_image(
+            &ImageCreateInfo {
+                label: Some("Deferred Renderer - Output image"),
+                width: size.width,
+                height: size.height,
+                format,
+                usage: ImageUsageFlags::COLOR_ATTACHMENT
+                    | ImageUsageFlags::SAMPLED
+                    | ImageUsageFlags::TRANSFER_SRC,
+                mip_levels: 1,
+                sharing_mode: Default::default(),
+                samples: SampleCount::One,
+            },
+            MemoryDomain::DeviceLocal,
+            None,
+        )?;
+        let view = gpu.create_default_view(&image)?;
+        Ok(Self { view, image })
+    }
+}
+
+/* A view of render_multi: the views can't share their targets, per-frame buffers and
+ * shadow maps, so each view but the first is drawn by a renderer of its own */
+struct SplitView {
+    // None for the first view, which is drawn by the renderer itself
+    renderer: Option<DeferredRenderingPipeline>,
+    // Has the view's size and the backbuffer's format, the view is copied from it
+    target: Option<OutputImage>,
+}
+
+/* The layout of an instance in the instance buffer: normals are transformed by the
+ * inverse transpose of the model's upper 3x3, so that they stay perpendicular
+ * to the surface under non-uniform scale. It's stored in a mat4 to avoid the
+ * std430 padding of mat3's columns */
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct GpuInstance {
+    model: Matrix4<f32>,
+    normal: Matrix4<f32>,
+}
+
+// A world space bounding box drawn by the bounds pipeline, pushed as a push constant
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct GpuBounds {
+    min: Vector4<f32>,
+    max: Vector4<f32>,
+}
+
+impl From<BoundingBox> for GpuBounds {
+    fn from(bounds: BoundingBox) -> Self {
+        Self {
+            min: bounds.min.to_homogeneous(),
+            max: bounds.max.to_homogeneous(),
+        }
+    }
+}
+
+// A transform with a negative determinant mirrors the geometry, flipping the winding of its faces
+fn flips_winding(model: &Matrix4<f32>) -> bool {
+    model.fixed_view::<3, 3>(0, 0).determinant() < 0.0
+}
+
+impl GpuInstance {
+    fn new(model: Matrix4<f32>) -> Self {
+        let upper = model.fixed_view::<3, 3>(0, 0).into_owned();
+        // A degenerate (e.g zero scaled) transform has no normals to speak of
+        let normal = upper
+            .try_inverse()
+            .map(|inverse| inverse.transpose())
+            .unwrap_or_else(Matrix3::identity);
+        Self {
+            model,
+            normal: normal.to_homogeneous(),
+        }
+    }
 }
 
 struct DrawCall<'a> {
+    buffers: &'a MeshBuffers,
     prim: &'a MeshPrimitive,
-    transform: Matrix4<f32>,
+    // Index of the draw's transform in the instance buffer, used as the draw's first instance
+    instance_index: u32,
     material: ResourceHandle<MaterialInstance>,
+    mirrored: bool,
 }
 
 pub struct DeferredRenderingPipeline {
@@ -151,11 +552,76 @@ pub struct DeferredRenderingPipeline {
     runner: GpuRunner,
     fxaa_vs: GpuShaderModule,
     fxaa_fs: GpuShaderModule,
+    upscale_fs: GpuShaderModule,
+    dof_fs: GpuShaderModule,
+    // The DepthOfField pass only runs when it's enabled
+    dof: Option<DofParams>,
+    ssr_fs: GpuShaderModule,
+    ssr_enabled: bool,
+    ssr_settings: SsrSettings,
+    /* The particles are simulated in place by particle_simulation_pipeline, each system
+     * owning max_particles slots of the buffer, and are then drawn straight from the buffer
+     * by particle_pipeline in the GBufferCombine pass, after the lighting */
+    particle_buffer: GpuBuffer,
+    particle_simulation_pipeline: Pipeline,
+    particle_simulation_descriptor_set: GpuDescriptorSet,
+    // How far each of the scene's particle systems has been simulated
+    particle_states: Vec<ParticleSimulationState>,
+    particle_pipeline: Pipeline,
+    // Draws the primitives' bounds as wireframe boxes in the GBufferCombine pass
+    bounds_pipeline: Pipeline,
+    draw_bounds: bool,
+
+    // Shadow maps are persistent: only the ones scheduled each frame are cleared and rendered
+    shadow_atlas: GpuImage,
+    shadow_atlas_view: GpuImageView,
+    // The depth aspect of the atlas, sampled by the lighting
+    shadow_atlas_sampled_view: GpuImageView,
+    // A comparison sampler, the filtering is chosen by shadow_quality
+    shadow_sampler: GpuSampler,
+    shadow_atlas_state: TransitionInfo,
+    shadow_maps: ShadowMapCache,
+    shadow_camera_stride: u64,
+    max_shadow_updates_per_frame: usize,
+    shadow_cascade_count: usize,
+    cascade_split_lambda: f32,
+    shadow_quality: ShadowQuality,
+    debug_view: DebugView,
+
+    output: Option<OutputImage>,
+    render_scale: f32,
+    upscaler: Upscaler,
+    // The size of the internal targets used by the last frame
+    render_size: Extent2D,
+    // The samples per pixel of the EarlyZPass and GBuffer passes, resolved before the lighting
+    sample_count: SampleCount,
+
+    // Skipped by the draw calls, e.g the surfaces sampling the reflection being rendered
+    pub(crate) hidden_material: Option<ResourceHandle<MaterialInstance>>,
+
+    // Grows to the largest number of views passed to render_multi
+    split_views: Vec<SplitView>,
+
+    // One for each frame in flight, empty when the device does not support timestamps
+    pass_timers: Vec<PassTimer>,
+    pass_timings: PassTimings,
+
     in_flight_frame: usize,
     max_frames_in_flight: usize,
 }
 
 impl DeferredRenderingPipeline {
+    pub const MAX_INSTANCES: usize = 10000;
+    pub const MAX_PARTICLES: usize = 65536;
+    pub const MIN_RENDER_SCALE: f32 = 0.25;
+    pub const MAX_TIMED_PASSES: u32 = 16;
+    // The format of the targets storing lighting, values above 1.0 must survive until tonemapping
+    pub const HDR_FORMAT: ImageFormat = ImageFormat::RgbaHalf;
+    /* The format of every depth target the materials are drawn into (the gbuffer's depth,
+     * the shadow atlas and render_depth_only's target): it has a stencil aspect,
+     * so that the materials with a stencil state can be drawn with the others */
+    pub const DEPTH_FORMAT: ImageFormat = ImageFormat::DepthStencil;
+
     pub fn new(
         gpu: &Gpu,
         screen_quad: GpuShaderModule,
@@ -163,18 +629,39 @@ impl DeferredRenderingPipeline {
         texture_copy: GpuShaderModule,
         tonemap_fs: GpuShaderModule,
     ) -> anyhow::Result<Self> {
+        let shadow_camera_stride = (size_of::<PerFrameData>() as u64)
+            .next_multiple_of(gpu.buffer_offset_alignment(BufferUsageFlags::UNIFORM_BUFFER));
+
+        // Zeroed particles are dead, see GpuParticle
+        let particle_buffer_size = size_of::<GpuParticle>() * Self::MAX_PARTICLES;
+        let particle_buffer = gpu.create_buffer_with_data(
+            &BufferCreateInfo {
+                label: Some("Deferred Renderer - Particle buffer"),
+                size: particle_buffer_size,
+                usage: BufferUsageFlags::STORAGE_BUFFER | BufferUsageFlags::TRANSFER_DST,
+                sharing_mode: Default::default(),
+            },
+            MemoryDomain::DeviceLocal,
+            &vec![0u8; particle_buffer_size],
+        )?;
+        let particle_simulation_descriptor_set = gpu.create_descriptor_set(&DescriptorSetInfo {
+            descriptors: &[DescriptorInfo::storage_buffer(
+                0,
+                &particle_buffer,
+                gpu::ShaderStage::Compute,
+            )],
+        })?;
+
         let mut frame_buffers = vec![];
-        for _ in 0..Swapchain::MAX_FRAMES_IN_FLIGHT {
+        for _ in 0..gpu.frames_in_flight() {
             let camera_buffer = {
                 let create_info = BufferCreateInfo {
                     label: Some("Deferred Renderer - Camera buffer"),
                     size: std::mem::size_of::<PerFrameData>(),
                     usage: BufferUsageFlags::UNIFORM_BUFFER | BufferUsageFlags::TRANSFER_DST,
+                    sharing_mode: Default::default(),
                 };
-                gpu.create_buffer(
-                    &create_info,
-                    MemoryDomain::HostVisible | MemoryDomain::HostCoherent,
-                )?
+                gpu.create_buffer(&create_info, MemoryDomain::DeviceLocalHostVisible)?
             };
             let light_buffer = {
                 let create_info = BufferCreateInfo {
@@ -183,18 +670,134 @@ impl DeferredRenderingPipeline {
                     usage: BufferUsageFlags::UNIFORM_BUFFER
                         | BufferUsageFlags::STORAGE_BUFFER
                         | BufferUsageFlags::TRANSFER_DST,
+                    sharing_mode: Default::default(),
+                };
+                gpu.create_buffer(&create_info, MemoryDomain::DeviceLocalHostVisible)?
+            };
+            let instance_buffer = {
+                let create_info = BufferCreateInfo {
+                    label: Some("Deferred Renderer - Instance buffer"),
+                    size: std::mem::size_of::<GpuInstance>() * Self::MAX_INSTANCES,
+                    usage: BufferUsageFlags::STORAGE_BUFFER | BufferUsageFlags::TRANSFER_DST,
+                    sharing_mode: Default::default(),
+                };
+                gpu.create_buffer(&create_info, MemoryDomain::DeviceLocalHostVisible)?
+            };
+            let particle_descriptor_set = gpu.create_descriptor_set(&DescriptorSetInfo {
+                descriptors: &[
+                    DescriptorInfo::uniform_buffer(0, &camera_buffer, gpu::ShaderStage::Vertex),
+                    DescriptorInfo::storage_buffer(1, &particle_buffer, gpu::ShaderStage::Vertex),
+                ],
+            })?;
+            let shadow_camera_buffer = {
+                let create_info = BufferCreateInfo {
+                    label: Some("Deferred Renderer - Shadow camera buffer"),
+                    size: shadow_camera_stride as usize * shadows::MAX_SHADOW_MAPS,
+                    usage: BufferUsageFlags::UNIFORM_BUFFER | BufferUsageFlags::TRANSFER_DST,
+                    sharing_mode: Default::default(),
                 };
-                gpu.create_buffer(
-                    &create_info,
-                    MemoryDomain::HostVisible | MemoryDomain::HostCoherent,
-                )?
+                gpu.create_buffer(&create_info, MemoryDomain::DeviceLocalHostVisible)?
             };
+            let shadow_descriptor_sets = (0..shadows::MAX_SHADOW_MAPS)
+                .map(|slot| {
+                    gpu.create_descriptor_set(&DescriptorSetInfo {
+                        descriptors: &[
+                            DescriptorInfo {
+                                binding: 0,
+                                element_type: DescriptorType::UniformBuffer(BufferRange {
+                                    handle: &shadow_camera_buffer,
+                                    offset: slot as u64 * shadow_camera_stride,
+                                    size: size_of::<PerFrameData>() as u64,
+                                }),
+                                binding_stage: gpu::ShaderStage::VertexFragment,
+                            },
+                            DescriptorInfo::storage_buffer(
+                                1,
+                                &instance_buffer,
+                                gpu::ShaderStage::VertexFragment,
+                            ),
+                            DescriptorInfo::storage_buffer(
+                                2,
+                                &light_buffer,
+                                gpu::ShaderStage::VertexFragment,
+                            ),
+                        ],
+                    })
+                })
+                .collect::<VkResult<Vec<_>>>()?;
+            let depth_only_camera_buffer = gpu.create_buffer(
+                &BufferCreateInfo {
+                    label: Some("Deferred Renderer - Depth only camera buffer"),
+                    size: std::mem::size_of::<PerFrameData>(),
+                    usage: BufferUsageFlags::UNIFORM_BUFFER | BufferUsageFlags::TRANSFER_DST,
+                    sharing_mode: Default::default(),
+                },
+                MemoryDomain::DeviceLocalHostVisible,
+            )?;
+            let depth_only_instance_buffer = gpu.create_buffer(
+                &BufferCreateInfo {
+                    label: Some("Deferred Renderer - Depth only instance buffer"),
+                    size: std::mem::size_of::<GpuInstance>() * Self::MAX_INSTANCES,
+                    usage: BufferUsageFlags::STORAGE_BUFFER | BufferUsageFlags::TRANSFER_DST,
+                    sharing_mode: Default::default(),
+                },
+                MemoryDomain::DeviceLocalHostVisible,
+            )?;
+            let depth_only_descriptor_set = gpu.create_descriptor_set(&DescriptorSetInfo {
+                descriptors: &[
+                    DescriptorInfo::uniform_buffer(
+                        0,
+                        &depth_only_camera_buffer,
+                        gpu::ShaderStage::VertexFragment,
+                    ),
+                    DescriptorInfo::storage_buffer(
+                        1,
+                        &depth_only_instance_buffer,
+                        gpu::ShaderStage::VertexFragment,
+                    ),
+                    DescriptorInfo::storage_buffer(
+                        2,
+                        &light_buffer,
+                        gpu::ShaderStage::VertexFragment,
+                    ),
+                ],
+            })?;
             frame_buffers.push(FrameBuffers {
                 camera_buffer,
                 light_buffer,
+                instance_buffer,
+                particle_descriptor_set,
+                shadow_camera_buffer,
+                shadow_descriptor_sets,
+                depth_only_camera_buffer,
+                depth_only_instance_buffer,
+                depth_only_descriptor_set,
             })
         }
 
+        let shadow_atlas = gpu.create_image(
+            &ImageCreateInfo {
+                label: Some("Deferred Renderer - Shadow atlas"),
+                width: shadows::SHADOW_ATLAS_SIZE,
+                height: shadows::SHADOW_ATLAS_SIZE,
+                format: Self::DEPTH_FORMAT.to_vk(),
+                usage: ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | ImageUsageFlags::SAMPLED,
+                mip_levels: 1,
+                sharing_mode: Default::default(),
+                samples: SampleCount::One,
+            },
+            MemoryDomain::DeviceLocal,
+            None,
+        )?;
+        let shadow_atlas_view = gpu.create_default_view(&shadow_atlas)?;
+        let shadow_atlas_sampled_view = gpu.create_sampled_view(&shadow_atlas)?;
+        // Outside of the shadow maps everything is lit
+        let shadow_sampler = Texture::create_sampler(
+            gpu,
+            &SamplerSettings::clamp_to_border(BorderColor::FLOAT_OPAQUE_WHITE)
+                .with_comparison(CompareOp::LESS_OR_EQUAL),
+        )?;
+
         let material_context = DeferredRenderingMaterialContext::new(gpu)?;
 
         let render_graph = RenderGraph::new();
@@ -207,6 +810,71 @@ impl DeferredRenderingPipeline {
             flags: ShaderModuleCreateFlags::empty(),
             code: bytemuck::cast_slice(FXAA_FS),
         })?;
+        let upscale_fs = gpu.create_shader_module(&ShaderModuleCreateInfo {
+            flags: ShaderModuleCreateFlags::empty(),
+            code: bytemuck::cast_slice(UPSCALE_FS),
+        })?;
+        let dof_fs = gpu.create_shader_module(&ShaderModuleCreateInfo {
+            flags: ShaderModuleCreateFlags::empty(),
+            code: bytemuck::cast_slice(DOF_FS),
+        })?;
+        let ssr_fs = gpu.create_shader_module(&ShaderModuleCreateInfo {
+            flags: ShaderModuleCreateFlags::empty(),
+            code: bytemuck::cast_slice(SSR_FS),
+        })?;
+        let particle_vs = gpu.create_shader_module(&ShaderModuleCreateInfo {
+            flags: ShaderModuleCreateFlags::empty(),
+            code: bytemuck::cast_slice(PARTICLE_VS),
+        })?;
+        let particle_fs = gpu.create_shader_module(&ShaderModuleCreateInfo {
+            flags: ShaderModuleCreateFlags::empty(),
+            code: bytemuck::cast_slice(PARTICLE_FS),
+        })?;
+        let particle_pipeline = Self::create_particle_pipeline(gpu, &particle_vs, &particle_fs)?;
+        let particle_cs = gpu.create_shader_module(&ShaderModuleCreateInfo {
+            flags: ShaderModuleCreateFlags::empty(),
+            code: bytemuck::cast_slice(PARTICLE_CS),
+        })?;
+        let particle_simulation_pipeline = Pipeline::new_compute(
+            gpu,
+            &ComputePipelineDescription {
+                global_bindings: &[GlobalBinding {
+                    set_index: 0,
+                    elements: &[BindingElement {
+                        binding_type: BindingType::Storage,
+                        index: 0,
+                        stage: gpu::ShaderStage::Compute,
+                    }],
+                    push_descriptor: false,
+                }],
+                compute_stage: ComputeStageInfo {
+                    entry_point: "main",
+                    module: &particle_cs,
+                },
+                push_constant_ranges: &[PushConstantRange {
+                    stage_flags: ShaderStageFlags::ALL,
+                    offset: 0,
+                    size: size_of::<ParticleSimulationStep>() as _,
+                }],
+            },
+        )?;
+        let bounds_vs = gpu.create_shader_module(&ShaderModuleCreateInfo {
+            flags: ShaderModuleCreateFlags::empty(),
+            code: bytemuck::cast_slice(BOUNDS_VS),
+        })?;
+        let bounds_fs = gpu.create_shader_module(&ShaderModuleCreateInfo {
+            flags: ShaderModuleCreateFlags::empty(),
+            code: bytemuck::cast_slice(BOUNDS_FS),
+        })?;
+        let bounds_pipeline = Self::create_bounds_pipeline(gpu, &bounds_vs, &bounds_fs)?;
+        let pass_timers = if gpu.supports_timestamps() {
+            (0..gpu.frames_in_flight())
+                .map(|_| PassTimer::new(gpu, Self::MAX_TIMED_PASSES))
+                .collect::<VkResult<Vec<_>>>()?
+        } else {
+            warn!("The device does not support timestamps, the passes won't be timed");
+            vec![]
+        };
 
         Ok(Self {
             material_context,
@@ -218,13 +886,273 @@ impl DeferredRenderingPipeline {
             tonemap_fs,
             fxaa_vs,
             fxaa_fs,
+            upscale_fs,
+            dof_fs,
+            dof: None,
+            ssr_fs,
+            ssr_enabled: false,
+            ssr_settings: SsrSettings::default(),
+            particle_buffer,
+            particle_simulation_pipeline,
+            particle_simulation_descriptor_set,
+            particle_states: vec![],
+            particle_pipeline,
+            bounds_pipeline,
+            draw_bounds: false,
+            shadow_atlas,
+            shadow_atlas_view,
+            shadow_atlas_sampled_view,
+            shadow_sampler,
+            shadow_atlas_state: ImageTransition::UndefinedToDepthAttachment
+                .transition_infos()
+                .0,
+            shadow_maps: ShadowMapCache::default(),
+            shadow_camera_stride,
+            max_shadow_updates_per_frame: shadows::MAX_SHADOW_MAPS,
+            shadow_cascade_count: shadows::MAX_CASCADES,
+            cascade_split_lambda: 0.75,
+            shadow_quality: ShadowQuality::default(),
+            debug_view: DebugView::default(),
+            output: None,
+            render_scale: 1.0,
+            upscaler: Upscaler::default(),
+            render_size: Extent2D::default(),
+            sample_count: SampleCount::One,
             fxaa_settings: Default::default(),
             runner: GpuRunner::new(),
+            hidden_material: None,
+            split_views: vec![],
+            pass_timers,
+            pass_timings: PassTimings::default(),
             in_flight_frame: 0,
-            max_frames_in_flight: Swapchain::MAX_FRAMES_IN_FLIGHT,
+            max_frames_in_flight: gpu.frames_in_flight(),
         })
     }
 
+    /* The bounds are drawn with the GBufferCombine pass's descriptor set, so their set 0 layout
+     * must match the pass's shader reads: the five gbuffer samplers, the camera buffer,
+     * the light buffer and the shadow atlas */
+    fn combine_pass_bindings() -> Vec<BindingElement> {
+        let mut set_zero_bindings: Vec<BindingElement> = (0..5)
+            .map(|index| BindingElement {
+                binding_type: BindingType::CombinedImageSampler,
+                index,
+                stage: gpu::ShaderStage::VertexFragment,
+            })
+            .collect();
+        set_zero_bindings.extend_from_slice(&[
+            BindingElement {
+                binding_type: BindingType::Uniform,
+                index: 5,
+                stage: gpu::ShaderStage::VertexFragment,
+            },
+            BindingElement {
+                binding_type: BindingType::Storage,
+                index: 6,
+                stage: gpu::ShaderStage::VertexFragment,
+            },
+            BindingElement {
+                binding_type: BindingType::CombinedImageSampler,
+                index: 7,
+                stage: gpu::ShaderStage::VertexFragment,
+            },
+        ]);
+        set_zero_bindings
+    }
+
+    fn create_particle_pipeline(
+        gpu: &Gpu,
+        particle_vs: &GpuShaderModule,
+        particle_fs: &GpuShaderModule,
+    ) -> anyhow::Result<Pipeline> {
+        let pipeline = Pipeline::new(
+            gpu,
+            &PipelineDescription {
+                global_bindings: &[GlobalBinding {
+                    set_index: 0,
+                    elements: &[
+                        BindingElement {
+                            binding_type: BindingType::Uniform,
+                            index: 0,
+                            stage: gpu::ShaderStage::Vertex,
+                        },
+                        BindingElement {
+                            binding_type: BindingType::Storage,
+                            index: 1,
+                            stage: gpu::ShaderStage::Vertex,
+                        },
+                    ],
+                    push_descriptor: false,
+                }],
+                vertex_inputs: &[],
+                vertex_stage: Some(VertexStageInfo {
+                    entry_point: "main",
+                    module: particle_vs,
+                }),
+                fragment_stage: Some(FragmentStageInfo {
+                    entry_point: "main",
+                    module: particle_fs,
+                    color_attachments: &[RenderPassAttachment {
+                        format: Self::HDR_FORMAT.to_vk(),
+                        samples: SampleCountFlags::TYPE_1,
+                        load_op: AttachmentLoadOp::LOAD,
+                        store_op: AttachmentStoreOp::STORE,
+                        stencil_load_op: AttachmentLoadOp::DONT_CARE,
+                        stencil_store_op: AttachmentStoreOp::DONT_CARE,
+                        initial_layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                        final_layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                        // Particles are additive, and leave the alpha untouched
+                        blend_state: BlendState {
+                            blend_enable: true,
+                            src_color_blend_factor: BlendFactor::ONE,
+                            dst_color_blend_factor: BlendFactor::ONE,
+                            color_blend_op: BlendOp::ADD,
+                            src_alpha_blend_factor: BlendFactor::ZERO,
+                            dst_alpha_blend_factor: BlendFactor::ONE,
+                            alpha_blend_op: BlendOp::ADD,
+                            color_write_mask: ColorComponentFlags::RGBA,
+                        },
+                    }],
+                    depth_stencil_attachments: &[DepthStencilAttachment {}],
+                }),
+                input_topology: gpu::PrimitiveTopology::TriangleStrip,
+                primitive_restart: false,
+                polygon_mode: gpu::PolygonMode::Fill,
+                cull_mode: gpu::CullMode::None,
+                front_face: gpu::FrontFace::ClockWise,
+                depth_stencil_state: DepthStencilState {
+                    depth_test_enable: true,
+                    depth_write_enable: false,
+                    depth_compare_op: CompareOp::LESS_OR_EQUAL,
+                    stencil_test_enable: false,
+                    front: StencilOpState::default(),
+                    back: StencilOpState::default(),
+                    min_depth_bounds: 0.0,
+                    max_depth_bounds: 1.0,
+                },
+                depth_stencil_format: Some(Self::DEPTH_FORMAT),
+                logic_op: None,
+                push_constant_ranges: &[],
+                conservative_rasterization: false,
+                samples: SampleCount::One,
+            },
+        )?;
+        Ok(pipeline)
+    }
+
+    fn create_bounds_pipeline(
+        gpu: &Gpu,
+        bounds_vs: &GpuShaderModule,
+        bounds_fs: &GpuShaderModule,
+    ) -> anyhow::Result<Pipeline> {
+        let pipeline = Pipeline::new(
+            gpu,
+            &PipelineDescription {
+                global_bindings: &[GlobalBinding {
+                    set_index: 0,
+                    elements: &Self::combine_pass_bindings(),
+                    push_descriptor: false,
+                }],
+                vertex_inputs: &[],
+                vertex_stage: Some(VertexStageInfo {
+                    entry_point: "main",
+                    module: bounds_vs,
+                }),
+                fragment_stage: Some(FragmentStageInfo {
+                    entry_point: "main",
+                    module: bounds_fs,
+                    color_attachments: &[RenderPassAttachment {
+                        format: Self::HDR_FORMAT.to_vk(),
+                        samples: SampleCountFlags::TYPE_1,
+                        load_op: AttachmentLoadOp::LOAD,
+                        store_op: AttachmentStoreOp::STORE,
+                        stencil_load_op: AttachmentLoadOp::DONT_CARE,
+                        stencil_store_op: AttachmentStoreOp::DONT_CARE,
+                        initial_layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                        final_layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                        blend_state: BlendState {
+                            blend_enable: false,
+                            src_color_blend_factor: BlendFactor::ONE,
+                            dst_color_blend_factor: BlendFactor::ZERO,
+                            color_blend_op: BlendOp::ADD,
+                            src_alpha_blend_factor: BlendFactor::ONE,
+                            dst_alpha_blend_factor: BlendFactor::ZERO,
+                            alpha_blend_op: BlendOp::ADD,
+                            color_write_mask: ColorComponentFlags::RGBA,
+                        },
+                    }],
+                    depth_stencil_attachments: &[DepthStencilAttachment {}],
+                }),
+                input_topology: gpu::PrimitiveTopology::LineList,
+                primitive_restart: false,
+                polygon_mode: gpu::PolygonMode::Fill,
+                cull_mode: gpu::CullMode::None,
+                front_face: gpu::FrontFace::ClockWise,
+                depth_stencil_state: DepthStencilState {
+                    depth_test_enable: true,
+                    depth_write_enable: false,
+                    depth_compare_op: CompareOp::LESS_OR_EQUAL,
+                    stencil_test_enable: false,
+                    front: StencilOpState::default(),
+                    back: StencilOpState::default(),
+                    min_depth_bounds: 0.0,
+                    max_depth_bounds: 1.0,
+                },
+                depth_stencil_format: Some(Self::DEPTH_FORMAT),
+                logic_op: None,
+                push_constant_ranges: &[PushConstantRange {
+                    stage_flags: ShaderStageFlags::ALL,
+                    offset: 0,
+                    size: size_of::<GpuBounds>() as _,
+                }],
+                conservative_rasterization: false,
+                samples: SampleCount::One,
+            },
+        )?;
+        Ok(pipeline)
+    }
+
+    /* The GPU time spent by the shadow maps and by each pass of the render graph,
+     * measured in the last frame that finished executing, usually a couple of frames ago */
+    pub fn timings(&self) -> &PassTimings {
+        &self.pass_timings
+    }
+
+    pub fn draw_bounds(&self) -> bool {
+        self.draw_bounds
+    }
+    /* Draws the world space bounds of each primitive of the scene as a wireframe box,
+     * depth tested against the scene: useful to check the bounds used by the lod selection */
+    pub fn set_draw_bounds(&mut self, draw_bounds: bool) {
+        self.draw_bounds = draw_bounds;
+    }
+
+    pub fn render_scale(&self) -> f32 {
+        self.render_scale
+    }
+    /* The scene and its lighting are rendered at a fraction of the backbuffer's resolution
+     * (e.g 0.5 renders at half resolution), then the frame is upscaled to the backbuffer
+     * with the renderer's upscaler. The scale is clamped to [MIN_RENDER_SCALE, 1.0] */
+    pub fn set_render_scale(&mut self, scale: f32) {
+        self.render_scale = scale.clamp(Self::MIN_RENDER_SCALE, 1.0);
+    }
+
+    pub fn upscaler(&self) -> Upscaler {
+        self.upscaler
+    }
+    pub fn set_upscaler(&mut self, upscaler: Upscaler) {
+        self.upscaler = upscaler;
+    }
+
+    // The size of the internal targets, never empty
+    fn scaled_extent(size: Extent2D, scale: f32) -> Extent2D {
+        let scale = |length: u32| ((length as f32 * scale).round() as u32).max(1);
+        Extent2D {
+            width: scale(size.width),
+            height: scale(size.height),
+        }
+    }
+
     pub fn fxaa_settings(&self) -> FxaaSettings {
         self.fxaa_settings
     }
@@ -235,67 +1163,489 @@ impl DeferredRenderingPipeline {
         self.fxaa_settings = settings;
     }
 
+    pub fn dof(&self) -> Option<DofParams> {
+        self.dof
+    }
+
+    // None disables the depth of field
+    pub fn set_dof(&mut self, dof: Option<DofParams>) {
+        self.dof = dof;
+    }
+
+    pub fn ssr_enabled(&self) -> bool {
+        self.ssr_enabled
+    }
+
+    pub fn set_ssr_enabled(&mut self, enabled: bool) {
+        self.ssr_enabled = enabled;
+    }
+
+    pub fn ssr_settings(&self) -> SsrSettings {
+        self.ssr_settings
+    }
+
+    pub fn set_ssr_settings(&mut self, settings: SsrSettings) {
+        self.ssr_settings = settings;
+    }
+
+    pub fn max_shadow_updates_per_frame(&self) -> usize {
+        self.max_shadow_updates_per_frame
+    }
+    // Limits how many shadow maps are rendered each frame, the others keep last frame's shadows
+    pub fn set_max_shadow_updates_per_frame(&mut self, max_updates: usize) {
+        self.max_shadow_updates_per_frame = max_updates;
+    }
+
+    /* The image holding the final frame (tonemapped and antialiased) that gets copied to the
+     * backbuffer, e.g to stream it or to hand it over to another library: after the frame's
+     * command buffer completes, the image is in the SHADER_READ_ONLY_OPTIMAL layout.
+     * The image is recreated when the backbuffer is resized, and it's None until the first frame */
+    pub fn output_image(&self) -> Option<&GpuImage> {
+        self.output.as_ref().map(|output| &output.image)
+    }
+
+    /* The targets are sized after the backbuffer and the render scale: when either changes,
+     * the output image is recreated and so are the render graph's images, whose descriptions
+     * change this frame. The previous targets may still be in use by the frames in flight,
+     * so they're waited for */
+    fn resize_targets(&mut self, size: Extent2D, render_size: Extent2D) -> anyhow::Result<()> {
+        if self.render_size == render_size
+            && self
+                .output
+                .as_ref()
+                .is_some_and(|output| output.image.extents() == size)
+        {
+            return Ok(());
+        }
+        if self.output.is_some() {
+            app_state().gpu.wait_device_idle()?;
+        }
+        let format = ImageFormat::Rgba8.to_vk();
+        self.output = Some(OutputImage::new(&app_state().gpu, size, format)?);
+        self.render_size = render_size;
+        Ok(())
+    }
+
+    pub fn sample_count(&self) -> SampleCount {
+        self.sample_count
+    }
+    /* The materials have a pipeline for each sample count supported by the device, so only
+     * the multisampled targets are recreated: they're graph images described with the new
+     * sample count from the next frame on. The old ones may still be in use by the frames
+     * in flight, so they're waited for */
+    pub fn set_sample_count(&mut self, gpu: &Gpu, samples: SampleCount) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            gpu.supports_sample_count(samples),
+            "The device does not support {} samples per pixel, the supported sample counts are {:?}",
+            samples.count(),
+            gpu.supported_sample_counts()
+        );
+        // The gbuffer's depth and stencil are resolved with their first sample
+        anyhow::ensure!(
+            samples == SampleCount::One
+                || gpu.supports_depth_stencil_resolve(DepthResolveMode::SampleZero),
+            "The device can't resolve the depth and stencil of a multisampled gbuffer"
+        );
+        if samples != self.sample_count {
+            gpu.wait_device_idle()?;
+            self.sample_count = samples;
+        }
+        Ok(())
+    }
+
+    pub fn shadow_quality(&self) -> ShadowQuality {
+        self.shadow_quality
+    }
+    pub fn set_shadow_quality(&mut self, quality: ShadowQuality) {
+        self.shadow_quality = quality;
+    }
+
+    pub fn debug_view(&self) -> DebugView {
+        self.debug_view
+    }
+    pub fn set_debug_view(&mut self, debug_view: DebugView) {
+        self.debug_view = debug_view;
+    }
+
+    pub fn shadow_cascade_count(&self) -> usize {
+        self.shadow_cascade_count
+    }
+    // How many slices of the view frustum get their own shadow map, for each directional light
+    pub fn set_shadow_cascade_count(&mut self, count: usize) {
+        assert!(
+            (1..=shadows::MAX_CASCADES).contains(&count),
+            "The cascade count must be between 1 and {}",
+            shadows::MAX_CASCADES
+        );
+        self.shadow_cascade_count = count;
+    }
+
+    pub fn cascade_split_lambda(&self) -> f32 {
+        self.cascade_split_lambda
+    }
+    // Blends the cascade splits between uniform (0.0) and logarithmic (1.0)
+    pub fn set_cascade_split_lambda(&mut self, lambda: f32) {
+        self.cascade_split_lambda = lambda.clamp(0.0, 1.0);
+    }
+
+    /* Renders the scene from each camera into its rect of the backbuffer, e.g for split screen
+     * or picture in picture: the later views are drawn over the earlier ones, and the parts of
+     * the backbuffer outside of every view are cleared to black. The views' commands are
+     * submitted before returning, the returned commands copy the views to the backbuffer and
+     * leave it ready to be presented: its image must have the TRANSFER_DST usage */
+    pub fn render_multi(
+        &mut self,
+        views: &[(Camera, Rect2D)],
+        scene: &Scene,
+        backbuffer: Backbuffer,
+        resource_map: &ResourceMap,
+    ) -> anyhow::Result<CommandBuffer<'_>> {
+        backbuffer.validate()?;
+        anyhow::ensure!(!views.is_empty(), "render_multi needs at least one view");
+        let usage = backbuffer.image.usage();
+        anyhow::ensure!(
+            usage.contains(ImageUsageFlags::TRANSFER_DST),
+            "The views are copied to the backbuffer, which needs the TRANSFER_DST usage"
+        );
+        for (_, rect) in views {
+            anyhow::ensure!(
+                Self::view_fits(rect, backbuffer.size),
+                "The view {:?} doesn't fit in the {}x{} backbuffer",
+                rect,
+                backbuffer.size.width,
+                backbuffer.size.height
+            );
+        }
+
+        let mut split_views = std::mem::take(&mut self.split_views);
+        let rendered = self.render_views(views, &mut split_views, scene, &backbuffer, resource_map);
+        self.split_views = split_views;
+        rendered?;
+
+        let gpu = &app_state().gpu;
+        let mut command_buffer = CommandBuffer::new(gpu, gpu::QueueType::Graphics)?;
+        let transfer_dst = ImageTransition::UndefinedToTransferDst.transition_infos();
+        command_buffer.transition_images(&[(backbuffer.image, transfer_dst.0, transfer_dst.1)]);
+        command_buffer.clear_color_image(backbuffer.image, [0.0, 0.0, 0.0, 1.0]);
+
+        // The renderer leaves its backbuffer ready to be presented
+        let view_state = TransitionInfo {
+            layout: ImageLayout::PRESENT_SRC_KHR,
+            access_mask: AccessFlags::COLOR_ATTACHMENT_WRITE,
+            stage_mask: PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        };
+        let transfer_src = ImageTransition::ColorAttachmentToTransferSrc
+            .transition_infos()
+            .1;
+        let layers = ImageSubresourceLayers {
+            aspect_mask: ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        for ((_, rect), split_view) in views.iter().zip(&self.split_views) {
+            let target = &split_view.target.as_ref().unwrap().image;
+            command_buffer.transition_images(&[(target, view_state, transfer_src)]);
+            let Rect2D { offset, extent } = *rect;
+            command_buffer.blit_image(
+                target,
+                ImageLayout::TRANSFER_SRC_OPTIMAL,
+                backbuffer.image,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[gpu::ImageBlit {
+                    src_subresource: layers,
+                    src_offsets: [
+                        Offset3D::default(),
+                        Offset3D {
+                            x: extent.width as i32,
+                            y: extent.height as i32,
+                            z: 1,
+                        },
+                    ],
+                    dst_subresource: layers,
+                    dst_offsets: [
+                        Offset3D {
+                            x: offset.x,
+                            y: offset.y,
+                            z: 0,
+                        },
+                        Offset3D {
+                            x: offset.x + extent.width as i32,
+                            y: offset.y + extent.height as i32,
+                            z: 1,
+                        },
+                    ],
+                }],
+                Filter::NEAREST,
+            );
+        }
+        let present = ImageTransition::ColorAttachmentToPresent
+            .transition_infos()
+            .1;
+        command_buffer.transition_images(&[(backbuffer.image, transfer_dst.1, present)]);
+        Ok(command_buffer)
+    }
+
+    fn view_fits(rect: &Rect2D, size: Extent2D) -> bool {
+        let fits = |offset: i32, extent: u32, size: u32| {
+            offset >= 0 && extent > 0 && offset as u64 + extent as u64 <= size as u64
+        };
+        fits(rect.offset.x, rect.extent.width, size.width)
+            && fits(rect.offset.y, rect.extent.height, size.height)
+    }
+
+    // Renders each view into its split view's target, submitting the commands of each view
+    fn render_views(
+        &mut self,
+        views: &[(Camera, Rect2D)],
+        split_views: &mut Vec<SplitView>,
+        scene: &Scene,
+        backbuffer: &Backbuffer,
+        resource_map: &ResourceMap,
+    ) -> anyhow::Result<()> {
+        let gpu = &app_state().gpu;
+        while split_views.len() < views.len() {
+            let renderer = if split_views.is_empty() {
+                None
+            } else {
+                Some(self.create_view_renderer(gpu)?)
+            };
+            split_views.push(SplitView {
+                renderer,
+                target: None,
+            });
+        }
+        for ((camera, rect), split_view) in views.iter().zip(split_views.iter_mut()) {
+            let target_matches = split_view.target.as_ref().is_some_and(|target| {
+                target.image.extents() == rect.extent
+                    && target.image.format() == backbuffer.format.into()
+            });
+            if !target_matches {
+                if split_view.target.is_some() {
+                    gpu.wait_device_idle()?;
+                }
+                let target = OutputImage::new(gpu, rect.extent, backbuffer.format)?;
+                split_view.target = Some(target);
+            }
+            let target = split_view.target.as_ref().unwrap();
+            let view_backbuffer = Backbuffer {
+                size: rect.extent,
+                format: backbuffer.format,
+                image: &target.image,
+                image_view: &target.view,
+            };
+            let command_buffer = match &mut split_view.renderer {
+                Some(renderer) => {
+                    self.copy_settings_to(renderer);
+                    renderer.render(camera, scene, view_backbuffer, resource_map)?
+                }
+                None => self.render(camera, scene, view_backbuffer, resource_map)?,
+            };
+            command_buffer.submit(&gpu::CommandBufferSubmitInfo::default())?;
+        }
+        Ok(())
+    }
+
+    // The renderer of a split view, using the same shaders as this one
+    fn create_view_renderer(&self, gpu: &Gpu) -> anyhow::Result<Self> {
+        let copy_module = |module: &GpuShaderModule| {
+            gpu.create_shader_module(&ShaderModuleCreateInfo {
+                flags: ShaderModuleCreateFlags::empty(),
+                code: bytemuck::cast_slice(module.code()),
+            })
+        };
+        Self::new(
+            gpu,
+            copy_module(&self.screen_quad)?,
+            copy_module(&self.gbuffer_combine)?,
+            copy_module(&self.texture_copy)?,
+            copy_module(&self.tonemap_fs)?,
+        )
+    }
+
+    // The split views are drawn with the settings of the renderer they belong to
+    fn copy_settings_to(&self, renderer: &mut Self) {
+        renderer.fxaa_settings = self.fxaa_settings;
+        renderer.dof = self.dof;
+        renderer.ssr_enabled = self.ssr_enabled;
+        renderer.ssr_settings = self.ssr_settings;
+        renderer.draw_bounds = self.draw_bounds;
+        renderer.max_shadow_updates_per_frame = self.max_shadow_updates_per_frame;
+        renderer.shadow_cascade_count = self.shadow_cascade_count;
+        renderer.cascade_split_lambda = self.cascade_split_lambda;
+        renderer.shadow_quality = self.shadow_quality;
+        renderer.debug_view = self.debug_view;
+        renderer.render_scale = self.render_scale;
+        renderer.upscaler = self.upscaler;
+        renderer.sample_count = self.sample_count;
+        renderer.hidden_material = self.hidden_material.clone();
+    }
+
+    /* Renders the depth of the scene as seen from pov into depth_image, with the materials'
+     * DepthOnly pipelines: the image must be a DEPTH_FORMAT image usable as a depth attachment,
+     * and depth_view must contain both its aspects (e.g Gpu::create_default_view).
+     * The commands are recorded in command_buffer, e.g the one returned by render:
+     * call it after render, at most once per frame. The image is cleared first,
+     * and it's left in SHADER_READ_ONLY_OPTIMAL so that it can be sampled afterwards */
+    pub fn render_depth_only(
+        &self,
+        command_buffer: &mut CommandBuffer,
+        pov: &Camera,
+        scene: &Scene,
+        resource_map: &ResourceMap,
+        depth_image: &GpuImage,
+        depth_view: &GpuImageView,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            depth_image.format() == Self::DEPTH_FORMAT,
+            "Depth only rendering needs a {:?} image, got {:?}",
+            Self::DEPTH_FORMAT,
+            depth_image.format()
+        );
+        // render has already moved on to the next frame's buffers
+        let frame_index =
+            (self.in_flight_frame + self.max_frames_in_flight - 1) % self.max_frames_in_flight;
+        let buffers = &self.frame_buffers[frame_index];
+
+        let projection = pov.projection();
+        let gpu = &app_state().gpu;
+        gpu.write_buffer_data(
+            &buffers.depth_only_camera_buffer,
+            &[PerFrameData {
+                eye: Vector4::new(pov.location[0], pov.location[1], pov.location[2], 0.0),
+                view: crate::utils::constants::MATRIX_COORDINATE_X_FLIP * pov.view_matrix(),
+                projection,
+            }],
+        )?;
+        let mut instances = vec![];
+        let draw_groups = Self::generate_draw_calls(
+            resource_map,
+            scene,
+            pov,
+            &projection,
+            self.hidden_material.as_ref(),
+            &mut instances,
+        );
+        if !instances.is_empty() {
+            gpu.write_buffer_data(&buffers.depth_only_instance_buffer, &instances)?;
+        }
+
+        let (undefined_state, attachment_state) =
+            ImageTransition::UndefinedToDepthAttachment.transition_infos();
+        let read_state = ImageTransition::TransferDstToShaderRead
+            .transition_infos()
+            .1;
+        let label = command_buffer.begin_debug_region("Depth only", [0.2, 0.2, 0.2, 1.0]);
+        command_buffer.transition_images(&[(depth_image, undefined_state, attachment_state)]);
+        let mut render_pass_command = command_buffer.begin_render_pass(&BeginRenderPassInfo {
+            color_attachments: &[],
+            depth_attachment: Some(DepthAttachment {
+                image_view: depth_view,
+                load_op: DepthLoadOp::Clear(1.0),
+                store_op: gpu::AttachmentStoreOp::Store,
+                initial_layout: ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                resolve: None,
+            }),
+            stencil_attachment: Some(StencilAttachment {
+                image_view: depth_view,
+                load_op: StencilLoadOp::Clear(0),
+                store_op: gpu::AttachmentStoreOp::Store,
+                initial_layout: ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                resolve: None,
+            }),
+            render_area: Rect2D {
+                offset: Offset2D::default(),
+                extent: depth_image.extents(),
+            },
+        });
+        Self::main_render_loop(
+            resource_map,
+            PipelineTarget::DepthOnly,
+            SampleCount::One,
+            &draw_groups,
+            &mut render_pass_command,
+            &buffers.depth_only_descriptor_set,
+        );
+        drop(render_pass_command);
+        command_buffer.transition_images(&[(depth_image, attachment_state, read_state)]);
+        label.end();
+        Ok(())
+    }
+
     fn main_render_loop(
         resource_map: &ResourceMap,
         pipeline_target: PipelineTarget,
-        draw_hashmap: &HashMap<&MasterMaterial, Vec<DrawCall>>,
-        ctx: &mut RenderPassContext,
+        samples: SampleCount,
+        draw_groups: &[(&MasterMaterial, Vec<DrawCall>)],
+        render_pass_command: &mut RenderPassCommand,
+        global_set: &GpuDescriptorSet,
     ) {
         let mut total_primitives_rendered = 0;
-        for (master, material_draw_calls) in draw_hashmap.iter() {
+        let mut global_set_bound = false;
+        // The primitives of a mesh share its buffers, so they're bound once per mesh
+        let mut bound_buffers: Option<&MeshBuffers> = None;
+        for (master, material_draw_calls) in draw_groups {
             {
                 let pipeline = master
-                    .get_pipeline(pipeline_target)
+                    .get_pipeline(pipeline_target, samples)
                     .expect("failed to fetch pipeline {pipeline_target:?}");
-                ctx.render_pass_command.bind_pipeline(pipeline);
-                ctx.render_pass_command.bind_descriptor_sets(
-                    PipelineBindPoint::GRAPHICS,
-                    pipeline,
-                    0,
-                    &[ctx.read_descriptor_set.expect("No descriptor set???")],
-                );
+                render_pass_command.bind_pipeline(pipeline);
+                // All the surface pipelines agree on the global set's layout, so binding
+                // another pipeline does not disturb it
+                if !global_set_bound {
+                    render_pass_command.bind_descriptor_sets(
+                        PipelineBindPoint::GRAPHICS,
+                        pipeline,
+                        MasterMaterial::GLOBAL_SET_INDEX,
+                        &[global_set],
+                    );
+                    global_set_bound = true;
+                }
 
                 for (idx, draw_call) in material_draw_calls.iter().enumerate() {
                     let material = &draw_call.material;
                     let material = resource_map.get(material);
-                    let primitive_label = ctx.render_pass_command.begin_debug_region(
+                    let primitive_label = render_pass_command.begin_debug_region(
                         &format!(
                             "{} - {}, total primitives rendered {total_primitives_rendered}",
                             material.name, idx
                         ),
                         [0.0, 0.3, 0.4, 1.0],
                     );
-                    ctx.render_pass_command.bind_descriptor_sets(
+                    render_pass_command.bind_descriptor_sets_with_offsets(
                         PipelineBindPoint::GRAPHICS,
                         pipeline,
-                        1,
+                        MasterMaterial::USER_SET_INDEX,
                         &[&material.user_descriptor_set],
+                        material.dynamic_offset(resource_map).as_slice(),
                     );
-                    ctx.render_pass_command.bind_index_buffer(
-                        &draw_call.prim.index_buffer,
-                        0,
-                        IndexType::UINT32,
-                    );
-                    ctx.render_pass_command.bind_vertex_buffer(
-                        0,
-                        &[
-                            &draw_call.prim.position_component,
-                            &draw_call.prim.color_component,
-                            &draw_call.prim.normal_component,
-                            &draw_call.prim.tangent_component,
-                            &draw_call.prim.uv_component,
-                        ],
-                        &[0, 0, 0, 0, 0],
-                    );
-                    ctx.render_pass_command
-                        .push_constant(pipeline, &draw_call.transform, 0);
-                    ctx.render_pass_command
-                        .draw_indexed(draw_call.prim.index_count, 1, 0, 0, 0);
+                    if !bound_buffers.is_some_and(|b| std::ptr::eq(b, draw_call.buffers)) {
+                        draw_call.buffers.bind(render_pass_command);
+                        bound_buffers = Some(draw_call.buffers);
+                    }
+                    render_pass_command.set_mirrored(draw_call.mirrored);
+                    let prim = draw_call.prim;
+                    if prim.index_count > 0 {
+                        render_pass_command.draw_indexed(
+                            prim.index_count,
+                            1,
+                            prim.first_index,
+                            prim.vertex_offset as i32,
+                            draw_call.instance_index,
+                        );
+                    } else {
+                        render_pass_command.draw(
+                            prim.vertex_count,
+                            1,
+                            prim.vertex_offset,
+                            draw_call.instance_index,
+                        );
+                    }
 
                     primitive_label.end();
                     total_primitives_rendered += 1;
                 }
-                ctx.render_pass_command.insert_debug_label(
+                render_pass_command.insert_debug_label(
                     &format!("Total primtives drawn this frame: {total_primitives_rendered}"),
                     [0.0, 0.3, 0.4, 1.0],
                 );
@@ -303,29 +1653,151 @@ impl DeferredRenderingPipeline {
         }
     }
 
+    fn scene_bounds(resource_map: &ResourceMap, scene: &Scene) -> BoundingBox {
+        scene
+            .primitives
+            .iter()
+            .map(|primitive| {
+                resource_map
+                    .get(&primitive.mesh)
+                    .bounds
+                    .transformed(&primitive.transform)
+            })
+            .reduce(|bounds, primitive_bounds| bounds.union(&primitive_bounds))
+            .unwrap_or_default()
+    }
+
+    /* The fraction of the screen height covered by the bounds' bounding sphere:
+     * when the eye is inside the sphere the size is infinite */
+    fn projected_screen_size(
+        pov: &Camera,
+        projection: &Matrix4<f32>,
+        world_bounds: &BoundingBox,
+    ) -> f32 {
+        let radius = world_bounds.extents().norm();
+        let distance = (world_bounds.center() - pov.location).norm();
+        if distance <= radius {
+            f32::INFINITY
+        } else {
+            radius * projection[(1, 1)].abs() / distance
+        }
+    }
+
+    /* Each particle system owns max_particles slots of the particle buffer, following the slots
+     * of the systems before it: returns the steps simulating the systems that changed since the
+     * last frame, and how many slots are in use */
+    fn particle_simulation_steps(
+        particle_states: &mut Vec<ParticleSimulationState>,
+        scene: &Scene,
+    ) -> (Vec<ParticleSimulationStep>, u32) {
+        let particle_systems = scene.all_particle_systems();
+        particle_states.resize(particle_systems.len(), ParticleSimulationState::default());
+        let mut steps = vec![];
+        let mut first_slot = 0;
+        for (particle_system, state) in particle_systems.iter().zip(particle_states) {
+            let slot_count = particle_system
+                .settings
+                .max_particles
+                .min(Self::MAX_PARTICLES - first_slot);
+            steps.extend(particle_system.simulation_step(
+                state,
+                first_slot as u32,
+                slot_count as u32,
+            ));
+            first_slot += slot_count;
+        }
+        (steps, first_slot as u32)
+    }
+
+    // The instances of the draw calls are collected in instances,
+    // in the order of the draw calls' instance indices
+    // Uploads the parameter blocks written since the last frame, before any pass binds them
+    fn flush_parameter_arenas(
+        scene: &Scene,
+        resource_map: &ResourceMap,
+        frame: u64,
+    ) -> anyhow::Result<()> {
+        let gpu = &app_state().gpu;
+        let materials = scene.primitives.iter().flat_map(|p| &p.materials);
+        for material in materials {
+            if let Some(arena) = resource_map.get(material).parameter_arena() {
+                resource_map.get(arena).flush(gpu, frame)?;
+            }
+        }
+        Ok(())
+    }
+
     fn generate_draw_calls<'r, 's>(
         resource_map: &'r ResourceMap,
         scene: &'s Scene,
-    ) -> HashMap<&'s MasterMaterial, Vec<DrawCall<'s>>>
+        pov: &Camera,
+        projection: &Matrix4<f32>,
+        hidden_material: Option<&ResourceHandle<MaterialInstance>>,
+        instances: &mut Vec<GpuInstance>,
+    ) -> Vec<(&'s MasterMaterial, Vec<DrawCall<'s>>)>
     where
         'r: 's,
     {
-        let mut draw_hashmap: HashMap<&MasterMaterial, Vec<DrawCall>> = HashMap::new();
+        let mut draw_calls = vec![];
 
         for primitive in scene.primitives.iter() {
             let mesh = resource_map.get(&primitive.mesh);
-            for (idx, mesh_prim) in mesh.primitives.iter().enumerate() {
+            let (buffers, mesh_primitives) = if mesh.lods.is_empty() {
+                (&mesh.buffers, mesh.primitives.as_slice())
+            } else {
+                let world_bounds = mesh.bounds.transformed(&primitive.transform);
+                mesh.select_lod(Self::projected_screen_size(pov, projection, &world_bounds))
+            };
+            let mirrored = flips_winding(&primitive.transform);
+            for (idx, mesh_prim) in mesh_primitives.iter().enumerate() {
                 let material_handle = primitive.materials[idx].clone();
+                if hidden_material == Some(&material_handle) {
+                    continue;
+                }
                 let material = resource_map.get(&material_handle);
                 let master = resource_map.get(&material.owner);
-                draw_hashmap.entry(master).or_default().push(DrawCall {
-                    prim: mesh_prim,
-                    transform: primitive.transform,
-                    material: material_handle,
-                });
+                if master.topology != mesh.topology {
+                    warn!(
+                        "Skipping primitive {idx}: material '{}' draws {:?}, but the mesh is {:?}",
+                        master.name, master.topology, mesh.topology
+                    );
+                    continue;
+                }
+                if master.vertex_input != mesh.vertex_input {
+                    warn!(
+                        "Skipping primitive {idx}: material '{}' expects the vertex input {:?}, but the mesh has {:?}",
+                        master.name, master.vertex_input, mesh.vertex_input
+                    );
+                    continue;
+                }
+                if master.vertex_input.is_none() && master.vertex_encoding != mesh.vertex_encoding {
+                    warn!(
+                        "Skipping primitive {idx}: material '{}' expects vertices encoded as {:?}, but the mesh uses {:?}",
+                        master.name, master.vertex_encoding, mesh.vertex_encoding
+                    );
+                    continue;
+                }
+                if instances.len() >= Self::MAX_INSTANCES {
+                    warn!(
+                        "Skipping primitive {idx}: more than {} instances in the scene",
+                        Self::MAX_INSTANCES
+                    );
+                    continue;
+                }
+                draw_calls.push((
+                    master,
+                    DrawCall {
+                        buffers,
+                        prim: mesh_prim,
+                        instance_index: instances.len() as u32,
+                        material: material_handle,
+                        mirrored,
+                    },
+                ));
+                instances.push(GpuInstance::new(primitive.transform));
             }
         }
-        draw_hashmap
+        group_in_draw_order(draw_calls, |master| &master.name)
     }
 }
 
@@ -341,7 +1813,7 @@ impl DeferredRenderingMaterialContext {
             gpu,
             &RenderPassDescription {
                 attachments: &[RenderPassAttachment {
-                    format: ImageFormat::Depth.to_vk(),
+                    format: DeferredRenderingPipeline::DEPTH_FORMAT.to_vk(),
                     samples: SampleCountFlags::TYPE_1,
                     load_op: AttachmentLoadOp::CLEAR,
                     store_op: AttachmentStoreOp::STORE,
@@ -459,7 +1931,7 @@ impl DeferredRenderingMaterialContext {
             },
             // Emissive
             RenderPassAttachment {
-                format: ImageFormat::Rgba8.to_vk(),
+                format: DeferredRenderingPipeline::HDR_FORMAT.to_vk(),
                 samples: SampleCountFlags::TYPE_1,
                 load_op: AttachmentLoadOp::CLEAR,
                 store_op: AttachmentStoreOp::STORE,
@@ -501,7 +1973,7 @@ impl DeferredRenderingMaterialContext {
             },
             // Depth
             RenderPassAttachment {
-                format: ImageFormat::Depth.to_vk(),
+                format: DeferredRenderingPipeline::DEPTH_FORMAT.to_vk(),
                 samples: SampleCountFlags::TYPE_1,
                 load_op: AttachmentLoadOp::LOAD,
                 store_op: AttachmentStoreOp::NONE,
@@ -632,9 +2104,24 @@ impl RenderingPipeline for DeferredRenderingPipeline {
         backbuffer: Backbuffer,
         resource_map: &ResourceMap,
     ) -> anyhow::Result<CommandBuffer> {
+        backbuffer.validate()?;
+        // The scene is rendered at render_size, the Upscale pass upscales it to the backbuffer
+        let render_size = Self::scaled_extent(backbuffer.size, self.render_scale);
+        self.resize_targets(backbuffer.size, render_size)?;
+        let tonemap_params = TonemapParams {
+            encode_srgb: !backbuffer.is_srgb() as u32,
+        };
+        let ssr_params = self
+            .ssr_enabled
+            .then(|| SsrShaderParams::new(self.ssr_settings, pov));
+        let dof_params = self
+            .dof
+            .map(|dof| DofShaderParams::new(dof, pov, render_size));
+
         let projection = pov.projection();
 
         let current_buffers = &self.frame_buffers[self.in_flight_frame];
+        let mut pass_timer = self.pass_timers.get_mut(self.in_flight_frame);
 
         self.in_flight_frame = (1 + self.in_flight_frame) % self.max_frames_in_flight;
 
@@ -644,14 +2131,95 @@ impl RenderingPipeline for DeferredRenderingPipeline {
                 &current_buffers.camera_buffer,
                 &[PerFrameData {
                     eye: Vector4::new(pov.location[0], pov.location[1], pov.location[2], 0.0),
-                    view: crate::utils::constants::MATRIX_COORDINATE_X_FLIP * pov.view(),
+                    view: crate::utils::constants::MATRIX_COORDINATE_X_FLIP * pov.view_matrix(),
                     projection,
                 }],
             )
             .unwrap();
 
-        let collected_active_lights: Vec<GpuLightInfo> =
-            scene.all_enabled_lights().map(|l| l.into()).collect();
+        let frame = crate::app_state().time().frames_since_start();
+        Self::flush_parameter_arenas(scene, resource_map, frame)?;
+        let cascade_splits = shadows::cascade_splits(
+            pov.near,
+            pov.far,
+            self.shadow_cascade_count,
+            self.cascade_split_lambda,
+        );
+        let mut shadow_map_sources = vec![];
+        // The atlas slots of each enabled light, lights that don't fit in the atlas get no shadows
+        let light_shadow_slots: Vec<Option<Range<usize>>> = scene
+            .all_enabled_lights()
+            .map(|light| {
+                if !shadows::casts_shadows(light) {
+                    return None;
+                }
+                let sources = shadows::shadow_map_sources(light, pov, &cascade_splits);
+                let slots = shadow_map_sources.len()..shadow_map_sources.len() + sources.len();
+                if slots.end > shadows::MAX_SHADOW_MAPS {
+                    return None;
+                }
+                shadow_map_sources.extend(sources);
+                Some(slots)
+            })
+            .collect();
+        let shadow_updates = self.shadow_maps.schedule(
+            &shadow_map_sources,
+            frame,
+            self.max_shadow_updates_per_frame,
+        );
+        if !shadow_updates.is_empty() {
+            let scene_bounds = Self::scene_bounds(resource_map, scene);
+            for &slot in &shadow_updates {
+                let source = &shadow_map_sources[slot];
+                let camera = shadows::shadow_camera(source, &scene_bounds)
+                    .expect("Shadow casters always have a shadow camera");
+                let view = crate::utils::constants::MATRIX_COORDINATE_X_FLIP * camera.view;
+                super::app_state().gpu.write_buffer_data_with_offset(
+                    &current_buffers.shadow_camera_buffer,
+                    slot as u64 * self.shadow_camera_stride,
+                    &[PerFrameData {
+                        eye: camera.eye.to_homogeneous(),
+                        view,
+                        projection: camera.projection,
+                    }],
+                )?;
+                self.shadow_maps.mark_rendered(
+                    slot,
+                    source,
+                    camera.projection * view,
+                    camera.covered,
+                    frame,
+                );
+            }
+        }
+
+        let collected_active_lights: Vec<GpuLightInfo> = scene
+            .all_enabled_lights()
+            .zip(light_shadow_slots)
+            .map(|(light, slots)| {
+                let mut light_info = GpuLightInfo::from(light);
+                let Some(slots) = slots else {
+                    return light_info;
+                };
+                let view_projections: Option<Vec<Matrix4<f32>>> = slots
+                    .clone()
+                    .map(|slot| self.shadow_maps.view_projection(slot))
+                    .collect();
+                // The shadows are used once all of the light's shadow maps have been rendered
+                if let Some(view_projections) = view_projections {
+                    light_info.ty[1] = view_projections.len() as u32;
+                    for (cascade, (slot, view_projection)) in
+                        slots.zip(view_projections).enumerate()
+                    {
+                        light_info.shadow_view_projections[cascade] = view_projection;
+                        light_info.shadow_rects[cascade] = shadows::shadow_map_uv_rect(slot);
+                        light_info.cascade_splits[cascade] =
+                            cascade_splits.get(cascade).copied().unwrap_or(f32::MAX);
+                    }
+                }
+                light_info
+            })
+            .collect();
 
         super::app_state()
             .gpu
@@ -672,40 +2240,87 @@ impl RenderingPipeline for DeferredRenderingPipeline {
 
         app_state().gpu.begin_frame()?;
 
-        let draw_hashmap = Self::generate_draw_calls(resource_map, scene);
+        let mut instances = vec![];
+        let draw_groups = Self::generate_draw_calls(
+            resource_map,
+            scene,
+            pov,
+            &projection,
+            self.hidden_material.as_ref(),
+            &mut instances,
+        );
+        if !instances.is_empty() {
+            super::app_state()
+                .gpu
+                .write_buffer_data(&current_buffers.instance_buffer, &instances)?;
+        }
+
+        let (particle_steps, particle_slots) =
+            Self::particle_simulation_steps(&mut self.particle_states, scene);
+
+        let bounds: Vec<GpuBounds> = if self.draw_bounds {
+            scene
+                .primitives
+                .iter()
+                .map(|primitive| {
+                    let mesh = resource_map.get(&primitive.mesh);
+                    mesh.bounds.transformed(&primitive.transform).into()
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+
+        let output = self.output.as_ref().unwrap();
 
         //#region render graph resources
         let framebuffer_rgba_desc = crate::ImageDescription {
-            width: backbuffer.size.width,
-            height: backbuffer.size.height,
+            width: render_size.width,
+            height: render_size.height,
             format: ImageFormat::Rgba8,
             samples: 1,
             present: false,
             clear_value: ClearValue::Color([0.0, 0.0, 0.0, 0.0]),
         };
         let framebuffer_normal_desc = crate::ImageDescription {
-            width: backbuffer.size.width,
-            height: backbuffer.size.height,
+            width: render_size.width,
+            height: render_size.height,
             format: ImageFormat::Rgba8,
             samples: 1,
             present: false,
             clear_value: ClearValue::Color([0.5, 0.5, 0.5, 1.0]),
         };
         let framebuffer_vector_desc = crate::ImageDescription {
-            width: backbuffer.size.width,
-            height: backbuffer.size.height,
+            width: render_size.width,
+            height: render_size.height,
             format: ImageFormat::RgbaFloat,
             samples: 1,
             present: false,
             clear_value: ClearValue::Color([0.0, 0.0, 0.0, 0.0]),
         };
+        let framebuffer_hdr_desc = crate::ImageDescription {
+            width: render_size.width,
+            height: render_size.height,
+            format: Self::HDR_FORMAT,
+            samples: 1,
+            present: false,
+            clear_value: ClearValue::Color([0.0, 0.0, 0.0, 0.0]),
+        };
         let framebuffer_depth_desc = crate::ImageDescription {
+            width: render_size.width,
+            height: render_size.height,
+            format: Self::DEPTH_FORMAT,
+            samples: 1,
+            present: false,
+            clear_value: ClearValue::Depth(1.0),
+        };
+        let framebuffer_output_desc = crate::ImageDescription {
             width: backbuffer.size.width,
             height: backbuffer.size.height,
-            format: ImageFormat::Depth,
+            format: ImageFormat::Rgba8,
             samples: 1,
             present: false,
-            clear_value: ClearValue::Depth(1.0),
+            clear_value: ClearValue::Color([0.0, 0.0, 0.0, 0.0]),
         };
         let framebuffer_swapchain_desc = crate::ImageDescription {
             width: backbuffer.size.width,
@@ -725,6 +2340,15 @@ impl RenderingPipeline for DeferredRenderingPipeline {
             true,
         )?;
 
+        let instance_buffer = self.render_graph.use_buffer(
+            "instance-buffer",
+            &BufferDescription {
+                length: (std::mem::size_of::<GpuInstance>() * Self::MAX_INSTANCES) as u64,
+                ty: BufferType::Storage,
+            },
+            true,
+        )?;
+
         let light_buffer = self.render_graph.use_buffer(
             "light-buffer",
             &BufferDescription {
@@ -737,18 +2361,49 @@ impl RenderingPipeline for DeferredRenderingPipeline {
         let swapchain_image =
             self.render_graph
                 .use_image("swapchain", &framebuffer_swapchain_desc, true)?;
+        let shadow_atlas = self.render_graph.use_image(
+            "shadow-atlas",
+            &crate::ImageDescription {
+                width: shadows::SHADOW_ATLAS_SIZE,
+                height: shadows::SHADOW_ATLAS_SIZE,
+                format: Self::DEPTH_FORMAT,
+                samples: 1,
+                present: false,
+                clear_value: ClearValue::Depth(1.0),
+            },
+            true,
+        )?;
         let depth_target =
             self.render_graph
                 .use_image("depth-buffer", &framebuffer_depth_desc, false)?;
         let color_target =
             self.render_graph
-                .use_image("color-buffer", &framebuffer_vector_desc, false)?;
+                .use_image("color-buffer", &framebuffer_hdr_desc, false)?;
+        let ssr_output = if ssr_params.is_some() {
+            Some(
+                self.render_graph
+                    .use_image("ssr-buffer", &framebuffer_hdr_desc, false)?,
+            )
+        } else {
+            None
+        };
+        let dof_output = if dof_params.is_some() {
+            Some(
+                self.render_graph
+                    .use_image("dof-buffer", &framebuffer_hdr_desc, false)?,
+            )
+        } else {
+            None
+        };
         let tonemap_output =
             self.render_graph
                 .use_image("tonemap-buffer", &framebuffer_rgba_desc, false)?;
         let fxaa_output =
             self.render_graph
                 .use_image("fxaa-buffer", &framebuffer_rgba_desc, false)?;
+        let upscale_output =
+            self.render_graph
+                .use_image("upscale-buffer", &framebuffer_output_desc, true)?;
 
         let position_target =
             self.render_graph
@@ -761,33 +2416,71 @@ impl RenderingPipeline for DeferredRenderingPipeline {
                 .use_image("diffuse_buffer", &framebuffer_rgba_desc, false)?;
         let emissive_target =
             self.render_graph
-                .use_image("emissive_buffer", &framebuffer_rgba_desc, false)?;
+                .use_image("emissive_buffer", &framebuffer_hdr_desc, false)?;
         let pbr_target =
             self.render_graph
                 .use_image("pbr_buffer", &framebuffer_rgba_desc, false)?;
 
         self.render_graph.persist_resource(&swapchain_image);
 
+        // The lighting reads the gbuffer's colors and depth from these targets
+        let resolved_targets = [
+            position_target,
+            normal_target,
+            diffuse_target,
+            emissive_target,
+            pbr_target,
+            depth_target,
+        ];
+        // A multisampled gbuffer is drawn into its own targets, resolved at the end of the pass
+        let multisampled_targets = if self.sample_count == SampleCount::One {
+            None
+        } else {
+            let samples = self.sample_count.count();
+            let mut targets = resolved_targets;
+            for (target, (label, description)) in targets.iter_mut().zip([
+                ("position-buffer-msaa", framebuffer_vector_desc),
+                ("normal_buffer-msaa", framebuffer_normal_desc),
+                ("diffuse_buffer-msaa", framebuffer_rgba_desc),
+                ("emissive_buffer-msaa", framebuffer_hdr_desc),
+                ("pbr_buffer-msaa", framebuffer_rgba_desc),
+                ("depth-buffer-msaa", framebuffer_depth_desc),
+            ]) {
+                *target = self.render_graph.use_image(
+                    label,
+                    &crate::ImageDescription {
+                        samples,
+                        ..description
+                    },
+                    false,
+                )?;
+            }
+            Some(targets)
+        };
+        let (gbuffer_targets, gbuffer_resolves): ([ResourceId; 6], &[ResourceId]) =
+            match multisampled_targets {
+                Some(targets) => (targets, &resolved_targets),
+                None => (resolved_targets, &[]),
+            };
+        let (gbuffer_colors, gbuffer_depth) = (&gbuffer_targets[..5], gbuffer_targets[5]);
+
         let dbuffer_pass = self
             .render_graph
-            .begin_render_pass("EarlyZPass", backbuffer.size)?
-            .writes_attachments(&[depth_target])
-            .shader_reads(&[camera_buffer])
+            .begin_render_pass("EarlyZPass", render_size)?
+            .writes_attachments(&[gbuffer_depth])
+            // Must match SURFACE_GLOBAL_INPUTS
+            .shader_reads(&[camera_buffer, instance_buffer, light_buffer])
             .mark_external()
             .commit();
 
         let gbuffer_pass = self
             .render_graph
-            .begin_render_pass("GBuffer", backbuffer.size)?
-            .writes_attachments(&[
-                position_target,
-                normal_target,
-                diffuse_target,
-                emissive_target,
-                pbr_target,
-            ])
-            .reads_attachments(&[depth_target])
-            .shader_reads(&[camera_buffer])
+            .begin_render_pass("GBuffer", render_size)?
+            .writes_attachments(gbuffer_colors)
+            .reads_attachments(&[gbuffer_depth])
+            .resolves_attachments(gbuffer_resolves)
+            // Must match SURFACE_GLOBAL_INPUTS
+            .shader_reads(&[camera_buffer, instance_buffer, light_buffer])
             .mark_external()
             .with_blend_state(BlendState {
                 blend_enable: false,
@@ -803,8 +2496,9 @@ impl RenderingPipeline for DeferredRenderingPipeline {
 
         let combine_pass = self
             .render_graph
-            .begin_render_pass("GBufferCombine", backbuffer.size)?
+            .begin_render_pass("GBufferCombine", render_size)?
             .writes_attachments(&[color_target])
+            .reads_attachments(&[depth_target])
             .shader_reads(&[
                 position_target,
                 normal_target,
@@ -813,6 +2507,7 @@ impl RenderingPipeline for DeferredRenderingPipeline {
                 pbr_target,
                 camera_buffer,
                 light_buffer,
+                shadow_atlas,
             ])
             .with_blend_state(BlendState {
                 blend_enable: false,
@@ -826,10 +2521,64 @@ impl RenderingPipeline for DeferredRenderingPipeline {
             })
             .commit();
 
+        // Adds the reflections to the lit frame
+        let ssr_pass = match ssr_output {
+            Some(ssr_output) => Some(
+                self.render_graph
+                    .begin_render_pass("ScreenSpaceReflections", render_size)?
+                    .shader_reads(&[
+                        color_target,
+                        position_target,
+                        normal_target,
+                        diffuse_target,
+                        pbr_target,
+                        depth_target,
+                        camera_buffer,
+                    ])
+                    .writes_attachments(&[ssr_output])
+                    .with_blend_state(BlendState {
+                        blend_enable: false,
+                        src_color_blend_factor: BlendFactor::ONE,
+                        dst_color_blend_factor: BlendFactor::ZERO,
+                        color_blend_op: BlendOp::ADD,
+                        src_alpha_blend_factor: BlendFactor::ONE,
+                        dst_alpha_blend_factor: BlendFactor::ZERO,
+                        alpha_blend_op: BlendOp::ADD,
+                        color_write_mask: ColorComponentFlags::RGBA,
+                    })
+                    .commit(),
+            ),
+            None => None,
+        };
+        let lit_output = ssr_output.unwrap_or(color_target);
+
+        // Blurs the lit frame, the depth is linearized with the camera's near and far planes
+        let dof_pass = match dof_output {
+            Some(dof_output) => Some(
+                self.render_graph
+                    .begin_render_pass("DepthOfField", render_size)?
+                    .shader_reads(&[lit_output, depth_target])
+                    .writes_attachments(&[dof_output])
+                    .with_blend_state(BlendState {
+                        blend_enable: false,
+                        src_color_blend_factor: BlendFactor::ONE,
+                        dst_color_blend_factor: BlendFactor::ZERO,
+                        color_blend_op: BlendOp::ADD,
+                        src_alpha_blend_factor: BlendFactor::ONE,
+                        dst_alpha_blend_factor: BlendFactor::ZERO,
+                        alpha_blend_op: BlendOp::ADD,
+                        color_write_mask: ColorComponentFlags::RGBA,
+                    })
+                    .commit(),
+            ),
+            None => None,
+        };
+        let tonemap_input = dof_output.unwrap_or(lit_output);
+
         let tonemap_pass = self
             .render_graph
-            .begin_render_pass("Tonemapping", backbuffer.size)?
-            .shader_reads(&[color_target])
+            .begin_render_pass("Tonemapping", render_size)?
+            .shader_reads(&[tonemap_input])
             .writes_attachments(&[tonemap_output])
             .with_blend_state(BlendState {
                 blend_enable: false,
@@ -844,7 +2593,7 @@ impl RenderingPipeline for DeferredRenderingPipeline {
             .commit();
         let fxaa_pass = self
             .render_graph
-            .begin_render_pass("Fxaa", backbuffer.size)?
+            .begin_render_pass("Fxaa", render_size)?
             .shader_reads(&[tonemap_output])
             .writes_attachments(&[fxaa_output])
             .with_blend_state(BlendState {
@@ -858,11 +2607,27 @@ impl RenderingPipeline for DeferredRenderingPipeline {
                 color_write_mask: ColorComponentFlags::RGBA,
             })
             .commit();
+        let upscale_pass = self
+            .render_graph
+            .begin_render_pass("Upscale", backbuffer.size)?
+            .shader_reads(&[fxaa_output])
+            .writes_attachments(&[upscale_output])
+            .with_blend_state(BlendState {
+                blend_enable: false,
+                src_color_blend_factor: BlendFactor::ONE,
+                dst_color_blend_factor: BlendFactor::ZERO,
+                color_blend_op: BlendOp::ADD,
+                src_alpha_blend_factor: BlendFactor::ONE,
+                dst_alpha_blend_factor: BlendFactor::ZERO,
+                alpha_blend_op: BlendOp::ADD,
+                color_write_mask: ColorComponentFlags::RGBA,
+            })
+            .commit();
 
         let present_render_pass = self
             .render_graph
             .begin_render_pass("Present", backbuffer.size)?
-            .shader_reads(&[fxaa_output])
+            .shader_reads(&[upscale_output])
             .writes_attachments(&[swapchain_image])
             .with_blend_state(BlendState {
                 blend_enable: false,
@@ -911,11 +2676,103 @@ impl RenderingPipeline for DeferredRenderingPipeline {
                         max_depth_bounds: 1.0,
                     },
                     logic_op: None,
-                    push_constant_ranges: &[],
+                    push_constant_ranges: &[PushConstantRange {
+                        stage_flags: ShaderStageFlags::ALL,
+                        offset: 0,
+                        size: std::mem::size_of::<CombineParams>() as _,
+                    }],
                 },
             },
         )?;
 
+        if let Some(ssr_pass) = &ssr_pass {
+            self.render_graph.define_pipeline_for_renderpass(
+                &crate::app_state().gpu,
+                ssr_pass,
+                "SsrPipeline",
+                &RenderGraphPipelineDescription {
+                    vertex_inputs: &[],
+                    stage: RenderStage::Graphics {
+                        vertex: ModuleInfo {
+                            module: &self.fxaa_vs,
+                            entry_point: "main",
+                        },
+                        fragment: ModuleInfo {
+                            module: &self.ssr_fs,
+                            entry_point: "main",
+                        },
+                    },
+                    fragment_state: FragmentState {
+                        input_topology: gpu::PrimitiveTopology::TriangleList,
+                        primitive_restart: false,
+                        polygon_mode: gpu::PolygonMode::Fill,
+                        cull_mode: gpu::CullMode::None,
+                        front_face: gpu::FrontFace::ClockWise,
+                        depth_stencil_state: DepthStencilState {
+                            depth_test_enable: false,
+                            depth_write_enable: false,
+                            depth_compare_op: CompareOp::ALWAYS,
+                            stencil_test_enable: false,
+                            front: StencilOpState::default(),
+                            back: StencilOpState::default(),
+                            min_depth_bounds: 0.0,
+                            max_depth_bounds: 1.0,
+                        },
+                        logic_op: None,
+                        push_constant_ranges: &[PushConstantRange {
+                            stage_flags: ShaderStageFlags::ALL,
+                            offset: 0,
+                            size: std::mem::size_of::<SsrShaderParams>() as _,
+                        }],
+                    },
+                },
+            )?;
+        }
+
+        if let Some(dof_pass) = &dof_pass {
+            self.render_graph.define_pipeline_for_renderpass(
+                &crate::app_state().gpu,
+                dof_pass,
+                "DofPipeline",
+                &RenderGraphPipelineDescription {
+                    vertex_inputs: &[],
+                    stage: RenderStage::Graphics {
+                        vertex: ModuleInfo {
+                            module: &self.fxaa_vs,
+                            entry_point: "main",
+                        },
+                        fragment: ModuleInfo {
+                            module: &self.dof_fs,
+                            entry_point: "main",
+                        },
+                    },
+                    fragment_state: FragmentState {
+                        input_topology: gpu::PrimitiveTopology::TriangleList,
+                        primitive_restart: false,
+                        polygon_mode: gpu::PolygonMode::Fill,
+                        cull_mode: gpu::CullMode::None,
+                        front_face: gpu::FrontFace::ClockWise,
+                        depth_stencil_state: DepthStencilState {
+                            depth_test_enable: false,
+                            depth_write_enable: false,
+                            depth_compare_op: CompareOp::ALWAYS,
+                            stencil_test_enable: false,
+                            front: StencilOpState::default(),
+                            back: StencilOpState::default(),
+                            min_depth_bounds: 0.0,
+                            max_depth_bounds: 1.0,
+                        },
+                        logic_op: None,
+                        push_constant_ranges: &[PushConstantRange {
+                            stage_flags: ShaderStageFlags::ALL,
+                            offset: 0,
+                            size: std::mem::size_of::<DofShaderParams>() as _,
+                        }],
+                    },
+                },
+            )?;
+        }
+
         self.render_graph.define_pipeline_for_renderpass(
             &crate::app_state().gpu,
             &tonemap_pass,
@@ -949,7 +2806,11 @@ impl RenderingPipeline for DeferredRenderingPipeline {
                         max_depth_bounds: 1.0,
                     },
                     logic_op: None,
-                    push_constant_ranges: &[],
+                    push_constant_ranges: &[PushConstantRange {
+                        stage_flags: ShaderStageFlags::ALL,
+                        offset: 0,
+                        size: std::mem::size_of::<TonemapParams>() as _,
+                    }],
                 },
             },
         )?;
@@ -995,6 +2856,47 @@ impl RenderingPipeline for DeferredRenderingPipeline {
                 },
             },
         )?;
+        self.render_graph.define_pipeline_for_renderpass(
+            &crate::app_state().gpu,
+            &upscale_pass,
+            "UpscalePipeline",
+            &RenderGraphPipelineDescription {
+                vertex_inputs: &[],
+                stage: RenderStage::Graphics {
+                    vertex: ModuleInfo {
+                        module: &self.fxaa_vs,
+                        entry_point: "main",
+                    },
+                    fragment: ModuleInfo {
+                        module: &self.upscale_fs,
+                        entry_point: "main",
+                    },
+                },
+                fragment_state: FragmentState {
+                    input_topology: gpu::PrimitiveTopology::TriangleList,
+                    primitive_restart: false,
+                    polygon_mode: gpu::PolygonMode::Fill,
+                    cull_mode: gpu::CullMode::None,
+                    front_face: gpu::FrontFace::ClockWise,
+                    depth_stencil_state: DepthStencilState {
+                        depth_test_enable: false,
+                        depth_write_enable: false,
+                        depth_compare_op: CompareOp::ALWAYS,
+                        stencil_test_enable: false,
+                        front: StencilOpState::default(),
+                        back: StencilOpState::default(),
+                        min_depth_bounds: 0.0,
+                        max_depth_bounds: 1.0,
+                    },
+                    logic_op: None,
+                    push_constant_ranges: &[PushConstantRange {
+                        stage_flags: ShaderStageFlags::ALL,
+                        offset: 0,
+                        size: std::mem::size_of::<UpscaleParams>() as _,
+                    }],
+                },
+            },
+        )?;
         self.render_graph.define_pipeline_for_renderpass(
             &app_state().gpu,
             &present_render_pass,
@@ -1037,36 +2939,239 @@ impl RenderingPipeline for DeferredRenderingPipeline {
 
         let mut graphics_command_buffer =
             CommandBuffer::new(&crate::app_state().gpu, gpu::QueueType::Graphics)?;
+
+        // The timer was last used by this frame's previous iteration
+        if let Some(timer) = pass_timer.as_deref_mut() {
+            match timer.read(&crate::app_state().gpu) {
+                Ok(timings) => self.pass_timings = timings,
+                // The previous iteration's commands were never submitted
+                Err(ash::vk::Result::NOT_READY) => {}
+                Err(e) => return Err(e.into()),
+            }
+            timer.reset(&mut graphics_command_buffer);
+        }
+
+        // The graph expects the atlas to be left in the same state it transitions shader reads to
+        let shadow_read_state = ImageTransition::TransferDstToShaderRead
+            .transition_infos()
+            .1;
+        if !shadow_updates.is_empty() {
+            let attachment_state = ImageTransition::UndefinedToDepthAttachment
+                .transition_infos()
+                .1;
+            if let Some(timer) = pass_timer.as_deref_mut() {
+                timer.begin_pass(&mut graphics_command_buffer, "Shadow maps");
+            }
+            let shadows_label =
+                graphics_command_buffer.begin_debug_region("Shadow maps", [0.2, 0.2, 0.2, 1.0]);
+            graphics_command_buffer.transition_images(&[(
+                &self.shadow_atlas,
+                self.shadow_atlas_state,
+                attachment_state,
+            )]);
+            for &slot in &shadow_updates {
+                let label = graphics_command_buffer
+                    .begin_debug_region(&format!("Shadow map {slot}"), [0.2, 0.2, 0.2, 1.0]);
+                let mut render_pass_command =
+                    graphics_command_buffer.begin_render_pass(&BeginRenderPassInfo {
+                        color_attachments: &[],
+                        depth_attachment: Some(DepthAttachment {
+                            image_view: &self.shadow_atlas_view,
+                            // Only the render area is cleared, the other shadow maps are preserved
+                            load_op: DepthLoadOp::Clear(1.0),
+                            store_op: gpu::AttachmentStoreOp::Store,
+                            initial_layout: ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                            resolve: None,
+                        }),
+                        stencil_attachment: Some(StencilAttachment {
+                            image_view: &self.shadow_atlas_view,
+                            load_op: StencilLoadOp::Clear(0),
+                            store_op: gpu::AttachmentStoreOp::Store,
+                            initial_layout: ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                            resolve: None,
+                        }),
+                        render_area: shadows::shadow_map_rect(slot),
+                    });
+                Self::main_render_loop(
+                    resource_map,
+                    PipelineTarget::DepthOnly,
+                    SampleCount::One,
+                    &draw_groups,
+                    &mut render_pass_command,
+                    &current_buffers.shadow_descriptor_sets[slot],
+                );
+                drop(render_pass_command);
+                label.end();
+            }
+            graphics_command_buffer.transition_images(&[(
+                &self.shadow_atlas,
+                attachment_state,
+                shadow_read_state,
+            )]);
+            shadows_label.end();
+            if let Some(timer) = pass_timer.as_deref_mut() {
+                timer.end_pass(&mut graphics_command_buffer);
+            }
+        } else if self.shadow_atlas_state.layout != shadow_read_state.layout {
+            graphics_command_buffer.transition_images(&[(
+                &self.shadow_atlas,
+                self.shadow_atlas_state,
+                shadow_read_state,
+            )]);
+        }
+        self.shadow_atlas_state = shadow_read_state;
+
+        if !particle_steps.is_empty() {
+            let particles_label = graphics_command_buffer
+                .begin_debug_region("Particle simulation", [0.8, 0.5, 0.0, 1.0]);
+            let particle_barrier = |command_buffer: &mut CommandBuffer, src, dst| {
+                command_buffer.pipeline_barrier(&PipelineBarrierInfo {
+                    src_stage_mask: src,
+                    dst_stage_mask: dst,
+                    buffer_memory_barriers: &[BufferMemoryBarrier {
+                        src_access_mask: AccessFlags::SHADER_READ | AccessFlags::SHADER_WRITE,
+                        dst_access_mask: AccessFlags::SHADER_READ | AccessFlags::SHADER_WRITE,
+                        src_queue_family_index: ash::vk::QUEUE_FAMILY_IGNORED,
+                        dst_queue_family_index: ash::vk::QUEUE_FAMILY_IGNORED,
+                        buffer: &self.particle_buffer,
+                        offset: 0,
+                        size: ash::vk::WHOLE_SIZE,
+                    }],
+                    ..Default::default()
+                });
+            };
+            // The previous frames must be done drawing the particles before they're simulated
+            particle_barrier(
+                &mut graphics_command_buffer,
+                PipelineStageFlags::VERTEX_SHADER,
+                PipelineStageFlags::COMPUTE_SHADER,
+            );
+            graphics_command_buffer.bind_compute_pipeline(&self.particle_simulation_pipeline);
+            graphics_command_buffer.bind_descriptor_sets(
+                PipelineBindPoint::COMPUTE,
+                &self.particle_simulation_pipeline,
+                0,
+                &[&self.particle_simulation_descriptor_set],
+            );
+            for step in &particle_steps {
+                graphics_command_buffer.push_constant(&self.particle_simulation_pipeline, step, 0);
+                graphics_command_buffer.dispatch(step.slot_count.div_ceil(64), 1, 1);
+            }
+            particle_barrier(
+                &mut graphics_command_buffer,
+                PipelineStageFlags::COMPUTE_SHADER,
+                PipelineStageFlags::VERTEX_SHADER,
+            );
+            particles_label.end();
+        }
+
         let mut context = GraphRunContext::new(
             &crate::app_state().gpu,
             &mut graphics_command_buffer,
             crate::app_state().time().frames_since_start(),
         );
+        if let Some(timer) = pass_timer {
+            context.set_pass_timer(timer);
+        }
 
         //#region context setup
         context.register_callback(&dbuffer_pass, |_: &Gpu, ctx| {
-            Self::main_render_loop(resource_map, PipelineTarget::DepthOnly, &draw_hashmap, ctx);
+            Self::main_render_loop(
+                resource_map,
+                PipelineTarget::DepthOnly,
+                self.sample_count,
+                &draw_groups,
+                &mut ctx.render_pass_command,
+                ctx.read_descriptor_set.expect("No descriptor set???"),
+            );
         });
         context.register_callback(&gbuffer_pass, |_: &Gpu, ctx| {
             Self::main_render_loop(
                 resource_map,
                 PipelineTarget::ColorAndDepth,
-                &draw_hashmap,
-                ctx,
+                self.sample_count,
+                &draw_groups,
+                &mut ctx.render_pass_command,
+                ctx.read_descriptor_set.expect("No descriptor set???"),
             );
         });
 
         context.register_callback(&combine_pass, |_: &Gpu, ctx| {
+            ctx.render_pass_command.push_constant(
+                ctx.pipeline.expect("No combine pipeline"),
+                &CombineParams::new(self.shadow_quality, self.debug_view),
+                0,
+            );
             ctx.render_pass_command.draw(4, 1, 0, 0);
+
+            if particle_slots > 0 {
+                let particles_label = ctx
+                    .render_pass_command
+                    .begin_debug_region("Particles", [0.8, 0.5, 0.0, 1.0]);
+                ctx.render_pass_command.bind_pipeline(&self.particle_pipeline);
+                ctx.render_pass_command.bind_descriptor_sets(
+                    PipelineBindPoint::GRAPHICS,
+                    &self.particle_pipeline,
+                    0,
+                    &[&current_buffers.particle_descriptor_set],
+                );
+                // The dead particles are collapsed by the vertex shader
+                ctx.render_pass_command.draw(4, particle_slots, 0, 0);
+                particles_label.end();
+            }
+
+            if !bounds.is_empty() {
+                let bounds_label = ctx
+                    .render_pass_command
+                    .begin_debug_region("Bounds", [0.0, 1.0, 0.0, 1.0]);
+                ctx.render_pass_command.bind_pipeline(&self.bounds_pipeline);
+                ctx.render_pass_command.bind_descriptor_sets(
+                    PipelineBindPoint::GRAPHICS,
+                    &self.bounds_pipeline,
+                    0,
+                    &[ctx.read_descriptor_set.expect("No descriptor set???")],
+                );
+                for primitive_bounds in &bounds {
+                    ctx.render_pass_command.push_constant(
+                        &self.bounds_pipeline,
+                        primitive_bounds,
+                        0,
+                    );
+                    ctx.render_pass_command.draw(24, 1, 0, 0);
+                }
+                bounds_label.end();
+            }
         });
+        if let (Some(ssr_pass), Some(ssr_params)) = (&ssr_pass, &ssr_params) {
+            context.register_callback(ssr_pass, |_: &Gpu, ctx| {
+                ctx.render_pass_command.push_constant(
+                    ctx.pipeline.expect("No SSR pipeline"),
+                    ssr_params,
+                    0,
+                );
+                ctx.render_pass_command.draw(3, 1, 0, 0);
+            });
+        }
+        if let (Some(dof_pass), Some(dof_params)) = (&dof_pass, &dof_params) {
+            context.register_callback(dof_pass, |_: &Gpu, ctx| {
+                ctx.render_pass_command.push_constant(
+                    ctx.pipeline.expect("No depth of field pipeline"),
+                    dof_params,
+                    0,
+                );
+                ctx.render_pass_command.draw(3, 1, 0, 0);
+            });
+        }
         context.register_callback(&tonemap_pass, |_: &Gpu, ctx| {
+            ctx.render_pass_command.push_constant(
+                ctx.pipeline.expect("No tonemap pipeline"),
+                &tonemap_params,
+                0,
+            );
             ctx.render_pass_command.draw(4, 1, 0, 0);
         });
         context.register_callback(&fxaa_pass, |_: &Gpu, ctx| {
-            let rcp_frame = vector![
-                backbuffer.size.width as f32,
-                backbuffer.size.height as f32
-            ];
+            let rcp_frame = vector![render_size.width as f32, render_size.height as f32];
             let rcp_frame = vector![1.0 / rcp_frame.x, 1.0 / rcp_frame.y];
 
             let params = FxaaShaderParams {
@@ -1083,6 +3188,14 @@ impl RenderingPipeline for DeferredRenderingPipeline {
             );
             ctx.render_pass_command.draw(3, 1, 0, 0);
         });
+        context.register_callback(&upscale_pass, |_: &Gpu, ctx| {
+            ctx.render_pass_command.push_constant(
+                ctx.pipeline.expect("No upscale pipeline"),
+                &UpscaleParams::from(self.upscaler),
+                0,
+            );
+            ctx.render_pass_command.draw(3, 1, 0, 0);
+        });
         context.register_callback(&present_render_pass, |_: &Gpu, ctx| {
             ctx.render_pass_command.draw(4, 1, 0, 0);
         });
@@ -1107,8 +3220,17 @@ impl RenderingPipeline for DeferredRenderingPipeline {
             backbuffer.image,
             backbuffer.image_view,
         );
+        context.inject_external_image(
+            &shadow_atlas,
+            &self.shadow_atlas,
+            &self.shadow_atlas_sampled_view,
+        );
+        context.inject_external_image(&upscale_output, &output.image, &output.view);
+        context.set_external_image_state(&shadow_atlas, shadow_read_state);
+        context.inject_external_sampler(&shadow_atlas, &self.shadow_sampler);
         context.injext_external_buffer(&camera_buffer, &current_buffers.camera_buffer);
         context.injext_external_buffer(&light_buffer, &current_buffers.light_buffer);
+        context.injext_external_buffer(&instance_buffer, &current_buffers.instance_buffer);
         //#endregion
         self.render_graph.run(context, &mut self.runner)?;
 
@@ -1186,7 +3308,7 @@ impl RenderingPipeline for DeferredRenderingPipeline {
             },
             // Emissive
             RenderPassAttachment {
-                format: ImageFormat::Rgba8.to_vk(),
+                format: Self::HDR_FORMAT.to_vk(),
                 samples: SampleCountFlags::TYPE_1,
                 load_op: AttachmentLoadOp::CLEAR,
                 store_op: AttachmentStoreOp::STORE,
@@ -1227,11 +3349,18 @@ impl RenderingPipeline for DeferredRenderingPipeline {
                 },
             },
         ];
+        let sample_counts = gpu.supported_sample_counts();
         let master_description = MasterMaterialDescription {
             name: material_description.name,
             domain: material_description.domain,
+            topology: material_description.topology,
+            vertex_encoding: material_description.vertex_encoding,
+            vertex_input: material_description.vertex_input,
+            stencil_state: material_description.stencil_state,
+            depth_format: DeferredRenderingPipeline::DEPTH_FORMAT,
+            sample_counts: &sample_counts,
             global_inputs: match material_description.domain {
-                MaterialDomain::Surface => &[BindingType::Uniform],
+                MaterialDomain::Surface => SURFACE_GLOBAL_INPUTS,
                 MaterialDomain::PostProcess => &[
                     BindingType::Uniform,              // Camera buffer
                     BindingType::CombinedImageSampler, // Previous post process result/ Initial scene color,
@@ -1241,6 +3370,7 @@ impl RenderingPipeline for DeferredRenderingPipeline {
                 ],
             },
             texture_inputs: material_description.texture_inputs,
+            texture_array_inputs: material_description.texture_array_inputs,
             material_parameters: material_description.material_parameters,
             vertex_info: &VertexStageInfo {
                 entry_point: "main",
@@ -1256,14 +3386,198 @@ impl RenderingPipeline for DeferredRenderingPipeline {
             polygon_mode: gpu::PolygonMode::Fill,
             cull_mode: gpu::CullMode::Back,
             front_face: gpu::FrontFace::CounterClockWise,
-            push_constant_ranges: &[PushConstantRange {
-                stage_flags: ShaderStageFlags::ALL,
-                offset: 0,
-                size: std::mem::size_of::<Matrix4<f32>>() as u32,
-            }],
+            // The transforms are read from the instance buffer, nothing is pushed
+            push_constant_ranges: &[],
             logic_op: None,
+            conservative_raster: material_description.conservative_raster,
         };
 
         MasterMaterial::new(gpu, &master_description)
     }
 }
+
+/* The draw order of a frame: the draws are grouped by their master material, so that
+ * each pipeline is bound once, and the groups are sorted by the material's name
+ * (materials sharing a name keep the order they first appear in).
+ * Inside a group, the draws keep the order of the scene's primitives.
+ * The order only depends on the scene's contents, so it's the same each frame */
+fn group_in_draw_order<'a, K: ?Sized, T>(
+    draws: impl IntoIterator<Item = (&'a K, T)>,
+    name: impl Fn(&K) -> &str,
+) -> Vec<(&'a K, Vec<T>)> {
+    let mut groups: Vec<(&K, Vec<T>)> = vec![];
+    let mut group_indices: HashMap<*const K, usize> = HashMap::new();
+    for (key, draw) in draws {
+        let index = *group_indices.entry(key as *const K).or_insert_with(|| {
+            groups.push((key, vec![]));
+            groups.len() - 1
+        });
+        groups[index].1.push(draw);
+    }
+    groups.sort_by(|(a, _), (b, _)| name(a).cmp(name(b)));
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{vector, Matrix4};
+
+    use ash::vk::{CompareOp, Extent2D, Offset2D, Rect2D};
+    use gpu::{ImageFormat, StencilState};
+
+    use crate::{Camera, MasterMaterial};
+
+    use super::{
+        flips_winding, group_in_draw_order, DeferredRenderingPipeline, DofParams, DofShaderParams,
+        GpuInstance, SsrSettings, SsrShaderParams,
+    };
+
+    #[test]
+    fn normals_stay_perpendicular_under_non_uniform_scale() {
+        let model = Matrix4::new_nonuniform_scaling(&vector![4.0, 1.0, 1.0])
+            * Matrix4::new_rotation(vector![0.0, 0.0, 0.5]);
+        let instance = GpuInstance::new(model);
+
+        // A surface along the x = y diagonal, with its normal
+        let tangent = vector![1.0, 1.0, 0.0, 0.0];
+        let normal = vector![1.0, -1.0, 0.0, 0.0];
+        let world_tangent = model * tangent;
+        let world_normal = instance.normal * normal;
+        assert!(world_tangent.dot(&world_normal).abs() < 1e-5);
+        assert!((model * normal).dot(&world_tangent).abs() > 1e-2);
+    }
+
+    #[test]
+    fn degenerate_transforms_keep_normals() {
+        let instance = GpuInstance::new(Matrix4::new_scaling(0.0));
+        assert_eq!(instance.normal, Matrix4::identity());
+    }
+
+    #[test]
+    fn negative_scales_flip_the_winding() {
+        let rotation = Matrix4::new_rotation(vector![0.0, 1.0, 0.0]);
+        assert!(!flips_winding(&rotation));
+        let mirror = Matrix4::new_nonuniform_scaling(&vector![-1.0, 1.0, 1.0]);
+        assert!(flips_winding(&(rotation * mirror)));
+        // Mirroring twice is a rotation
+        let other_mirror = Matrix4::new_nonuniform_scaling(&vector![1.0, -1.0, 1.0]);
+        assert!(!flips_winding(&(mirror * other_mirror)));
+    }
+
+    #[test]
+    fn draw_order_is_stable() {
+        let metal = "metal".to_owned();
+        let glass = "glass".to_owned();
+        let other_glass = "glass".to_owned();
+        let draws = [
+            (&metal, 0),
+            (&other_glass, 1),
+            (&glass, 2),
+            (&metal, 3),
+            (&other_glass, 4),
+        ];
+        let groups = group_in_draw_order(draws, |name| name);
+        assert_eq!(groups.len(), 3);
+        assert!(std::ptr::eq(groups[0].0, &other_glass));
+        assert_eq!(groups[0].1, vec![1, 4]);
+        assert!(std::ptr::eq(groups[1].0, &glass));
+        assert_eq!(groups[1].1, vec![2]);
+        assert!(std::ptr::eq(groups[2].0, &metal));
+        assert_eq!(groups[2].1, vec![0, 3]);
+
+        // The groups don't depend on the order the materials are first seen in
+        let groups = group_in_draw_order([(&glass, 0), (&metal, 1)], |name| name);
+        let reversed = group_in_draw_order([(&metal, 1), (&glass, 0)], |name| name);
+        assert_eq!(groups, reversed);
+    }
+
+    #[test]
+    fn scaled_extent_is_never_empty() {
+        let size = Extent2D {
+            width: 1920,
+            height: 1081,
+        };
+        let half = DeferredRenderingPipeline::scaled_extent(size, 0.5);
+        assert_eq!((half.width, half.height), (960, 541));
+        let full = DeferredRenderingPipeline::scaled_extent(size, 1.0);
+        assert_eq!(full, size);
+        let tiny = DeferredRenderingPipeline::scaled_extent(
+            Extent2D {
+                width: 1,
+                height: 3,
+            },
+            0.25,
+        );
+        assert_eq!((tiny.width, tiny.height), (1, 1));
+    }
+
+    #[test]
+    fn views_must_fit_in_the_backbuffer() {
+        let size = Extent2D {
+            width: 1280,
+            height: 720,
+        };
+        let view = |x, y, width, height| Rect2D {
+            offset: Offset2D { x, y },
+            extent: Extent2D { width, height },
+        };
+        let fits = |view| DeferredRenderingPipeline::view_fits(&view, size);
+        assert!(fits(view(0, 0, 640, 720)));
+        assert!(fits(view(640, 0, 640, 720)));
+        assert!(!fits(view(641, 0, 640, 720)));
+        assert!(!fits(view(-1, 0, 640, 720)));
+        assert!(!fits(view(0, 0, 0, 720)));
+    }
+
+    #[test]
+    fn dof_params_are_clamped() {
+        let pov = Camera::default();
+        let dof = DofParams {
+            focus_distance: 0.0,
+            aperture: -1.0,
+            max_blur_radius: 100.0,
+        };
+        let size = Extent2D {
+            width: 200,
+            height: 100,
+        };
+        let params = DofShaderParams::new(dof, &pov, size);
+        assert_eq!(params.rcp_frame, vector![0.005, 0.01]);
+        assert_eq!(params.focus_distance, pov.near);
+        assert_eq!(params.aperture, 0.0);
+        assert_eq!(params.max_blur_radius, DofParams::MAX_BLUR_RADIUS);
+    }
+
+    #[test]
+    fn ssr_settings_are_clamped() {
+        let settings = SsrSettings {
+            max_steps: 1000,
+            step_size: 0.0,
+            thickness: -1.0,
+            max_roughness: 2.0,
+        };
+        let params = SsrShaderParams::new(settings, &Camera::default());
+        assert_eq!(params.max_steps, SsrSettings::MAX_STEPS);
+        assert!(params.step_size > 0.0);
+        assert_eq!(params.thickness, 0.0);
+        assert_eq!(params.max_roughness, 1.0);
+    }
+
+    /* The materials' pipelines are built for DEPTH_FORMAT: RenderPassCommand::bind_pipeline
+     * checks it against the depth attachment, and its stencil aspect against the stencil
+     * attachment, which the render graph binds for the depth targets with a stencil aspect */
+    #[test]
+    fn stencil_materials_match_the_depth_targets() {
+        let outline = StencilState {
+            compare_op: CompareOp::NOT_EQUAL,
+            ..Default::default()
+        };
+        let validate = |format, stencil| MasterMaterial::validate_depth_format("", format, stencil);
+        let depth_format = DeferredRenderingPipeline::DEPTH_FORMAT;
+        assert!(depth_format.has_stencil());
+        assert!(validate(depth_format, Some(&outline)).is_ok());
+        assert!(validate(depth_format, None).is_ok());
+        assert!(validate(ImageFormat::Depth, Some(&outline)).is_err());
+        assert!(validate(ImageFormat::Rgba8, None).is_err());
+    }
+}
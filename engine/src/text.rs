@@ -0,0 +1,406 @@
+use ash::vk::{
+    AttachmentLoadOp, AttachmentStoreOp, BlendFactor, BlendOp, BufferUsageFlags,
+    ColorComponentFlags, CompareOp, Format, ImageLayout, Offset2D, PipelineBindPoint,
+    PushConstantRange, Rect2D, SampleCountFlags, ShaderModuleCreateFlags, ShaderStageFlags,
+    StencilOpState,
+};
+use engine_macros::glsl;
+use gpu::{
+    BeginRenderPassInfo, BindingElement, BindingType, BlendState, BufferCreateInfo,
+    ColorAttachment, ColorLoadOp, CommandBuffer, DepthStencilState, DescriptorInfo,
+    DescriptorSetInfo, FragmentStageInfo, GlobalBinding, Gpu, GpuBuffer, GpuDescriptorSet,
+    ImageTransition, MemoryDomain, Pipeline, PipelineDescription, RenderPassAttachment,
+    SampleCount, ShaderModuleCreateInfo, VertexStageInfo,
+};
+use nalgebra::{vector, Vector2, Vector4};
+use resource_map::{ResourceHandle, ResourceMap};
+
+use crate::{Backbuffer, Texture};
+
+const TEXT_VS: &[u32] = glsl!(
+    kind = vertex,
+    path = "src/shaders/text_vs.vert",
+    entry_point = "main"
+);
+
+const TEXT_FS: &[u32] = glsl!(
+    kind = fragment,
+    path = "src/shaders/text_fs.frag",
+    entry_point = "main"
+);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FontKind {
+    // The atlas' alpha is the coverage of the glyphs, and its color tints them
+    Bitmap,
+    // The atlas' alpha is the distance from the glyphs' edges, which lie at 0.5
+    Sdf,
+}
+
+/*
+A monospaced font stored in an atlas as a grid of columns x rows glyphs, laid out left to right
+and top to bottom starting from first_character.
+SDF atlases should be loaded with Texture::new_with_data, as their distances are linear
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Font {
+    pub kind: FontKind,
+    pub columns: u32,
+    pub rows: u32,
+    pub first_character: char,
+    // The size of a glyph on screen in pixels, which is also the advance between glyphs
+    pub glyph_size: Vector2<f32>,
+}
+
+impl Font {
+    // The atlas' uvs of c as (min u, min v, max u, max v), None if the atlas has no such glyph
+    pub fn glyph_uv_rect(&self, c: char) -> Option<Vector4<f32>> {
+        let index = (c as u32).checked_sub(self.first_character as u32)?;
+        if index >= self.columns * self.rows {
+            return None;
+        }
+        let size = vector![1.0 / self.columns as f32, 1.0 / self.rows as f32];
+        let min = vector![
+            (index % self.columns) as f32 * size.x,
+            (index / self.columns) as f32 * size.y
+        ];
+        Some(vector![min.x, min.y, min.x + size.x, min.y + size.y])
+    }
+
+    /* Appends the glyphs of text starting at position (the top left corner of its first glyph):
+     * newlines start a new line below position, the characters missing from the atlas are skipped,
+     * leaving a blank space */
+    fn layout_text(
+        &self,
+        position: Vector2<f32>,
+        text: &str,
+        color: Vector4<f32>,
+        glyphs: &mut Vec<GpuGlyph>,
+    ) {
+        let mut cursor = position;
+        for c in text.chars() {
+            if c == '\n' {
+                cursor = vector![position.x, cursor.y + self.glyph_size.y];
+                continue;
+            }
+            if let Some(uv_rect) = self.glyph_uv_rect(c) {
+                glyphs.push(GpuGlyph {
+                    rect: vector![cursor.x, cursor.y, self.glyph_size.x, self.glyph_size.y],
+                    uv_rect,
+                    color,
+                });
+            }
+            cursor.x += self.glyph_size.x;
+        }
+    }
+}
+
+// The layout of a glyph in the text renderer's glyph buffer
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct GpuGlyph {
+    // x, y, width, height in pixels
+    rect: Vector4<f32>,
+    uv_rect: Vector4<f32>,
+    color: Vector4<f32>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TextParams {
+    target_size: Vector2<f32>,
+    is_sdf: u32,
+}
+
+struct FrameGlyphs {
+    buffer: GpuBuffer,
+    descriptor_set: GpuDescriptorSet,
+}
+
+/*
+Draws screen space text with a font atlas: the strings queued with draw_text() during a frame
+are drawn as a batch of alpha blended quads on top of the backbuffer by render()
+ */
+pub struct TextRenderer {
+    font: Font,
+    atlas: ResourceHandle<Texture>,
+    pipeline: Pipeline,
+    target_format: Format,
+    frames: Vec<FrameGlyphs>,
+    in_flight_frame: usize,
+    glyphs: Vec<GpuGlyph>,
+}
+
+impl TextRenderer {
+    pub const MAX_GLYPHS: usize = 16384;
+
+    // target_format is the format of the backbuffers the text is drawn on
+    pub fn new(
+        gpu: &Gpu,
+        resource_map: &ResourceMap,
+        atlas: ResourceHandle<Texture>,
+        font: Font,
+        target_format: Format,
+    ) -> anyhow::Result<Self> {
+        let texture = resource_map.get(&atlas);
+        let frames = (0..gpu.frames_in_flight())
+            .map(|_| {
+                let buffer = gpu.create_buffer(
+                    &BufferCreateInfo {
+                        label: Some("Text Renderer - Glyph buffer"),
+                        size: std::mem::size_of::<GpuGlyph>() * Self::MAX_GLYPHS,
+                        usage: BufferUsageFlags::STORAGE_BUFFER | BufferUsageFlags::TRANSFER_DST,
+                        sharing_mode: Default::default(),
+                    },
+                    MemoryDomain::HostVisible | MemoryDomain::HostCoherent,
+                )?;
+                let descriptor_set = gpu.create_descriptor_set(&DescriptorSetInfo {
+                    descriptors: &[
+                        DescriptorInfo::storage_buffer(0, &buffer, gpu::ShaderStage::Vertex),
+                        DescriptorInfo::combined_image_sampler(
+                            1,
+                            &resource_map.get(&texture.sampler).0,
+                            &resource_map.get(&texture.image_view).view,
+                            gpu::ShaderStage::Fragment,
+                        ),
+                    ],
+                })?;
+                Ok(FrameGlyphs {
+                    buffer,
+                    descriptor_set,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            font,
+            atlas,
+            pipeline: Self::create_pipeline(gpu, target_format)?,
+            target_format,
+            frames,
+            in_flight_frame: 0,
+            glyphs: vec![],
+        })
+    }
+
+    fn create_pipeline(gpu: &Gpu, target_format: Format) -> anyhow::Result<Pipeline> {
+        let text_vs = gpu.create_shader_module(&ShaderModuleCreateInfo {
+            flags: ShaderModuleCreateFlags::empty(),
+            code: bytemuck::cast_slice(TEXT_VS),
+        })?;
+        let text_fs = gpu.create_shader_module(&ShaderModuleCreateInfo {
+            flags: ShaderModuleCreateFlags::empty(),
+            code: bytemuck::cast_slice(TEXT_FS),
+        })?;
+        let pipeline = Pipeline::new(
+            gpu,
+            &PipelineDescription {
+                global_bindings: &[GlobalBinding {
+                    set_index: 0,
+                    elements: &[
+                        BindingElement {
+                            binding_type: BindingType::Storage,
+                            index: 0,
+                            stage: gpu::ShaderStage::Vertex,
+                        },
+                        BindingElement {
+                            binding_type: BindingType::CombinedImageSampler,
+                            index: 1,
+                            stage: gpu::ShaderStage::Fragment,
+                        },
+                    ],
+                    push_descriptor: false,
+                }],
+                vertex_inputs: &[],
+                vertex_stage: Some(VertexStageInfo {
+                    entry_point: "main",
+                    module: &text_vs,
+                }),
+                fragment_stage: Some(FragmentStageInfo {
+                    entry_point: "main",
+                    module: &text_fs,
+                    color_attachments: &[RenderPassAttachment {
+                        format: target_format,
+                        samples: SampleCountFlags::TYPE_1,
+                        load_op: AttachmentLoadOp::LOAD,
+                        store_op: AttachmentStoreOp::STORE,
+                        stencil_load_op: AttachmentLoadOp::DONT_CARE,
+                        stencil_store_op: AttachmentStoreOp::DONT_CARE,
+                        initial_layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                        final_layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                        blend_state: BlendState {
+                            blend_enable: true,
+                            src_color_blend_factor: BlendFactor::SRC_ALPHA,
+                            dst_color_blend_factor: BlendFactor::ONE_MINUS_SRC_ALPHA,
+                            color_blend_op: BlendOp::ADD,
+                            src_alpha_blend_factor: BlendFactor::ONE,
+                            dst_alpha_blend_factor: BlendFactor::ONE_MINUS_SRC_ALPHA,
+                            alpha_blend_op: BlendOp::ADD,
+                            color_write_mask: ColorComponentFlags::RGBA,
+                        },
+                    }],
+                    depth_stencil_attachments: &[],
+                }),
+                input_topology: gpu::PrimitiveTopology::TriangleStrip,
+                primitive_restart: false,
+                polygon_mode: gpu::PolygonMode::Fill,
+                cull_mode: gpu::CullMode::None,
+                front_face: gpu::FrontFace::ClockWise,
+                depth_stencil_state: DepthStencilState {
+                    depth_test_enable: false,
+                    depth_write_enable: false,
+                    depth_compare_op: CompareOp::ALWAYS,
+                    stencil_test_enable: false,
+                    front: StencilOpState::default(),
+                    back: StencilOpState::default(),
+                    min_depth_bounds: 0.0,
+                    max_depth_bounds: 1.0,
+                },
+                depth_stencil_format: None,
+                logic_op: None,
+                push_constant_ranges: &[PushConstantRange {
+                    stage_flags: ShaderStageFlags::ALL,
+                    offset: 0,
+                    size: std::mem::size_of::<TextParams>() as _,
+                }],
+                conservative_rasterization: false,
+                samples: SampleCount::One,
+            },
+        )?;
+        Ok(pipeline)
+    }
+
+    pub fn font(&self) -> &Font {
+        &self.font
+    }
+    pub fn atlas(&self) -> &ResourceHandle<Texture> {
+        &self.atlas
+    }
+
+    /* Queues text to be drawn by the next render(), position is the top left corner
+     * of the text in pixels from the backbuffer's top left corner */
+    pub fn draw_text(&mut self, position: Vector2<f32>, text: &str, color: Vector4<f32>) {
+        self.font
+            .layout_text(position, text, color, &mut self.glyphs);
+    }
+
+    /* Draws the queued text on the backbuffer, which must have been left ready to be presented
+     * (e.g by RenderingPipeline::render), and is left that way. The queue is cleared afterwards */
+    pub fn render(
+        &mut self,
+        command_buffer: &mut CommandBuffer,
+        backbuffer: &Backbuffer,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            backbuffer.format == self.target_format,
+            "The text renderer draws on {:?} backbuffers, got {:?}",
+            self.target_format,
+            backbuffer.format
+        );
+        if self.glyphs.is_empty() {
+            return Ok(());
+        }
+        if self.glyphs.len() > Self::MAX_GLYPHS {
+            log::warn!(
+                "Too many glyphs queued ({}), only the first {} are drawn",
+                self.glyphs.len(),
+                Self::MAX_GLYPHS
+            );
+            self.glyphs.truncate(Self::MAX_GLYPHS);
+        }
+        let frame = &self.frames[self.in_flight_frame];
+        crate::app_state()
+            .gpu
+            .write_buffer_data(&frame.buffer, &self.glyphs)?;
+
+        let (present_state, attachment_state) =
+            ImageTransition::PresentToColorAttachment.transition_infos();
+        let label = command_buffer.begin_debug_region("Text", [0.9, 0.9, 0.9, 1.0]);
+        command_buffer.transition_images(&[(backbuffer.image, present_state, attachment_state)]);
+        {
+            let color_attachments = [ColorAttachment {
+                image_view: backbuffer.image_view,
+                load_op: ColorLoadOp::Load,
+                store_op: gpu::AttachmentStoreOp::Store,
+                initial_layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                resolve: None,
+            }];
+            let mut render_pass_command = command_buffer.begin_render_pass(&BeginRenderPassInfo {
+                color_attachments: &color_attachments,
+                depth_attachment: None,
+                stencil_attachment: None,
+                render_area: Rect2D {
+                    offset: Offset2D::default(),
+                    extent: backbuffer.size,
+                },
+            });
+            render_pass_command.bind_pipeline(&self.pipeline);
+            render_pass_command.bind_descriptor_sets(
+                PipelineBindPoint::GRAPHICS,
+                &self.pipeline,
+                0,
+                &[&frame.descriptor_set],
+            );
+            render_pass_command.push_constant(
+                &self.pipeline,
+                &TextParams {
+                    target_size: vector![
+                        backbuffer.size.width as f32,
+                        backbuffer.size.height as f32
+                    ],
+                    is_sdf: (self.font.kind == FontKind::Sdf) as u32,
+                },
+                0,
+            );
+            render_pass_command.draw(4, self.glyphs.len() as u32, 0, 0);
+        }
+        command_buffer.transition_images(&[(backbuffer.image, attachment_state, present_state)]);
+        label.end();
+
+        self.glyphs.clear();
+        self.in_flight_frame = (self.in_flight_frame + 1) % self.frames.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::vector;
+
+    use super::{Font, FontKind};
+
+    #[test]
+    fn glyphs_are_laid_out_from_the_atlas_grid() {
+        let font = Font {
+            kind: FontKind::Bitmap,
+            columns: 16,
+            rows: 6,
+            first_character: ' ',
+            glyph_size: vector![8.0, 16.0],
+        };
+        assert_eq!(
+            font.glyph_uv_rect(' '),
+            Some(vector![0.0, 0.0, 0.0625, 1.0 / 6.0])
+        );
+        // '0' is the first glyph of the second row
+        let zero = font.glyph_uv_rect('0').unwrap();
+        assert_eq!(zero.x, 0.0);
+        assert_eq!(zero.y, 1.0 / 6.0);
+        assert_eq!(font.glyph_uv_rect('\t'), None);
+        assert_eq!(font.glyph_uv_rect('é'), None);
+
+        let mut glyphs = vec![];
+        let white = vector![1.0, 1.0, 1.0, 1.0];
+        font.layout_text(vector![10.0, 20.0], "ab\n\tc", white, &mut glyphs);
+        let rects: Vec<_> = glyphs.iter().map(|glyph| glyph.rect).collect();
+        assert_eq!(
+            rects,
+            vec![
+                vector![10.0, 20.0, 8.0, 16.0],
+                vector![18.0, 20.0, 8.0, 16.0],
+                // The tab is missing from the atlas, and leaves a blank
+                vector![18.0, 36.0, 8.0, 16.0],
+            ]
+        );
+    }
+}
@@ -1,12 +1,17 @@
+use std::path::Path;
+
+use anyhow::Context;
 use ash::{
     prelude::VkResult,
     vk::{
-        self, BorderColor, CompareOp, ComponentMapping, Filter, Format, ImageAspectFlags,
-        ImageSubresourceRange, ImageUsageFlags, ImageViewType, SamplerAddressMode,
+        self, BorderColor, CompareOp, Filter, ImageUsageFlags, SamplerAddressMode,
         SamplerCreateFlags, SamplerCreateInfo, SamplerMipmapMode, StructureType,
     },
 };
-use gpu::{Gpu, GpuImage, GpuImageView, GpuSampler, ImageCreateInfo, MemoryDomain};
+use gpu::{
+    Gpu, GpuImage, GpuImageView, GpuSampler, ImageCreateInfo, MemoryDomain, SampleCount, ToVk,
+};
+use image::{imageops, imageops::FilterType, RgbaImage};
 use resource_map::{Resource, ResourceHandle, ResourceMap};
 
 pub struct ImageResource(pub GpuImage);
@@ -32,6 +37,93 @@ impl Resource for SamplerResource {
         "GPU Sampler"
     }
 }
+
+/*
+Sampling settings used when creating a texture's sampler:
+use SamplerAddressMode::CLAMP_TO_BORDER together with a border color
+when the texture is sampled outside [0, 1] (e.g shadow maps).
+When comparison is set, the sampler is created as a comparison sampler,
+which can be used for hardware PCF on depth textures.
+The default settings filter trilinearly with 16x anisotropy
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SamplerSettings {
+    pub mag_filter: Filter,
+    pub min_filter: Filter,
+    pub mipmap_mode: SamplerMipmapMode,
+    // The last mip that can be sampled, None samples the whole mip chain
+    pub max_mip: Option<u32>,
+    // None disables anisotropic filtering, the level is clamped to the device's limit
+    pub max_anisotropy: Option<u32>,
+    pub address_mode_u: SamplerAddressMode,
+    pub address_mode_v: SamplerAddressMode,
+    pub address_mode_w: SamplerAddressMode,
+    pub border_color: BorderColor,
+    pub comparison: Option<CompareOp>,
+}
+
+impl Default for SamplerSettings {
+    fn default() -> Self {
+        Self {
+            mag_filter: Filter::LINEAR,
+            min_filter: Filter::LINEAR,
+            mipmap_mode: SamplerMipmapMode::LINEAR,
+            max_mip: None,
+            max_anisotropy: Some(16),
+            address_mode_u: SamplerAddressMode::REPEAT,
+            address_mode_v: SamplerAddressMode::REPEAT,
+            address_mode_w: SamplerAddressMode::REPEAT,
+            border_color: BorderColor::default(),
+            comparison: None,
+        }
+    }
+}
+
+impl SamplerSettings {
+    pub fn clamp_to_border(border_color: BorderColor) -> Self {
+        Self {
+            address_mode_u: SamplerAddressMode::CLAMP_TO_BORDER,
+            address_mode_v: SamplerAddressMode::CLAMP_TO_BORDER,
+            address_mode_w: SamplerAddressMode::CLAMP_TO_BORDER,
+            border_color,
+            ..Default::default()
+        }
+    }
+
+    // Samples the nearest texel of the nearest mip, e.g for pixel art or lookup textures
+    pub fn nearest() -> Self {
+        Self {
+            mag_filter: Filter::NEAREST,
+            min_filter: Filter::NEAREST,
+            mipmap_mode: SamplerMipmapMode::NEAREST,
+            max_anisotropy: None,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_address_mode(mut self, address_mode: SamplerAddressMode) -> Self {
+        self.address_mode_u = address_mode;
+        self.address_mode_v = address_mode;
+        self.address_mode_w = address_mode;
+        self
+    }
+
+    pub fn with_max_anisotropy(mut self, max_anisotropy: Option<u32>) -> Self {
+        self.max_anisotropy = max_anisotropy;
+        self
+    }
+
+    pub fn with_max_mip(mut self, max_mip: Option<u32>) -> Self {
+        self.max_mip = max_mip;
+        self
+    }
+
+    pub fn with_comparison(mut self, compare_op: CompareOp) -> Self {
+        self.comparison = Some(compare_op);
+        self
+    }
+}
+
 pub struct Texture {
     pub image_view: ResourceHandle<TextureImageView>,
     pub sampler: ResourceHandle<SamplerResource>,
@@ -43,58 +135,81 @@ impl Texture {
         width: u32,
         height: u32,
         data: Option<&[u8]>,
+        sampler_settings: &SamplerSettings,
         label: Option<&str>,
-    ) -> VkResult<(GpuImage, GpuImageView, GpuSampler)> {
+    ) -> anyhow::Result<(GpuImage, GpuImageView, GpuSampler)> {
+        // Empty textures are written by the user, who's in charge of their mips
+        let mip_levels = match data {
+            Some(_) => Self::mip_count(width, height),
+            None => 1,
+        };
         let image = gpu.create_image(
             &ImageCreateInfo {
                 label,
                 width,
                 height,
                 format: vk::Format::R8G8B8A8_UNORM,
-                usage: ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::SAMPLED,
+                usage: ImageUsageFlags::TRANSFER_SRC
+                    | ImageUsageFlags::TRANSFER_DST
+                    | ImageUsageFlags::SAMPLED,
+                mip_levels,
+                sharing_mode: Default::default(),
+                samples: SampleCount::One,
             },
             MemoryDomain::DeviceLocal,
-            data,
+            None,
         )?;
+        if let Some(data) = data {
+            let base = RgbaImage::from_raw(width, height, data.to_vec())
+                .context("The texture data does not match the texture's size")?;
+            Self::write_with_mips(gpu, &image, base)?;
+        }
 
-        let rgba_view = gpu.create_image_view(&gpu::ImageViewCreateInfo {
-            image: &image,
-            view_type: ImageViewType::TYPE_2D,
-            format: Format::R8G8B8A8_UNORM,
-            components: ComponentMapping::default(),
-            subresource_range: ImageSubresourceRange {
-                aspect_mask: ImageAspectFlags::COLOR,
-                base_mip_level: 0,
-                level_count: 1,
-                base_array_layer: 0,
-                layer_count: 1,
-            },
-        })?;
+        let rgba_view = gpu.create_default_view(&image)?;
 
-        let sampler = gpu.create_sampler(&SamplerCreateInfo {
+        let sampler = Self::create_sampler(gpu, sampler_settings)?;
+        Ok((image, rgba_view, sampler))
+    }
+
+    pub fn create_sampler(gpu: &Gpu, settings: &SamplerSettings) -> VkResult<GpuSampler> {
+        let max_anisotropy = settings.max_anisotropy.map(|anisotropy| {
+            (anisotropy.max(1) as f32).min(
+                gpu.physical_device_properties()
+                    .limits
+                    .max_sampler_anisotropy,
+            )
+        });
+        gpu.create_sampler(&SamplerCreateInfo {
             s_type: StructureType::SAMPLER_CREATE_INFO,
             p_next: std::ptr::null(),
             flags: SamplerCreateFlags::empty(),
-            mag_filter: Filter::LINEAR,
-            min_filter: Filter::LINEAR,
-            mipmap_mode: SamplerMipmapMode::LINEAR,
-            address_mode_u: SamplerAddressMode::REPEAT,
-            address_mode_v: SamplerAddressMode::REPEAT,
-            address_mode_w: SamplerAddressMode::REPEAT,
+            mag_filter: settings.mag_filter,
+            min_filter: settings.min_filter,
+            mipmap_mode: settings.mipmap_mode,
+            address_mode_u: settings.address_mode_u,
+            address_mode_v: settings.address_mode_v,
+            address_mode_w: settings.address_mode_w,
             mip_lod_bias: 0.0,
-            anisotropy_enable: vk::TRUE,
-            max_anisotropy: gpu
-                .physical_device_properties()
-                .limits
-                .max_sampler_anisotropy,
-            compare_enable: vk::FALSE,
-            compare_op: CompareOp::ALWAYS,
+            anisotropy_enable: if max_anisotropy.is_some() {
+                vk::TRUE
+            } else {
+                vk::FALSE
+            },
+            max_anisotropy: max_anisotropy.unwrap_or(1.0),
+            compare_enable: if settings.comparison.is_some() {
+                vk::TRUE
+            } else {
+                vk::FALSE
+            },
+            compare_op: settings.comparison.unwrap_or(CompareOp::ALWAYS),
             min_lod: 0.0,
-            max_lod: 0.0,
-            border_color: BorderColor::default(),
+            // The lod is clamped to the mips of the sampled view anyways
+            max_lod: settings
+                .max_mip
+                .map_or(vk::LOD_CLAMP_NONE, |max_mip| max_mip as f32),
+            border_color: settings.border_color,
             unnormalized_coordinates: vk::FALSE,
-        })?;
-        Ok((image, rgba_view, sampler))
+        })
     }
 
     pub fn new_empty(
@@ -103,16 +218,16 @@ impl Texture {
         width: u32,
         height: u32,
         label: Option<&str>,
-    ) -> VkResult<Self> {
-        let (image, view, sampler) = Self::new_impl(gpu, width, height, None, label)?;
-        let image = resource_map.add(ImageResource(image));
-        let image_view = TextureImageView { image, view };
-        let image_view = resource_map.add(image_view);
-        let sampler = resource_map.add(SamplerResource(sampler));
-        Ok(Self {
-            image_view,
-            sampler,
-        })
+    ) -> anyhow::Result<Self> {
+        Self::new_with_sampler_settings(
+            gpu,
+            resource_map,
+            width,
+            height,
+            None,
+            &SamplerSettings::default(),
+            label,
+        )
     }
     pub fn new_with_data(
         gpu: &Gpu,
@@ -120,19 +235,175 @@ impl Texture {
         width: u32,
         height: u32,
         data: &[u8],
+        sampler_settings: &SamplerSettings,
+        label: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        Self::new_with_sampler_settings(
+            gpu,
+            resource_map,
+            width,
+            height,
+            Some(data),
+            sampler_settings,
+            label,
+        )
+    }
+
+    pub fn new_with_sampler_settings(
+        gpu: &Gpu,
+        resource_map: &mut ResourceMap,
+        width: u32,
+        height: u32,
+        data: Option<&[u8]>,
+        sampler_settings: &SamplerSettings,
+        label: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let (image, view, sampler) =
+            Self::new_impl(gpu, width, height, data, sampler_settings, label)?;
+
+        Ok(Self::add_to_resource_map(
+            resource_map,
+            image,
+            view,
+            sampler,
+        ))
+    }
+
+    /* Creates a texture that can be rendered to, e.g as the backbuffer of a renderer:
+     * its contents are undefined until something is rendered to it. It's sRGB like the
+     * textures loaded from files, so the renderer encodes its output and the samplers decode it */
+    pub fn new_render_target(
+        gpu: &Gpu,
+        resource_map: &mut ResourceMap,
+        width: u32,
+        height: u32,
+        sampler_settings: &SamplerSettings,
         label: Option<&str>,
-    ) -> VkResult<Self> {
-        let (image, view, sampler) = Self::new_impl(gpu, width, height, Some(data), label)?;
+    ) -> anyhow::Result<Self> {
+        let image = gpu.create_image(
+            &ImageCreateInfo {
+                label,
+                width,
+                height,
+                format: vk::Format::R8G8B8A8_SRGB,
+                usage: ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::SAMPLED,
+                mip_levels: 1,
+                sharing_mode: Default::default(),
+                samples: SampleCount::One,
+            },
+            MemoryDomain::DeviceLocal,
+            None,
+        )?;
+        let view = gpu.create_default_view(&image)?;
+        let sampler = Self::create_sampler(gpu, sampler_settings)?;
+        Ok(Self::add_to_resource_map(
+            resource_map,
+            image,
+            view,
+            sampler,
+        ))
+    }
 
+    /*
+    Loads and decodes the image at path, uploading it along with its whole mip chain:
+    the texture is assumed to contain color data, so it's sampled as sRGB
+     */
+    pub fn from_file(
+        gpu: &Gpu,
+        resource_map: &mut ResourceMap,
+        path: impl AsRef<Path>,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let label = path.to_string_lossy();
+        let base = image::open(path)
+            .with_context(|| format!("Failed to load texture {}", path.display()))?
+            .into_rgba8();
+
+        let image = gpu.create_image(
+            &ImageCreateInfo {
+                label: Some(&label),
+                width: base.width(),
+                height: base.height(),
+                format: vk::Format::R8G8B8A8_SRGB,
+                usage: ImageUsageFlags::TRANSFER_SRC
+                    | ImageUsageFlags::TRANSFER_DST
+                    | ImageUsageFlags::SAMPLED,
+                mip_levels: Self::mip_count(base.width(), base.height()),
+                sharing_mode: Default::default(),
+                samples: SampleCount::One,
+            },
+            MemoryDomain::DeviceLocal,
+            None,
+        )?;
+        Self::write_with_mips(gpu, &image, base)?;
+
+        let view = gpu.create_default_view(&image)?;
+        let sampler = Self::create_sampler(gpu, &SamplerSettings::default())?;
+        Ok(Self::add_to_resource_map(
+            resource_map,
+            image,
+            view,
+            sampler,
+        ))
+    }
+
+    // The length of the full mip chain of an image of this size, floor(log2(max(w, h))) + 1
+    pub fn mip_count(width: u32, height: u32) -> u32 {
+        u32::BITS - width.max(height).max(1).leading_zeros()
+    }
+
+    /* Writes base to the first mip of the image, then fills the other mips by downsampling it:
+     * on the gpu when the image's format can be blitted, otherwise on the cpu */
+    fn write_with_mips(gpu: &Gpu, image: &GpuImage, base: RgbaImage) -> anyhow::Result<()> {
+        if image.mip_levels() > 1 && gpu.supports_mipmap_blits(image.format().to_vk()) {
+            gpu.write_image_data(image, base.as_raw())?;
+            gpu.generate_mipmaps(image)?;
+        } else {
+            let mips = Self::generate_mips(base);
+            let mip_data: Vec<&[u8]> = mips
+                .iter()
+                .take(image.mip_levels() as usize)
+                .map(|mip| mip.as_raw().as_slice())
+                .collect();
+            gpu.write_image_mips(image, &mip_data)?;
+        }
+        Ok(())
+    }
+
+    // Halves the image until it's 1x1, the returned vector starts with the full size image
+    fn generate_mips(image: RgbaImage) -> Vec<RgbaImage> {
+        let mut mips = vec![image];
+        loop {
+            let last = mips.last().unwrap();
+            if last.width() == 1 && last.height() == 1 {
+                break;
+            }
+            let mip = imageops::resize(
+                last,
+                (last.width() / 2).max(1),
+                (last.height() / 2).max(1),
+                FilterType::Triangle,
+            );
+            mips.push(mip);
+        }
+        mips
+    }
+
+    fn add_to_resource_map(
+        resource_map: &mut ResourceMap,
+        image: GpuImage,
+        view: GpuImageView,
+        sampler: GpuSampler,
+    ) -> Self {
         let image = resource_map.add(ImageResource(image));
         let image_view = TextureImageView { image, view };
         let image_view = resource_map.add(image_view);
         let sampler = resource_map.add(SamplerResource(sampler));
 
-        Ok(Self {
+        Self {
             image_view,
             sampler,
-        })
+        }
     }
 }
 
@@ -141,3 +412,20 @@ impl Resource for Texture {
         "Texture"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+
+    use super::Texture;
+
+    #[test]
+    fn mip_count_matches_generated_mips() {
+        for (width, height) in [(1, 1), (2, 2), (256, 256), (300, 17), (1, 64)] {
+            let mips = Texture::generate_mips(RgbaImage::new(width, height));
+            assert_eq!(Texture::mip_count(width, height), mips.len() as u32);
+            let last = mips.last().unwrap();
+            assert_eq!((last.width(), last.height()), (1, 1));
+        }
+    }
+}
@@ -1,5 +1,8 @@
 use std::time::*;
 
+use ash::{prelude::VkResult, vk::PipelineStageFlags};
+use gpu::{CommandBuffer, Gpu, QueryPool};
+
 pub struct Time {
     app_start: Instant,
     last_frame: Instant,
@@ -34,6 +37,11 @@ impl Time {
         self.since_app_start = delta;
     }
 
+    // Keeps the time spent without rendering (e.g while minimized) out of the next frame's delta
+    pub(crate) fn resume(&mut self) {
+        self.last_frame = Instant::now();
+    }
+
     pub(crate) fn end_frame(&mut self) {
         self.frame_counter += 1;
     }
@@ -50,3 +58,165 @@ impl Time {
         self.frame_counter
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FrameStage {
+    Input,
+    Update,
+    Acquire,
+    Record,
+    Submit,
+    Present,
+}
+
+/* CPU time spent in each stage of a frame, in seconds */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    // Time between the end of the previous frame and the beginning of this one,
+    // spent processing the window events
+    pub input: f32,
+    pub update: f32,
+    // Time spent waiting for the next swapchain image
+    pub acquire: f32,
+    // Time spent drawing, excluding the acquire and submit stages
+    pub record: f32,
+    pub submit: f32,
+    pub present: f32,
+    pub total: f32,
+}
+
+impl FrameStats {
+    pub fn add(&mut self, stage: FrameStage, seconds: f32) {
+        let value = match stage {
+            FrameStage::Input => &mut self.input,
+            FrameStage::Update => &mut self.update,
+            FrameStage::Acquire => &mut self.acquire,
+            FrameStage::Record => &mut self.record,
+            FrameStage::Submit => &mut self.submit,
+            FrameStage::Present => &mut self.present,
+        };
+        *value += seconds;
+    }
+
+    pub fn get(&self, stage: FrameStage) -> f32 {
+        match stage {
+            FrameStage::Input => self.input,
+            FrameStage::Update => self.update,
+            FrameStage::Acquire => self.acquire,
+            FrameStage::Record => self.record,
+            FrameStage::Submit => self.submit,
+            FrameStage::Present => self.present,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PassTiming {
+    pub name: String,
+    pub milliseconds: f32,
+}
+
+/* GPU time spent in each pass of a frame, in the order the passes were executed */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PassTimings {
+    pub passes: Vec<PassTiming>,
+}
+
+impl PassTimings {
+    // The timestamps are in nanoseconds, each pass has a begin and an end timestamp
+    fn from_timestamps(names: &[String], timestamps: &[u64]) -> Self {
+        Self {
+            passes: names
+                .iter()
+                .zip(timestamps.chunks_exact(2))
+                .map(|(name, range)| PassTiming {
+                    name: name.clone(),
+                    milliseconds: range[1].saturating_sub(range[0]) as f32 / 1_000_000.0,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<f32> {
+        self.passes
+            .iter()
+            .find(|pass| pass.name == name)
+            .map(|pass| pass.milliseconds)
+    }
+
+    pub fn total(&self) -> f32 {
+        self.passes.iter().map(|pass| pass.milliseconds).sum()
+    }
+}
+
+/*
+Measures the passes recorded in a command buffer with a pair of timestamps each:
+the timings can be read once the command buffer has been executed, until the next reset
+ */
+pub(crate) struct PassTimer {
+    pool: QueryPool,
+    passes: Vec<String>,
+    pass_open: bool,
+}
+
+impl PassTimer {
+    pub(crate) fn new(gpu: &Gpu, max_passes: u32) -> VkResult<Self> {
+        Ok(Self {
+            pool: gpu.create_query_pool(max_passes * 2)?,
+            passes: vec![],
+            pass_open: false,
+        })
+    }
+
+    // Must be recorded outside of render passes, before the first pass
+    pub(crate) fn reset(&mut self, command_buffer: &mut CommandBuffer) {
+        command_buffer.reset_query_pool(&self.pool);
+        self.passes.clear();
+        self.pass_open = false;
+    }
+
+    // The passes after the first max_passes are not measured
+    pub(crate) fn begin_pass(&mut self, command_buffer: &mut CommandBuffer, name: &str) {
+        let index = self.passes.len() as u32 * 2;
+        if index + 2 > self.pool.count() {
+            return;
+        }
+        command_buffer.write_timestamp(&self.pool, PipelineStageFlags::TOP_OF_PIPE, index);
+        self.passes.push(name.to_owned());
+        self.pass_open = true;
+    }
+
+    pub(crate) fn end_pass(&mut self, command_buffer: &mut CommandBuffer) {
+        if std::mem::take(&mut self.pass_open) {
+            let index = self.passes.len() as u32 * 2 - 1;
+            command_buffer.write_timestamp(&self.pool, PipelineStageFlags::BOTTOM_OF_PIPE, index);
+        }
+    }
+
+    // Fails with NOT_READY until the measured command buffer has been executed
+    pub(crate) fn read(&self, gpu: &Gpu) -> VkResult<PassTimings> {
+        if self.passes.is_empty() {
+            return Ok(PassTimings::default());
+        }
+        let timestamps = self
+            .pool
+            .get_results_range(gpu, 0..self.passes.len() as u32 * 2)?;
+        Ok(PassTimings::from_timestamps(&self.passes, &timestamps))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PassTimings;
+
+    #[test]
+    fn pass_timings_from_timestamps() {
+        let names = ["Shadows".to_owned(), "GBuffer".to_owned()];
+        let timings =
+            PassTimings::from_timestamps(&names, &[1_000_000, 3_500_000, 4_000_000, 5_000_000]);
+        assert_eq!(timings.get("Shadows"), Some(2.5));
+        assert_eq!(timings.get("GBuffer"), Some(1.0));
+        assert_eq!(timings.get("Tonemapping"), None);
+        assert_eq!(timings.total(), 3.5);
+    }
+}
@@ -21,6 +21,12 @@ bitflags! {
         const HostVisible =     0b00000010;
         const HostCoherent =    0b00000100;
         const HostCached =      0b00001000;
+        /* Memory the GPU reads at full speed and the CPU writes directly, ideal for the data
+         * updated each frame: when the device has no such memory (e.g without resizable BAR),
+         * the allocation falls back to host visible memory */
+        const DeviceLocalHostVisible = Self::DeviceLocal.bits()
+            | Self::HostVisible.bits()
+            | Self::HostCoherent.bits();
     }
 }
 
@@ -43,6 +49,7 @@ impl From<MemoryDomain> for MemoryPropertyFlags {
     }
 }
 
+// The allocation's offset must be a multiple of memory_requirements.alignment
 pub struct AllocationRequirements {
     pub memory_requirements: MemoryRequirements,
     pub memory_domain: MemoryDomain,
@@ -53,6 +60,8 @@ pub struct MemoryAllocation {
     pub device_memory: DeviceMemory,
     pub offset: u64,
     pub size: u64,
+    // The alignment the allocation was requested with, offset is a multiple of it
+    pub alignment: u64,
     pub persistent_ptr: Option<NonNull<c_void>>,
 }
 
@@ -75,15 +84,26 @@ pub struct PasstroughAllocator {
     num_allocations: u32,
 }
 impl PasstroughAllocator {
+    // The memory type must have all the properties of the domain
     fn find_memory_type(&self, type_filter: u32, memory_domain: MemoryDomain) -> Option<u32> {
         let mem_properties = memory_domain.into();
         (0..self.memory_properties.memory_type_count).find(|&i| {
             (type_filter & (1 << i)) > 0
                 && self.memory_properties.memory_types[i as usize]
                     .property_flags
-                    .intersects(mem_properties)
+                    .contains(mem_properties)
         })
     }
+
+    fn allocate_memory(&self, memory_type_index: u32, size: u64) -> VkResult<DeviceMemory> {
+        let allocate_info = MemoryAllocateInfo {
+            s_type: StructureType::MEMORY_ALLOCATE_INFO,
+            p_next: std::ptr::null(),
+            allocation_size: size,
+            memory_type_index,
+        };
+        unsafe { self.device.allocate_memory(&allocate_info, None) }
+    }
 }
 
 impl GpuAllocator for PasstroughAllocator {
@@ -104,26 +124,42 @@ impl GpuAllocator for PasstroughAllocator {
         &mut self,
         allocation_requirements: AllocationRequirements,
     ) -> VkResult<MemoryAllocation> {
-        let memory_type_index = self.find_memory_type(
-            allocation_requirements.memory_requirements.memory_type_bits,
-            allocation_requirements.memory_domain,
+        let alignment = allocation_requirements.memory_requirements.alignment.max(1);
+        assert!(
+            alignment.is_power_of_two(),
+            "Allocation alignments must be powers of two, got {alignment}"
         );
-        let memory_type_index = if let Some(index) = memory_type_index {
-            index
-        } else {
-            return Err(ash::vk::Result::ERROR_OUT_OF_DEVICE_MEMORY);
+        let memory_type_bits = allocation_requirements.memory_requirements.memory_type_bits;
+        let memory_domain = allocation_requirements.memory_domain;
+        let size = allocation_requirements.memory_requirements.size;
+        /* Host visible device local memory falls back to host memory when the device has none,
+         * or when it's exhausted: without resizable BAR it's usually only 256MB */
+        let fallback_domain = memory_domain
+            .contains(MemoryDomain::DeviceLocal | MemoryDomain::HostVisible)
+            .then(|| memory_domain.difference(MemoryDomain::DeviceLocal));
+        let device_memory = match self.find_memory_type(memory_type_bits, memory_domain) {
+            Some(index) => match self.allocate_memory(index, size) {
+                Err(ash::vk::Result::ERROR_OUT_OF_DEVICE_MEMORY) if fallback_domain.is_some() => {
+                    None
+                }
+                result => Some(result?),
+            },
+            None => None,
         };
-        let allocate_info = MemoryAllocateInfo {
-            s_type: StructureType::MEMORY_ALLOCATE_INFO,
-            p_next: std::ptr::null(),
-            allocation_size: allocation_requirements.memory_requirements.size,
-            memory_type_index,
+        let device_memory = match (device_memory, fallback_domain) {
+            (Some(device_memory), _) => device_memory,
+            (None, Some(fallback_domain)) => {
+                let index = self
+                    .find_memory_type(memory_type_bits, fallback_domain)
+                    .ok_or(ash::vk::Result::ERROR_OUT_OF_DEVICE_MEMORY)?;
+                self.allocate_memory(index, size)?
+            }
+            (None, None) => return Err(ash::vk::Result::ERROR_OUT_OF_DEVICE_MEMORY),
         };
-        let device_memory = unsafe { self.device.allocate_memory(&allocate_info, None) }?;
         self.num_allocations += 1;
         trace!(
             "PasstroughAllocator: Allocated {} bytes, there are {} allocations",
-            allocate_info.allocation_size,
+            size,
             self.num_allocations
         );
 
@@ -145,8 +181,10 @@ impl GpuAllocator for PasstroughAllocator {
 
         Ok(MemoryAllocation {
             device_memory,
+            // Each allocation owns its memory, and offset 0 satisfies any alignment
             offset: 0,
-            size: allocate_info.allocation_size,
+            size,
+            alignment,
             persistent_ptr,
         })
     }
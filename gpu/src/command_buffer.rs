@@ -3,14 +3,17 @@ use std::{ffi::CString, ops::Deref};
 
 use ash::{extensions::ext::DebugUtils, prelude::VkResult, RawPtr, vk::{
     self, CommandBufferAllocateInfo, CommandBufferBeginInfo, CommandBufferLevel,
-    CommandBufferUsageFlags, DebugUtilsLabelEXT, DependencyFlags, IndexType, Offset2D,
-    PipelineBindPoint, PipelineStageFlags, Rect2D, ShaderStageFlags,
+    CommandBufferUsageFlags, DebugUtilsLabelEXT, DependencyFlags, IndexType,
+    PipelineBindPoint, PipelineStageFlags, Rect2D, ShaderStageFlags, StencilFaceFlags,
     StructureType, SubmitInfo, Viewport,
     ClearDepthStencilValue
 }};
-use ash::vk::{ImageLayout, RenderingAttachmentInfoKHR, RenderingFlags, RenderingInfoKHR, ResolveModeFlags};
+use ash::vk::{AccessFlags, ImageLayout, ImageUsageFlags, RenderingAttachmentInfoKHR, RenderingFlags, RenderingInfoKHR, ResolveModeFlags};
 
-use crate::{GPUFence, GPUSemaphore, GpuImage, ToVk, GpuImageView};
+use crate::{
+    with_descriptor_writes, DescriptorInfo, FrontFace, GPUFence, GPUSemaphore, GpuImage,
+    GpuImageView, ImageFormat, QueryPool, ToVk, TransitionInfo,
+};
 
 use super::{
     Gpu, GpuBuffer, GpuDescriptorSet, Pipeline, QueueType,
@@ -39,8 +42,18 @@ where
     command_buffer: &'c mut CommandBuffer<'g>,
     viewport_area: Option<Viewport>,
     scissor_area: Option<Rect2D>,
+    line_width: Option<f32>,
+    pipeline_line_width: f32,
+    pipeline_front_face: FrontFace,
+    mirrored: bool,
+    stencil_reference: u32,
+    blend_constants: [f32; 4],
+    color_formats: Vec<vk::Format>,
+    depth_format: Option<ImageFormat>,
+    stencil_format: Option<ImageFormat>,
     has_draw_command: bool,
     render_area: Rect2D,
+    pipeline_layout: Option<vk::PipelineLayout>,
 }
 pub struct MemoryBarrier {
     pub src_access_mask: vk::AccessFlags,
@@ -60,6 +73,48 @@ impl ToVk for MemoryBarrier {
     }
 }
 
+// The bytes copied by CommandBuffer::copy_buffer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferCopyRegion {
+    pub src_offset: u64,
+    pub dst_offset: u64,
+    pub size: u64,
+}
+
+impl ToVk for BufferCopyRegion {
+    type Inner = vk::BufferCopy;
+
+    fn to_vk(&self) -> Self::Inner {
+        Self::Inner {
+            src_offset: self.src_offset,
+            dst_offset: self.dst_offset,
+            size: self.size,
+        }
+    }
+}
+
+// The regions blitted by CommandBuffer::blit_image, the offsets are the corners of each region
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ImageBlit {
+    pub src_subresource: vk::ImageSubresourceLayers,
+    pub src_offsets: [vk::Offset3D; 2],
+    pub dst_subresource: vk::ImageSubresourceLayers,
+    pub dst_offsets: [vk::Offset3D; 2],
+}
+
+impl ToVk for ImageBlit {
+    type Inner = vk::ImageBlit;
+
+    fn to_vk(&self) -> Self::Inner {
+        Self::Inner {
+            src_subresource: self.src_subresource,
+            src_offsets: self.src_offsets,
+            dst_subresource: self.dst_subresource,
+            dst_offsets: self.dst_offsets,
+        }
+    }
+}
+
 pub struct BufferMemoryBarrier<'a> {
     pub src_access_mask: vk::AccessFlags,
     pub dst_access_mask: vk::AccessFlags,
@@ -118,6 +173,144 @@ impl<'a> ToVk for ImageMemoryBarrier<'a> {
     }
 }
 
+// The states an image goes through in the common transitions, see ImageTransition
+#[derive(Clone, Copy)]
+enum ImageState {
+    Undefined,
+    ColorAttachment,
+    DepthAttachment,
+    ShaderRead,
+    DepthShaderRead,
+    TransferSrc,
+    TransferDst,
+    Present,
+}
+
+impl ImageState {
+    fn transition_info(&self) -> TransitionInfo {
+        let (layout, access_mask, stage_mask) = match self {
+            ImageState::Undefined => (
+                ImageLayout::UNDEFINED,
+                AccessFlags::empty(),
+                PipelineStageFlags::TOP_OF_PIPE,
+            ),
+            ImageState::ColorAttachment => (
+                ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                AccessFlags::COLOR_ATTACHMENT_READ | AccessFlags::COLOR_ATTACHMENT_WRITE,
+                PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            ),
+            ImageState::DepthAttachment => (
+                ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                    | AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                PipelineStageFlags::EARLY_FRAGMENT_TESTS | PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            ),
+            ImageState::ShaderRead => (
+                ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                AccessFlags::SHADER_READ,
+                PipelineStageFlags::VERTEX_SHADER | PipelineStageFlags::FRAGMENT_SHADER,
+            ),
+            ImageState::DepthShaderRead => (
+                ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+                AccessFlags::SHADER_READ,
+                PipelineStageFlags::VERTEX_SHADER | PipelineStageFlags::FRAGMENT_SHADER,
+            ),
+            ImageState::TransferSrc => (
+                ImageLayout::TRANSFER_SRC_OPTIMAL,
+                AccessFlags::TRANSFER_READ,
+                PipelineStageFlags::TRANSFER,
+            ),
+            ImageState::TransferDst => (
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                AccessFlags::TRANSFER_WRITE,
+                PipelineStageFlags::TRANSFER,
+            ),
+            ImageState::Present => (
+                ImageLayout::PRESENT_SRC_KHR,
+                AccessFlags::empty(),
+                PipelineStageFlags::BOTTOM_OF_PIPE,
+            ),
+        };
+        TransitionInfo {
+            layout,
+            access_mask,
+            stage_mask,
+        }
+    }
+}
+
+/* The transitions images commonly go through, each one expanding to the
+ * layouts, access masks and stages needed to correctly synchronize it:
+ * use them with CommandBuffer::transition instead of building the barriers by hand */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ImageTransition {
+    UndefinedToColorAttachment,
+    UndefinedToDepthAttachment,
+    UndefinedToTransferDst,
+    ColorAttachmentToShaderRead,
+    DepthAttachmentToShaderRead,
+    ShaderReadToColorAttachment,
+    ShaderReadToDepthAttachment,
+    ColorAttachmentToTransferSrc,
+    ShaderReadToTransferSrc,
+    TransferDstToTransferSrc,
+    TransferSrcToShaderRead,
+    TransferDstToShaderRead,
+    ColorAttachmentToPresent,
+    PresentToColorAttachment,
+}
+
+impl ImageTransition {
+    // The source and destination state of the transition
+    pub fn transition_infos(&self) -> (TransitionInfo, TransitionInfo) {
+        let (old, new) = match self {
+            ImageTransition::UndefinedToColorAttachment => {
+                (ImageState::Undefined, ImageState::ColorAttachment)
+            }
+            ImageTransition::UndefinedToDepthAttachment => {
+                (ImageState::Undefined, ImageState::DepthAttachment)
+            }
+            ImageTransition::UndefinedToTransferDst => {
+                (ImageState::Undefined, ImageState::TransferDst)
+            }
+            ImageTransition::ColorAttachmentToShaderRead => {
+                (ImageState::ColorAttachment, ImageState::ShaderRead)
+            }
+            ImageTransition::DepthAttachmentToShaderRead => {
+                (ImageState::DepthAttachment, ImageState::DepthShaderRead)
+            }
+            ImageTransition::ShaderReadToColorAttachment => {
+                (ImageState::ShaderRead, ImageState::ColorAttachment)
+            }
+            ImageTransition::ShaderReadToDepthAttachment => {
+                (ImageState::DepthShaderRead, ImageState::DepthAttachment)
+            }
+            ImageTransition::ColorAttachmentToTransferSrc => {
+                (ImageState::ColorAttachment, ImageState::TransferSrc)
+            }
+            ImageTransition::ShaderReadToTransferSrc => {
+                (ImageState::ShaderRead, ImageState::TransferSrc)
+            }
+            ImageTransition::TransferDstToTransferSrc => {
+                (ImageState::TransferDst, ImageState::TransferSrc)
+            }
+            ImageTransition::TransferSrcToShaderRead => {
+                (ImageState::TransferSrc, ImageState::ShaderRead)
+            }
+            ImageTransition::TransferDstToShaderRead => {
+                (ImageState::TransferDst, ImageState::ShaderRead)
+            }
+            ImageTransition::ColorAttachmentToPresent => {
+                (ImageState::ColorAttachment, ImageState::Present)
+            }
+            ImageTransition::PresentToColorAttachment => {
+                (ImageState::Present, ImageState::ColorAttachment)
+            }
+        };
+        (old.transition_info(), new.transition_info())
+    }
+}
+
 #[derive(Default)]
 pub struct PipelineBarrierInfo<'a> {
     pub src_stage_mask: PipelineStageFlags,
@@ -184,7 +377,14 @@ impl<'g> CommandBuffer<'g> {
         let image_memory_barriers: Vec<_> = barrier_info
             .image_memory_barriers
             .iter()
-            .map(|b| b.to_vk())
+            .map(|b| {
+                b.image.track_state(TransitionInfo {
+                    layout: b.new_layout,
+                    access_mask: b.dst_access_mask,
+                    stage_mask: barrier_info.dst_stage_mask,
+                });
+                b.to_vk()
+            })
             .collect();
         unsafe {
             device.cmd_pipeline_barrier(
@@ -199,16 +399,270 @@ impl<'g> CommandBuffer<'g> {
         };
     }
 
+    pub fn copy_buffer_to_image(
+        &mut self,
+        source: &GpuBuffer,
+        dest: &GpuImage,
+        dest_layout: ImageLayout,
+        regions: &[vk::BufferImageCopy],
+    ) {
+        self.has_recorded_anything = true;
+        unsafe {
+            self.gpu.vk_logical_device().cmd_copy_buffer_to_image(
+                self.inner_command_buffer,
+                source.inner,
+                dest.inner,
+                dest_layout,
+                regions,
+            )
+        };
+    }
+
+    // E.g to upload a HostVisible staging buffer into a DeviceLocal one
+    pub fn copy_buffer(
+        &mut self,
+        source: &GpuBuffer,
+        dest: &GpuBuffer,
+        regions: &[BufferCopyRegion],
+    ) {
+        for region in regions {
+            assert!(
+                region.src_offset + region.size <= source.size,
+                "Copy region {:?} reads past the end of the source buffer",
+                region
+            );
+            assert!(
+                region.dst_offset + region.size <= dest.size,
+                "Copy region {:?} writes past the end of the destination buffer",
+                region
+            );
+        }
+        let regions: Vec<_> = regions.iter().map(ToVk::to_vk).collect();
+        self.has_recorded_anything = true;
+        unsafe {
+            self.gpu.vk_logical_device().cmd_copy_buffer(
+                self.inner_command_buffer,
+                source.inner,
+                dest.inner,
+                &regions,
+            )
+        };
+    }
+
+    /* Copies the regions of src onto dst, scaling them with filter when their sizes differ:
+     * src must have been created with TRANSFER_SRC usage and dst with TRANSFER_DST usage */
+    pub fn blit_image(
+        &mut self,
+        src: &GpuImage,
+        src_layout: ImageLayout,
+        dst: &GpuImage,
+        dst_layout: ImageLayout,
+        regions: &[ImageBlit],
+        filter: vk::Filter,
+    ) {
+        assert!(
+            src.usage().contains(ImageUsageFlags::TRANSFER_SRC),
+            "The source of a blit must be created with the TRANSFER_SRC usage, it has {:?}",
+            src.usage()
+        );
+        assert!(
+            dst.usage().contains(ImageUsageFlags::TRANSFER_DST),
+            "The destination of a blit must be created with the TRANSFER_DST usage, it has {:?}",
+            dst.usage()
+        );
+        let regions: Vec<_> = regions.iter().map(ToVk::to_vk).collect();
+        self.has_recorded_anything = true;
+        unsafe {
+            self.gpu.vk_logical_device().cmd_blit_image(
+                self.inner_command_buffer,
+                src.inner,
+                src_layout,
+                dst.inner,
+                dst_layout,
+                &regions,
+                filter,
+            )
+        };
+    }
+
+    /* Clears all the mips of a color image, which must be in the TRANSFER_DST_OPTIMAL layout
+     * and must have been created with the TRANSFER_DST usage */
+    pub fn clear_color_image(&mut self, image: &GpuImage, color: [f32; 4]) {
+        assert!(
+            image.usage().contains(ImageUsageFlags::TRANSFER_DST),
+            "A cleared image must be created with the TRANSFER_DST usage, it has {:?}",
+            image.usage()
+        );
+        self.has_recorded_anything = true;
+        unsafe {
+            self.gpu.vk_logical_device().cmd_clear_color_image(
+                self.inner_command_buffer,
+                image.inner,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                &vk::ClearColorValue { float32: color },
+                &[vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: vk::REMAINING_MIP_LEVELS,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                }],
+            )
+        };
+    }
+
+    // Must be recorded outside of render passes, before the queries are written again
+    pub fn reset_query_pool(&mut self, pool: &QueryPool) {
+        self.has_recorded_anything = true;
+        unsafe {
+            self.gpu.vk_logical_device().cmd_reset_query_pool(
+                self.inner_command_buffer,
+                pool.inner,
+                0,
+                pool.count,
+            )
+        };
+    }
+
+    // Writes the time when the commands recorded before reach stage, see QueryPool::get_results
+    pub fn write_timestamp(&mut self, pool: &QueryPool, stage: PipelineStageFlags, index: u32) {
+        self.has_recorded_anything = true;
+        unsafe {
+            self.gpu.vk_logical_device().cmd_write_timestamp(
+                self.inner_command_buffer,
+                stage,
+                pool.inner,
+                index,
+            )
+        };
+    }
+
+    // Compute pipelines are bound outside of render passes, see Pipeline::new_compute
+    pub fn bind_compute_pipeline(&mut self, pipeline: &Pipeline) {
+        debug_assert_eq!(
+            pipeline.bind_point,
+            PipelineBindPoint::COMPUTE,
+            "Tried to bind a graphics pipeline as a compute pipeline"
+        );
+        self.has_recorded_anything = true;
+        unsafe {
+            self.gpu.vk_logical_device().cmd_bind_pipeline(
+                self.inner_command_buffer,
+                PipelineBindPoint::COMPUTE,
+                pipeline.pipeline,
+            )
+        };
+    }
+
+    // Runs the bound compute pipeline over group_x * group_y * group_z workgroups
+    pub fn dispatch(&mut self, group_x: u32, group_y: u32, group_z: u32) {
+        self.has_recorded_anything = true;
+        unsafe {
+            self.gpu.vk_logical_device().cmd_dispatch(
+                self.inner_command_buffer,
+                group_x,
+                group_y,
+                group_z,
+            )
+        };
+    }
+
+    pub fn push_constant<T: Copy + Sized>(&self, pipeline: &Pipeline, data: &T, offset: u32) {
+        let device = self.gpu.vk_logical_device();
+        unsafe {
+            let ptr: *const u8 = data as *const T as *const u8;
+            let slice = std::slice::from_raw_parts(ptr, std::mem::size_of::<T>());
+            device.cmd_push_constants(
+                self.inner_command_buffer,
+                pipeline.pipeline_layout,
+                ShaderStageFlags::ALL,
+                offset,
+                slice,
+            );
+        }
+    }
+
+    // Transitions all the mips of the image, see ImageTransition
+    pub fn transition(&mut self, image: &GpuImage, transition: ImageTransition) {
+        let (old, new) = transition.transition_infos();
+        self.transition_images(&[(image, old, new)]);
+    }
+
+    /* Transitions all the mips of each (image, old, new) entry with a single barrier,
+     * which waits on the union of the old stages and blocks the union of the new ones */
+    pub fn transition_images(
+        &mut self,
+        transitions: &[(&GpuImage, TransitionInfo, TransitionInfo)],
+    ) {
+        if transitions.is_empty() {
+            return;
+        }
+        let mut src_stage_mask = PipelineStageFlags::empty();
+        let mut dst_stage_mask = PipelineStageFlags::empty();
+        let image_memory_barriers: Vec<_> = transitions
+            .iter()
+            .map(|(image, old, new)| {
+                src_stage_mask |= old.stage_mask;
+                dst_stage_mask |= new.stage_mask;
+                ImageMemoryBarrier {
+                    src_access_mask: old.access_mask,
+                    dst_access_mask: new.access_mask,
+                    old_layout: old.layout,
+                    new_layout: new.layout,
+                    src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+                    dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+                    image,
+                    subresource_range: vk::ImageSubresourceRange {
+                        aspect_mask: image.format.aspect_mask(),
+                        base_mip_level: 0,
+                        level_count: image.mip_levels,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    },
+                }
+            })
+            .collect();
+        self.pipeline_barrier(&PipelineBarrierInfo {
+            src_stage_mask,
+            dst_stage_mask,
+            dependency_flags: DependencyFlags::empty(),
+            image_memory_barriers: &image_memory_barriers,
+            ..Default::default()
+        });
+    }
+
     pub fn bind_descriptor_sets(
         &self,
         bind_point: PipelineBindPoint,
         material: &Pipeline,
         first_index: u32,
         descriptor_sets: &[&GpuDescriptorSet],
+    ) {
+        self.bind_descriptor_sets_with_offsets(
+            bind_point,
+            material,
+            first_index,
+            descriptor_sets,
+            &[],
+        )
+    }
+
+    /* The sets' dynamic descriptors are bound at dynamic_offsets, one for each dynamic
+     * descriptor in binding order, starting from the first set */
+    pub fn bind_descriptor_sets_with_offsets(
+        &self,
+        bind_point: PipelineBindPoint,
+        material: &Pipeline,
+        first_index: u32,
+        descriptor_sets: &[&GpuDescriptorSet],
+        dynamic_offsets: &[u32],
     ) {
         let descriptor_sets: Vec<_> = descriptor_sets
             .iter()
-            .map(|d| d.allocation.descriptor_set)
+            .map(|d| {
+                d.mark_used();
+                d.allocation.descriptor_set
+            })
             .collect();
         unsafe {
             self.gpu.vk_logical_device().cmd_bind_descriptor_sets(
@@ -217,7 +671,7 @@ impl<'g> CommandBuffer<'g> {
                 material.pipeline_layout,
                 first_index,
                 &descriptor_sets,
-                &[],
+                dynamic_offsets,
             );
         }
     }
@@ -272,6 +726,10 @@ impl<'g> CommandBuffer<'g> {
     pub fn inner(&self) -> vk::CommandBuffer {
         self.inner_command_buffer
     }
+
+    pub(crate) fn has_recorded_anything(&self) -> bool {
+        self.has_recorded_anything
+    }
 }
 
 // Debug utilities
@@ -448,12 +906,24 @@ impl ToVk for AttachmentStoreOp {
     }
 }
 
+/* The attachments of a dynamic render pass: initial_layout is the layout the image
+ * is rendered in, and no transition is performed when the render pass begins.
+ * Callers must transition the image to that layout beforehand (e.g with
+ * Gpu::transition_image_layout_in_command_buffer), the render graph does it for its resources */
 #[derive(Clone, Copy)]
 pub struct ColorAttachment<'a> {
     pub image_view: &'a GpuImageView,
     pub load_op: ColorLoadOp,
     pub store_op: AttachmentStoreOp,
     pub initial_layout: ImageLayout,
+    pub resolve: Option<ColorResolve<'a>>,
+}
+
+// The samples of a multisampled color attachment are averaged into image_view at the end of the render pass
+#[derive(Clone, Copy)]
+pub struct ColorResolve<'a> {
+    pub image_view: &'a GpuImageView,
+    pub layout: ImageLayout,
 }
 
 #[derive(Clone, Copy)]
@@ -462,6 +932,39 @@ pub struct DepthAttachment<'a> {
     pub load_op: DepthLoadOp,
     pub store_op: AttachmentStoreOp,
     pub initial_layout: ImageLayout,
+    pub resolve: Option<DepthResolve<'a>>,
+}
+
+/* How the samples of a multisampled depth attachment are combined into
+ * the single sampled resolve target: SampleZero is supported by every device,
+ * the other modes depend on VkPhysicalDeviceDepthStencilResolveProperties */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DepthResolveMode {
+    SampleZero,
+    Average,
+    Min,
+    Max,
+}
+
+impl ToVk for DepthResolveMode {
+    type Inner = ResolveModeFlags;
+
+    fn to_vk(&self) -> Self::Inner {
+        match self {
+            DepthResolveMode::SampleZero => Self::Inner::SAMPLE_ZERO,
+            DepthResolveMode::Average => Self::Inner::AVERAGE,
+            DepthResolveMode::Min => Self::Inner::MIN,
+            DepthResolveMode::Max => Self::Inner::MAX,
+        }
+    }
+}
+
+// The depth is resolved into image_view at the end of the render pass
+#[derive(Clone, Copy)]
+pub struct DepthResolve<'a> {
+    pub mode: DepthResolveMode,
+    pub image_view: &'a GpuImageView,
+    pub layout: ImageLayout,
 }
 
 #[derive(Clone, Copy)]
@@ -470,6 +973,9 @@ pub struct StencilAttachment<'a> {
     pub load_op: StencilLoadOp,
     pub store_op: AttachmentStoreOp,
     pub initial_layout: ImageLayout,
+    /* The stencil of a combined depth-stencil view is resolved along with the depth:
+     * both must use the same mode and view, see Gpu::supports_depth_stencil_resolve */
+    pub resolve: Option<DepthResolve<'a>>,
 }
 
 #[derive(Clone, Copy)]
@@ -482,15 +988,66 @@ pub struct BeginRenderPassInfo<'a> {
 
 impl<'c, 'g> RenderPassCommand<'c, 'g> {
     fn new(command_buffer: &'c mut CommandBuffer<'g>, info: &BeginRenderPassInfo<'c>) -> Self {
+        if cfg!(debug_assertions) {
+            let views = info
+                .color_attachments
+                .iter()
+                .map(|a| a.image_view)
+                .chain(info.depth_attachment.iter().map(|a| a.image_view))
+                .chain(info.stencil_attachment.iter().map(|a| a.image_view));
+            for view in views {
+                let extents = view.extents();
+                let area = info.render_area;
+                assert!(
+                    area.offset.x.max(0) as u32 + area.extent.width <= extents.width
+                        && area.offset.y.max(0) as u32 + area.extent.height <= extents.height,
+                    "The render area {:?} exceeds the attachment extents {:?}",
+                    area,
+                    extents
+                );
+            }
+            for attch in info.color_attachments {
+                assert!(
+                    matches!(
+                        attch.initial_layout,
+                        ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+                            | ImageLayout::ATTACHMENT_OPTIMAL
+                            | ImageLayout::GENERAL
+                    ),
+                    "Color attachments must be rendered in an attachment layout, got {:?}: transition the image before beginning the render pass",
+                    attch.initial_layout
+                );
+            }
+            let depth_layouts = info
+                .depth_attachment
+                .map(|a| a.initial_layout)
+                .into_iter()
+                .chain(info.stencil_attachment.map(|a| a.initial_layout));
+            for layout in depth_layouts {
+                assert!(
+                    matches!(
+                        layout,
+                        ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+                            | ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL
+                            | ImageLayout::DEPTH_ATTACHMENT_OPTIMAL
+                            | ImageLayout::STENCIL_ATTACHMENT_OPTIMAL
+                            | ImageLayout::ATTACHMENT_OPTIMAL
+                            | ImageLayout::GENERAL
+                    ),
+                    "Depth and stencil attachments must be rendered in a depth stencil layout, got {:?}: transition the image before beginning the render pass",
+                    layout
+                );
+            }
+        }
         let color_attachments: Vec<_> = info.color_attachments.iter().map(|attch| {
            RenderingAttachmentInfoKHR {
                s_type: StructureType::RENDERING_ATTACHMENT_INFO,
                p_next: std::ptr::null(),
                image_view: attch.image_view.inner,
                image_layout: attch.initial_layout,
-               resolve_mode: ResolveModeFlags::NONE,
-               resolve_image_view: vk::ImageView::null(),
-               resolve_image_layout: ImageLayout::UNDEFINED,
+               resolve_mode: attch.resolve.map_or(ResolveModeFlags::NONE, |_| ResolveModeFlags::AVERAGE),
+               resolve_image_view: attch.resolve.map_or(vk::ImageView::null(), |r| r.image_view.inner),
+               resolve_image_layout: attch.resolve.map_or(ImageLayout::UNDEFINED, |r| r.layout),
                load_op: attch.load_op.to_vk(),
                store_op: attch.store_op.to_vk(),
                clear_value: match attch.load_op {
@@ -502,25 +1059,47 @@ impl<'c, 'g> RenderPassCommand<'c, 'g> {
            } 
         }).collect();
 
+        /* The depth and stencil attachments of a combined depth-stencil image must be the same view:
+         * both attachments share the same clear value, so that clearing one aspect
+         * never writes a bogus value into the other one */
+        if let (Some(depth), Some(stencil)) = (&info.depth_attachment, &info.stencil_attachment) {
+            debug_assert!(
+                depth.image_view.inner == stencil.image_view.inner,
+                "The depth and stencil attachments must use the same combined depth-stencil view"
+            );
+            debug_assert!(
+                depth.resolve.map(|r| (r.mode, r.image_view.inner))
+                    == stencil.resolve.map(|r| (r.mode, r.image_view.inner)),
+                "The depth and stencil attachments must be resolved with the same mode and view"
+            );
+        }
+        let depth_stencil_clear = ash::vk::ClearValue {
+            depth_stencil: ClearDepthStencilValue {
+                depth: match info.depth_attachment.map(|attch| attch.load_op) {
+                    Some(DepthLoadOp::Clear(d)) => d,
+                    _ => 0.0,
+                },
+                stencil: match info.stencil_attachment.map(|attch| attch.load_op) {
+                    Some(StencilLoadOp::Clear(s)) => s as _,
+                    _ => 0,
+                },
+            },
+        };
+
         let depth_attachment = info.depth_attachment.map(|attch| {
             RenderingAttachmentInfoKHR {
                 s_type: StructureType::RENDERING_ATTACHMENT_INFO,
                 p_next: std::ptr::null(),
                 image_view: attch.image_view.inner,
                 image_layout: attch.initial_layout,
-                resolve_mode: ResolveModeFlags::NONE,
-                resolve_image_view: vk::ImageView::null(),
-                resolve_image_layout: ImageLayout::UNDEFINED,
+                resolve_mode: attch.resolve.map_or(ResolveModeFlags::NONE, |r| r.mode.to_vk()),
+                resolve_image_view: attch
+                    .resolve
+                    .map_or(vk::ImageView::null(), |r| r.image_view.inner),
+                resolve_image_layout: attch.resolve.map_or(ImageLayout::UNDEFINED, |r| r.layout),
                 load_op: attch.load_op.to_vk(),
                 store_op: attch.store_op.to_vk(),
-                clear_value: match attch.load_op {
-                    DepthLoadOp::Clear(d) => {ash::vk::ClearValue {
-                        depth_stencil: ClearDepthStencilValue {
-                            depth: d,
-                            stencil: 255,
-                        }}}
-                    _ => ash::vk::ClearValue::default()
-                }
+                clear_value: depth_stencil_clear,
             }
         });
 
@@ -530,19 +1109,16 @@ impl<'c, 'g> RenderPassCommand<'c, 'g> {
                 p_next: std::ptr::null(),
                 image_view: attch.image_view.inner,
                 image_layout: attch.initial_layout,
-                resolve_mode: ResolveModeFlags::NONE,
-                resolve_image_view: vk::ImageView::null(),
-                resolve_image_layout: ImageLayout::UNDEFINED,
+                resolve_mode: attch
+                    .resolve
+                    .map_or(ResolveModeFlags::NONE, |r| r.mode.to_vk()),
+                resolve_image_view: attch
+                    .resolve
+                    .map_or(vk::ImageView::null(), |r| r.image_view.inner),
+                resolve_image_layout: attch.resolve.map_or(ImageLayout::UNDEFINED, |r| r.layout),
                 load_op: attch.load_op.to_vk(),
                 store_op: attch.store_op.to_vk(),
-                clear_value: match attch.load_op {
-                    StencilLoadOp::Clear(s) => {ash::vk::ClearValue {
-                        depth_stencil: ClearDepthStencilValue {
-                            depth: 0.0,
-                            stencil: s as _,
-                        }}}
-                    _ => ash::vk::ClearValue::default()
-                }
+                clear_value: depth_stencil_clear,
             }
         });
         
@@ -571,11 +1147,53 @@ impl<'c, 'g> RenderPassCommand<'c, 'g> {
             has_draw_command: false,
             viewport_area: None,
             scissor_area: None,
+            line_width: None,
+            pipeline_line_width: 1.0,
+            pipeline_front_face: FrontFace::default(),
+            mirrored: false,
+            stencil_reference: 0,
+            blend_constants: [0.0; 4],
+            color_formats: info
+                .color_attachments
+                .iter()
+                .map(|attch| attch.image_view.format().to_vk())
+                .collect(),
+            depth_format: info.depth_attachment.map(|attch| attch.image_view.format()),
+            stencil_format: info.stencil_attachment.map(|attch| attch.image_view.format()),
             render_area: info.render_area,
+            pipeline_layout: None,
         }
     }
 
     pub fn bind_pipeline(&mut self, material: &Pipeline) {
+        debug_assert_eq!(
+            material.bind_point,
+            PipelineBindPoint::GRAPHICS,
+            "Compute pipelines must be bound outside of render passes"
+        );
+        debug_assert_eq!(
+            material.color_formats.len(),
+            self.color_formats.len(),
+            "The pipeline writes {} color attachments, but the render pass has {}",
+            material.color_formats.len(),
+            self.color_formats.len()
+        );
+        debug_assert_eq!(
+            material.color_formats, self.color_formats,
+            "The pipeline color formats don't match the render pass color attachments"
+        );
+        debug_assert_eq!(
+            material.depth_stencil_format, self.depth_format,
+            "The pipeline depth format doesn't match the render pass depth attachment"
+        );
+        debug_assert_eq!(
+            material.depth_stencil_format.filter(|f| f.has_stencil()),
+            self.stencil_format,
+            "The pipeline stencil format doesn't match the render pass stencil attachment"
+        );
+        self.pipeline_line_width = material.line_width;
+        self.pipeline_front_face = material.front_face;
+        self.pipeline_layout = Some(material.pipeline_layout);
         let device = self.command_buffer.gpu.vk_logical_device();
         unsafe {
             device.cmd_bind_pipeline(
@@ -639,8 +1257,8 @@ impl<'c, 'g> RenderPassCommand<'c, 'g> {
         let viewport = match self.viewport_area {
             Some(viewport) => viewport,
             None => Viewport {
-                x: 0 as f32,
-                y: 0.0,
+                x: self.render_area.offset.x as f32,
+                y: self.render_area.offset.y as f32,
                 width: self.render_area.extent.width as f32,
                 height,
                 min_depth: 0.0,
@@ -649,17 +1267,51 @@ impl<'c, 'g> RenderPassCommand<'c, 'g> {
         };
         let scissor = match self.scissor_area {
             Some(scissor) => scissor,
-            None => Rect2D {
-                offset: Offset2D { x: 0, y: 0 },
-                extent: self.render_area.extent,
-            },
+            None => self.render_area,
+        };
+        let line_width = self
+            .command_buffer
+            .gpu
+            .clamp_line_width(self.line_width.unwrap_or(self.pipeline_line_width));
+        let front_face = if self.mirrored {
+            self.pipeline_front_face.flipped()
+        } else {
+            self.pipeline_front_face
         };
         unsafe {
             device.cmd_set_viewport(self.command_buffer.inner(), 0, &[viewport]);
             device.cmd_set_scissor(self.command_buffer.inner(), 0, &[scissor]);
+            device.cmd_set_line_width(self.command_buffer.inner(), line_width);
+            device.cmd_set_stencil_reference(
+                self.command_buffer.inner(),
+                StencilFaceFlags::FRONT_AND_BACK,
+                self.stencil_reference,
+            );
+            device.cmd_set_blend_constants(self.command_buffer.inner(), &self.blend_constants);
+            device.cmd_set_front_face(self.command_buffer.inner(), front_face.to_vk());
         }
     }
 
+    pub fn set_stencil_reference(&mut self, reference: u32) {
+        self.stencil_reference = reference;
+    }
+
+    pub fn set_blend_constants(&mut self, blend_constants: [f32; 4]) {
+        self.blend_constants = blend_constants;
+    }
+
+    /* Flips the front face of the bound pipelines for the following draws, so that the geometry
+     * mirrored by its transform (i.e its determinant is negative) is culled like the rest */
+    pub fn set_mirrored(&mut self, mirrored: bool) {
+        self.mirrored = mirrored;
+    }
+
+    // Overrides the line width of the bound pipelines for all the following draws,
+    // the width is clamped to the range supported by the device
+    pub fn set_line_width(&mut self, line_width: f32) {
+        self.line_width = Some(line_width);
+    }
+
     pub fn bind_index_buffer(
         &self,
         buffer: &GpuBuffer,
@@ -696,19 +1348,32 @@ impl<'c, 'g> RenderPassCommand<'c, 'g> {
         }
     }
 
-    pub fn push_constant<T: Copy + Sized>(&self, pipeline: &Pipeline, data: &T, offset: u32) {
-        let device = self.command_buffer.gpu.vk_logical_device();
-        unsafe {
-            let ptr: *const u8 = data as *const T as *const u8;
-            let slice = std::slice::from_raw_parts(ptr, std::mem::size_of::<T>());
-            device.cmd_push_constants(
+    /* Pushes the descriptors into the command buffer without allocating a descriptor set:
+     * the bound pipeline must have been created with a push descriptor GlobalBinding at set_index,
+     * which needs Gpu::supports_push_descriptors */
+    pub fn push_descriptor_set(&self, set_index: u32, descriptors: &[DescriptorInfo]) {
+        let gpu = self.command_buffer.gpu;
+        assert!(
+            gpu.supports_push_descriptors(),
+            "Push descriptors are not supported by the device"
+        );
+        let pipeline_layout = self
+            .pipeline_layout
+            .expect("A pipeline must be bound before pushing descriptors");
+        let push_descriptor = gpu.state.push_descriptor.as_ref().unwrap();
+        with_descriptor_writes(vk::DescriptorSet::null(), descriptors, |writes| unsafe {
+            push_descriptor.cmd_push_descriptor_set(
                 self.command_buffer.inner_command_buffer,
-                pipeline.pipeline_layout,
-                ShaderStageFlags::ALL,
-                offset,
-                slice,
+                PipelineBindPoint::GRAPHICS,
+                pipeline_layout,
+                set_index,
+                writes,
             );
-        }
+        });
+    }
+
+    pub fn push_constant<T: Copy + Sized>(&self, pipeline: &Pipeline, data: &T, offset: u32) {
+        self.command_buffer.push_constant(pipeline, data, offset);
     }
 }
 
@@ -1,8 +1,4 @@
-use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
-    hash::{Hash, Hasher},
-    ptr::addr_of,
-};
+use std::{collections::HashMap, ptr::addr_of};
 
 use ash::{
     prelude::VkResult,
@@ -16,6 +12,96 @@ use ash::{
 use log::trace;
 
 use super::DescriptorSetInfo;
+use crate::{BindingElement, ToVk};
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DescriptorBindingSignature {
+    pub binding: u32,
+    pub descriptor_type: DescriptorType,
+    pub descriptor_count: u32,
+    pub stage_flags: ShaderStageFlags,
+    // The sampler baked into the layout, sets with different immutable samplers can't be swapped
+    pub immutable_sampler: Option<vk::Sampler>,
+}
+
+/*
+The bindings of a descriptor set layout, without the resources bound to them:
+descriptor sets and pipelines with the same signature share the same
+VkDescriptorSetLayout, so a set allocated for a signature can be bound to any
+pipeline created with the same signature at that set index
+ */
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct DescriptorSetLayoutSignature {
+    bindings: Vec<DescriptorBindingSignature>,
+    push_descriptor: bool,
+}
+
+impl DescriptorSetLayoutSignature {
+    pub fn new(bindings: impl IntoIterator<Item = DescriptorBindingSignature>) -> Self {
+        let mut bindings: Vec<_> = bindings.into_iter().collect();
+        bindings.sort();
+        Self {
+            bindings,
+            push_descriptor: false,
+        }
+    }
+
+    // Layouts for push descriptors can only be used with RenderPassCommand::push_descriptor_set,
+    // no descriptor set can be allocated from them
+    pub fn with_push_descriptor(mut self, push_descriptor: bool) -> Self {
+        self.push_descriptor = push_descriptor;
+        self
+    }
+
+    pub fn is_push_descriptor(&self) -> bool {
+        self.push_descriptor
+    }
+
+    pub fn from_set_info(info: &DescriptorSetInfo) -> Self {
+        Self::new(
+            info.descriptors
+                .iter()
+                .map(|descriptor_info| DescriptorBindingSignature {
+                    binding: descriptor_info.binding,
+                    descriptor_type: match descriptor_info.element_type {
+                        super::DescriptorType::UniformBuffer(_) => DescriptorType::UNIFORM_BUFFER,
+                        super::DescriptorType::UniformBufferDynamic(_) => {
+                            DescriptorType::UNIFORM_BUFFER_DYNAMIC
+                        }
+                        super::DescriptorType::StorageBuffer(_) => DescriptorType::STORAGE_BUFFER,
+                        super::DescriptorType::Sampler(_)
+                        | super::DescriptorType::ImmutableSampler(_) => DescriptorType::SAMPLER,
+                        super::DescriptorType::CombinedImageSampler(_)
+                        | super::DescriptorType::DepthComparisonSampler(_)
+                        | super::DescriptorType::CombinedImageSamplerArray(_)
+                        | super::DescriptorType::CombinedImageImmutableSampler(_) => {
+                            DescriptorType::COMBINED_IMAGE_SAMPLER
+                        }
+                    },
+                    descriptor_count: descriptor_info.element_type.count(),
+                    stage_flags: descriptor_info.binding_stage.to_vk(),
+                    immutable_sampler: descriptor_info.element_type.immutable_sampler(),
+                }),
+        )
+    }
+
+    pub fn from_binding_elements(elements: &[BindingElement]) -> Self {
+        Self::new(elements.iter().map(|element| {
+            let binding = DescriptorSetLayoutBinding::from(element);
+            DescriptorBindingSignature {
+                binding: binding.binding,
+                descriptor_type: binding.descriptor_type,
+                descriptor_count: binding.descriptor_count,
+                stage_flags: binding.stage_flags,
+                immutable_sampler: element.binding_type.immutable_sampler(),
+            }
+        }))
+    }
+
+    pub fn bindings(&self) -> &[DescriptorBindingSignature] {
+        &self.bindings
+    }
+}
 
 pub struct DescriptorSetAllocation {
     pub owner_pool: vk::DescriptorPool,
@@ -26,6 +112,12 @@ pub struct DescriptorSetAllocation {
 pub trait DescriptorSetAllocator {
     fn allocate(&mut self, info: &DescriptorSetInfo) -> VkResult<DescriptorSetAllocation>;
     fn deallocate(&mut self, descriptor_set: &DescriptorSetAllocation) -> VkResult<()>;
+
+    // The returned layout is owned by the allocator, and must not be destroyed
+    fn get_descriptor_set_layout(
+        &mut self,
+        signature: &DescriptorSetLayoutSignature,
+    ) -> VkResult<vk::DescriptorSetLayout>;
 }
 
 /*
@@ -34,7 +126,7 @@ each time a descriptor set allocation fails
  */
 pub struct PooledDescriptorSetAllocator {
     usable_descriptor_pools: Vec<DescriptorPool>,
-    hashed_layouts: HashMap<u64, DescriptorSetLayout>,
+    hashed_layouts: HashMap<DescriptorSetLayoutSignature, DescriptorSetLayout>,
     device: ash::Device,
 }
 impl PooledDescriptorSetAllocator {
@@ -48,6 +140,10 @@ impl PooledDescriptorSetAllocator {
                 ty: DescriptorType::UNIFORM_BUFFER,
                 descriptor_count: 100,
             };
+            let pool_size_uniform_buffer_dynamic = DescriptorPoolSize {
+                ty: DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+                descriptor_count: 100,
+            };
             let pool_size_storage_buffer = DescriptorPoolSize {
                 ty: DescriptorType::STORAGE_BUFFER,
                 descriptor_count: 100,
@@ -66,9 +162,10 @@ impl PooledDescriptorSetAllocator {
                     p_next: std::ptr::null(),
                     flags: DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET,
                     max_sets: 100,
-                    pool_size_count: 4,
+                    pool_size_count: 5,
                     p_pool_sizes: [
                         pool_size_uniform_buffer,
+                        pool_size_uniform_buffer_dynamic,
                         pool_size_storage_buffer,
                         pool_size_combined_image_sampler,
                         pool_size_sampler,
@@ -83,66 +180,34 @@ impl PooledDescriptorSetAllocator {
         Ok(())
     }
 
-    fn get_descriptor_set_layout(
-        &mut self,
-        info: &DescriptorSetInfo,
-    ) -> VkResult<vk::DescriptorSetLayout> {
-        let mut hasher = DefaultHasher::new();
-        info.hash(&mut hasher);
-        let hash = hasher.finish();
-
-        if let Some(layout) = self.hashed_layouts.get(&hash) {
-            Ok(*layout)
-        } else {
-            let new_layout = self.construct_descriptor_set_layout(info)?;
-            self.hashed_layouts.insert(hash, new_layout);
-            trace!(
-                "Created a new descriptor set layout! There are {} layouts allocated",
-                self.hashed_layouts.len()
-            );
-            Ok(new_layout)
-        }
-    }
-
     fn construct_descriptor_set_layout(
         &self,
-        info: &DescriptorSetInfo,
+        signature: &DescriptorSetLayoutSignature,
     ) -> VkResult<DescriptorSetLayout> {
-        let mut descriptor_set_bindings = vec![];
-        for descriptor_info in info.descriptors {
-            let stage_flags = match descriptor_info.binding_stage {
-                super::ShaderStage::Vertex => ShaderStageFlags::VERTEX,
-                super::ShaderStage::Fragment => ShaderStageFlags::FRAGMENT,
-                super::ShaderStage::Compute => ShaderStageFlags::COMPUTE,
-                crate::ShaderStage::VertexFragment => {
-                    ShaderStageFlags::VERTEX | ShaderStageFlags::FRAGMENT
-                }
-                crate::ShaderStage::All => ShaderStageFlags::ALL_GRAPHICS,
-            };
-            let descriptor_type = match descriptor_info.element_type {
-                super::DescriptorType::UniformBuffer(_) => DescriptorType::UNIFORM_BUFFER,
-                super::DescriptorType::StorageBuffer(_) => DescriptorType::STORAGE_BUFFER,
-                super::DescriptorType::Sampler(_) => DescriptorType::SAMPLER,
-                super::DescriptorType::CombinedImageSampler(_) => {
-                    DescriptorType::COMBINED_IMAGE_SAMPLER
-                }
-            };
-            let binding = DescriptorSetLayoutBinding {
-                binding: descriptor_info.binding,
-                descriptor_type,
-                descriptor_count: 1,
-                stage_flags,
-                p_immutable_samplers: std::ptr::null(),
-            };
-
-            descriptor_set_bindings.push(binding);
-        }
+        let descriptor_set_bindings: Vec<DescriptorSetLayoutBinding> = signature
+            .bindings()
+            .iter()
+            .map(|binding| DescriptorSetLayoutBinding {
+                binding: binding.binding,
+                descriptor_type: binding.descriptor_type,
+                descriptor_count: binding.descriptor_count,
+                stage_flags: binding.stage_flags,
+                p_immutable_samplers: binding
+                    .immutable_sampler
+                    .as_ref()
+                    .map_or(std::ptr::null(), |sampler| sampler as *const _),
+            })
+            .collect();
         unsafe {
             self.device.create_descriptor_set_layout(
                 &vk::DescriptorSetLayoutCreateInfo {
                     s_type: StructureType::DESCRIPTOR_SET_LAYOUT_CREATE_INFO,
                     p_next: std::ptr::null(),
-                    flags: DescriptorSetLayoutCreateFlags::empty(),
+                    flags: if signature.is_push_descriptor() {
+                        DescriptorSetLayoutCreateFlags::PUSH_DESCRIPTOR_KHR
+                    } else {
+                        DescriptorSetLayoutCreateFlags::empty()
+                    },
                     binding_count: descriptor_set_bindings.len() as _,
                     p_bindings: descriptor_set_bindings.as_ptr(),
                 },
@@ -168,7 +233,8 @@ impl PooledDescriptorSetAllocator {
 
 impl DescriptorSetAllocator for PooledDescriptorSetAllocator {
     fn allocate(&mut self, info: &DescriptorSetInfo) -> VkResult<DescriptorSetAllocation> {
-        let descriptor_set_layout = self.get_descriptor_set_layout(info)?;
+        let descriptor_set_layout =
+            self.get_descriptor_set_layout(&DescriptorSetLayoutSignature::from_set_info(info))?;
 
         let mut did_try_once = false;
 
@@ -213,6 +279,23 @@ impl DescriptorSetAllocator for PooledDescriptorSetAllocator {
                 .free_descriptor_sets(allocation.owner_pool, &[allocation.descriptor_set])
         }
     }
+
+    fn get_descriptor_set_layout(
+        &mut self,
+        signature: &DescriptorSetLayoutSignature,
+    ) -> VkResult<vk::DescriptorSetLayout> {
+        if let Some(layout) = self.hashed_layouts.get(signature) {
+            Ok(*layout)
+        } else {
+            let new_layout = self.construct_descriptor_set_layout(signature)?;
+            self.hashed_layouts.insert(signature.clone(), new_layout);
+            trace!(
+                "Created a new descriptor set layout! There are {} layouts allocated",
+                self.hashed_layouts.len()
+            );
+            Ok(new_layout)
+        }
+    }
 }
 
 impl Drop for PooledDescriptorSetAllocator {
@@ -18,19 +18,19 @@ use ash::{
         DebugUtilsMessengerCreateFlagsEXT, DebugUtilsMessengerCreateInfoEXT,
         DebugUtilsObjectNameInfoEXT, DependencyFlags, DescriptorBufferInfo, DescriptorImageInfo,
         DeviceCreateFlags, DeviceCreateInfo, DeviceQueueCreateFlags, DeviceQueueCreateInfo,
-        Extent2D, Extent3D, Fence, FormatFeatureFlags, FramebufferCreateFlags, Handle,
+        Extent2D, Extent3D, Fence, FenceCreateFlags, FenceCreateInfo, FormatFeatureFlags, FramebufferCreateFlags, Handle,
         ImageAspectFlags, ImageCreateFlags, ImageLayout, ImageSubresourceLayers,
         ImageSubresourceRange, ImageTiling, ImageType, ImageViewCreateFlags, ImageViewType,
         InstanceCreateFlags, InstanceCreateInfo, MemoryHeap, MemoryHeapFlags, 
         Offset3D, PhysicalDevice, PhysicalDeviceFeatures, PhysicalDeviceProperties,
         PhysicalDeviceType, PipelineCache, PipelineCacheCreateFlags, PipelineCacheCreateInfo,
-        PipelineStageFlags, Queue, QueueFlags, SampleCountFlags, SamplerCreateInfo,
+        PipelineStageFlags, Queue, QueueFlags, SamplerCreateInfo,
         ShaderModuleCreateFlags, SharingMode, StructureType, SubmitInfo, WriteDescriptorSet,
         API_VERSION_1_3,
     },
     *,
 };
-use ash::extensions::khr::DynamicRendering;
+use ash::extensions::khr::{DynamicRendering, PushDescriptor};
 use ash::vk::{PhysicalDeviceDynamicRenderingFeaturesKHR, PhysicalDeviceFeatures2KHR};
 
 use log::{error, trace, warn};
@@ -38,10 +38,18 @@ use raw_window_handle::HasRawDisplayHandle;
 use thiserror::Error;
 use winit::window::Window;
 
+#[cfg(feature = "leak-detection")]
+use crate::leak_tracking::{LiveResource, LiveResources, ResourceKind};
+#[cfg(feature = "renderdoc")]
+use crate::renderdoc::RenderDoc;
 use crate::swapchain::SwapchainFrame;
+#[cfg(debug_assertions)]
+use crate::use_tracking::{FrameClock, UseTracker};
 use crate::{
-    get_allocation_callbacks, GpuFramebuffer, GpuImageView, GpuShaderModule, ImageFormat,
-    ImageMemoryBarrier, PipelineBarrierInfo, QueueType, RenderPass, Swapchain, ToVk,
+    get_allocation_callbacks, BufferCopyRegion, DepthResolveMode, GpuFramebuffer, GpuImageView,
+    GpuShaderModule, ImageBlit, ImageFormat, ImageMemoryBarrier, ImageTransition,
+    PipelineBarrierInfo, QueryPool, QueueType, RenderPass, RenderPassDescription, SampleCount,
+    StagingArena, Swapchain, ToVk, TypedBuffer,
 };
 
 use super::descriptor_set::PooledDescriptorSetAllocator;
@@ -49,8 +57,8 @@ use super::descriptor_set::PooledDescriptorSetAllocator;
 use super::{
     allocator::{GpuAllocator, PasstroughAllocator},
     descriptor_set::DescriptorSetAllocator,
-    AllocationRequirements, DescriptorSetInfo, GpuBuffer, GpuDescriptorSet, GpuImage, GpuSampler,
-    MemoryDomain,
+    AllocationRequirements, DescriptorInfo, DescriptorSetInfo, GPUFence, GpuBuffer,
+    GpuDescriptorSet, GpuImage, GpuSampler, MemoryDomain,
 };
 
 const KHRONOS_VALIDATION_LAYER: &str = "VK_LAYER_KHRONOS_validation";
@@ -69,9 +77,25 @@ impl GpuDescription {
     }
 }
 
+// Every render pass is recorded with it, the engine has no VkRenderPass/VkFramebuffer path
+const DYNAMIC_RENDERING_EXTENSION: &str = "VK_KHR_dynamic_rendering";
+
+// Enabled when available, see Gpu::supports_conservative_rasterization
+const CONSERVATIVE_RASTERIZATION_EXTENSION: &str = "VK_EXT_conservative_rasterization";
+
+// Enabled when available, see Gpu::supports_push_descriptors
+const PUSH_DESCRIPTOR_EXTENSION: &str = "VK_KHR_push_descriptor";
+
 #[derive(Default, Clone, Copy)]
 struct SupportedFeatures {
     supports_rgb_images: bool,
+    supports_wide_lines: bool,
+    supports_conservative_rasterization: bool,
+    supports_push_descriptors: bool,
+    supports_device_local_host_visible: bool,
+    // See VkPhysicalDeviceDepthStencilResolveProperties
+    depth_resolve_modes: vk::ResolveModeFlags,
+    stencil_resolve_modes: vk::ResolveModeFlags,
 }
 
 pub struct GpuState {
@@ -91,6 +115,8 @@ pub struct GpuState {
     features: SupportedFeatures,
     messenger: Option<vk::DebugUtilsMessengerEXT>,
     pub dynamic_rendering: DynamicRendering,
+    // None when the device doesn't support VK_KHR_push_descriptor
+    pub push_descriptor: Option<PushDescriptor>,
 }
 
 impl Drop for GpuState {
@@ -175,8 +201,27 @@ impl Drop for GpuThreadLocalState {
 pub struct Gpu {
     pub(crate) state: Arc<GpuState>,
     pub(crate) thread_local_states: Vec<GpuThreadLocalState>,
-    pub(crate) staging_buffer: GpuBuffer,
+    // Stages the uploads of write_buffer_data and write_image_mips
+    pub(crate) staging_arena: RefCell<StagingArena>,
     pub(crate) swapchain: Swapchain,
+    #[cfg(debug_assertions)]
+    pub(crate) frame_clock: FrameClock,
+    #[cfg(feature = "renderdoc")]
+    renderdoc: Option<RenderDoc>,
+    #[cfg(feature = "leak-detection")]
+    pub(crate) live_resources: LiveResources,
+}
+
+#[cfg(feature = "leak-detection")]
+impl Drop for Gpu {
+    fn drop(&mut self) {
+        for leak in self.report_leaks() {
+            warn!(
+                "{:?} {:#x} (label: {:?}) is still alive while dropping the Gpu",
+                leak.kind, leak.handle, leak.label
+            );
+        }
+    }
 }
 
 pub struct GpuConfiguration<'a> {
@@ -185,6 +230,10 @@ pub struct GpuConfiguration<'a> {
     pub pipeline_cache_path: Option<&'a str>,
     pub enable_debug_utilities: bool,
     pub window: Window,
+    /* The frames the CPU can record while the GPU is still processing the previous ones,
+     * each with its own command pools, fences and semaphores. It is independent of the
+     * number of swapchain images, see Gpu::DEFAULT_FRAMES_IN_FLIGHT */
+    pub frames_in_flight: usize,
 }
 
 #[derive(Error, Debug, Clone)]
@@ -204,12 +253,36 @@ pub enum GpuError {
 
     #[error("Invalid queue family")]
     InvalidQueueFamilies(QueueFamilies),
+
+    #[error("The device does not support the required extensions {0:?}")]
+    MissingDeviceExtensions(Vec<String>),
+
+    #[error("Failed to create buffer {label:?} of {size} bytes in memory domain {memory_domain:?}: {result}")]
+    BufferCreationFailed {
+        label: Option<String>,
+        size: usize,
+        memory_domain: MemoryDomain,
+        result: vk::Result,
+    },
+
+    #[error("Failed to create image {label:?} of {width}x{height} with format {format:?} in memory domain {memory_domain:?}: {result}")]
+    ImageCreationFailed {
+        label: Option<String>,
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        memory_domain: MemoryDomain,
+        result: vk::Result,
+    },
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct QueueFamily {
     pub index: u32,
     pub count: u32,
+    pub capabilities: QueueFlags,
+    // The meaningful bits of the timestamps written on the family's queues, 0 if unsupported
+    pub timestamp_valid_bits: u32,
 }
 
 #[derive(Clone, Debug)]
@@ -254,10 +327,26 @@ impl QueueFamilies {
             && self.graphics_family.index != self.async_compute_family.index
             && self.graphics_family.index != self.transfer_family.index
     }
+
+    pub fn family(&self, queue_type: QueueType) -> &QueueFamily {
+        match queue_type {
+            QueueType::Graphics => &self.graphics_family,
+            QueueType::AsyncCompute => &self.async_compute_family,
+            QueueType::Transfer => &self.transfer_family,
+        }
+    }
 }
 
 impl Gpu {
+    // The size of the arena the uploads done through the Gpu are staged in
+    pub const STAGING_ARENA_SIZE: u64 = 1024 * 1024 * 64;
+    pub const DEFAULT_FRAMES_IN_FLIGHT: usize = 2;
+
     pub fn new(configuration: GpuConfiguration) -> Result<Self> {
+        assert!(
+            configuration.frames_in_flight > 0,
+            "The Gpu needs at least one frame in flight"
+        );
         let entry = unsafe { Entry::load()? };
 
         let mut instance_extensions =
@@ -276,8 +365,8 @@ impl Gpu {
         let instance = Self::create_instance(&entry, &configuration, &instance_extensions)?;
         trace!("Created instance");
 
-        let device_extensions = vec!["VK_KHR_swapchain".into(),
-                                                "VK_KHR_dynamic_rendering".into(),];
+        let mut device_extensions: Vec<String> = vec!["VK_KHR_swapchain".into(),
+                                                DYNAMIC_RENDERING_EXTENSION.into(),];
 
         let physical_device = Self::select_discrete_physical_device(&instance)?;
         trace!("Created physical device");
@@ -301,6 +390,12 @@ impl Gpu {
         )?;
 
         let supported_features = find_supported_features(&instance, physical_device);
+        if supported_features.supports_conservative_rasterization {
+            device_extensions.push(CONSERVATIVE_RASTERIZATION_EXTENSION.into());
+        }
+        if supported_features.supports_push_descriptors {
+            device_extensions.push(PUSH_DESCRIPTOR_EXTENSION.into());
+        }
 
         let logical_device = Self::create_device(
             &configuration,
@@ -362,6 +457,11 @@ impl Gpu {
             Self::create_pipeline_cache(&logical_device, configuration.pipeline_cache_path)?;
 
         let dynamic_rendering = Self::create_dynamic_rendering(&instance, &logical_device)?;
+        let push_descriptor = if supported_features.supports_push_descriptors {
+            Some(PushDescriptor::new(&instance, &logical_device))
+        } else {
+            None
+        };
         
         let state = Arc::new(GpuState {
             entry,
@@ -380,39 +480,112 @@ impl Gpu {
             descriptor_set_allocator: Arc::new(RefCell::new(descriptor_set_allocator)),
             messenger,
             dynamic_rendering,
+            push_descriptor,
         });
 
-        let swapchain = Swapchain::new(state.clone(), configuration.window)?;
+        let frames_in_flight = configuration.frames_in_flight;
+        let swapchain = Swapchain::new(state.clone(), configuration.window, frames_in_flight)?;
         let mut thread_local_states = vec![];
-        for _ in 0..Swapchain::MAX_FRAMES_IN_FLIGHT {
+        for _ in 0..frames_in_flight {
             let state = GpuThreadLocalState::new(state.clone())?;
             thread_local_states.push(state);
         }
 
-        let staging_buffer = create_staging_buffer(&state)?;
+        let staging_arena = StagingArena::new(
+            state.logical_device.clone(),
+            create_staging_buffer(&state, Self::STAGING_ARENA_SIZE)?,
+        );
         Ok(Gpu {
             state,
             thread_local_states,
-            staging_buffer,
+            staging_arena: RefCell::new(staging_arena),
             swapchain,
+            #[cfg(debug_assertions)]
+            frame_clock: FrameClock::new(frames_in_flight),
+            #[cfg(feature = "renderdoc")]
+            renderdoc: RenderDoc::load(),
+            #[cfg(feature = "leak-detection")]
+            live_resources: LiveResources::default(),
         })
     }
 
+    /* Captures the next frame presented with RenderDoc, returns false when the application
+     * is not running under RenderDoc */
+    #[cfg(feature = "renderdoc")]
+    pub fn trigger_capture(&self) -> bool {
+        match &self.renderdoc {
+            Some(renderdoc) => {
+                renderdoc.trigger_capture();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /* The buffers, images, descriptor sets and pipelines created by the Gpu that are still
+     * alive, in creation order: the remaining ones are logged when the Gpu is dropped */
+    #[cfg(feature = "leak-detection")]
+    pub fn report_leaks(&self) -> Vec<LiveResource> {
+        self.live_resources.live()
+    }
+
     pub fn acquire_next_image(&mut self) -> VkResult<(&GpuImage, &GpuImageView)> {
-        self.swapchain.acquire_next_image()
+        let next_image = self.swapchain.acquire_next_image();
+        #[cfg(debug_assertions)]
+        if next_image.is_ok() {
+            self.frame_clock.advance();
+        }
+        next_image
     }
 
+    /* Presents the current swapchain image: the frame's commands are expected to leave it in
+     * PRESENT_SRC_KHR, otherwise it's transitioned by an extra submission waiting on them */
     pub fn present(&mut self) -> VkResult<bool> {
-        self.swapchain.present()
+        let image = self.swapchain.current_image();
+        let state = image
+            .tracked_state()
+            .expect("The layout of the swapchain images is always tracked");
+        if state.layout == ImageLayout::PRESENT_SRC_KHR {
+            return self.swapchain.present();
+        }
+
+        let frame = self.swapchain.get_current_swapchain_frame();
+        unsafe {
+            self.vk_logical_device()
+                .reset_fences(&[frame.present_transition_fence.inner])
+        }?;
+        let mut command_buffer = crate::CommandBuffer::new(self, QueueType::Graphics)?;
+        command_buffer.transition_images(&[(
+            image,
+            state,
+            ImageTransition::ColorAttachmentToPresent
+                .transition_infos()
+                .1,
+        )]);
+        command_buffer.submit(&crate::CommandBufferSubmitInfo {
+            wait_semaphores: &[&frame.render_finished_semaphore],
+            wait_stages: &[PipelineStageFlags::ALL_COMMANDS],
+            signal_semaphores: &[&frame.present_transition_semaphore],
+            fence: Some(&frame.present_transition_fence),
+        })?;
+        self.swapchain
+            .present_after(&frame.present_transition_semaphore)
     }
 
+    /* Resets the command pool of the current frame: only the first call of each frame
+     * resets it, so that more than one renderer can record the commands of a frame */
     pub fn begin_frame(&self) -> VkResult<()> {
+        if self.swapchain.frame_begun.get() {
+            return Ok(());
+        }
         unsafe {
             self.vk_logical_device().reset_command_pool(
                 self.thread_local_states[self.swapchain.current_frame.get()].graphics_command_pool,
                 CommandPoolResetFlags::empty(),
             )
-        }
+        }?;
+        self.swapchain.frame_begun.set(true);
+        Ok(())
     }
 
     fn create_instance(
@@ -520,14 +693,20 @@ impl Gpu {
                 graphics_family: QueueFamily {
                     index: g.0,
                     count: g.1.queue_count,
+                    capabilities: g.1.queue_flags,
+                    timestamp_valid_bits: g.1.timestamp_valid_bits,
                 },
                 async_compute_family: QueueFamily {
                     index: a.0,
                     count: a.1.queue_count,
+                    capabilities: a.1.queue_flags,
+                    timestamp_valid_bits: a.1.timestamp_valid_bits,
                 },
                 transfer_family: QueueFamily {
                     index: t.0,
                     count: t.1.queue_count,
+                    capabilities: t.1.queue_flags,
+                    timestamp_valid_bits: t.1.timestamp_valid_bits,
                 },
                 indices: vec![g.0, a.0, t.0],
             }),
@@ -576,7 +755,7 @@ impl Gpu {
 
         let device_features = PhysicalDeviceFeatures {
             sampler_anisotropy: vk::TRUE,
-            
+            wide_lines: selected_device.device_features.wide_lines,
             ..Default::default()
         };
 
@@ -655,7 +834,7 @@ impl Gpu {
         device_extensions: &[String],
         instance: &Instance,
         physical_device: &SelectedPhysicalDevice,
-    ) -> VkResult<()> {
+    ) -> Result<()> {
         trace!(
             "Requested device extensions: {}",
             device_extensions.join(",")
@@ -672,10 +851,25 @@ impl Gpu {
             .collect();
         
         
-        for requested_extension in device_extensions {
-            if !all_supported_extensions.contains(requested_extension) {
-                error!("Device extension {:?} is not supported", requested_extension);
-            }
+        let missing_extensions: Vec<_> = device_extensions
+            .iter()
+            .filter(|extension| !all_supported_extensions.contains(extension))
+            .cloned()
+            .collect();
+        for missing_extension in &missing_extensions {
+            error!("Device extension {:?} is not supported", missing_extension);
+        }
+        if missing_extensions
+            .iter()
+            .any(|extension| extension == DYNAMIC_RENDERING_EXTENSION)
+        {
+            error!(
+                "{} is required to render, try updating the graphics drivers",
+                DYNAMIC_RENDERING_EXTENSION
+            );
+        }
+        if !missing_extensions.is_empty() {
+            bail!(GpuError::MissingDeviceExtensions(missing_extensions));
         }
 
         Ok(())
@@ -766,86 +960,22 @@ impl Gpu {
         descriptor_set: &vk::DescriptorSet,
         info: &DescriptorSetInfo,
     ) -> VkResult<()> {
-        let mut buffer_descriptors = vec![];
-        let mut image_descriptors = vec![];
-        info.descriptors.iter().for_each(|i| match &i.element_type {
-            super::DescriptorType::UniformBuffer(buf) => buffer_descriptors.push((
-                i.binding,
-                DescriptorBufferInfo {
-                    buffer: buf.handle.inner,
-                    offset: buf.offset,
-                    range: buf.size,
-                },
-                vk::DescriptorType::UNIFORM_BUFFER,
-            )),
-            super::DescriptorType::StorageBuffer(buf) => buffer_descriptors.push((
-                i.binding,
-                DescriptorBufferInfo {
-                    buffer: buf.handle.inner,
-                    offset: buf.offset,
-                    range: buf.size,
-                },
-                vk::DescriptorType::STORAGE_BUFFER,
-            )),
-            super::DescriptorType::Sampler(sam) => image_descriptors.push((
-                i.binding,
-                DescriptorImageInfo {
-                    sampler: sam.sampler.inner,
-                    image_view: sam.image_view.inner,
-                    image_layout: sam.image_layout,
-                },
-                vk::DescriptorType::SAMPLER,
-            )),
-            super::DescriptorType::CombinedImageSampler(sam) => image_descriptors.push((
-                i.binding,
-                DescriptorImageInfo {
-                    sampler: sam.sampler.inner,
-                    image_view: sam.image_view.inner,
-                    image_layout: sam.image_layout,
-                },
-                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-            )),
-        });
-
-        let mut write_descriptor_sets = vec![];
-
-        for (bind, desc, ty) in &buffer_descriptors {
-            write_descriptor_sets.push(WriteDescriptorSet {
-                s_type: StructureType::WRITE_DESCRIPTOR_SET,
-                p_next: null(),
-                dst_set: *descriptor_set,
-                dst_binding: *bind,
-                dst_array_element: 0,
-                descriptor_count: 1,
-                descriptor_type: *ty,
-                p_image_info: std::ptr::null(),
-                p_buffer_info: addr_of!(*desc),
-                p_texel_buffer_view: std::ptr::null(),
-            });
-        }
-        for (bind, desc, ty) in &image_descriptors {
-            write_descriptor_sets.push(WriteDescriptorSet {
-                s_type: StructureType::WRITE_DESCRIPTOR_SET,
-                p_next: null(),
-                dst_set: *descriptor_set,
-                dst_binding: *bind,
-                dst_array_element: 0,
-                descriptor_count: 1,
-                descriptor_type: *ty,
-                p_image_info: addr_of!(*desc),
-                p_buffer_info: std::ptr::null(),
-                p_texel_buffer_view: std::ptr::null(),
-            });
-        }
-        unsafe {
-            self.vk_logical_device()
-                .update_descriptor_sets(&write_descriptor_sets, &[]);
-        }
+        with_descriptor_writes(
+            *descriptor_set,
+            info.descriptors,
+            |write_descriptor_sets| unsafe {
+                self.vk_logical_device()
+                    .update_descriptor_sets(write_descriptor_sets, &[]);
+            },
+        );
         Ok(())
     }
 
     pub fn wait_device_idle(&self) -> VkResult<()> {
-        unsafe { self.vk_logical_device().device_wait_idle() }
+        unsafe { self.vk_logical_device().device_wait_idle() }?;
+        #[cfg(debug_assertions)]
+        self.frame_clock.device_idle();
+        Ok(())
     }
     pub fn wait_queue_idle(&self, queue_type: QueueType) -> VkResult<()> {
         unsafe {
@@ -861,6 +991,58 @@ impl Gpu {
         self.state.physical_device.device_properties
     }
 
+    // Clamps the line width to the range supported by the device:
+    // when wideLines is not supported, the only valid width is 1.0
+    pub fn clamp_line_width(&self, width: f32) -> f32 {
+        if !self.state.features.supports_wide_lines {
+            return 1.0;
+        }
+        let [min, max] = self.physical_device_properties().limits.line_width_range;
+        width.clamp(min, max)
+    }
+
+    /* Whether color and depth targets with this sample count can be rendered to:
+     * a single sample is always supported */
+    pub fn supports_sample_count(&self, samples: SampleCount) -> bool {
+        let limits = self.physical_device_properties().limits;
+        (limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts)
+            .contains(samples.to_vk())
+    }
+
+    // The sample counts supported by the device, from the lowest to the highest
+    pub fn supported_sample_counts(&self) -> Vec<SampleCount> {
+        SampleCount::ALL
+            .into_iter()
+            .filter(|samples| self.supports_sample_count(*samples))
+            .collect()
+    }
+
+    /* Whether both aspects of a multisampled depth-stencil attachment can be resolved with mode:
+     * the depth and the stencil are always resolved with the same mode, which doesn't need
+     * independentResolve, see StencilAttachment::resolve */
+    pub fn supports_depth_stencil_resolve(&self, mode: DepthResolveMode) -> bool {
+        let features = &self.state.features;
+        features.depth_resolve_modes.contains(mode.to_vk())
+            && features.stencil_resolve_modes.contains(mode.to_vk())
+    }
+
+    // Whether pipelines can enable conservative rasterization
+    pub fn supports_conservative_rasterization(&self) -> bool {
+        self.state.features.supports_conservative_rasterization
+    }
+
+    // Whether pipelines can have push descriptor sets, see RenderPassCommand::push_descriptor_set
+    pub fn supports_push_descriptors(&self) -> bool {
+        self.state.features.supports_push_descriptors
+    }
+
+    /* Whether the device has host visible device local memory (e.g. with resizable BAR):
+     * when it doesn't, or when that memory is exhausted, MemoryDomain::DeviceLocalHostVisible
+     * buffers fall back to host memory */
+    pub fn supports_device_local_host_visible(&self) -> bool {
+        self.state.features.supports_device_local_host_visible
+    }
+
     pub fn create_shader_module(
         &self,
         create_info: &ShaderModuleCreateInfo,
@@ -882,7 +1064,8 @@ impl Gpu {
             p_code,
         };
 
-        let shader = GpuShaderModule::create(self.vk_logical_device(), &create_info)?;
+        let shader =
+            GpuShaderModule::create(self.vk_logical_device(), &create_info, code.to_vec())?;
 
         Ok(shader)
     }
@@ -928,6 +1111,11 @@ impl Gpu {
         &self.swapchain
     }
 
+    // The per-frame resources of the renderers should be allocated once for each frame in flight
+    pub fn frames_in_flight(&self) -> usize {
+        self.thread_local_states.len()
+    }
+
     pub fn swapchain_mut(&mut self) -> &mut Swapchain {
         &mut self.swapchain
     }
@@ -937,6 +1125,128 @@ impl Gpu {
     }
 }
 
+/* Builds the writes for the descriptors, then passes them to f: the writes point to data
+ * that lives only during the call. dst_set is ignored when the writes are pushed */
+pub(crate) fn with_descriptor_writes<R>(
+    descriptor_set: vk::DescriptorSet,
+    descriptors: &[DescriptorInfo],
+    f: impl FnOnce(&[WriteDescriptorSet]) -> R,
+) -> R {
+    let mut buffer_descriptors = vec![];
+    let mut image_descriptors = vec![];
+    let mut image_array_descriptors = vec![];
+    descriptors.iter().for_each(|i| match &i.element_type {
+        super::DescriptorType::UniformBuffer(buf) => buffer_descriptors.push((
+            i.binding,
+            DescriptorBufferInfo {
+                buffer: buf.handle.inner,
+                offset: buf.offset,
+                range: buf.size,
+            },
+            vk::DescriptorType::UNIFORM_BUFFER,
+        )),
+        super::DescriptorType::UniformBufferDynamic(buf) => buffer_descriptors.push((
+            i.binding,
+            DescriptorBufferInfo {
+                buffer: buf.handle.inner,
+                offset: buf.offset,
+                range: buf.size,
+            },
+            vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+        )),
+        super::DescriptorType::StorageBuffer(buf) => buffer_descriptors.push((
+            i.binding,
+            DescriptorBufferInfo {
+                buffer: buf.handle.inner,
+                offset: buf.offset,
+                range: buf.size,
+            },
+            vk::DescriptorType::STORAGE_BUFFER,
+        )),
+        super::DescriptorType::Sampler(sam) => image_descriptors.push((
+            i.binding,
+            DescriptorImageInfo {
+                sampler: sam.sampler.inner,
+                image_view: sam.image_view.inner,
+                image_layout: sam.image_layout,
+            },
+            vk::DescriptorType::SAMPLER,
+        )),
+        // Immutable samplers are part of the layout, there's nothing to write
+        super::DescriptorType::ImmutableSampler(_) => {}
+        super::DescriptorType::CombinedImageSampler(sam)
+        | super::DescriptorType::DepthComparisonSampler(sam)
+        | super::DescriptorType::CombinedImageImmutableSampler(sam) => image_descriptors.push((
+            i.binding,
+            DescriptorImageInfo {
+                sampler: sam.sampler.inner,
+                image_view: sam.image_view.inner,
+                image_layout: sam.image_layout,
+            },
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        )),
+        super::DescriptorType::CombinedImageSamplerArray(elements) => {
+            image_array_descriptors.push((
+                i.binding,
+                elements
+                    .iter()
+                    .map(|sam| DescriptorImageInfo {
+                        sampler: sam.sampler.inner,
+                        image_view: sam.image_view.inner,
+                        image_layout: sam.image_layout,
+                    })
+                    .collect::<Vec<_>>(),
+            ))
+        }
+    });
+
+    let mut write_descriptor_sets = vec![];
+
+    for (bind, desc, ty) in &buffer_descriptors {
+        write_descriptor_sets.push(WriteDescriptorSet {
+            s_type: StructureType::WRITE_DESCRIPTOR_SET,
+            p_next: null(),
+            dst_set: descriptor_set,
+            dst_binding: *bind,
+            dst_array_element: 0,
+            descriptor_count: 1,
+            descriptor_type: *ty,
+            p_image_info: std::ptr::null(),
+            p_buffer_info: addr_of!(*desc),
+            p_texel_buffer_view: std::ptr::null(),
+        });
+    }
+    for (bind, desc, ty) in &image_descriptors {
+        write_descriptor_sets.push(WriteDescriptorSet {
+            s_type: StructureType::WRITE_DESCRIPTOR_SET,
+            p_next: null(),
+            dst_set: descriptor_set,
+            dst_binding: *bind,
+            dst_array_element: 0,
+            descriptor_count: 1,
+            descriptor_type: *ty,
+            p_image_info: addr_of!(*desc),
+            p_buffer_info: std::ptr::null(),
+            p_texel_buffer_view: std::ptr::null(),
+        });
+    }
+    for (bind, descs) in &image_array_descriptors {
+        write_descriptor_sets.push(WriteDescriptorSet {
+            s_type: StructureType::WRITE_DESCRIPTOR_SET,
+            p_next: null(),
+            dst_set: descriptor_set,
+            dst_binding: *bind,
+            dst_array_element: 0,
+            descriptor_count: descs.len() as _,
+            descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            p_image_info: descs.as_ptr(),
+            p_buffer_info: std::ptr::null(),
+            p_texel_buffer_view: std::ptr::null(),
+        });
+    }
+    f(&write_descriptor_sets)
+}
+
 fn find_supported_features(
     instance: &Instance,
     physical_device: SelectedPhysicalDevice,
@@ -962,20 +1272,75 @@ fn find_supported_features(
         supported_features.supports_rgb_images = true;
         trace!("Selected physical device supports RGB Images");
     }
+
+    if physical_device.device_features.wide_lines == vk::TRUE {
+        supported_features.supports_wide_lines = true;
+        trace!("Selected physical device supports wide lines");
+    }
+
+    let extensions =
+        unsafe { instance.enumerate_device_extension_properties(physical_device.physical_device) }
+            .unwrap_or_default();
+    let has_extension = |name: &str| {
+        extensions
+            .iter()
+            .any(|ext| unsafe { CStr::from_ptr(ext.extension_name.as_ptr()) }.to_str() == Ok(name))
+    };
+    if has_extension(CONSERVATIVE_RASTERIZATION_EXTENSION) {
+        supported_features.supports_conservative_rasterization = true;
+        trace!("Selected physical device supports conservative rasterization");
+    }
+
+    if has_extension(PUSH_DESCRIPTOR_EXTENSION) {
+        supported_features.supports_push_descriptors = true;
+        trace!("Selected physical device supports push descriptors");
+    }
+
+    let memory_properties =
+        unsafe { instance.get_physical_device_memory_properties(physical_device.physical_device) };
+    let flags: vk::MemoryPropertyFlags = MemoryDomain::DeviceLocalHostVisible.into();
+    if memory_properties.memory_types[..memory_properties.memory_type_count as usize]
+        .iter()
+        .any(|memory_type| memory_type.property_flags.contains(flags))
+    {
+        supported_features.supports_device_local_host_visible = true;
+        trace!("Selected physical device has host visible device local memory");
+    }
+
+    let mut resolve_properties = vk::PhysicalDeviceDepthStencilResolveProperties::default();
+    let mut properties =
+        vk::PhysicalDeviceProperties2::builder().push_next(&mut resolve_properties);
+    unsafe {
+        instance.get_physical_device_properties2(physical_device.physical_device, &mut properties)
+    };
+    supported_features.depth_resolve_modes = resolve_properties.supported_depth_resolve_modes;
+    supported_features.stencil_resolve_modes = resolve_properties.supported_stencil_resolve_modes;
     supported_features
 }
 
-fn create_staging_buffer(state: &Arc<GpuState>) -> VkResult<GpuBuffer> {
-    let mb_64 = 1024 * 1024 * 64;
+// Concurrent sharing requires unique families, a single family is shared exclusively
+fn concurrent_sharing_mode(mut indices: Vec<u32>) -> (SharingMode, Vec<u32>) {
+    indices.sort();
+    indices.dedup();
+    if indices.len() > 1 {
+        (SharingMode::CONCURRENT, indices)
+    } else {
+        (SharingMode::EXCLUSIVE, vec![])
+    }
+}
+
+fn create_staging_buffer(state: &Arc<GpuState>, size: u64) -> VkResult<GpuBuffer> {
+    let (sharing_mode, queue_family_indices) =
+        concurrent_sharing_mode(state.queue_families.indices.clone());
     let create_info: vk::BufferCreateInfo = vk::BufferCreateInfo {
         s_type: StructureType::BUFFER_CREATE_INFO,
         p_next: std::ptr::null(),
         flags: BufferCreateFlags::empty(),
-        size: mb_64 as u64,
+        size,
         usage: BufferUsageFlags::TRANSFER_SRC,
-        sharing_mode: SharingMode::CONCURRENT,
-        queue_family_index_count: state.queue_families.indices.len() as _,
-        p_queue_family_indices: state.queue_families.indices.as_ptr(),
+        sharing_mode,
+        queue_family_index_count: queue_family_indices.len() as _,
+        p_queue_family_indices: queue_family_indices.as_ptr(),
     };
 
     let buffer = unsafe { state.logical_device.create_buffer(&create_info, None) }?;
@@ -992,28 +1357,48 @@ fn create_staging_buffer(state: &Arc<GpuState>) -> VkResult<GpuBuffer> {
     unsafe {
         state
             .logical_device
-            .bind_buffer_memory(buffer, allocation.device_memory, 0)
+            .bind_buffer_memory(buffer, allocation.device_memory, allocation.offset)
     }?;
 
     let buffer = GpuBuffer::create(
         state.logical_device.clone(),
         buffer,
         MemoryDomain::HostVisible,
+        size,
         allocation,
         state.gpu_memory_allocator.clone(),
     )?;
     Ok(buffer)
 }
 
+/*
+How a buffer/image is shared between the queue families:
+Exclusive resources are owned by the graphics queue family and need an ownership
+transfer (see BufferMemoryBarrier/ImageMemoryBarrier) to be used on other queues,
+Concurrent resources can be used by all the listed queues without any transfer
+ */
+#[derive(Clone, Copy, Debug, Default)]
+pub enum QueueSharingMode<'a> {
+    Exclusive,
+    Concurrent(&'a [QueueType]),
+    #[default]
+    ConcurrentAllQueues,
+}
+
 pub struct ImageCreateInfo<'a> {
     pub label: Option<&'a str>,
     pub width: u32,
     pub height: u32,
     pub format: vk::Format,
     pub usage: vk::ImageUsageFlags,
+    pub mip_levels: u32,
+    pub sharing_mode: QueueSharingMode<'a>,
+    // Multisampled images are render targets, resolved into a single sampled image to be read
+    pub samples: SampleCount,
 }
 
 pub struct ImageViewCreateInfo<'a> {
+    pub label: Option<&'a str>,
     pub image: &'a GpuImage,
     pub view_type: ImageViewType,
     pub format: vk::Format,
@@ -1024,6 +1409,7 @@ pub struct BufferCreateInfo<'a> {
     pub label: Option<&'a str>,
     pub size: usize,
     pub usage: BufferUsageFlags,
+    pub sharing_mode: QueueSharingMode<'a>,
 }
 
 #[derive(Clone, Copy)]
@@ -1047,13 +1433,181 @@ pub struct ShaderModuleCreateInfo<'a> {
 }
 
 impl Gpu {
+    fn resolve_sharing_mode(&self, sharing_mode: &QueueSharingMode) -> (SharingMode, Vec<u32>) {
+        match sharing_mode {
+            QueueSharingMode::Exclusive => (SharingMode::EXCLUSIVE, vec![]),
+            QueueSharingMode::Concurrent(queues) => concurrent_sharing_mode(
+                queues
+                    .iter()
+                    .map(|q| self.state.queue_families.family(*q).index)
+                    .collect(),
+            ),
+            QueueSharingMode::ConcurrentAllQueues => {
+                concurrent_sharing_mode(self.state.queue_families.indices.clone())
+            }
+        }
+    }
+
+    /* The alignment required by the offsets of the buffer ranges bound to descriptors
+     * (e.g uniform buffers bound with a dynamic offset), for buffers with this usage */
+    pub fn buffer_offset_alignment(&self, usage: BufferUsageFlags) -> u64 {
+        let limits = self.physical_device_properties().limits;
+        let mut alignment = 1;
+        if usage.contains(BufferUsageFlags::UNIFORM_BUFFER) {
+            alignment = alignment.max(limits.min_uniform_buffer_offset_alignment);
+        }
+        if usage.contains(BufferUsageFlags::STORAGE_BUFFER) {
+            alignment = alignment.max(limits.min_storage_buffer_offset_alignment);
+        }
+        if usage.intersects(
+            BufferUsageFlags::UNIFORM_TEXEL_BUFFER | BufferUsageFlags::STORAGE_TEXEL_BUFFER,
+        ) {
+            alignment = alignment.max(limits.min_texel_buffer_offset_alignment);
+        }
+        alignment
+    }
+
+    // An arena to stage the uploads recorded in user command buffers, see StagingArena
+    pub fn create_staging_arena(&self, size: u64) -> VkResult<StagingArena> {
+        Ok(StagingArena::new(
+            self.vk_logical_device(),
+            create_staging_buffer(&self.state, size)?,
+        ))
+    }
+
     pub fn create_buffer(
         &self,
         create_info: &BufferCreateInfo,
         memory_domain: MemoryDomain,
+    ) -> Result<GpuBuffer> {
+        self.create_buffer_impl(create_info, memory_domain)
+            .map_err(|result| {
+                GpuError::BufferCreationFailed {
+                    label: create_info.label.map(|l| l.to_owned()),
+                    size: create_info.size,
+                    memory_domain,
+                    result,
+                }
+                .into()
+            })
+    }
+
+    /* Creates a buffer holding count elements of T: an empty buffer still takes
+     * the space of one element, since Vulkan doesn't allow empty buffers */
+    pub fn create_buffer_typed<T: Copy>(
+        &self,
+        label: Option<&str>,
+        count: usize,
+        usage: BufferUsageFlags,
+        memory_domain: MemoryDomain,
+    ) -> Result<TypedBuffer<T>> {
+        let buffer = self.create_buffer(
+            &BufferCreateInfo {
+                label,
+                size: std::mem::size_of::<T>() * count.max(1),
+                usage,
+                sharing_mode: Default::default(),
+            },
+            memory_domain,
+        )?;
+        Ok(TypedBuffer::new(buffer, count))
+    }
+
+    /* Creates a buffer holding as many elements as data, initialized with it:
+     * the DeviceLocal buffers are uploaded like in create_buffer_with_data */
+    pub fn create_buffer_typed_with_data<T: Copy>(
+        &self,
+        label: Option<&str>,
+        data: &[T],
+        usage: BufferUsageFlags,
+        memory_domain: MemoryDomain,
+    ) -> Result<TypedBuffer<T>> {
+        let buffer = self.create_buffer_with_data(
+            &BufferCreateInfo {
+                label,
+                size: std::mem::size_of::<T>() * data.len().max(1),
+                usage,
+                sharing_mode: Default::default(),
+            },
+            memory_domain,
+            data,
+        )?;
+        Ok(TypedBuffer::new(buffer, data.len()))
+    }
+
+    /*
+     * Creates a buffer filled with data: unlike write_buffer_data, the DeviceLocal buffers are
+     * always uploaded through a staging buffer of their own, without going through the staging
+     * arena. Blocks until the copy has completed
+     */
+    pub fn create_buffer_with_data<T: Copy>(
+        &self,
+        create_info: &BufferCreateInfo,
+        memory_domain: MemoryDomain,
+        data: &[T],
+    ) -> Result<GpuBuffer> {
+        let data_size = std::mem::size_of_val(data);
+        assert!(
+            data_size <= create_info.size,
+            "Tried to create a buffer of {} bytes with {} bytes of data",
+            create_info.size,
+            data_size
+        );
+        // The DeviceLocal buffers are copied to from the staging buffer
+        let usage = if memory_domain.contains(MemoryDomain::HostVisible) {
+            create_info.usage
+        } else {
+            create_info.usage | BufferUsageFlags::TRANSFER_DST
+        };
+        let buffer = self.create_buffer(
+            &BufferCreateInfo {
+                usage,
+                ..*create_info
+            },
+            memory_domain,
+        )?;
+        if data.is_empty() {
+            return Ok(buffer);
+        }
+
+        if memory_domain.contains(MemoryDomain::HostVisible) {
+            buffer.write_data(0, data);
+        } else {
+            let staging_buffer = create_staging_buffer(&self.state, data_size as u64)?;
+            staging_buffer.write_data(0, data);
+
+            // Exclusive buffers are owned by the graphics queue family, see QueueSharingMode
+            let queue_type = match create_info.sharing_mode {
+                QueueSharingMode::Exclusive => QueueType::Graphics,
+                QueueSharingMode::Concurrent(queues) if !queues.contains(&QueueType::Transfer) => {
+                    QueueType::Graphics
+                }
+                _ => QueueType::Transfer,
+            };
+            self.run_immediate(queue_type, |command_buffer| {
+                command_buffer.copy_buffer(
+                    &staging_buffer,
+                    &buffer,
+                    &[BufferCopyRegion {
+                        src_offset: 0,
+                        dst_offset: 0,
+                        size: data_size as u64,
+                    }],
+                );
+            })?;
+        }
+        Ok(buffer)
+    }
+
+    fn create_buffer_impl(
+        &self,
+        create_info: &BufferCreateInfo,
+        memory_domain: MemoryDomain,
     ) -> VkResult<GpuBuffer> {
         let size = create_info.size as u64;
         assert_ne!(size, 0, "Can't create a buffer with size 0!");
+        let (sharing_mode, queue_family_indices) =
+            self.resolve_sharing_mode(&create_info.sharing_mode);
 
         let create_info_vk = vk::BufferCreateInfo {
             s_type: StructureType::BUFFER_CREATE_INFO,
@@ -1066,20 +1620,24 @@ impl Gpu {
                 } else {
                     BufferUsageFlags::TRANSFER_DST
                 },
-            sharing_mode: SharingMode::CONCURRENT,
-            queue_family_index_count: self.state.queue_families.indices.len() as _,
-            p_queue_family_indices: self.state.queue_families.indices.as_ptr(),
+            sharing_mode,
+            queue_family_index_count: queue_family_indices.len() as _,
+            p_queue_family_indices: queue_family_indices.as_ptr(),
         };
         let buffer = unsafe {
             self.state
                 .logical_device
                 .create_buffer(&create_info_vk, None)
         }?;
-        let memory_requirements = unsafe {
+        let mut memory_requirements = unsafe {
             self.state
                 .logical_device
                 .get_buffer_memory_requirements(buffer)
         };
+        // Start the buffer on an offset any of its ranges could be bound at
+        memory_requirements.alignment = memory_requirements
+            .alignment
+            .max(self.buffer_offset_alignment(create_info.usage));
 
         let allocation_requirements = AllocationRequirements {
             memory_requirements,
@@ -1092,20 +1650,40 @@ impl Gpu {
             .borrow_mut()
             .allocate(allocation_requirements)?;
         unsafe {
-            self.state
-                .logical_device
-                .bind_buffer_memory(buffer, allocation.device_memory, 0)
+            self.state.logical_device.bind_buffer_memory(
+                buffer,
+                allocation.device_memory,
+                allocation.offset,
+            )
         }?;
 
         self.set_object_debug_name(create_info.label, buffer)?;
+        #[cfg(feature = "leak-detection")]
+        let live_token =
+            self.live_resources
+                .register(ResourceKind::Buffer, buffer.as_raw(), create_info.label);
 
-        GpuBuffer::create(
+        let buffer = GpuBuffer::create(
             self.vk_logical_device(),
             buffer,
             memory_domain,
+            size,
             allocation,
             self.state.gpu_memory_allocator.clone(),
-        )
+        )?;
+        #[cfg(feature = "leak-detection")]
+        let buffer = buffer.with_live_token(live_token);
+        Ok(buffer)
+    }
+
+    /* Names the object in the debug tools (e.g RenderDoc, the validation messages),
+     * does nothing when the debug utilities are not enabled */
+    pub fn set_object_name<T: ToVk<Inner = H>, H: Handle>(
+        &self,
+        object: &T,
+        name: &str,
+    ) -> VkResult<()> {
+        self.set_object_debug_name(Some(name), object.to_vk())
     }
 
     fn set_object_debug_name<T: Handle>(
@@ -1135,6 +1713,27 @@ impl Gpu {
         self.write_buffer_data_with_offset(buffer, 0, data)
     }
 
+    // Writes data starting from the buffer's first_element-th element
+    pub fn write_typed_buffer_data<T: Copy>(
+        &self,
+        buffer: &TypedBuffer<T>,
+        first_element: usize,
+        data: &[T],
+    ) -> VkResult<()> {
+        assert!(
+            first_element + data.len() <= buffer.len(),
+            "Tried to write elements {}..{} of a buffer holding {} elements",
+            first_element,
+            first_element + data.len(),
+            buffer.len()
+        );
+        self.write_buffer_data_with_offset(
+            buffer,
+            (first_element * std::mem::size_of::<T>()) as u64,
+            data,
+        )
+    }
+
     pub fn write_buffer_data_with_offset<T: Copy>(
         &self,
         buffer: &GpuBuffer,
@@ -1145,59 +1744,294 @@ impl Gpu {
             return Ok(());
         }
 
+        let data_size = std::mem::size_of_val(data) as u64;
         if buffer.memory_domain.contains(MemoryDomain::HostVisible) {
             buffer.write_data(offset, data);
+        } else if data_size > self.staging_arena.borrow().capacity() {
+            // Too large for the staging arena, see create_buffer_with_data
+            let staging_buffer = create_staging_buffer(&self.state, data_size)?;
+            staging_buffer.write_data(0, data);
+            self.run_immediate(QueueType::Graphics, |command_buffer| {
+                command_buffer.copy_buffer(
+                    &staging_buffer,
+                    buffer,
+                    &[BufferCopyRegion {
+                        src_offset: 0,
+                        dst_offset: offset,
+                        size: data_size,
+                    }],
+                );
+            })?;
         } else {
-            self.staging_buffer.write_data(0, data);
-            self.copy_buffer(
-                &self.staging_buffer,
-                buffer,
-                offset,
-                std::mem::size_of_val(data),
-            )?;
+            let range = self.staging_arena.borrow_mut().write(data, 16)?;
+            self.run_immediate(QueueType::Graphics, |command_buffer| {
+                command_buffer.copy_buffer(
+                    self.staging_arena.borrow().buffer(),
+                    buffer,
+                    &[BufferCopyRegion {
+                        src_offset: range.offset,
+                        dst_offset: offset,
+                        size: range.size,
+                    }],
+                );
+            })?;
         }
         Ok(())
     }
 
+    /* Writes each buffer's data starting from the buffer's start: the data of the DeviceLocal
+     * buffers is staged together, like the mips in write_image_mips, and copied with a single
+     * submission. Blocks until the copies have completed */
+    pub fn write_buffers_data(&self, writes: &[(&GpuBuffer, &[u8])]) -> VkResult<()> {
+        let (host_visible, device_local): (Vec<_>, Vec<_>) = writes
+            .iter()
+            .filter(|(_, data)| !data.is_empty())
+            .partition(|(buffer, _)| buffer.memory_domain.contains(MemoryDomain::HostVisible));
+        for (buffer, data) in host_visible {
+            buffer.write_data(0, data);
+        }
+        if device_local.is_empty() {
+            return Ok(());
+        }
+
+        let staged_size: u64 = device_local
+            .iter()
+            .map(|(_, data)| (data.len() as u64).next_multiple_of(16))
+            .sum();
+        // Like the mip chains, the data too large for the staging arena gets a buffer of its own
+        let dedicated_staging_buffer = if staged_size > self.staging_arena.borrow().capacity() {
+            Some(create_staging_buffer(&self.state, staged_size)?)
+        } else {
+            None
+        };
+        let mut dedicated_offset = 0;
+        let mut copies = vec![];
+        for (buffer, data) in device_local {
+            let src_offset = match &dedicated_staging_buffer {
+                Some(staging_buffer) => {
+                    staging_buffer.write_data(dedicated_offset, data);
+                    let data_offset = dedicated_offset;
+                    dedicated_offset += (data.len() as u64).next_multiple_of(16);
+                    data_offset
+                }
+                None => self.staging_arena.borrow_mut().write(data, 16)?.offset,
+            };
+            let region = BufferCopyRegion {
+                src_offset,
+                dst_offset: 0,
+                size: data.len() as u64,
+            };
+            copies.push((buffer, region));
+        }
+
+        self.run_immediate(QueueType::Graphics, |command_buffer| {
+            let staging_arena = self.staging_arena.borrow();
+            let staging_buffer = dedicated_staging_buffer
+                .as_ref()
+                .unwrap_or_else(|| staging_arena.buffer());
+            for (buffer, region) in &copies {
+                command_buffer.copy_buffer(staging_buffer, buffer, std::slice::from_ref(region));
+            }
+        })
+    }
+
     pub fn write_image_data(&self, image: &GpuImage, data: &[u8]) -> VkResult<()> {
-        self.staging_buffer.write_data(0, data);
+        self.write_image_mips(image, &[data])
+    }
 
-        self.transition_image_layout(
-            image,
-            TransitionInfo {
-                layout: ImageLayout::UNDEFINED,
-                access_mask: AccessFlags::empty(),
-                stage_mask: PipelineStageFlags::TOP_OF_PIPE,
-            },
-            TransitionInfo {
-                layout: ImageLayout::TRANSFER_DST_OPTIMAL,
-                access_mask: AccessFlags::TRANSFER_WRITE,
-                stage_mask: PipelineStageFlags::TRANSFER,
-            },
-            ImageAspectFlags::COLOR,
-        )?;
+    // Writes the image's mip chain starting from mip 0: mips[i] contains the tightly packed texels of mip i,
+    // the mips that aren't written are left undefined
+    pub fn write_image_mips(&self, image: &GpuImage, mips: &[&[u8]]) -> VkResult<()> {
+        assert!(
+            mips.len() as u32 <= image.mip_levels,
+            "Tried to write {} mips on an image with {} mips",
+            mips.len(),
+            image.mip_levels
+        );
+        // Buffer offsets must be a multiple of the texel size
+        let staged_size: u64 = mips
+            .iter()
+            .map(|data| (data.len() as u64).next_multiple_of(16))
+            .sum();
+        // Mip chains too large for the staging arena get a staging buffer of their own
+        let dedicated_staging_buffer = if staged_size > self.staging_arena.borrow().capacity() {
+            Some(create_staging_buffer(&self.state, staged_size)?)
+        } else {
+            None
+        };
+        let mut dedicated_offset = 0;
+        let mut regions = vec![];
+        for (mip_level, data) in mips.iter().enumerate() {
+            let buffer_offset = match &dedicated_staging_buffer {
+                Some(staging_buffer) => {
+                    staging_buffer.write_data(dedicated_offset, data);
+                    let mip_offset = dedicated_offset;
+                    dedicated_offset += (data.len() as u64).next_multiple_of(16);
+                    mip_offset
+                }
+                None => self.staging_arena.borrow_mut().write(data, 16)?.offset,
+            };
+            let extents = image.mip_extents(mip_level as u32);
+            regions.push(vk::BufferImageCopy {
+                buffer_offset,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: ImageSubresourceLayers {
+                    aspect_mask: ImageAspectFlags::COLOR,
+                    mip_level: mip_level as u32,
+                    layer_count: 1,
+                    base_array_layer: 0,
+                },
+                image_offset: Offset3D { x: 0, y: 0, z: 0 },
+                image_extent: Extent3D {
+                    width: extents.width,
+                    height: extents.height,
+                    depth: 1,
+                },
+            });
+        }
 
-        self.copy_buffer_to_image(
-            &self.staging_buffer,
-            image,
-            image.extents.width,
-            image.extents.height,
-        )?;
-        self.transition_image_layout(
-            image,
-            TransitionInfo {
-                layout: ImageLayout::TRANSFER_DST_OPTIMAL,
-                access_mask: AccessFlags::TRANSFER_WRITE,
-                stage_mask: PipelineStageFlags::TRANSFER,
-            },
-            TransitionInfo {
-                layout: ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-                access_mask: AccessFlags::SHADER_READ,
-                stage_mask: PipelineStageFlags::FRAGMENT_SHADER | PipelineStageFlags::VERTEX_SHADER,
-            },
-            ImageAspectFlags::COLOR,
-        )?;
-        Ok(())
+        self.run_immediate(QueueType::Graphics, |command_buffer| {
+            let staging_arena = self.staging_arena.borrow();
+            let staging_buffer = dedicated_staging_buffer
+                .as_ref()
+                .unwrap_or_else(|| staging_arena.buffer());
+            command_buffer.transition(image, ImageTransition::UndefinedToTransferDst);
+            command_buffer.copy_buffer_to_image(
+                staging_buffer,
+                image,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                &regions,
+            );
+            command_buffer.transition(image, ImageTransition::TransferDstToShaderRead);
+        })
+    }
+
+    // Whether optimally tiled images with this format support all the features
+    pub fn format_supports(&self, format: ImageFormat, features: FormatFeatureFlags) -> bool {
+        self.optimal_tiling_features(format.to_vk())
+            .contains(features)
+    }
+
+    fn optimal_tiling_features(&self, format: vk::Format) -> FormatFeatureFlags {
+        let properties = unsafe {
+            self.state.instance.get_physical_device_format_properties(
+                self.state.physical_device.physical_device,
+                format,
+            )
+        };
+        properties.optimal_tiling_features
+    }
+
+    // Whether the mips of images with this format can be generated on the gpu, see generate_mipmaps
+    pub fn supports_mipmap_blits(&self, format: vk::Format) -> bool {
+        self.optimal_tiling_features(format).contains(
+            FormatFeatureFlags::BLIT_SRC
+                | FormatFeatureFlags::BLIT_DST
+                | FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR,
+        )
+    }
+
+    /* Fills the mips after the first one by blitting each mip onto the next one:
+     * mip 0 must already be written (e.g by write_image_data), and the whole image
+     * ends in SHADER_READ_ONLY_OPTIMAL. The image must have been created with
+     * TRANSFER_SRC and TRANSFER_DST usage, with a format that supports_mipmap_blits */
+    pub fn generate_mipmaps(&self, image: &GpuImage) -> VkResult<()> {
+        assert!(
+            self.supports_mipmap_blits(image.format.to_vk()),
+            "The format {:?} does not support blits, generate the mips on the cpu instead",
+            image.format
+        );
+        // The access masks of the layouts the mips go through
+        let access_mask = |layout: ImageLayout| match layout {
+            ImageLayout::TRANSFER_SRC_OPTIMAL => AccessFlags::TRANSFER_READ,
+            ImageLayout::TRANSFER_DST_OPTIMAL => AccessFlags::TRANSFER_WRITE,
+            ImageLayout::SHADER_READ_ONLY_OPTIMAL => AccessFlags::SHADER_READ,
+            _ => AccessFlags::empty(),
+        };
+        let mip_barrier =
+            |base_mip_level: u32, level_count: u32, old_layout, new_layout| ImageMemoryBarrier {
+                src_access_mask: access_mask(old_layout),
+                dst_access_mask: access_mask(new_layout),
+                old_layout,
+                new_layout,
+                src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+                dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+                image,
+                subresource_range: ImageSubresourceRange {
+                    aspect_mask: ImageAspectFlags::COLOR,
+                    base_mip_level,
+                    level_count,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+            };
+        let mip_layers = |mip_level: u32| ImageSubresourceLayers {
+            aspect_mask: ImageAspectFlags::COLOR,
+            mip_level,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let mip_corner = |mip_level: u32| {
+            let extents = image.mip_extents(mip_level);
+            Offset3D {
+                x: extents.width as i32,
+                y: extents.height as i32,
+                z: 1,
+            }
+        };
+        let shader_read = ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        let transfer_src = ImageLayout::TRANSFER_SRC_OPTIMAL;
+        let transfer_dst = ImageLayout::TRANSFER_DST_OPTIMAL;
+        let undefined = ImageLayout::UNDEFINED;
+
+        self.run_immediate(QueueType::Graphics, |command_buffer| {
+            command_buffer.pipeline_barrier(&PipelineBarrierInfo {
+                src_stage_mask: PipelineStageFlags::ALL_COMMANDS,
+                dst_stage_mask: PipelineStageFlags::TRANSFER,
+                dependency_flags: DependencyFlags::empty(),
+                image_memory_barriers: &[
+                    mip_barrier(0, 1, shader_read, transfer_src),
+                    mip_barrier(1, vk::REMAINING_MIP_LEVELS, undefined, transfer_dst),
+                ],
+                ..Default::default()
+            });
+            for mip_level in 1..image.mip_levels {
+                command_buffer.blit_image(
+                    image,
+                    ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    image,
+                    ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[ImageBlit {
+                        src_subresource: mip_layers(mip_level - 1),
+                        src_offsets: [Offset3D::default(), mip_corner(mip_level - 1)],
+                        dst_subresource: mip_layers(mip_level),
+                        dst_offsets: [Offset3D::default(), mip_corner(mip_level)],
+                    }],
+                    vk::Filter::LINEAR,
+                );
+                // The mip is the source of the next blit
+                command_buffer.pipeline_barrier(&PipelineBarrierInfo {
+                    src_stage_mask: PipelineStageFlags::TRANSFER,
+                    dst_stage_mask: PipelineStageFlags::TRANSFER,
+                    dependency_flags: DependencyFlags::empty(),
+                    image_memory_barriers: &[mip_barrier(mip_level, 1, transfer_dst, transfer_src)],
+                    ..Default::default()
+                });
+            }
+            command_buffer.pipeline_barrier(&PipelineBarrierInfo {
+                src_stage_mask: PipelineStageFlags::TRANSFER,
+                dst_stage_mask: PipelineStageFlags::ALL_COMMANDS,
+                dependency_flags: DependencyFlags::empty(),
+                image_memory_barriers: &[mip_barrier(
+                    0,
+                    vk::REMAINING_MIP_LEVELS,
+                    transfer_src,
+                    shader_read,
+                )],
+                ..Default::default()
+            });
+        })
     }
 
     pub fn create_image(
@@ -1205,11 +2039,33 @@ impl Gpu {
         create_info: &ImageCreateInfo,
         memory_domain: MemoryDomain,
         data: Option<&[u8]>,
+    ) -> Result<GpuImage> {
+        self.create_image_impl(create_info, memory_domain, data)
+            .map_err(|result| {
+                GpuError::ImageCreationFailed {
+                    label: create_info.label.map(|l| l.to_owned()),
+                    width: create_info.width,
+                    height: create_info.height,
+                    format: create_info.format,
+                    memory_domain,
+                    result,
+                }
+                .into()
+            })
+    }
+
+    fn create_image_impl(
+        &self,
+        create_info: &ImageCreateInfo,
+        memory_domain: MemoryDomain,
+        data: Option<&[u8]>,
     ) -> VkResult<GpuImage> {
         let mut format = create_info.format;
         if format == ImageFormat::Rgb8.to_vk() && !self.state.features.supports_rgb_images {
             format = ImageFormat::Rgba8.to_vk();
         }
+        let (sharing_mode, queue_family_indices) =
+            self.resolve_sharing_mode(&create_info.sharing_mode);
 
         let image = unsafe {
             let create_info = vk::ImageCreateInfo {
@@ -1223,18 +2079,18 @@ impl Gpu {
                     height: create_info.height,
                     depth: 1,
                 },
-                mip_levels: 1,
+                mip_levels: create_info.mip_levels.max(1),
                 array_layers: 1,
-                samples: SampleCountFlags::TYPE_1,
+                samples: create_info.samples.to_vk(),
                 tiling: if memory_domain.contains(MemoryDomain::HostVisible) {
                     ImageTiling::LINEAR
                 } else {
                     ImageTiling::OPTIMAL
                 },
                 usage: create_info.usage,
-                sharing_mode: SharingMode::CONCURRENT,
-                queue_family_index_count: self.state.queue_families.indices.len() as _,
-                p_queue_family_indices: self.state.queue_families.indices.as_ptr(),
+                sharing_mode,
+                queue_family_index_count: queue_family_indices.len() as _,
+                p_queue_family_indices: queue_family_indices.as_ptr(),
                 initial_layout: ImageLayout::UNDEFINED,
             };
             self.state.logical_device.create_image(&create_info, None)?
@@ -1254,23 +2110,33 @@ impl Gpu {
             .borrow_mut()
             .allocate(allocation_requirements)?;
         unsafe {
-            self.state
-                .logical_device
-                .bind_image_memory(image, allocation.device_memory, 0)
+            self.state.logical_device.bind_image_memory(
+                image,
+                allocation.device_memory,
+                allocation.offset,
+            )
         }?;
         self.set_object_debug_name(create_info.label, image)?;
+        #[cfg(feature = "leak-detection")]
+        let live_token =
+            self.live_resources
+                .register(ResourceKind::Image, image.as_raw(), create_info.label);
 
         let image = GpuImage::create(
             self,
             image,
             allocation,
-            self.state.gpu_memory_allocator.clone(),
             Extent2D {
                 width: create_info.width,
                 height: create_info.height,
             },
             format.into(),
-        )?;
+            create_info.mip_levels.max(1),
+            create_info.usage,
+        )?
+        .with_label(create_info.label);
+        #[cfg(feature = "leak-detection")]
+        let image = image.with_live_token(live_token);
 
         if let Some(data) = data {
             if create_info.format == ImageFormat::Rgb8.to_vk()
@@ -1319,18 +2185,116 @@ impl Gpu {
             components: create_info.components,
             subresource_range: create_info.subresource_range,
         };
-        GpuImageView::create(
+        let view = GpuImageView::create(
             self.vk_logical_device(),
             &vk_create_info,
             gpu_view_format,
             image,
-            create_info.image.extents,
-        )
+            create_info
+                .image
+                .mip_extents(create_info.subresource_range.base_mip_level),
+        )?;
+        self.set_object_debug_name(create_info.label, view.inner)?;
+        Ok(view)
+    }
+
+    // Creates a 2D view of the whole image, deriving the format and aspect from the image itself
+    pub fn create_default_view(&self, image: &GpuImage) -> VkResult<GpuImageView> {
+        self.create_full_view(image, image.format.aspect_mask())
+    }
+
+    /* A view of all the mips that can be sampled by descriptors: unlike the default view,
+     * the view of a depth-stencil image only contains the depth aspect */
+    pub fn create_sampled_view(&self, image: &GpuImage) -> VkResult<GpuImageView> {
+        self.create_full_view(image, image.format.sampled_aspect_mask())
+    }
+
+    fn create_full_view(
+        &self,
+        image: &GpuImage,
+        aspect_mask: ImageAspectFlags,
+    ) -> VkResult<GpuImageView> {
+        self.create_image_view(&ImageViewCreateInfo {
+            label: image.view_label("view").as_deref(),
+            image,
+            view_type: ImageViewType::TYPE_2D,
+            format: image.format.to_vk(),
+            components: vk::ComponentMapping::default(),
+            subresource_range: ImageSubresourceRange {
+                aspect_mask,
+                base_mip_level: 0,
+                level_count: image.mip_levels,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+        })
+    }
+
+    // Creates a 2D view of a single mip level of the image: the view's extents are
+    // the ones of the mip, so that it can be used as a render pass attachment
+    pub fn create_mip_view(&self, image: &GpuImage, mip_level: u32) -> VkResult<GpuImageView> {
+        assert!(
+            mip_level < image.mip_levels,
+            "Requested mip {} of an image with {} mips",
+            mip_level,
+            image.mip_levels
+        );
+        self.create_image_view(&ImageViewCreateInfo {
+            label: image
+                .view_label(&format!("mip {mip_level} view"))
+                .as_deref(),
+            image,
+            view_type: ImageViewType::TYPE_2D,
+            format: image.format.to_vk(),
+            components: vk::ComponentMapping::default(),
+            subresource_range: ImageSubresourceRange {
+                aspect_mask: image.format.aspect_mask(),
+                base_mip_level: mip_level,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+        })
     }
     pub fn create_sampler(&self, create_info: &SamplerCreateInfo) -> VkResult<GpuSampler> {
         GpuSampler::create(self.vk_logical_device(), create_info)
     }
 
+    /* A pool of count timestamp queries, written with CommandBuffer::write_timestamp:
+     * the pool must be reset with CommandBuffer::reset_query_pool before being written */
+    pub fn create_query_pool(&self, count: u32) -> VkResult<QueryPool> {
+        let graphics_family = &self.state.queue_families.graphics_family;
+        QueryPool::create(
+            self.vk_logical_device(),
+            &vk::QueryPoolCreateInfo {
+                s_type: StructureType::QUERY_POOL_CREATE_INFO,
+                p_next: std::ptr::null(),
+                flags: vk::QueryPoolCreateFlags::empty(),
+                query_type: vk::QueryType::TIMESTAMP,
+                query_count: count,
+                pipeline_statistics: vk::QueryPipelineStatisticFlags::empty(),
+            },
+            count,
+            graphics_family.timestamp_valid_bits,
+        )
+    }
+
+    // Whether the graphics queue supports timestamp queries
+    pub fn supports_timestamps(&self) -> bool {
+        let graphics_family = &self.state.queue_families.graphics_family;
+        self.physical_device_properties()
+            .limits
+            .timestamp_compute_and_graphics
+            == vk::TRUE
+            && graphics_family.timestamp_valid_bits > 0
+    }
+
+    /* The engine renders with dynamic rendering, the render passes and framebuffers are
+     * for the applications recording traditional VkRenderPasses on their own */
+    pub fn create_render_pass(&self, description: &RenderPassDescription) -> VkResult<RenderPass> {
+        RenderPass::new(self, description)
+    }
+
     pub fn create_framebuffer(
         &self,
         create_info: &FramebufferCreateInfo,
@@ -1446,17 +2410,49 @@ impl Gpu {
         new_layout: TransitionInfo,
         aspect_mask: ImageAspectFlags,
     ) -> VkResult<()> {
-        let mut command_buffer = super::CommandBuffer::new(self, crate::QueueType::Graphics)?;
+        self.run_immediate(QueueType::Graphics, |command_buffer| {
+            self.transition_image_layout_in_command_buffer(
+                image,
+                command_buffer,
+                old_layout,
+                new_layout,
+                aspect_mask,
+            )
+        })
+    }
 
-        self.transition_image_layout_in_command_buffer(
-            image,
-            &mut command_buffer,
-            old_layout,
-            new_layout,
-            aspect_mask,
-        );
-        command_buffer.submit(&crate::CommandBufferSubmitInfo::default())?;
-        self.wait_queue_idle(QueueType::Graphics)
+    // Records the commands issued by f on a one-shot command buffer, then submits it on the
+    // queue and blocks until the commands have been executed
+    pub fn run_immediate<F: FnOnce(&mut super::CommandBuffer)>(
+        &self,
+        queue_type: QueueType,
+        f: F,
+    ) -> VkResult<()> {
+        let mut command_buffer = super::CommandBuffer::new(self, queue_type)?;
+        f(&mut command_buffer);
+        if !command_buffer.has_recorded_anything() {
+            return command_buffer.submit(&crate::CommandBufferSubmitInfo::default());
+        }
+
+        let fence = GPUFence::create(
+            self.vk_logical_device(),
+            &FenceCreateInfo {
+                s_type: StructureType::FENCE_CREATE_INFO,
+                p_next: std::ptr::null(),
+                flags: FenceCreateFlags::empty(),
+            },
+        )?;
+        command_buffer.submit(&crate::CommandBufferSubmitInfo {
+            fence: Some(&fence),
+            ..Default::default()
+        })?;
+        unsafe {
+            self.vk_logical_device()
+                .wait_for_fences(&[fence.inner], true, u64::MAX)
+        }?;
+        // The staged data read by the command buffer can be reused
+        self.staging_arena.borrow_mut().submit(fence);
+        Ok(())
     }
 
     pub fn transition_image_layout_in_command_buffer(
@@ -1478,7 +2474,7 @@ impl Gpu {
             subresource_range: ImageSubresourceRange {
                 aspect_mask,
                 base_mip_level: 0,
-                level_count: 1,
+                level_count: image.mip_levels,
                 base_array_layer: 0,
                 layer_count: 1,
             },
@@ -1499,45 +2495,10 @@ impl Gpu {
         width: u32,
         height: u32,
     ) -> VkResult<()> {
-        unsafe {
-            let command_pool = self.state.logical_device.create_command_pool(
-                &CommandPoolCreateInfo {
-                    s_type: StructureType::COMMAND_POOL_CREATE_INFO,
-                    p_next: std::ptr::null(),
-                    flags: CommandPoolCreateFlags::empty(),
-                    queue_family_index: self.graphics_queue_family_index(),
-                },
-                None,
-            )?;
-
-            let command_buffer =
-                self.state
-                    .logical_device
-                    .allocate_command_buffers(&CommandBufferAllocateInfo {
-                        s_type: StructureType::COMMAND_BUFFER_ALLOCATE_INFO,
-                        p_next: std::ptr::null(),
-                        command_pool,
-                        level: CommandBufferLevel::PRIMARY,
-                        command_buffer_count: 1,
-                    })?[0];
-
-            self.state.logical_device.begin_command_buffer(
-                command_buffer,
-                &CommandBufferBeginInfo {
-                    s_type: StructureType::COMMAND_BUFFER_BEGIN_INFO,
-                    p_next: std::ptr::null(),
-                    flags: CommandBufferUsageFlags::ONE_TIME_SUBMIT,
-                    p_inheritance_info: std::ptr::null(),
-                },
-            )?;
-
-            let src_buffer = source_buffer.inner;
-            let dst_image = dest_image.inner;
-
-            self.state.logical_device.cmd_copy_buffer_to_image(
-                command_buffer,
-                src_buffer,
-                dst_image,
+        self.run_immediate(QueueType::Graphics, |command_buffer| {
+            command_buffer.copy_buffer_to_image(
+                source_buffer,
+                dest_image,
                 ImageLayout::TRANSFER_DST_OPTIMAL,
                 &[vk::BufferImageCopy {
                     buffer_offset: 0,
@@ -1557,37 +2518,7 @@ impl Gpu {
                     },
                 }],
             );
-            self.state
-                .logical_device
-                .end_command_buffer(command_buffer)?;
-            self.state.logical_device.queue_submit(
-                self.state.graphics_queue,
-                &[SubmitInfo {
-                    s_type: StructureType::SUBMIT_INFO,
-                    p_next: std::ptr::null(),
-                    wait_semaphore_count: 0,
-                    p_wait_semaphores: std::ptr::null(),
-                    p_wait_dst_stage_mask: std::ptr::null(),
-                    command_buffer_count: 1,
-                    p_command_buffers: addr_of!(command_buffer),
-                    signal_semaphore_count: 0,
-                    p_signal_semaphores: std::ptr::null(),
-                }],
-                Fence::null(),
-            )?;
-            self.state
-                .logical_device
-                .queue_wait_idle(self.graphics_queue())?;
-
-            self.state
-                .logical_device
-                .free_command_buffers(command_pool, &[command_buffer]);
-            self.state
-                .logical_device
-                .destroy_command_pool(command_pool, None);
-        }
-
-        Ok(())
+        })
     }
 
     pub fn create_descriptor_set(&self, info: &DescriptorSetInfo) -> VkResult<GpuDescriptorSet> {
@@ -1597,10 +2528,22 @@ impl Gpu {
             .borrow_mut()
             .allocate(info)?;
         self.initialize_descriptor_set(&allocated_descriptor_set.descriptor_set, info)?;
-        GpuDescriptorSet::create(
+        let descriptor_set = GpuDescriptorSet::create(
             allocated_descriptor_set,
             self.state.descriptor_set_allocator.clone(),
-        )
+        )?;
+        #[cfg(debug_assertions)]
+        let descriptor_set =
+            descriptor_set.with_use_tracker(UseTracker::new(self.frame_clock.clone()));
+        #[cfg(feature = "leak-detection")]
+        let descriptor_set = {
+            let handle = descriptor_set.inner.as_raw();
+            let live_token =
+                self.live_resources
+                    .register(ResourceKind::DescriptorSet, handle, None);
+            descriptor_set.with_live_token(live_token)
+        };
+        Ok(descriptor_set)
     }
 
     pub fn save_pipeline_cache(&self, path: &str) -> VkResult<()> {
@@ -0,0 +1,99 @@
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResourceKind {
+    Buffer,
+    Image,
+    DescriptorSet,
+    Pipeline,
+}
+
+// A resource created by the Gpu that hasn't been dropped yet, see Gpu::report_leaks
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LiveResource {
+    pub kind: ResourceKind,
+    // The raw Vulkan handle of the resource
+    pub handle: u64,
+    pub label: Option<String>,
+}
+
+#[derive(Default)]
+struct LiveResourcesState {
+    next_id: u64,
+    resources: BTreeMap<u64, LiveResource>,
+}
+
+/*
+The registry of the resources created by the Gpu: each resource holds a LiveToken,
+which removes it from the registry when the resource is dropped
+ */
+#[derive(Clone, Default)]
+pub(crate) struct LiveResources {
+    state: Rc<RefCell<LiveResourcesState>>,
+}
+
+impl LiveResources {
+    pub(crate) fn register(
+        &self,
+        kind: ResourceKind,
+        handle: u64,
+        label: Option<&str>,
+    ) -> LiveToken {
+        let mut state = self.state.borrow_mut();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.resources.insert(
+            id,
+            LiveResource {
+                kind,
+                handle,
+                label: label.map(|label| label.to_owned()),
+            },
+        );
+        LiveToken {
+            resources: self.clone(),
+            id,
+        }
+    }
+
+    // The live resources, in creation order
+    pub(crate) fn live(&self) -> Vec<LiveResource> {
+        self.state.borrow().resources.values().cloned().collect()
+    }
+}
+
+pub(crate) struct LiveToken {
+    resources: LiveResources,
+    id: u64,
+}
+
+impl Drop for LiveToken {
+    fn drop(&mut self) {
+        self.resources.state.borrow_mut().resources.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LiveResources, ResourceKind};
+
+    #[test]
+    fn dropped_resources_are_not_live() {
+        let resources = LiveResources::default();
+        let buffer = resources.register(ResourceKind::Buffer, 1, Some("Vertices"));
+        let image = resources.register(ResourceKind::Image, 2, None);
+        let pipeline = resources.register(ResourceKind::Pipeline, 3, None);
+        assert_eq!(resources.live().len(), 3);
+
+        drop(image);
+        let live = resources.live();
+        assert_eq!(live.len(), 2);
+        assert_eq!(live[0].kind, ResourceKind::Buffer);
+        assert_eq!(live[0].label.as_deref(), Some("Vertices"));
+        assert_eq!(live[1].kind, ResourceKind::Pipeline);
+
+        drop(buffer);
+        drop(pipeline);
+        assert!(resources.live().is_empty());
+    }
+}
@@ -2,21 +2,33 @@ mod allocator;
 mod command_buffer;
 mod descriptor_set;
 mod gpu;
+#[cfg(feature = "leak-detection")]
+mod leak_tracking;
 mod pipeline;
+#[cfg(feature = "renderdoc")]
+mod renderdoc;
+mod staging;
 mod swapchain;
 mod types;
+#[cfg(debug_assertions)]
+mod use_tracking;
 
 pub use crate::gpu::*;
 pub use allocator::*;
 use ash::vk::ImageLayout;
+use bitflags::bitflags;
 pub use command_buffer::*;
+pub use descriptor_set::{DescriptorBindingSignature, DescriptorSetLayoutSignature};
+#[cfg(feature = "leak-detection")]
+pub use leak_tracking::{LiveResource, ResourceKind};
 pub use pipeline::*;
+pub use staging::{StagingArena, StagingRange};
 use std::fmt::{Debug, Formatter};
 use std::hash::Hash;
-pub use swapchain::Swapchain;
+pub use swapchain::{PresentModeSelection, Swapchain};
 pub use types::*;
 
-#[derive(Default)]
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum QueueType {
     #[default]
     Graphics,
@@ -48,6 +60,17 @@ pub struct BufferRange<'a> {
     pub size: u64,
 }
 
+impl<'a> BufferRange<'a> {
+    // A range covering the whole buffer
+    pub fn whole(handle: &'a GpuBuffer) -> Self {
+        Self {
+            handle,
+            offset: 0,
+            size: ash::vk::WHOLE_SIZE,
+        }
+    }
+}
+
 #[derive(Clone, Hash)]
 pub struct SamplerState<'a> {
     pub sampler: &'a GpuSampler,
@@ -55,12 +78,53 @@ pub struct SamplerState<'a> {
     pub image_layout: ImageLayout,
 }
 
+impl<'a> SamplerState<'a> {
+    // Samples image_view in the SHADER_READ_ONLY_OPTIMAL layout
+    pub fn new(sampler: &'a GpuSampler, image_view: &'a GpuImageView) -> Self {
+        Self {
+            sampler,
+            image_view,
+            image_layout: ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum DescriptorType<'a> {
     UniformBuffer(BufferRange<'a>),
+    /* The range is bound at a dynamic offset from its start, passed when binding the set,
+     * see CommandBuffer::bind_descriptor_sets_with_offsets */
+    UniformBufferDynamic(BufferRange<'a>),
     StorageBuffer(BufferRange<'a>),
     Sampler(SamplerState<'a>),
     CombinedImageSampler(SamplerState<'a>),
+    // A depth image sampled through a comparison sampler (e.g sampler2DShadow)
+    DepthComparisonSampler(SamplerState<'a>),
+    // A fixed size array of combined image samplers at a single binding, e.g sampler2D[4]
+    CombinedImageSamplerArray(Vec<SamplerState<'a>>),
+    // A sampler baked into the set's layout, nothing is written to the set
+    ImmutableSampler(&'a GpuSampler),
+    // A combined image sampler whose sampler is baked into the set's layout
+    CombinedImageImmutableSampler(SamplerState<'a>),
+}
+
+impl<'a> DescriptorType<'a> {
+    // How many descriptors the binding holds
+    pub fn count(&self) -> u32 {
+        match self {
+            DescriptorType::CombinedImageSamplerArray(elements) => elements.len() as u32,
+            _ => 1,
+        }
+    }
+
+    // The sampler the set's layout is created with, if any
+    pub fn immutable_sampler(&self) -> Option<ash::vk::Sampler> {
+        match self {
+            DescriptorType::ImmutableSampler(sampler) => Some(sampler.inner),
+            DescriptorType::CombinedImageImmutableSampler(state) => Some(state.sampler.inner),
+            _ => None,
+        }
+    }
 }
 
 impl<'a> std::hash::Hash for DescriptorType<'a> {
@@ -75,13 +139,35 @@ impl<'a> Debug for DescriptorType<'a> {
     }
 }
 
-#[derive(Clone, Copy, Hash, Debug, PartialEq, Ord, PartialOrd, Eq)]
-pub enum ShaderStage {
-    Vertex,
-    Fragment,
-    VertexFragment,
-    Compute,
-    All,
+bitflags! {
+    /* The stages a binding is visible from: declare the bindings only in the stages
+     * that actually access them, e.g Fragment for textures and Vertex for transforms */
+    #[repr(transparent)]
+    #[derive(Clone, Copy, Debug, Ord, PartialOrd, PartialEq, Eq, Hash)]
+    pub struct ShaderStage: u32 {
+        const Vertex =          0b00000001;
+        const Fragment =        0b00000010;
+        const Compute =         0b00000100;
+        const VertexFragment =  Self::Vertex.bits() | Self::Fragment.bits();
+        const All =             Self::VertexFragment.bits() | Self::Compute.bits();
+    }
+}
+
+impl ToVk for ShaderStage {
+    type Inner = ash::vk::ShaderStageFlags;
+    fn to_vk(&self) -> Self::Inner {
+        let mut flags = Self::Inner::empty();
+        if self.contains(ShaderStage::Vertex) {
+            flags |= Self::Inner::VERTEX;
+        }
+        if self.contains(ShaderStage::Fragment) {
+            flags |= Self::Inner::FRAGMENT;
+        }
+        if self.contains(ShaderStage::Compute) {
+            flags |= Self::Inner::COMPUTE;
+        }
+        flags
+    }
 }
 
 #[derive(Clone, Hash, Debug)]
@@ -91,6 +177,88 @@ pub struct DescriptorInfo<'a> {
     pub binding_stage: ShaderStage,
 }
 
+impl<'a> DescriptorInfo<'a> {
+    pub fn combined_image_sampler(
+        binding: u32,
+        sampler: &'a GpuSampler,
+        image_view: &'a GpuImageView,
+        binding_stage: ShaderStage,
+    ) -> Self {
+        Self {
+            binding,
+            element_type: DescriptorType::CombinedImageSampler(SamplerState::new(
+                sampler, image_view,
+            )),
+            binding_stage,
+        }
+    }
+
+    // The elements are bound in order, starting from the first element of the array
+    pub fn combined_image_sampler_array(
+        binding: u32,
+        elements: Vec<SamplerState<'a>>,
+        binding_stage: ShaderStage,
+    ) -> Self {
+        assert!(
+            !elements.is_empty(),
+            "Cannot bind an empty array of samplers at binding {binding}"
+        );
+        Self {
+            binding,
+            element_type: DescriptorType::CombinedImageSamplerArray(elements),
+            binding_stage,
+        }
+    }
+
+    /* The immutable samplers are part of the set's layout: the pipelines using the set
+     * must declare the same sampler with BindingType::ImmutableSampler */
+    pub fn immutable_sampler(
+        binding: u32,
+        sampler: &'a GpuSampler,
+        binding_stage: ShaderStage,
+    ) -> Self {
+        Self {
+            binding,
+            element_type: DescriptorType::ImmutableSampler(sampler),
+            binding_stage,
+        }
+    }
+
+    // The pipelines must declare the same sampler with BindingType::CombinedImageImmutableSampler
+    pub fn combined_image_immutable_sampler(
+        binding: u32,
+        sampler: &'a GpuSampler,
+        image_view: &'a GpuImageView,
+        binding_stage: ShaderStage,
+    ) -> Self {
+        Self {
+            binding,
+            element_type: DescriptorType::CombinedImageImmutableSampler(SamplerState::new(
+                sampler, image_view,
+            )),
+            binding_stage,
+        }
+    }
+
+    // Binds the whole buffer as an uniform buffer
+    pub fn uniform_buffer(binding: u32, buffer: &'a GpuBuffer, binding_stage: ShaderStage) -> Self {
+        Self {
+            binding,
+            element_type: DescriptorType::UniformBuffer(BufferRange::whole(buffer)),
+            binding_stage,
+        }
+    }
+
+    // Binds the whole buffer as a storage buffer
+    pub fn storage_buffer(binding: u32, buffer: &'a GpuBuffer, binding_stage: ShaderStage) -> Self {
+        Self {
+            binding,
+            element_type: DescriptorType::StorageBuffer(BufferRange::whole(buffer)),
+            binding_stage,
+        }
+    }
+}
+
 #[derive(Clone, Hash)]
 pub struct DescriptorSetInfo<'a> {
     pub descriptors: &'a [DescriptorInfo<'a>],
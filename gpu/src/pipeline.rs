@@ -6,8 +6,8 @@ use ash::{
     prelude::VkResult,
     vk::{
         self, AttachmentDescription, AttachmentDescriptionFlags, AttachmentReference,
-        DescriptorSetLayout, DescriptorSetLayoutBinding, DescriptorSetLayoutCreateFlags,
-        DescriptorSetLayoutCreateInfo, DescriptorType, DynamicState, GraphicsPipelineCreateInfo,
+        ComputePipelineCreateInfo, ConservativeRasterizationModeEXT, DescriptorSetLayout,
+        DescriptorSetLayoutBinding, DescriptorType, DynamicState, GraphicsPipelineCreateInfo,
         PipelineBindPoint, PipelineColorBlendAttachmentState, PipelineColorBlendStateCreateFlags,
         PipelineColorBlendStateCreateInfo, PipelineCreateFlags,
         PipelineDepthStencilStateCreateFlags, PipelineDepthStencilStateCreateInfo,
@@ -15,18 +15,21 @@ use ash::{
         PipelineInputAssemblyStateCreateFlags, PipelineInputAssemblyStateCreateInfo,
         PipelineLayout, PipelineLayoutCreateFlags, PipelineLayoutCreateInfo,
         PipelineMultisampleStateCreateFlags, PipelineMultisampleStateCreateInfo,
-        PipelineRasterizationStateCreateFlags, PipelineRasterizationStateCreateInfo,
-        PipelineShaderStageCreateFlags, PipelineShaderStageCreateInfo,
-        PipelineTessellationStateCreateFlags, PipelineTessellationStateCreateInfo,
-        PipelineVertexInputStateCreateFlags, PipelineVertexInputStateCreateInfo,
-        PipelineViewportStateCreateFlags, PipelineViewportStateCreateInfo, PushConstantRange,
-        RenderPassCreateFlags, RenderPassCreateInfo, SampleCountFlags, ShaderStageFlags,
-        StructureType, SubpassDescriptionFlags, VertexInputAttributeDescription,
-        VertexInputBindingDescription,
+        PipelineRasterizationConservativeStateCreateFlagsEXT,
+        PipelineRasterizationConservativeStateCreateInfoEXT, PipelineRasterizationStateCreateFlags,
+        PipelineRasterizationStateCreateInfo, PipelineShaderStageCreateFlags,
+        PipelineShaderStageCreateInfo, PipelineTessellationStateCreateFlags,
+        PipelineTessellationStateCreateInfo, PipelineVertexInputStateCreateFlags,
+        PipelineVertexInputStateCreateInfo, PipelineViewportStateCreateFlags,
+        PipelineViewportStateCreateInfo, PushConstantRange, RenderPassCreateFlags,
+        RenderPassCreateInfo, SampleCountFlags, ShaderStageFlags, StructureType,
+        SubpassDescriptionFlags, VertexInputAttributeDescription, VertexInputBindingDescription,
     },
 };
 
-use crate::{ImageFormat, ToVk};
+#[cfg(feature = "leak-detection")]
+use crate::leak_tracking::{LiveToken, ResourceKind};
+use crate::{DescriptorSetLayoutSignature, ImageFormat, SampleCount, ToVk};
 
 use super::{Gpu, GpuShaderModule, GpuState, ShaderStage};
 
@@ -41,9 +44,26 @@ fn vk_bool(b: bool) -> u32 {
 #[derive(Clone, Copy, Debug)]
 pub enum BindingType {
     Uniform,
+    // See DescriptorType::UniformBufferDynamic
+    UniformDynamic,
     Storage,
     Sampler,
     CombinedImageSampler,
+    // A fixed size array of combined image samplers, e.g sampler2D[count]
+    CombinedImageSamplerArray { count: u32 },
+    // The sampler is baked into the set's layout, see DescriptorInfo::immutable_sampler
+    ImmutableSampler(vk::Sampler),
+    CombinedImageImmutableSampler(vk::Sampler),
+}
+
+impl BindingType {
+    pub fn immutable_sampler(&self) -> Option<vk::Sampler> {
+        match self {
+            BindingType::ImmutableSampler(sampler)
+            | BindingType::CombinedImageImmutableSampler(sampler) => Some(*sampler),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -57,6 +77,8 @@ pub struct BindingElement {
 pub struct GlobalBinding<'a> {
     pub set_index: u32,
     pub elements: &'a [BindingElement],
+    // When true the set's descriptors are pushed with RenderPassCommand::push_descriptor_set
+    pub push_descriptor: bool,
 }
 
 impl From<&BindingElement> for DescriptorSetLayoutBinding {
@@ -65,21 +87,26 @@ impl From<&BindingElement> for DescriptorSetLayoutBinding {
             binding: b.index,
             descriptor_type: match b.binding_type {
                 BindingType::Uniform => DescriptorType::UNIFORM_BUFFER,
+                BindingType::UniformDynamic => DescriptorType::UNIFORM_BUFFER_DYNAMIC,
                 BindingType::Storage => DescriptorType::STORAGE_BUFFER,
-                BindingType::Sampler => DescriptorType::SAMPLER,
-                BindingType::CombinedImageSampler => DescriptorType::COMBINED_IMAGE_SAMPLER,
-            },
-            descriptor_count: 1,
-            stage_flags: match b.stage {
-                ShaderStage::Vertex => ShaderStageFlags::VERTEX,
-                ShaderStage::Fragment => ShaderStageFlags::FRAGMENT,
-                ShaderStage::Compute => ShaderStageFlags::COMPUTE,
-                ShaderStage::VertexFragment => {
-                    ShaderStageFlags::VERTEX | ShaderStageFlags::FRAGMENT
+                BindingType::Sampler | BindingType::ImmutableSampler(_) => DescriptorType::SAMPLER,
+                BindingType::CombinedImageSampler
+                | BindingType::CombinedImageSamplerArray { .. }
+                | BindingType::CombinedImageImmutableSampler(_) => {
+                    DescriptorType::COMBINED_IMAGE_SAMPLER
                 }
-                ShaderStage::All => ShaderStageFlags::ALL_GRAPHICS,
             },
-            p_immutable_samplers: std::ptr::null(),
+            descriptor_count: match b.binding_type {
+                BindingType::CombinedImageSamplerArray { count } => count,
+                _ => 1,
+            },
+            stage_flags: b.stage.to_vk(),
+            // Points into the element, which must outlive the returned binding
+            p_immutable_samplers: match &b.binding_type {
+                BindingType::ImmutableSampler(sampler)
+                | BindingType::CombinedImageImmutableSampler(sampler) => sampler as *const _,
+                _ => std::ptr::null(),
+            },
         }
     }
 }
@@ -90,14 +117,14 @@ pub enum InputRate {
     PerInstance,
 }
 
-#[derive(Clone, Copy, Debug, Hash)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub struct VertexAttributeDescription {
     pub location: u32,
     pub format: vk::Format,
     pub offset: u32,
 }
 
-#[derive(Clone, Copy, Debug, Hash)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub struct VertexBindingDescription<'a> {
     pub binding: u32,
     pub input_rate: InputRate,
@@ -105,12 +132,38 @@ pub struct VertexBindingDescription<'a> {
     pub attributes: &'a [VertexAttributeDescription],
 }
 
+/* A vertex format stored interleaved in a single vertex buffer, e.g for voxels or sprites:
+ * the attributes' offsets are relative to the start of the vertex */
+pub trait Vertex: Copy + 'static {
+    const ATTRIBUTES: &'static [VertexAttributeDescription];
+}
+
+impl VertexBindingDescription<'static> {
+    // The binding of a buffer of V, to be used in GraphicsPipelineDescription::vertex_inputs
+    pub fn of<V: Vertex>(binding: u32, input_rate: InputRate) -> Self {
+        Self {
+            binding,
+            input_rate,
+            stride: std::mem::size_of::<V>() as u32,
+            attributes: V::ATTRIBUTES,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct ComputeStageInfo<'a> {
+    pub entry_point: &'a str,
+    pub module: &'a GpuShaderModule,
+}
+
 #[derive(Clone, Copy)]
 pub struct VertexStageInfo<'a> {
     pub entry_point: &'a str,
     pub module: &'a GpuShaderModule,
 }
 
+// Blend factors referencing the CONSTANT_COLOR/CONSTANT_ALPHA values
+// use the constants set with RenderPassCommand::set_blend_constants
 #[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq, PartialOrd, Ord)]
 pub struct BlendState {
     pub blend_enable: bool,
@@ -146,11 +199,14 @@ pub struct FragmentStageInfo<'a> {
     pub depth_stencil_attachments: &'a [DepthStencilAttachment],
 }
 
-#[derive(Clone, Copy, Debug, Default, Hash)]
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
 pub enum PrimitiveTopology {
     #[default]
     TriangleList,
     TriangleStrip,
+    PointList,
+    LineList,
+    LineStrip,
 }
 
 #[derive(Clone, Copy, Debug, Default)]
@@ -177,6 +233,27 @@ pub enum FrontFace {
     ClockWise,
 }
 
+impl FrontFace {
+    // The front face of the geometry mirrored by a transform with a negative determinant
+    pub fn flipped(self) -> Self {
+        match self {
+            FrontFace::CounterClockWise => FrontFace::ClockWise,
+            FrontFace::ClockWise => FrontFace::CounterClockWise,
+        }
+    }
+}
+
+impl ToVk for FrontFace {
+    type Inner = vk::FrontFace;
+
+    fn to_vk(&self) -> Self::Inner {
+        match self {
+            FrontFace::CounterClockWise => vk::FrontFace::COUNTER_CLOCKWISE,
+            FrontFace::ClockWise => vk::FrontFace::CLOCKWISE,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Default)]
 pub struct DepthStencilState {
     pub depth_test_enable: bool,
@@ -189,6 +266,47 @@ pub struct DepthStencilState {
     pub max_depth_bounds: f32,
 }
 
+// Stencil test/operations, applied to both front and back faces:
+// the reference value is dynamic, see RenderPassCommand::set_stencil_reference
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StencilState {
+    pub fail_op: vk::StencilOp,
+    pub pass_op: vk::StencilOp,
+    pub depth_fail_op: vk::StencilOp,
+    pub compare_op: vk::CompareOp,
+    pub compare_mask: u32,
+    pub write_mask: u32,
+}
+
+impl Default for StencilState {
+    fn default() -> Self {
+        Self {
+            fail_op: vk::StencilOp::KEEP,
+            pass_op: vk::StencilOp::KEEP,
+            depth_fail_op: vk::StencilOp::KEEP,
+            compare_op: vk::CompareOp::ALWAYS,
+            compare_mask: 0xFF,
+            write_mask: 0xFF,
+        }
+    }
+}
+
+impl ToVk for StencilState {
+    type Inner = vk::StencilOpState;
+
+    fn to_vk(&self) -> Self::Inner {
+        vk::StencilOpState {
+            fail_op: self.fail_op,
+            pass_op: self.pass_op,
+            depth_fail_op: self.depth_fail_op,
+            compare_op: self.compare_op,
+            compare_mask: self.compare_mask,
+            write_mask: self.write_mask,
+            reference: 0,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Default, Hash)]
 pub enum LogicOp {
     #[default]
@@ -336,6 +454,11 @@ impl RenderPass {
             state: gpu.state.clone(),
         })
     }
+
+    // E.g to record vkCmdBeginRenderPass with a GpuFramebuffer created from this pass
+    pub fn inner(&self) -> vk::RenderPass {
+        self.inner
+    }
 }
 
 #[derive(Clone, Copy, Default)]
@@ -350,35 +473,72 @@ pub struct PipelineDescription<'a> {
     pub cull_mode: CullMode,
     pub front_face: FrontFace,
     pub depth_stencil_state: DepthStencilState,
+    // The format of the depth attachment the pipeline renders to: when the format
+    // has a stencil component, it's used for the stencil attachment too
+    pub depth_stencil_format: Option<ImageFormat>,
     pub logic_op: Option<LogicOp>,
     pub push_constant_ranges: &'a [PushConstantRange],
+    // Rasterizes every pixel touched by a primitive, needs VK_EXT_conservative_rasterization
+    pub conservative_rasterization: bool,
+    // Must match the sample count of the attachments the pipeline renders to
+    pub samples: SampleCount,
 }
 
-impl<'a> PipelineDescription<'a> {
-    fn create_descriptor_set_layouts(&self, gpu: &Gpu) -> VkResult<Vec<DescriptorSetLayout>> {
-        let mut layouts: Vec<DescriptorSetLayout> = vec![];
-        for element in self.global_bindings.iter() {
-            let bindings: Vec<DescriptorSetLayoutBinding> =
-                element.elements.iter().map(|b| b.into()).collect();
-
-            let create_info = DescriptorSetLayoutCreateInfo {
-                s_type: StructureType::DESCRIPTOR_SET_LAYOUT_CREATE_INFO,
-                p_next: std::ptr::null(),
-                flags: DescriptorSetLayoutCreateFlags::empty(),
-                binding_count: bindings.len() as _,
-                p_bindings: bindings.as_ptr(),
-            };
-            unsafe {
-                let layout = gpu
-                    .state
-                    .logical_device
-                    .create_descriptor_set_layout(&create_info, None)?;
-                layouts.push(layout);
-            }
-        }
-        Ok(layouts)
+// Describes a pipeline made of a single compute shader, see Pipeline::new_compute
+#[derive(Clone, Copy)]
+pub struct ComputePipelineDescription<'a> {
+    pub global_bindings: &'a [GlobalBinding<'a>],
+    pub compute_stage: ComputeStageInfo<'a>,
+    pub push_constant_ranges: &'a [PushConstantRange],
+}
+
+// The layouts are shared with the descriptor sets allocated with the same signature
+fn create_descriptor_set_layouts(
+    gpu: &Gpu,
+    global_bindings: &[GlobalBinding],
+) -> VkResult<Vec<DescriptorSetLayout>> {
+    if global_bindings
+        .iter()
+        .any(|element| element.push_descriptor)
+        && !gpu.supports_push_descriptors()
+    {
+        log::error!("Push descriptors are not supported by the device");
+        return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+    }
+    let mut allocator = gpu.state.descriptor_set_allocator.borrow_mut();
+    global_bindings
+        .iter()
+        .map(|element| {
+            allocator.get_descriptor_set_layout(
+                &DescriptorSetLayoutSignature::from_binding_elements(element.elements)
+                    .with_push_descriptor(element.push_descriptor),
+            )
+        })
+        .collect()
+}
+
+fn create_pipeline_layout(
+    gpu: &Gpu,
+    global_bindings: &[GlobalBinding],
+    push_constant_ranges: &[PushConstantRange],
+) -> VkResult<PipelineLayout> {
+    let descriptor_set_layouts = create_descriptor_set_layouts(gpu, global_bindings)?;
+    let layout_infos = PipelineLayoutCreateInfo {
+        s_type: StructureType::PIPELINE_LAYOUT_CREATE_INFO,
+        p_next: std::ptr::null(),
+        flags: PipelineLayoutCreateFlags::empty(),
+        set_layout_count: descriptor_set_layouts.len() as _,
+        p_set_layouts: descriptor_set_layouts.as_ptr(),
+        push_constant_range_count: push_constant_ranges.len() as _,
+        p_push_constant_ranges: push_constant_ranges.as_ptr(),
+    };
+    unsafe {
+        gpu.vk_logical_device()
+            .create_pipeline_layout(&layout_infos, None)
     }
+}
 
+impl<'a> PipelineDescription<'a> {
     fn get_output_attachments(&self) -> Vec<PipelineColorBlendAttachmentState> {
         let mut pipeline_color_blend_attachment_states = vec![];
 
@@ -435,8 +595,16 @@ impl<'a> PipelineDescription<'a> {
 pub struct Pipeline {
     pub(super) pipeline: vk::Pipeline,
     pub(super) pipeline_layout: PipelineLayout,
+    pub(super) line_width: f32,
+    pub(super) front_face: FrontFace,
+    pub(super) color_formats: Vec<vk::Format>,
+    pub(super) depth_stencil_format: Option<ImageFormat>,
+    pub(super) bind_point: PipelineBindPoint,
 
     shared_state: Arc<GpuState>,
+    // Only held to be dropped with the pipeline, see Gpu::report_leaks
+    #[cfg(feature = "leak-detection")]
+    _live_token: LiveToken,
 }
 
 impl Eq for Pipeline {}
@@ -454,11 +622,32 @@ impl std::hash::Hash for Pipeline {
 }
 
 impl Pipeline {
+    pub fn color_attachment_count(&self) -> usize {
+        self.color_formats.len()
+    }
+
+    pub fn color_formats(&self) -> &[vk::Format] {
+        &self.color_formats
+    }
+
+    pub fn depth_stencil_format(&self) -> Option<ImageFormat> {
+        self.depth_stencil_format
+    }
+
+    pub fn bind_point(&self) -> PipelineBindPoint {
+        self.bind_point
+    }
+
     pub fn new(
         gpu: &Gpu,
         pipeline_description: &PipelineDescription,
     ) -> VkResult<Self> {
-        let descriptor_set_layouts = pipeline_description.create_descriptor_set_layouts(gpu)?;
+        if pipeline_description.conservative_rasterization
+            && !gpu.supports_conservative_rasterization()
+        {
+            log::error!("Conservative rasterization is not supported by the device");
+            return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+        }
         let color_blend_attachments = pipeline_description.get_output_attachments();
         let mut stages = vec![];
 
@@ -501,19 +690,11 @@ impl Pipeline {
         let (input_binding_descriptions, input_attribute_descriptions) =
             pipeline_description.get_input_bindings_and_attributes();
 
-        let pipeline_layout = unsafe {
-            let layout_infos = PipelineLayoutCreateInfo {
-                s_type: StructureType::PIPELINE_LAYOUT_CREATE_INFO,
-                p_next: std::ptr::null(),
-                flags: PipelineLayoutCreateFlags::empty(),
-                set_layout_count: descriptor_set_layouts.len() as _,
-                p_set_layouts: descriptor_set_layouts.as_ptr(),
-                push_constant_range_count: pipeline_description.push_constant_ranges.len() as _,
-                p_push_constant_ranges: pipeline_description.push_constant_ranges.as_ptr(),
-            };
-            gpu.vk_logical_device()
-                .create_pipeline_layout(&layout_infos, None)?
-        };
+        let pipeline_layout = create_pipeline_layout(
+            gpu,
+            pipeline_description.global_bindings,
+            pipeline_description.push_constant_ranges,
+        )?;
 
         let pipeline = unsafe {
             let input_stage = PipelineVertexInputStateCreateInfo {
@@ -533,6 +714,9 @@ impl Pipeline {
                 topology: match pipeline_description.input_topology {
                     PrimitiveTopology::TriangleList => vk::PrimitiveTopology::TRIANGLE_LIST,
                     PrimitiveTopology::TriangleStrip => vk::PrimitiveTopology::TRIANGLE_STRIP,
+                    PrimitiveTopology::PointList => vk::PrimitiveTopology::POINT_LIST,
+                    PrimitiveTopology::LineList => vk::PrimitiveTopology::LINE_LIST,
+                    PrimitiveTopology::LineStrip => vk::PrimitiveTopology::LINE_STRIP,
                 },
                 primitive_restart_enable: if pipeline_description.primitive_restart {
                     vk::TRUE
@@ -564,9 +748,21 @@ impl Pipeline {
                 _ => 1.0,
             };
 
+            let conservative_state = PipelineRasterizationConservativeStateCreateInfoEXT {
+                s_type: StructureType::PIPELINE_RASTERIZATION_CONSERVATIVE_STATE_CREATE_INFO_EXT,
+                p_next: std::ptr::null(),
+                flags: PipelineRasterizationConservativeStateCreateFlagsEXT::empty(),
+                conservative_rasterization_mode: ConservativeRasterizationModeEXT::OVERESTIMATE,
+                extra_primitive_overestimation_size: 0.0,
+            };
+
             let raster_state = PipelineRasterizationStateCreateInfo {
                 s_type: StructureType::PIPELINE_RASTERIZATION_STATE_CREATE_INFO,
-                p_next: std::ptr::null(),
+                p_next: if pipeline_description.conservative_rasterization {
+                    addr_of!(conservative_state).cast()
+                } else {
+                    std::ptr::null()
+                },
                 flags: PipelineRasterizationStateCreateFlags::empty(),
                 depth_clamp_enable: vk::FALSE,
                 rasterizer_discard_enable: vk::FALSE,
@@ -581,10 +777,7 @@ impl Pipeline {
                     CullMode::None => vk::CullModeFlags::NONE,
                     CullMode::FrontAndBack => vk::CullModeFlags::FRONT_AND_BACK,
                 },
-                front_face: match pipeline_description.front_face {
-                    FrontFace::CounterClockWise => vk::FrontFace::COUNTER_CLOCKWISE,
-                    FrontFace::ClockWise => vk::FrontFace::CLOCKWISE,
-                },
+                front_face: pipeline_description.front_face.to_vk(),
                 depth_bias_enable: vk::FALSE,
                 depth_bias_constant_factor: 0.0,
                 depth_bias_clamp: 0.0,
@@ -596,7 +789,7 @@ impl Pipeline {
                 s_type: StructureType::PIPELINE_MULTISAMPLE_STATE_CREATE_INFO,
                 p_next: std::ptr::null(),
                 flags: PipelineMultisampleStateCreateFlags::empty(),
-                rasterization_samples: SampleCountFlags::TYPE_1,
+                rasterization_samples: pipeline_description.samples.to_vk(),
                 sample_shading_enable: vk::FALSE,
                 min_sample_shading: 1.0,
                 p_sample_mask: std::ptr::null(),
@@ -645,9 +838,16 @@ impl Pipeline {
                 s_type: StructureType::PIPELINE_DYNAMIC_STATE_CREATE_INFO,
                 p_next: std::ptr::null(),
                 flags: PipelineDynamicStateCreateFlags::empty(),
-                dynamic_state_count: 2,
-                p_dynamic_states: &[DynamicState::VIEWPORT, DynamicState::SCISSOR]
-                    as *const DynamicState,
+                dynamic_state_count: 6,
+                p_dynamic_states: &[
+                    DynamicState::VIEWPORT,
+                    DynamicState::SCISSOR,
+                    DynamicState::LINE_WIDTH,
+                    DynamicState::STENCIL_REFERENCE,
+                    DynamicState::BLEND_CONSTANTS,
+                    // Flipped by the render pass for mirrored geometry
+                    DynamicState::FRONT_FACE,
+                ] as *const DynamicState,
             };
 
             let color_attachment = pipeline_description
@@ -661,15 +861,14 @@ impl Pipeline {
                 view_mask: 0,
                 color_attachment_count: color_attachment.len() as _,
                 p_color_attachment_formats: color_attachment.as_ptr(),
-                depth_attachment_format: if pipeline_description
-                    .depth_stencil_state
-                    .depth_test_enable
-                {
-                    ImageFormat::Depth.to_vk()
-                } else {
-                    Format::UNDEFINED
+                depth_attachment_format: pipeline_description
+                    .depth_stencil_format
+                    .map(|f| f.to_vk())
+                    .unwrap_or(Format::UNDEFINED),
+                stencil_attachment_format: match pipeline_description.depth_stencil_format {
+                    Some(f) if f.has_stencil() => f.to_vk(),
+                    _ => Format::UNDEFINED,
                 },
-                stencil_attachment_format: Format::UNDEFINED,
             };
 
             let create_infos = [GraphicsPipelineCreateInfo {
@@ -713,7 +912,91 @@ impl Pipeline {
         Ok(Self {
             pipeline,
             pipeline_layout,
+            line_width: match pipeline_description.polygon_mode {
+                PolygonMode::Line(w) => w,
+                _ => 1.0,
+            },
+            front_face: pipeline_description.front_face,
+            color_formats: pipeline_description
+                .fragment_stage
+                .map(|frag| frag.color_attachments.iter().map(|c| c.format).collect())
+                .unwrap_or_default(),
+            depth_stencil_format: pipeline_description.depth_stencil_format,
+            bind_point: PipelineBindPoint::GRAPHICS,
             shared_state: gpu.state.clone(),
+            #[cfg(feature = "leak-detection")]
+            _live_token: gpu.live_resources.register(
+                ResourceKind::Pipeline,
+                vk::Handle::as_raw(pipeline),
+                None,
+            ),
+        })
+    }
+
+    /* Creates a pipeline running a compute shader, which is bound and dispatched outside of
+     * render passes with CommandBuffer::bind_compute_pipeline and CommandBuffer::dispatch */
+    pub fn new_compute(
+        gpu: &Gpu,
+        pipeline_description: &ComputePipelineDescription,
+    ) -> VkResult<Self> {
+        let pipeline_layout = create_pipeline_layout(
+            gpu,
+            pipeline_description.global_bindings,
+            pipeline_description.push_constant_ranges,
+        )?;
+
+        let entry_point = CString::new(pipeline_description.compute_stage.entry_point).unwrap();
+        let create_infos = [ComputePipelineCreateInfo {
+            s_type: StructureType::COMPUTE_PIPELINE_CREATE_INFO,
+            p_next: std::ptr::null(),
+            flags: PipelineCreateFlags::empty(),
+            stage: PipelineShaderStageCreateInfo {
+                s_type: StructureType::PIPELINE_SHADER_STAGE_CREATE_INFO,
+                p_next: std::ptr::null(),
+                flags: PipelineShaderStageCreateFlags::empty(),
+                stage: ShaderStageFlags::COMPUTE,
+                module: pipeline_description.compute_stage.module.inner,
+                p_name: entry_point.as_ptr(),
+                p_specialization_info: std::ptr::null(),
+            },
+            layout: pipeline_layout,
+            base_pipeline_handle: vk::Pipeline::null(),
+            base_pipeline_index: 0,
+        }];
+
+        let pipelines = unsafe {
+            gpu.state.logical_device.create_compute_pipelines(
+                gpu.state.pipeline_cache,
+                &create_infos,
+                None,
+            )
+        };
+        let pipeline = match pipelines {
+            Ok(pipelines) => pipelines[0],
+            Err((_, e)) => {
+                unsafe {
+                    gpu.vk_logical_device()
+                        .destroy_pipeline_layout(pipeline_layout, None);
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            pipeline,
+            pipeline_layout,
+            line_width: 1.0,
+            front_face: FrontFace::default(),
+            color_formats: vec![],
+            depth_stencil_format: None,
+            bind_point: PipelineBindPoint::COMPUTE,
+            shared_state: gpu.state.clone(),
+            #[cfg(feature = "leak-detection")]
+            _live_token: gpu.live_resources.register(
+                ResourceKind::Pipeline,
+                vk::Handle::as_raw(pipeline),
+                None,
+            ),
         })
     }
 }
@@ -730,3 +1013,171 @@ impl Drop for Pipeline {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ash::vk::{self, BufferUsageFlags, PipelineBindPoint, ShaderModuleCreateFlags};
+    use engine_macros::glsl;
+    use winit::event_loop::EventLoopBuilder;
+
+    use crate::{
+        BindingElement, BindingType, BufferCreateInfo, BufferMemoryBarrier, BufferRange,
+        CommandBuffer, CommandBufferSubmitInfo, ComputePipelineDescription, ComputeStageInfo,
+        DescriptorInfo, DescriptorSetInfo, DescriptorType, GlobalBinding, Gpu, GpuConfiguration,
+        InputRate, MemoryDomain, Pipeline, PipelineBarrierInfo, QueueType, ShaderModuleCreateInfo,
+        ShaderStage, Vertex, VertexAttributeDescription, VertexBindingDescription,
+    };
+
+    const FILL_BUFFER_CS: &[u32] = glsl!(
+        kind = compute,
+        source = "
+#version 460
+layout(local_size_x = 64) in;
+layout(set = 0, binding = 0) buffer Output {
+    uint values[];
+} output_buffer;
+void main() {
+    uint index = gl_GlobalInvocationID.x;
+    output_buffer.values[index] = index * 2;
+}",
+        entry_point = "main"
+    );
+
+    #[test]
+    #[ignore = "needs a Vulkan device and a display"]
+    fn compute_pipeline_writes_a_storage_buffer() {
+        const COUNT: usize = 256;
+
+        let mut event_loop = EventLoopBuilder::new();
+        #[cfg(target_os = "linux")]
+        winit::platform::unix::EventLoopBuilderExtUnix::with_any_thread(&mut event_loop, true);
+        let event_loop = event_loop.build();
+        let window = winit::window::WindowBuilder::new()
+            .with_visible(false)
+            .build(&event_loop)
+            .unwrap();
+        let gpu = Gpu::new(GpuConfiguration {
+            app_name: "compute test",
+            engine_name: "compute test",
+            pipeline_cache_path: None,
+            enable_debug_utilities: false,
+            window,
+            frames_in_flight: Gpu::DEFAULT_FRAMES_IN_FLIGHT,
+        })
+        .unwrap();
+
+        let module = gpu
+            .create_shader_module(&ShaderModuleCreateInfo {
+                flags: ShaderModuleCreateFlags::empty(),
+                code: bytemuck::cast_slice(FILL_BUFFER_CS),
+            })
+            .unwrap();
+        let pipeline = Pipeline::new_compute(
+            &gpu,
+            &ComputePipelineDescription {
+                global_bindings: &[GlobalBinding {
+                    set_index: 0,
+                    elements: &[BindingElement {
+                        binding_type: BindingType::Storage,
+                        index: 0,
+                        stage: ShaderStage::Compute,
+                    }],
+                    push_descriptor: false,
+                }],
+                compute_stage: ComputeStageInfo {
+                    entry_point: "main",
+                    module: &module,
+                },
+                push_constant_ranges: &[],
+            },
+        )
+        .unwrap();
+
+        let buffer = gpu
+            .create_buffer(
+                &BufferCreateInfo {
+                    label: Some("compute output"),
+                    size: std::mem::size_of::<u32>() * COUNT,
+                    usage: BufferUsageFlags::STORAGE_BUFFER,
+                    sharing_mode: Default::default(),
+                },
+                MemoryDomain::HostVisible | MemoryDomain::HostCoherent,
+            )
+            .unwrap();
+        let descriptor_set = gpu
+            .create_descriptor_set(&DescriptorSetInfo {
+                descriptors: &[DescriptorInfo {
+                    binding: 0,
+                    element_type: DescriptorType::StorageBuffer(BufferRange::whole(&buffer)),
+                    binding_stage: ShaderStage::Compute,
+                }],
+            })
+            .unwrap();
+
+        let mut command_buffer = CommandBuffer::new(&gpu, QueueType::Graphics).unwrap();
+        command_buffer.bind_compute_pipeline(&pipeline);
+        command_buffer.bind_descriptor_sets(
+            PipelineBindPoint::COMPUTE,
+            &pipeline,
+            0,
+            &[&descriptor_set],
+        );
+        command_buffer.dispatch(COUNT as u32 / 64, 1, 1);
+        // Makes the shader's writes visible to the host reads below
+        command_buffer.pipeline_barrier(&PipelineBarrierInfo {
+            src_stage_mask: vk::PipelineStageFlags::COMPUTE_SHADER,
+            dst_stage_mask: vk::PipelineStageFlags::HOST,
+            buffer_memory_barriers: &[BufferMemoryBarrier {
+                src_access_mask: vk::AccessFlags::SHADER_WRITE,
+                dst_access_mask: vk::AccessFlags::HOST_READ,
+                src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+                dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+                buffer: &buffer,
+                offset: 0,
+                size: vk::WHOLE_SIZE,
+            }],
+            ..Default::default()
+        });
+        command_buffer
+            .submit(&CommandBufferSubmitInfo::default())
+            .unwrap();
+        gpu.wait_queue_idle(QueueType::Graphics).unwrap();
+
+        let values = buffer.read_data::<u32>(0, COUNT);
+        let expected: Vec<u32> = (0..COUNT as u32).map(|i| i * 2).collect();
+        assert_eq!(values, expected);
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    #[allow(dead_code)]
+    struct SpriteVertex {
+        position: [f32; 2],
+        color: [u8; 4],
+    }
+
+    impl Vertex for SpriteVertex {
+        const ATTRIBUTES: &'static [VertexAttributeDescription] = &[
+            VertexAttributeDescription {
+                location: 0,
+                format: vk::Format::R32G32_SFLOAT,
+                offset: 0,
+            },
+            VertexAttributeDescription {
+                location: 1,
+                format: vk::Format::R8G8B8A8_UNORM,
+                offset: 8,
+            },
+        ];
+    }
+
+    #[test]
+    fn vertex_binding_from_vertex_format() {
+        let binding = VertexBindingDescription::of::<SpriteVertex>(1, InputRate::PerInstance);
+        assert_eq!(binding.binding, 1);
+        assert_eq!(binding.input_rate, InputRate::PerInstance);
+        assert_eq!(binding.stride, 12);
+        assert_eq!(binding.attributes.len(), 2);
+        assert_eq!(binding.attributes[1].format, vk::Format::R8G8B8A8_UNORM);
+    }
+}
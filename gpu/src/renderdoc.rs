@@ -0,0 +1,99 @@
+use std::ffi::{c_char, c_int, c_void, CStr};
+
+// eRENDERDOC_API_Version_1_1_2
+const API_VERSION_1_1_2: c_int = 10102;
+
+type GetApi = unsafe extern "C" fn(version: c_int, out_api: *mut *mut c_void) -> c_int;
+
+/* The head of RENDERDOC_API_1_1_2 from renderdoc_app.h: the table is only read,
+ * so the entries after TriggerCapture are left out */
+#[repr(C)]
+struct RenderDocApi {
+    // GetAPIVersion up to GetCapture
+    _unused: [*const c_void; 15],
+    trigger_capture: unsafe extern "C" fn(),
+}
+
+/*
+The RenderDoc in-application API: it's only available when the application
+has been launched from (or injected by) RenderDoc, the library is never loaded by us
+ */
+pub(crate) struct RenderDoc {
+    api: *const RenderDocApi,
+}
+
+impl RenderDoc {
+    pub(crate) fn load() -> Option<Self> {
+        let get_api = platform::find_symbol(c"RENDERDOC_GetAPI")?;
+        let get_api: GetApi = unsafe { std::mem::transmute(get_api) };
+        let mut api = std::ptr::null_mut();
+        if unsafe { get_api(API_VERSION_1_1_2, &mut api) } != 1 || api.is_null() {
+            log::warn!("RenderDoc is loaded, but it does not support the API version 1.1.2");
+            return None;
+        }
+        log::info!("RenderDoc API loaded, captures can be triggered");
+        Some(Self { api: api.cast() })
+    }
+
+    // The capture starts with the next frame presented
+    pub(crate) fn trigger_capture(&self) {
+        unsafe { ((*self.api).trigger_capture)() }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::*;
+
+    const RTLD_NOW: c_int = 2;
+    const RTLD_NOLOAD: c_int = 4;
+
+    #[link(name = "dl")]
+    extern "C" {
+        fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+        fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    }
+
+    pub(super) fn find_symbol(name: &CStr) -> Option<*mut c_void> {
+        unsafe {
+            let library = dlopen(c"librenderdoc.so".as_ptr(), RTLD_NOW | RTLD_NOLOAD);
+            if library.is_null() {
+                return None;
+            }
+            let symbol = dlsym(library, name.as_ptr());
+            (!symbol.is_null()).then_some(symbol)
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::*;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetModuleHandleA(module_name: *const c_char) -> *mut c_void;
+        fn GetProcAddress(module: *mut c_void, proc_name: *const c_char) -> *mut c_void;
+    }
+
+    pub(super) fn find_symbol(name: &CStr) -> Option<*mut c_void> {
+        unsafe {
+            let module = GetModuleHandleA(c"renderdoc.dll".as_ptr());
+            if module.is_null() {
+                return None;
+            }
+            let symbol = GetProcAddress(module, name.as_ptr());
+            (!symbol.is_null()).then_some(symbol)
+        }
+    }
+}
+
+// RenderDoc does not run anywhere else
+#[cfg(not(any(target_os = "linux", windows)))]
+mod platform {
+    use super::*;
+
+    pub(super) fn find_symbol(_name: &CStr) -> Option<*mut c_void> {
+        None
+    }
+}
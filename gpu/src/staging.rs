@@ -0,0 +1,243 @@
+use std::collections::VecDeque;
+
+use ash::{prelude::VkResult, vk};
+
+use crate::{GPUFence, GpuBuffer};
+
+// A range of a StagingArena's buffer, valid until the submission it's used by completes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StagingRange {
+    pub offset: u64,
+    pub size: u64,
+}
+
+// The offsets handed out by a StagingArena: a ring where the oldest group of ranges is freed first
+#[derive(Debug)]
+struct Ring {
+    capacity: u64,
+    head: u64,
+    // The bytes in use, including the padding skipped by the allocations
+    used: u64,
+    // The start of each group of ranges still in use and the bytes it takes, oldest first
+    groups: VecDeque<(u64, u64)>,
+}
+
+impl Ring {
+    fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            head: 0,
+            used: 0,
+            groups: VecDeque::new(),
+        }
+    }
+
+    fn tail(&self) -> u64 {
+        self.groups.front().map_or(self.head, |&(start, _)| start)
+    }
+
+    fn try_allocate(&mut self, size: u64, alignment: u64) -> Option<u64> {
+        let start = self.head.next_multiple_of(alignment);
+        let tail = self.tail();
+        let offset = if self.used == 0 || self.head > tail {
+            if start + size <= self.capacity {
+                start
+            } else if size <= tail || (self.used == 0 && size <= self.capacity) {
+                0
+            } else {
+                return None;
+            }
+        } else if start + size <= tail {
+            // The head has wrapped around, it can grow up to the oldest group
+            start
+        } else {
+            return None;
+        };
+        let taken = if offset >= self.head {
+            offset + size - self.head
+        } else {
+            self.capacity - self.head + offset + size
+        };
+        self.head = offset + size;
+        self.used += taken;
+        if let Some((_, bytes)) = self.groups.back_mut() {
+            *bytes += taken;
+        }
+        Some(offset)
+    }
+
+    // The ranges allocated from now on form a new group, freed by free_oldest
+    fn begin_group(&mut self) {
+        self.groups.push_back((self.head, 0));
+    }
+
+    fn free_oldest(&mut self) {
+        if let Some((_, bytes)) = self.groups.pop_front() {
+            self.used -= bytes;
+        }
+        if self.used == 0 {
+            self.head = 0;
+            for (start, _) in &mut self.groups {
+                *start = 0;
+            }
+        }
+    }
+}
+
+struct Submission {
+    fence: GPUFence,
+}
+
+/*
+A ring of host visible memory the uploads are staged in: write() copies the data into the ring,
+and the returned range must be consumed by a command buffer submitted with a fence,
+which is then handed to submit(). The ranges written before submit() are only reused
+once that fence signals, so the staged data can't be overwritten while the gpu still reads it.
+When the ring is full, write() waits for the oldest submissions to complete
+ */
+pub struct StagingArena {
+    device: ash::Device,
+    buffer: GpuBuffer,
+    ring: Ring,
+    // The submissions reading the arena's groups of ranges, in the same order as the ring's groups
+    submissions: VecDeque<Submission>,
+    has_pending_writes: bool,
+}
+
+impl StagingArena {
+    pub(crate) fn new(device: ash::Device, buffer: GpuBuffer) -> Self {
+        let capacity = buffer.size;
+        Self {
+            device,
+            buffer,
+            ring: Ring::new(capacity),
+            submissions: VecDeque::new(),
+            has_pending_writes: false,
+        }
+    }
+
+    pub fn buffer(&self) -> &GpuBuffer {
+        &self.buffer
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.ring.capacity
+    }
+
+    /* Copies data into the arena, at an offset that's a multiple of alignment.
+     * Fails with ERROR_OUT_OF_HOST_MEMORY when the data can't fit in the arena
+     * even after all the submissions complete */
+    pub fn write<T: Copy>(&mut self, data: &[T], alignment: u64) -> VkResult<StagingRange> {
+        let size = std::mem::size_of_val(data) as u64;
+        assert!(size > 0, "Cannot stage 0 bytes of data!");
+        assert!(
+            alignment.is_power_of_two(),
+            "Staging alignments must be powers of two, got {alignment}"
+        );
+        if !self.has_pending_writes {
+            self.ring.begin_group();
+            self.has_pending_writes = true;
+        }
+        let offset = loop {
+            if let Some(offset) = self.ring.try_allocate(size, alignment) {
+                break offset;
+            }
+            if !self.wait_oldest_submission()? {
+                return Err(vk::Result::ERROR_OUT_OF_HOST_MEMORY);
+            }
+        };
+        self.buffer.write_data(offset, data);
+        Ok(StagingRange { offset, size })
+    }
+
+    /* The ranges written since the last call are reused once the fence signals:
+     * the fence must be the one of the submission reading them */
+    pub fn submit(&mut self, fence: GPUFence) {
+        if !self.has_pending_writes {
+            return;
+        }
+        self.has_pending_writes = false;
+        self.submissions.push_back(Submission { fence });
+        self.reclaim_completed();
+    }
+
+    fn reclaim_completed(&mut self) {
+        while let Some(submission) = self.submissions.front() {
+            let completed = unsafe { self.device.get_fence_status(submission.fence.inner) };
+            if !matches!(completed, Ok(true)) {
+                break;
+            }
+            self.submissions.pop_front();
+            self.ring.free_oldest();
+        }
+    }
+
+    // Returns false when there are no submissions to wait for
+    fn wait_oldest_submission(&mut self) -> VkResult<bool> {
+        let Some(submission) = self.submissions.pop_front() else {
+            return Ok(false);
+        };
+        Self::wait_fence(&self.device, &submission.fence)?;
+        self.ring.free_oldest();
+        Ok(true)
+    }
+
+    fn wait_fence(device: &ash::Device, fence: &GPUFence) -> VkResult<()> {
+        unsafe { device.wait_for_fences(&[fence.inner], true, u64::MAX) }
+    }
+}
+
+impl Drop for StagingArena {
+    fn drop(&mut self) {
+        // The buffer can't be destroyed while the gpu reads it
+        for submission in self.submissions.drain(..) {
+            Self::wait_fence(&self.device, &submission.fence)
+                .expect("Failed to wait for a staging submission while dropping the arena");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ring;
+
+    #[test]
+    fn ring_allocations_are_aligned() {
+        let mut ring = Ring::new(64);
+        ring.begin_group();
+        assert_eq!(ring.try_allocate(3, 1), Some(0));
+        assert_eq!(ring.try_allocate(8, 16), Some(16));
+        assert_eq!(ring.try_allocate(4, 4), Some(24));
+    }
+
+    #[test]
+    fn ring_wraps_around_when_the_oldest_group_is_freed() {
+        let mut ring = Ring::new(64);
+        ring.begin_group();
+        assert_eq!(ring.try_allocate(32, 1), Some(0));
+        ring.begin_group();
+        assert_eq!(ring.try_allocate(24, 1), Some(32));
+        // The first group is still in use
+        assert_eq!(ring.try_allocate(16, 1), None);
+
+        ring.free_oldest();
+        assert_eq!(ring.try_allocate(16, 1), Some(0));
+        // The second group still blocks the rest of the ring
+        assert_eq!(ring.try_allocate(24, 1), None);
+        assert_eq!(ring.try_allocate(16, 1), Some(16));
+    }
+
+    #[test]
+    fn empty_ring_starts_over() {
+        let mut ring = Ring::new(64);
+        ring.begin_group();
+        assert_eq!(ring.try_allocate(48, 1), Some(0));
+        ring.free_oldest();
+        ring.begin_group();
+        assert_eq!(ring.try_allocate(64, 1), Some(0));
+        assert_eq!(ring.try_allocate(1, 1), None);
+        ring.free_oldest();
+        ring.begin_group();
+        assert_eq!(ring.try_allocate(65, 1), None);
+    }
+}
@@ -14,11 +14,11 @@ use ash::{
     },
     Device,
 };
-use log::{info, trace, warn};
+use log::{error, info, trace, warn};
 use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
 use winit::window::Window;
 
-use crate::{GpuImage, GpuImageView};
+use crate::{GpuImage, GpuImageView, ImageFormat, ImageTransition};
 
 use super::{GPUFence, GPUSemaphore, GpuState};
 
@@ -49,6 +49,9 @@ pub struct SwapchainFrame {
     pub in_flight_fence: GPUFence,
     pub render_finished_semaphore: GPUSemaphore,
     pub image_available_semaphore: GPUSemaphore,
+    // Used by the submission transitioning the image to PRESENT_SRC_KHR, see Gpu::present
+    pub(crate) present_transition_fence: GPUFence,
+    pub(crate) present_transition_semaphore: GPUSemaphore,
 }
 
 impl SwapchainFrame {
@@ -72,6 +75,24 @@ impl SwapchainFrame {
         )?;
 
         let image_available_semaphore = GPUSemaphore::create(
+            device.clone(),
+            &SemaphoreCreateInfo {
+                s_type: StructureType::SEMAPHORE_CREATE_INFO,
+                p_next: std::ptr::null(),
+                flags: SemaphoreCreateFlags::empty(),
+            },
+        )?;
+
+        let present_transition_fence = GPUFence::create(
+            device.clone(),
+            &FenceCreateInfo {
+                s_type: StructureType::FENCE_CREATE_INFO,
+                p_next: std::ptr::null(),
+                flags: FenceCreateFlags::SIGNALED,
+            },
+        )?;
+
+        let present_transition_semaphore = GPUSemaphore::create(
             device,
             &SemaphoreCreateInfo {
                 s_type: StructureType::SEMAPHORE_CREATE_INFO,
@@ -83,10 +104,20 @@ impl SwapchainFrame {
             in_flight_fence,
             render_finished_semaphore,
             image_available_semaphore,
+            present_transition_fence,
+            present_transition_semaphore,
         })
     }
 }
 
+// The outcome of Swapchain::select_present_mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresentModeSelection {
+    Requested,
+    // The surface doesn't support the requested mode, FIFO is always supported
+    FallbackToFifo,
+}
+
 pub struct Swapchain {
     pub(super) surface_extension: Surface,
     pub swapchain_extension: ash::extensions::khr::Swapchain,
@@ -107,13 +138,19 @@ pub struct Swapchain {
     current_swapchain_index: Cell<u32>,
     state: Arc<GpuState>,
     pub current_frame: Cell<usize>,
+    // Set once the command pool of the current frame has been reset
+    pub(crate) frame_begun: Cell<bool>,
     pub next_image_fence: GPUFence,
 }
 
 impl Swapchain {
-    pub const MAX_FRAMES_IN_FLIGHT: usize = 2;
+    const IMAGE_USAGE: ImageUsageFlags = ImageUsageFlags::COLOR_ATTACHMENT;
 
-    pub(crate) fn new(state: Arc<GpuState>, window: Window) -> VkResult<Self> {
+    pub(crate) fn new(
+        state: Arc<GpuState>,
+        window: Window,
+        frames_in_flight: usize,
+    ) -> VkResult<Self> {
         let surface_extension = Surface::new(&state.entry, &state.instance);
         let swapchain_extension =
             ash::extensions::khr::Swapchain::new(&state.instance, &state.logical_device);
@@ -132,12 +169,12 @@ impl Swapchain {
             height: window.outer_size().height,
         };
 
-        let mut frames_in_flight = vec![];
-        for _ in 0..Self::MAX_FRAMES_IN_FLIGHT {
-            let swapchain_frame = SwapchainFrame::new(state.logical_device.clone())
-                .expect("TODO: change return type to anyhow::result");
-            frames_in_flight.push(swapchain_frame);
-        }
+        let frames_in_flight = (0..frames_in_flight)
+            .map(|_| {
+                SwapchainFrame::new(state.logical_device.clone())
+                    .expect("TODO: change return type to anyhow::result")
+            })
+            .collect();
 
         let mut me = Self {
             surface_extension,
@@ -157,6 +194,7 @@ impl Swapchain {
             frames_in_flight,
             next_image_fence,
             current_frame: Cell::new(0),
+            frame_begun: Cell::new(false),
             state,
             window,
         };
@@ -172,7 +210,14 @@ impl Swapchain {
         unsafe {
             self.state
                 .logical_device
-                .wait_for_fences(&[current_frame.in_flight_fence.inner], true, u64::MAX)
+                .wait_for_fences(
+                    &[
+                        current_frame.in_flight_fence.inner,
+                        current_frame.present_transition_fence.inner,
+                    ],
+                    true,
+                    u64::MAX,
+                )
                 .unwrap();
             self.state
                 .logical_device
@@ -204,6 +249,12 @@ impl Swapchain {
                     .unwrap();
                 self.current_swapchain_index.replace(next_image);
                 let image_idx = self.current_swapchain_index.get() as usize;
+                // The previous contents of the image are discarded
+                self.current_swapchain_images[image_idx].track_state(
+                    ImageTransition::UndefinedToColorAttachment
+                        .transition_infos()
+                        .0,
+                );
                 return Ok(unsafe {
                     (
                         &self.current_swapchain_images[image_idx],
@@ -220,10 +271,19 @@ impl Swapchain {
         &self.frames_in_flight[self.current_frame.get()]
     }
 
+    // The image returned by the last acquire_next_image
+    pub(crate) fn current_image(&self) -> &GpuImage {
+        &self.current_swapchain_images[self.current_swapchain_index.get() as usize]
+    }
+
+    // Presents the current image once the frame's render_finished_semaphore is signaled
     pub fn present(&self) -> VkResult<bool> {
+        self.present_after(&self.get_current_swapchain_frame().render_finished_semaphore)
+    }
+
+    pub(crate) fn present_after(&self, wait_semaphore: &GPUSemaphore) -> VkResult<bool> {
         unsafe {
-            let current_frame = self.get_current_swapchain_frame();
-            let wait_semaphore = current_frame.render_finished_semaphore.inner;
+            let wait_semaphore = wait_semaphore.inner;
             self.swapchain_extension.queue_present(
                 self.state.graphics_queue,
                 &PresentInfoKHR {
@@ -240,18 +300,24 @@ impl Swapchain {
         }
 
         self.current_frame
-            .replace((self.current_frame.get() + 1) % Self::MAX_FRAMES_IN_FLIGHT);
+            .replace((self.current_frame.get() + 1) % self.frames_in_flight.len());
+        self.frame_begun.set(false);
         Ok(true)
     }
 
-    fn pick_swapchain_format(supported_formats: &[SurfaceFormatKHR]) -> SurfaceFormatKHR {
+    // Only the formats with a matching ImageFormat can be used for the swapchain images
+    fn pick_swapchain_format(supported_formats: &[SurfaceFormatKHR]) -> Option<SurfaceFormatKHR> {
         for format in supported_formats.iter() {
             if format.format == Format::R8G8B8A8_SRGB {
-                return *format;
+                return Some(*format);
             }
         }
 
-        supported_formats[0]
+        supported_formats.iter().copied().find(|format| {
+            ImageFormat::try_from_vk(format.format)
+                .map_err(|error| warn!("Skipping surface format: {error}"))
+                .is_ok()
+        })
     }
 
     pub fn recreate_swapchain(&mut self) -> VkResult<()> {
@@ -286,14 +352,16 @@ impl Swapchain {
                 )
         }?;
 
-        self.supported_present_modes = unsafe {
-            self.surface_extension
-                .get_physical_device_surface_present_modes(
-                    self.state.physical_device.physical_device,
-                    self.surface,
-                )
-        }?;
-        self.present_format = Self::pick_swapchain_format(&self.supported_presentation_formats);
+        self.supported_present_modes = self.query_present_modes()?;
+        self.present_format = Self::pick_swapchain_format(&self.supported_presentation_formats)
+            .ok_or_else(|| {
+                error!(
+                    "None of the surface formats {:?} can be used for the swapchain",
+                    self.supported_presentation_formats
+                );
+                vk::Result::ERROR_FORMAT_NOT_SUPPORTED
+            })?;
+        self.present_extent = self.window_extent();
 
         self.validate_selected_swapchain_settings();
 
@@ -307,7 +375,7 @@ impl Swapchain {
             image_color_space: self.present_format.color_space,
             image_extent: self.present_extent,
             image_array_layers: 1,
-            image_usage: ImageUsageFlags::COLOR_ATTACHMENT,
+            image_usage: self.image_usage(),
             image_sharing_mode: SharingMode::EXCLUSIVE,
             queue_family_index_count: 0,
             p_queue_family_indices: std::ptr::null(),
@@ -333,6 +401,38 @@ impl Swapchain {
 
         Ok(())
     }
+
+    fn query_present_modes(&self) -> VkResult<Vec<PresentModeKHR>> {
+        unsafe {
+            self.surface_extension
+                .get_physical_device_surface_present_modes(
+                    self.state.physical_device.physical_device,
+                    self.surface,
+                )
+        }
+    }
+
+    /* The images can also be copied to when the surface allows it,
+     * e.g by DeferredRenderingPipeline::render_multi */
+    fn image_usage(&self) -> ImageUsageFlags {
+        let supported = self.surface_capabilities.supported_usage_flags;
+        Self::IMAGE_USAGE | (supported & ImageUsageFlags::TRANSFER_DST)
+    }
+
+    // The surface's extent follows the window's, unless the surface lets the swapchain pick it
+    fn window_extent(&self) -> Extent2D {
+        let current_extent = self.surface_capabilities.current_extent;
+        if current_extent.width != u32::MAX {
+            current_extent
+        } else {
+            let size = self.window.inner_size();
+            Extent2D {
+                width: size.width,
+                height: size.height,
+            }
+        }
+    }
+
     fn validate_selected_swapchain_settings(&mut self) {
         if !self.supported_present_modes.contains(&self.present_mode) {
             warn!(
@@ -342,17 +442,6 @@ impl Swapchain {
             self.present_mode = PresentModeKHR::FIFO
         };
 
-        if !self
-            .supported_presentation_formats
-            .contains(&self.present_format)
-        {
-            warn!(
-                "Device does not support present format {:?}, selecting the first available one",
-                &self.present_format
-            );
-            self.present_format = self.supported_presentation_formats[0];
-        }
-
         if self.swapchain_image_count.get() < self.surface_capabilities.min_image_count
             || self.swapchain_image_count.get() > self.surface_capabilities.max_image_count
         {
@@ -408,6 +497,7 @@ impl Swapchain {
                     *i,
                     self.extents(),
                     self.present_format().into(),
+                    self.image_usage(),
                 )
             })
             .collect();
@@ -493,9 +583,35 @@ impl Swapchain {
         self.drop_swapchain_structs();
     }
 
-    pub fn select_present_mode(&mut self, present_mode: PresentModeKHR) -> VkResult<()> {
-        self.present_mode = present_mode;
-        self.recreate_swapchain()
+    /* Recreates the swapchain with the given present mode, or with FIFO when the surface
+     * doesn't support it: e.g MAILBOX is not available everywhere */
+    pub fn select_present_mode(
+        &mut self,
+        present_mode: PresentModeKHR,
+    ) -> VkResult<PresentModeSelection> {
+        self.supported_present_modes = self.query_present_modes()?;
+        let selection = if self.supported_present_modes.contains(&present_mode) {
+            self.present_mode = present_mode;
+            PresentModeSelection::Requested
+        } else {
+            warn!(
+                "The surface does not support the present mode {}, falling back to FIFO",
+                util::stringify_present_mode(present_mode)
+            );
+            self.present_mode = PresentModeKHR::FIFO;
+            PresentModeSelection::FallbackToFifo
+        };
+        self.recreate_swapchain()?;
+        Ok(selection)
+    }
+
+    // The present modes supported by the surface, as of the last swapchain recreation
+    pub fn supported_present_modes(&self) -> Vec<PresentModeKHR> {
+        self.supported_present_modes.clone()
+    }
+
+    pub fn present_mode(&self) -> PresentModeKHR {
+        self.present_mode
     }
 
     pub fn extents(&self) -> Extent2D {
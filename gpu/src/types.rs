@@ -1,4 +1,10 @@
-use std::{cell::RefCell, ops::Deref, sync::Arc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    marker::PhantomData,
+    ops::{Deref, Range},
+    sync::Arc,
+};
 
 use super::{allocator::GpuAllocator, gpu::Gpu};
 use ash::vk::{ImageAspectFlags, ImageLayout, ImageUsageFlags};
@@ -12,8 +18,12 @@ use ash::{
 
 use super::{
     descriptor_set::{DescriptorSetAllocation, DescriptorSetAllocator},
-    MemoryAllocation, MemoryDomain,
+    ImageTransition, MemoryAllocation, MemoryDomain, TransitionInfo,
 };
+#[cfg(feature = "leak-detection")]
+use crate::leak_tracking::LiveToken;
+#[cfg(debug_assertions)]
+use crate::use_tracking::UseTracker;
 
 pub fn get_allocation_callbacks() -> Option<&'static AllocationCallbacks> {
     None
@@ -30,9 +40,16 @@ pub enum ImageFormat {
     Rgba8,
     Bgra8,
     SRgba8,
+    SBgra8,
     Rgb8,
     RgbaFloat,
+    RgbaHalf,
+    R8,
+    Rg8,
+    RHalf,
+    RgHalf,
     Depth,
+    DepthStencil,
 }
 
 impl ImageFormat {
@@ -41,14 +58,29 @@ impl ImageFormat {
             ImageFormat::Rgba8
             | ImageFormat::Bgra8
             | ImageFormat::SRgba8
+            | ImageFormat::SBgra8
             | ImageFormat::Rgb8
-            | ImageFormat::RgbaFloat => true,
-            ImageFormat::Depth => false,
+            | ImageFormat::RgbaFloat
+            | ImageFormat::RgbaHalf
+            | ImageFormat::R8
+            | ImageFormat::Rg8
+            | ImageFormat::RHalf
+            | ImageFormat::RgHalf => true,
+            ImageFormat::Depth | ImageFormat::DepthStencil => false,
         }
     }
 
+    // The hardware decodes reads from and encodes writes to sRGB formats
+    pub fn is_srgb(&self) -> bool {
+        matches!(self, ImageFormat::SRgba8 | ImageFormat::SBgra8)
+    }
+
     pub fn is_depth(&self) -> bool {
-        ImageFormat::Depth == *self
+        matches!(self, ImageFormat::Depth | ImageFormat::DepthStencil)
+    }
+
+    pub fn has_stencil(&self) -> bool {
+        ImageFormat::DepthStencil == *self
     }
     pub fn default_usage_flags(&self) -> ImageUsageFlags {
         if self.is_color() {
@@ -62,12 +94,22 @@ impl ImageFormat {
     pub fn aspect_mask(&self) -> ImageAspectFlags {
         if self.is_color() {
             ImageAspectFlags::COLOR
+        } else if self.has_stencil() {
+            ImageAspectFlags::DEPTH | ImageAspectFlags::STENCIL
         } else if self.is_depth() {
             ImageAspectFlags::DEPTH
         } else {
             unreachable!()
         }
     }
+    // Descriptors sample a single aspect, the depth of the depth-stencil images
+    pub fn sampled_aspect_mask(&self) -> ImageAspectFlags {
+        if self.is_color() {
+            ImageAspectFlags::COLOR
+        } else {
+            ImageAspectFlags::DEPTH
+        }
+    }
     pub fn preferred_attachment_read_layout(&self) -> ImageLayout {
         if self.is_color() {
             ImageLayout::SHADER_READ_ONLY_OPTIMAL
@@ -96,9 +138,10 @@ impl ImageFormat {
             unreachable!()
         }
     }
+    // Color images are written by shaders as storage images, which must be in the GENERAL layout
     pub fn preferred_shader_write_layout(&self) -> ImageLayout {
         if self.is_color() {
-            ImageLayout::SHADER_READ_ONLY_OPTIMAL
+            ImageLayout::GENERAL
         } else if self.is_depth() {
             ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
         } else {
@@ -115,23 +158,53 @@ impl ToVk for ImageFormat {
             ImageFormat::SRgba8 => vk::Format::R8G8B8A8_SRGB,
             ImageFormat::Rgb8 => vk::Format::R8G8B8_UNORM,
             ImageFormat::RgbaFloat => vk::Format::R32G32B32A32_SFLOAT,
+            ImageFormat::RgbaHalf => vk::Format::R16G16B16A16_SFLOAT,
+            ImageFormat::R8 => vk::Format::R8_UNORM,
+            ImageFormat::Rg8 => vk::Format::R8G8_UNORM,
+            ImageFormat::RHalf => vk::Format::R16_SFLOAT,
+            ImageFormat::RgHalf => vk::Format::R16G16_SFLOAT,
             ImageFormat::Depth => vk::Format::D32_SFLOAT,
+            ImageFormat::DepthStencil => vk::Format::D32_SFLOAT_S8_UINT,
             ImageFormat::Bgra8 => vk::Format::B8G8R8A8_UNORM,
+            ImageFormat::SBgra8 => vk::Format::B8G8R8A8_SRGB,
         }
     }
 }
 
-impl From<&vk::Format> for ImageFormat {
-    fn from(value: &vk::Format) -> Self {
-        match *value {
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("The format {0:?} has no matching ImageFormat")]
+pub struct UnsupportedFormatError(pub vk::Format);
+
+impl ImageFormat {
+    /* The fallible version of ImageFormat::from(vk::Format), for formats coming from outside
+     * the crate (e.g the surface's formats).
+     * Not a TryFrom implementation, since the From one already provides an infallible TryFrom */
+    pub fn try_from_vk(value: vk::Format) -> Result<Self, UnsupportedFormatError> {
+        let format = match value {
             vk::Format::R8G8B8A8_UNORM => ImageFormat::Rgba8,
             vk::Format::R8G8B8A8_SRGB => ImageFormat::SRgba8,
             vk::Format::R8G8B8_UNORM => ImageFormat::Rgb8,
             vk::Format::D32_SFLOAT => ImageFormat::Depth,
+            vk::Format::D32_SFLOAT_S8_UINT => ImageFormat::DepthStencil,
             vk::Format::R32G32B32A32_SFLOAT => ImageFormat::RgbaFloat,
+            vk::Format::R16G16B16A16_SFLOAT => ImageFormat::RgbaHalf,
+            vk::Format::R8_UNORM => ImageFormat::R8,
+            vk::Format::R8G8_UNORM => ImageFormat::Rg8,
+            vk::Format::R16_SFLOAT => ImageFormat::RHalf,
+            vk::Format::R16G16_SFLOAT => ImageFormat::RgHalf,
             vk::Format::B8G8R8A8_UNORM => ImageFormat::Bgra8,
-            _ => panic!("ImageFormat::from(vk::Format): cannot convert {:?} to ImageFormat, most likely a bug: report it", value)
-        }
+            vk::Format::B8G8R8A8_SRGB => ImageFormat::SBgra8,
+            _ => return Err(UnsupportedFormatError(value)),
+        };
+        Ok(format)
+    }
+}
+
+impl From<&vk::Format> for ImageFormat {
+    fn from(value: &vk::Format) -> Self {
+        ImageFormat::try_from_vk(*value).unwrap_or_else(|error| {
+            panic!("ImageFormat::from(vk::Format): {error}, most likely a bug: report it")
+        })
     }
 }
 
@@ -141,6 +214,46 @@ impl From<vk::Format> for ImageFormat {
     }
 }
 
+// The samples per pixel of a multisampled render target, see Gpu::supports_sample_count
+#[derive(Clone, Debug, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum SampleCount {
+    #[default]
+    One,
+    Two,
+    Four,
+    Eight,
+}
+
+impl SampleCount {
+    pub const ALL: [SampleCount; 4] = [
+        SampleCount::One,
+        SampleCount::Two,
+        SampleCount::Four,
+        SampleCount::Eight,
+    ];
+
+    pub fn count(&self) -> u32 {
+        match self {
+            SampleCount::One => 1,
+            SampleCount::Two => 2,
+            SampleCount::Four => 4,
+            SampleCount::Eight => 8,
+        }
+    }
+}
+
+impl ToVk for SampleCount {
+    type Inner = vk::SampleCountFlags;
+    fn to_vk(&self) -> Self::Inner {
+        match self {
+            SampleCount::One => vk::SampleCountFlags::TYPE_1,
+            SampleCount::Two => vk::SampleCountFlags::TYPE_2,
+            SampleCount::Four => vk::SampleCountFlags::TYPE_4,
+            SampleCount::Eight => vk::SampleCountFlags::TYPE_8,
+        }
+    }
+}
+
 macro_rules! impl_raii_wrapper_hash {
     ($name:ident) => {
         impl std::hash::Hash for $name {
@@ -221,8 +334,13 @@ pub struct GpuBuffer {
     device: ash::Device,
     pub(super) inner: vk::Buffer,
     pub(super) memory_domain: MemoryDomain,
+    // The size the buffer was created with, its allocation may be larger
+    pub(super) size: u64,
     pub(super) allocation: MemoryAllocation,
     pub(super) allocator: Arc<RefCell<dyn GpuAllocator>>,
+    // Set by Gpu::create_buffer, see Gpu::report_leaks
+    #[cfg(feature = "leak-detection")]
+    pub(super) live_token: Option<LiveToken>,
 }
 
 impl GpuBuffer {
@@ -230,6 +348,7 @@ impl GpuBuffer {
         device: ash::Device,
         buffer: Buffer,
         memory_domain: MemoryDomain,
+        size: u64,
         allocation: MemoryAllocation,
         allocator: Arc<RefCell<dyn GpuAllocator>>,
     ) -> VkResult<Self> {
@@ -237,10 +356,19 @@ impl GpuBuffer {
             device,
             inner: buffer,
             memory_domain,
+            size,
             allocation,
             allocator,
+            #[cfg(feature = "leak-detection")]
+            live_token: None,
         })
     }
+
+    #[cfg(feature = "leak-detection")]
+    pub(crate) fn with_live_token(mut self, live_token: LiveToken) -> Self {
+        self.live_token = Some(live_token);
+        self
+    }
 }
 impl Drop for GpuBuffer {
     fn drop(&mut self) {
@@ -258,14 +386,23 @@ impl Deref for GpuBuffer {
 }
 
 impl GpuBuffer {
+    // The alignment of the buffer's memory, at least the buffer_offset_alignment of its usage
+    pub fn alignment(&self) -> u64 {
+        self.allocation.alignment
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
     pub fn write_data<I: Sized + Copy>(&self, offset: u64, data: &[I]) {
         let data_length = std::mem::size_of_val(data) as u64;
         assert!(
             data_length > 0,
             "Cannot write on a buffer with 0 data length!"
         );
-        assert!(offset < self.allocation.size);
-        assert!(data_length + offset <= self.allocation.size);
+        assert!(offset < self.size);
+        assert!(data_length + offset <= self.size);
 
         let address = unsafe {
             self.allocation
@@ -278,11 +415,73 @@ impl GpuBuffer {
 
         address.copy_from_slice(data);
     }
+
+    // Copies count elements starting at offset, the GPU must be done writing them
+    pub fn read_data<I: Sized + Copy>(&self, offset: u64, count: usize) -> Vec<I> {
+        let data_length = (std::mem::size_of::<I>() * count) as u64;
+        assert!(offset < self.size);
+        assert!(data_length + offset <= self.size);
+
+        let address = unsafe {
+            self.allocation
+                .persistent_ptr
+                .expect("Tried to read from a buffer without a persistent ptr!")
+                .as_ptr()
+                .add(offset as _)
+        } as *const I;
+        let address = unsafe { std::slice::from_raw_parts(address, count) };
+
+        address.to_vec()
+    }
+}
+
+impl GpuShaderModule {
+    // The SPIR-V the module was created from, e.g to reflect its bindings
+    pub fn code(&self) -> &[u32] {
+        &self.code
+    }
 }
 
 impl_raii_wrapper_hash!(GpuBuffer);
 impl_raii_wrapper_to_vk!(GpuBuffer, vk::Buffer);
 
+/* A buffer holding len() elements of T, created by Gpu::create_buffer_typed:
+ * it derefs to the untyped GpuBuffer, which into_inner() hands back */
+pub struct TypedBuffer<T: Copy> {
+    buffer: GpuBuffer,
+    len: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Copy> TypedBuffer<T> {
+    pub(super) fn new(buffer: GpuBuffer, len: usize) -> Self {
+        Self {
+            buffer,
+            len,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn into_inner(self) -> GpuBuffer {
+        self.buffer
+    }
+}
+
+impl<T: Copy> Deref for TypedBuffer<T> {
+    type Target = GpuBuffer;
+    fn deref(&self) -> &Self::Target {
+        &self.buffer
+    }
+}
+
 pub struct GpuImage {
     device: ash::Device,
     pub(super) inner: vk::Image,
@@ -290,31 +489,89 @@ pub struct GpuImage {
     pub(super) allocator: Option<Arc<RefCell<dyn GpuAllocator>>>,
     pub(super) extents: Extent2D,
     pub(super) format: ImageFormat,
+    pub(super) mip_levels: u32,
+    pub(super) usage: ImageUsageFlags,
+    // The image's debug name, the views created by the crate are named after it
+    label: Option<String>,
+    /* The state left by the last barrier recorded on the image, only tracked for the images
+     * the crate must transition itself (the swapchain images, see Gpu::present) */
+    tracked_state: Option<Cell<TransitionInfo>>,
+
+    // The views are boxed so that their address doesn't change when the map grows
+    views: RefCell<HashMap<ImageViewDescription, Box<GpuImageView>>>,
+    // Set by Gpu::create_image, see Gpu::report_leaks
+    #[cfg(feature = "leak-detection")]
+    live_token: Option<LiveToken>,
+}
+
+/* Describes a view of a subresource of a GpuImage, used as the key of the image's view cache */
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct ImageViewDescription {
+    pub view_type: vk::ImageViewType,
+    pub aspect_mask: ImageAspectFlags,
+    pub base_mip_level: u32,
+    pub level_count: u32,
+    pub base_array_layer: u32,
+    pub layer_count: u32,
 }
+
+impl ImageViewDescription {
+    pub fn mip(aspect_mask: ImageAspectFlags, mip_level: u32) -> Self {
+        Self {
+            view_type: vk::ImageViewType::TYPE_2D,
+            aspect_mask,
+            base_mip_level: mip_level,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        }
+    }
+}
+
 impl GpuImage {
     pub(super) fn create(
         gpu: &Gpu,
         image: vk::Image,
         allocation: MemoryAllocation,
-        allocator: Arc<RefCell<dyn GpuAllocator>>,
         extents: Extent2D,
         format: ImageFormat,
+        mip_levels: u32,
+        usage: ImageUsageFlags,
     ) -> VkResult<Self> {
         Ok(Self {
             device: gpu.state.logical_device.clone(),
             inner: image,
             allocation: Some(allocation),
-            allocator: Some(allocator),
+            allocator: Some(gpu.state.gpu_memory_allocator.clone()),
             extents,
             format,
+            mip_levels,
+            usage,
+            label: None,
+            tracked_state: None,
+            views: Default::default(),
+            #[cfg(feature = "leak-detection")]
+            live_token: None,
         })
     }
 
+    #[cfg(feature = "leak-detection")]
+    pub(crate) fn with_live_token(mut self, live_token: LiveToken) -> Self {
+        self.live_token = Some(live_token);
+        self
+    }
+
+    pub(crate) fn with_label(mut self, label: Option<&str>) -> Self {
+        self.label = label.map(|label| label.to_owned());
+        self
+    }
+
     pub(super) fn wrap(
         device: ash::Device,
         inner: vk::Image,
         extents: Extent2D,
         format: ImageFormat,
+        usage: ImageUsageFlags,
     ) -> Self {
         Self {
             device,
@@ -323,6 +580,27 @@ impl GpuImage {
             allocator: None,
             extents,
             format,
+            mip_levels: 1,
+            usage,
+            label: None,
+            tracked_state: Some(Cell::new(
+                ImageTransition::UndefinedToColorAttachment
+                    .transition_infos()
+                    .0,
+            )),
+            views: Default::default(),
+            #[cfg(feature = "leak-detection")]
+            live_token: None,
+        }
+    }
+
+    pub(crate) fn tracked_state(&self) -> Option<TransitionInfo> {
+        self.tracked_state.as_ref().map(Cell::get)
+    }
+
+    pub(crate) fn track_state(&self, state: TransitionInfo) {
+        if let Some(tracked_state) = &self.tracked_state {
+            tracked_state.set(state);
         }
     }
 
@@ -333,9 +611,69 @@ impl GpuImage {
     pub fn extents(&self) -> Extent2D {
         self.extents
     }
+
+    pub fn mip_levels(&self) -> u32 {
+        self.mip_levels
+    }
+
+    // The usage the image was created with
+    pub fn usage(&self) -> ImageUsageFlags {
+        self.usage
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    // The debug name of a view created by the crate, e.g "Depth buffer - mip 0 view"
+    pub(crate) fn view_label(&self, view: &str) -> Option<String> {
+        self.label.as_ref().map(|label| format!("{label} - {view}"))
+    }
+
+    // The memory backing the image, None for images the application doesn't own (e.g swapchain images)
+    pub fn allocation(&self) -> Option<&MemoryAllocation> {
+        self.allocation.as_ref()
+    }
+
+    pub fn mip_extents(&self, mip_level: u32) -> Extent2D {
+        Extent2D {
+            width: (self.extents.width >> mip_level).max(1),
+            height: (self.extents.height >> mip_level).max(1),
+        }
+    }
+
+    // Returns a view of the image described by desc, creating it the first time it's requested:
+    // the views live as long as the image does
+    pub fn view(&self, gpu: &Gpu, desc: &ImageViewDescription) -> VkResult<&GpuImageView> {
+        let mut views = self.views.borrow_mut();
+        if !views.contains_key(desc) {
+            let label = self.view_label(&format!("mip {} view", desc.base_mip_level));
+            let view = gpu.create_image_view(&crate::ImageViewCreateInfo {
+                label: label.as_deref(),
+                image: self,
+                view_type: desc.view_type,
+                format: self.format.to_vk(),
+                components: vk::ComponentMapping::default(),
+                subresource_range: vk::ImageSubresourceRange {
+                    aspect_mask: desc.aspect_mask,
+                    base_mip_level: desc.base_mip_level,
+                    level_count: desc.level_count,
+                    base_array_layer: desc.base_array_layer,
+                    layer_count: desc.layer_count,
+                },
+            })?;
+            views.insert(*desc, Box::new(view));
+        }
+        let view: *const GpuImageView = views[desc].as_ref();
+
+        // SAFETY: the views are boxed and never removed from the cache until the image is dropped
+        Ok(unsafe { &*view })
+    }
 }
 impl Drop for GpuImage {
     fn drop(&mut self) {
+        // The cached views must be destroyed before the image they refer to
+        self.views.get_mut().clear();
         if let (Some(allocator), Some(allocation)) = (&self.allocator, &self.allocation) {
             allocator.borrow_mut().deallocate(allocation);
             unsafe {
@@ -392,6 +730,12 @@ pub struct GpuDescriptorSet {
     pub(super) inner: vk::DescriptorSet,
     pub(super) allocation: DescriptorSetAllocation,
     pub(super) allocator: Arc<RefCell<dyn DescriptorSetAllocator>>,
+    // Set by Gpu::create_descriptor_set, reports the sets dropped while still in use
+    #[cfg(debug_assertions)]
+    pub(super) use_tracker: Option<UseTracker>,
+    // Set by Gpu::create_descriptor_set, see Gpu::report_leaks
+    #[cfg(feature = "leak-detection")]
+    live_token: Option<LiveToken>,
 }
 
 impl PartialEq for GpuDescriptorSet {
@@ -411,11 +755,46 @@ impl GpuDescriptorSet {
             inner: allocation.descriptor_set,
             allocation,
             allocator,
+            #[cfg(debug_assertions)]
+            use_tracker: None,
+            #[cfg(feature = "leak-detection")]
+            live_token: None,
         })
     }
+
+    #[cfg(debug_assertions)]
+    pub(crate) fn with_use_tracker(mut self, use_tracker: UseTracker) -> Self {
+        self.use_tracker = Some(use_tracker);
+        self
+    }
+
+    #[cfg(feature = "leak-detection")]
+    pub(crate) fn with_live_token(mut self, live_token: LiveToken) -> Self {
+        self.live_token = Some(live_token);
+        self
+    }
+
+    pub(crate) fn mark_used(&self) {
+        #[cfg(debug_assertions)]
+        if let Some(tracker) = &self.use_tracker {
+            tracker.mark_used();
+        }
+    }
 }
 impl Drop for GpuDescriptorSet {
     fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        if let Some(frame) = self
+            .use_tracker
+            .as_ref()
+            .and_then(UseTracker::in_flight_use)
+        {
+            log::warn!(
+                "Descriptor set {:?} was dropped while frame {frame} may still be using it: \
+                keep it alive until the frame completes",
+                self.inner
+            );
+        }
         self.allocator
             .borrow_mut()
             .deallocate(&self.allocation)
@@ -436,12 +815,59 @@ define_raii_wrapper!((struct GpuSampler {}, vk::Sampler, ash::Device::destroy_sa
         |device: &ash::Device| { unsafe { device.create_sampler(create_info, get_allocation_callbacks()) }}
     }
 });
-define_raii_wrapper!((struct GpuShaderModule {}, vk::ShaderModule, ash::Device::destroy_shader_module) {
+define_raii_wrapper!((struct GpuShaderModule { code: Vec<u32>, }, vk::ShaderModule, ash::Device::destroy_shader_module) {
     (create_info: &ShaderModuleCreateInfo,) => {
         |device: &ash::Device| { unsafe { device.create_shader_module(create_info, get_allocation_callbacks()) }}
     }
 });
 
+define_raii_wrapper!((struct QueryPool { count: u32, timestamp_valid_bits: u32, }, vk::QueryPool, ash::Device::destroy_query_pool) {
+    (create_info: &vk::QueryPoolCreateInfo,) => {
+        |device: &ash::Device| { unsafe { device.create_query_pool(create_info, get_allocation_callbacks()) }}
+    }
+});
+
+impl QueryPool {
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /* The timestamps written in the pool, in nanoseconds: fails with NOT_READY
+     * until the command buffers writing them have been executed */
+    pub fn get_results(&self, gpu: &Gpu) -> VkResult<Vec<u64>> {
+        self.get_results_range(gpu, 0..self.count)
+    }
+
+    // Like get_results, reading only the queries in range
+    pub fn get_results_range(&self, gpu: &Gpu, range: Range<u32>) -> VkResult<Vec<u64>> {
+        let mut ticks = vec![0u64; range.len()];
+        unsafe {
+            self.device.get_query_pool_results(
+                self.inner,
+                range.start,
+                range.len() as u32,
+                &mut ticks,
+                vk::QueryResultFlags::TYPE_64,
+            )
+        }?;
+        let period = gpu.physical_device_properties().limits.timestamp_period as f64;
+        Ok(ticks
+            .into_iter()
+            .map(|ticks| ticks_to_nanoseconds(ticks, self.timestamp_valid_bits, period))
+            .collect())
+    }
+}
+
+// The bits of a timestamp above the queue's valid bits are undefined
+fn ticks_to_nanoseconds(ticks: u64, valid_bits: u32, period: f64) -> u64 {
+    let ticks = if valid_bits >= 64 {
+        ticks
+    } else {
+        ticks & ((1u64 << valid_bits) - 1)
+    };
+    (ticks as f64 * period) as u64
+}
+
 define_raii_wrapper!((struct GpuFramebuffer {}, vk::Framebuffer, ash::Device::destroy_framebuffer) {
     (create_info: &vk::FramebufferCreateInfo,) => {
         |device: &ash::Device| {
@@ -450,3 +876,107 @@ define_raii_wrapper!((struct GpuFramebuffer {}, vk::Framebuffer, ash::Device::de
             }
         }
 );
+
+#[cfg(test)]
+mod tests {
+    use ash::vk::{ImageAspectFlags, ImageUsageFlags};
+
+    use ash::vk::{self, ImageLayout};
+
+    use super::{ticks_to_nanoseconds, ImageFormat, ToVk, UnsupportedFormatError};
+
+    const ALL_FORMATS: [ImageFormat; 13] = [
+        ImageFormat::Rgba8,
+        ImageFormat::Bgra8,
+        ImageFormat::SRgba8,
+        ImageFormat::SBgra8,
+        ImageFormat::Rgb8,
+        ImageFormat::RgbaFloat,
+        ImageFormat::RgbaHalf,
+        ImageFormat::R8,
+        ImageFormat::Rg8,
+        ImageFormat::RHalf,
+        ImageFormat::RgHalf,
+        ImageFormat::Depth,
+        ImageFormat::DepthStencil,
+    ];
+
+    #[test]
+    fn image_formats_round_trip() {
+        for format in ALL_FORMATS {
+            assert_eq!(ImageFormat::from(format.to_vk()), format);
+        }
+    }
+
+    #[test]
+    fn shader_write_layouts() {
+        for format in ALL_FORMATS {
+            let expected = if format.is_depth() {
+                ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+            } else {
+                ImageLayout::GENERAL
+            };
+            assert_eq!(
+                format.preferred_shader_write_layout(),
+                expected,
+                "{format:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn single_and_two_channel_formats_are_color() {
+        for format in [
+            ImageFormat::R8,
+            ImageFormat::Rg8,
+            ImageFormat::RHalf,
+            ImageFormat::RgHalf,
+        ] {
+            assert!(format.is_color());
+            assert_eq!(format.aspect_mask(), ImageAspectFlags::COLOR);
+            assert_eq!(
+                format.default_usage_flags(),
+                ImageUsageFlags::COLOR_ATTACHMENT
+            );
+        }
+    }
+
+    #[test]
+    fn depth_stencil_images_sample_their_depth() {
+        assert_eq!(
+            ImageFormat::DepthStencil.aspect_mask(),
+            ImageAspectFlags::DEPTH | ImageAspectFlags::STENCIL
+        );
+        assert_eq!(
+            ImageFormat::DepthStencil.sampled_aspect_mask(),
+            ImageAspectFlags::DEPTH
+        );
+        assert_eq!(
+            ImageFormat::Depth.sampled_aspect_mask(),
+            ImageAspectFlags::DEPTH
+        );
+        assert_eq!(
+            ImageFormat::Rgba8.sampled_aspect_mask(),
+            ImageAspectFlags::COLOR
+        );
+    }
+
+    #[test]
+    fn unknown_formats_are_reported() {
+        assert_eq!(
+            ImageFormat::try_from_vk(vk::Format::R8G8B8A8_SRGB),
+            Ok(ImageFormat::SRgba8)
+        );
+        assert_eq!(
+            ImageFormat::try_from_vk(vk::Format::A2B10G10R10_UNORM_PACK32),
+            Err(UnsupportedFormatError(vk::Format::A2B10G10R10_UNORM_PACK32))
+        );
+    }
+
+    #[test]
+    fn timestamps_are_masked_by_the_valid_bits() {
+        assert_eq!(ticks_to_nanoseconds(1_000, 64, 1.0), 1_000);
+        assert_eq!(ticks_to_nanoseconds(1_000, 64, 2.5), 2_500);
+        assert_eq!(ticks_to_nanoseconds((1 << 36) | 1_000, 36, 1.0), 1_000);
+    }
+}
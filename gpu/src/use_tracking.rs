@@ -0,0 +1,109 @@
+use std::{cell::Cell, rc::Rc};
+
+struct FrameClockState {
+    frame: Cell<u64>,
+    // All the frames before this one have completed on the gpu
+    completed: Cell<u64>,
+    frames_in_flight: u64,
+}
+
+/*
+Counts the frames recorded by the Gpu: a frame has completed once the swapchain
+waited for its fence, which happens when the same frame slot is acquired again,
+or when the whole device is waited for
+ */
+#[derive(Clone)]
+pub(crate) struct FrameClock {
+    state: Rc<FrameClockState>,
+}
+
+impl FrameClock {
+    pub(crate) fn new(frames_in_flight: usize) -> Self {
+        Self {
+            state: Rc::new(FrameClockState {
+                frame: Cell::new(0),
+                completed: Cell::new(0),
+                frames_in_flight: frames_in_flight as u64,
+            }),
+        }
+    }
+
+    // Called once the next frame's slot has been waited for
+    pub(crate) fn advance(&self) {
+        let frame = self.state.frame.get() + 1;
+        self.state.frame.set(frame);
+        let completed = (frame + 1).saturating_sub(self.state.frames_in_flight);
+        self.state
+            .completed
+            .set(self.state.completed.get().max(completed));
+    }
+
+    pub(crate) fn device_idle(&self) {
+        self.state.completed.set(self.state.frame.get());
+    }
+}
+
+/*
+Remembers the last frame a resource was recorded in, so that dropping it
+while a command buffer may still use it can be reported: it's undefined behaviour,
+which the validation layers only catch if the command buffer is used again
+ */
+pub(crate) struct UseTracker {
+    clock: FrameClock,
+    last_use: Cell<Option<u64>>,
+}
+
+impl UseTracker {
+    pub(crate) fn new(clock: FrameClock) -> Self {
+        Self {
+            clock,
+            last_use: Cell::new(None),
+        }
+    }
+
+    pub(crate) fn mark_used(&self) {
+        self.last_use.set(Some(self.clock.state.frame.get()));
+    }
+
+    // The frame still using the resource, if any
+    pub(crate) fn in_flight_use(&self) -> Option<u64> {
+        self.last_use
+            .get()
+            .filter(|&frame| frame >= self.clock.state.completed.get())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameClock, UseTracker};
+
+    #[test]
+    fn uses_complete_after_the_frames_in_flight() {
+        let clock = FrameClock::new(2);
+        let tracker = UseTracker::new(clock.clone());
+        assert_eq!(tracker.in_flight_use(), None);
+
+        tracker.mark_used();
+        assert_eq!(tracker.in_flight_use(), Some(0));
+        clock.advance();
+        // Frame 0 may still be running while frame 1 is recorded
+        assert_eq!(tracker.in_flight_use(), Some(0));
+        clock.advance();
+        assert_eq!(tracker.in_flight_use(), None);
+    }
+
+    #[test]
+    fn waiting_the_device_completes_the_previous_frames() {
+        let clock = FrameClock::new(2);
+        let tracker = UseTracker::new(clock.clone());
+        tracker.mark_used();
+        clock.advance();
+        clock.device_idle();
+        assert_eq!(tracker.in_flight_use(), None);
+
+        // The current frame may still be recording
+        tracker.mark_used();
+        clock.device_idle();
+        assert_eq!(tracker.in_flight_use(), Some(1));
+    }
+}
@@ -1,5 +1,8 @@
-use engine::AppState;
-use log::trace;
+use std::time::Instant;
+
+use engine::{AppState, FrameStage};
+use gilrs::Gilrs;
+use log::{trace, warn};
 use winit::{
     dpi::PhysicalSize,
     event::Event,
@@ -22,12 +25,32 @@ pub trait App {
         app_state: &AppState,
         event: winit::event::DeviceEvent,
     ) -> anyhow::Result<()>;
+
+    // Called for each key event of the window, after app_state.keyboard has been updated
+    fn keyboard_input(
+        &mut self,
+        _app_state: &AppState,
+        _input: winit::event::KeyboardInput,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    // Called after the swapchain has been recreated with the new size of the window
+    fn on_resize(&mut self, _app_state: &AppState, _size: PhysicalSize<u32>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    // Called for each gamepad event, polled once per loop iteration
+    fn gamepad_input(&mut self, _app_state: &AppState, _event: gilrs::Event) -> anyhow::Result<()> {
+        Ok(())
+    }
     fn update(&mut self, app_state: &mut AppState) -> anyhow::Result<()>;
     fn draw(&mut self, app_state: &mut AppState) -> anyhow::Result<()>;
 }
 
 pub fn app_loop<A: App + 'static>(
     app: &mut A,
+    gilrs: Option<&mut Gilrs>,
     event: Event<'_, ()>,
 ) -> anyhow::Result<ControlFlow> {
     let app_state_mut = engine::app_state_mut();
@@ -38,12 +61,22 @@ pub fn app_loop<A: App + 'static>(
             winit::event::WindowEvent::CloseRequested => {
                 return Ok(ControlFlow::ExitWithCode(0));
             }
-            winit::event::WindowEvent::Resized(_) => {
-                app_state_mut
-                    .gpu
-                    .swapchain_mut()
-                    .recreate_swapchain()
-                    .unwrap();
+            winit::event::WindowEvent::Resized(size) => {
+                // A minimized window reports a 0x0 size: the swapchain is recreated
+                // once the window is restored
+                app_state_mut.set_window_minimized(size.width == 0 || size.height == 0);
+                if !app_state_mut.is_window_minimized() {
+                    app_state_mut.gpu.wait_device_idle()?;
+                    app_state_mut.gpu.swapchain_mut().recreate_swapchain()?;
+                    app.on_resize(app_state_mut, size)?;
+                }
+            }
+            winit::event::WindowEvent::KeyboardInput { input, .. } => {
+                app_state_mut.keyboard.update(&input);
+                app.keyboard_input(app_state_mut, input)?;
+            }
+            winit::event::WindowEvent::Focused(false) => {
+                app_state_mut.keyboard.release_all();
             }
             _ => {}
         },
@@ -54,9 +87,21 @@ pub fn app_loop<A: App + 'static>(
         winit::event::Event::Suspended => {}
         winit::event::Event::Resumed => {}
         winit::event::Event::MainEventsCleared => {
+            if let Some(gilrs) = gilrs {
+                while let Some(event) = gilrs.next_event() {
+                    app.gamepad_input(app_state_mut, event)?;
+                }
+            }
+            // Don't spin while minimized, the window reports when it's restored
+            if app_state_mut.is_window_minimized() {
+                return Ok(ControlFlow::Wait);
+            }
             app_state_mut.gpu.swapchain_mut().window.request_redraw();
         }
         winit::event::Event::RedrawRequested(..) => {
+            if app_state_mut.is_window_minimized() {
+                return Ok(ControlFlow::Wait);
+            }
             app_state_mut.begin_frame().unwrap();
 
             let window_name = app.window_name(app_state_mut);
@@ -67,8 +112,14 @@ pub fn app_loop<A: App + 'static>(
                 .window
                 .set_title(&window_name);
 
+            let update_start = Instant::now();
             app.update(app_state_mut)?;
+            app_state_mut.record_frame_stage(FrameStage::Update, update_start);
+
+            // The acquire and submit stages recorded by the app are subtracted from this
+            let draw_start = Instant::now();
             app.draw(app_state_mut)?;
+            app_state_mut.record_frame_stage(FrameStage::Record, draw_start);
             app_state_mut.end_frame().unwrap();
         }
         winit::event::Event::RedrawEventsCleared => {}
@@ -101,10 +152,20 @@ pub fn bootstrap<A: App + 'static>() -> anyhow::Result<()> {
 
     trace!("Created app");
 
-    event_loop.run(move |event, _, control_flow| match app_loop(app, event) {
-        Ok(flow) => {
-            *control_flow = flow;
+    let mut gilrs = match Gilrs::new() {
+        Ok(gilrs) => Some(gilrs),
+        Err(e) => {
+            warn!("Failed to initialize gamepad support: {}", e);
+            None
         }
-        Err(e) => panic!("In main body of application: {}", e),
-    })
+    };
+
+    event_loop.run(
+        move |event, _, control_flow| match app_loop(app, gilrs.as_mut(), event) {
+            Ok(flow) => {
+                *control_flow = flow;
+            }
+            Err(e) => panic!("In main body of application: {}", e),
+        },
+    )
 }
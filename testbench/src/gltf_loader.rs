@@ -1,21 +1,20 @@
 ﻿use crate::utils;
-use ash::vk::{
-    ComponentMapping, Filter, ImageAspectFlags, ImageSubresourceRange, ImageUsageFlags,
-    ImageViewType, SamplerAddressMode, SamplerCreateInfo,
-};
+use ash::vk::{Filter, ImageUsageFlags, SamplerAddressMode, SamplerMipmapMode};
 use engine::{
-    ImageResource, MasterMaterial, MaterialDescription, MaterialDomain, MaterialInstance,
-    MaterialInstanceDescription, MaterialParameterOffsetSize, Mesh, MeshCreateInfo,
-    MeshPrimitiveCreateInfo, RenderingPipeline, SamplerResource, Scene, ScenePrimitive, Texture,
-    TextureImageView, TextureInput,
+    reflect_shader, ImageResource, MasterMaterial, MaterialDescription, MaterialDomain,
+    MaterialInstance, MaterialInstanceDescription, Mesh, MeshCreateInfo, MeshPrimitiveCreateInfo,
+    RenderingPipeline, SamplerResource, SamplerSettings, Scene, SceneNodeHandle, ScenePrimitive,
+    Texture, TextureImageView, TextureInput,
 };
 use gltf::image::Data;
 use gltf::Document;
-use gpu::{Gpu, ImageCreateInfo, ImageViewCreateInfo, MemoryDomain, ToVk};
+use gpu::{Gpu, ImageCreateInfo, MemoryDomain, SampleCount, ToVk};
+use log::warn;
 use nalgebra::{vector, Matrix4, Quaternion, UnitQuaternion, Vector3, Vector4};
 use resource_map::{ResourceHandle, ResourceMap};
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::mem::size_of;
+use std::f32::consts::FRAC_PI_2;
 use std::path::Path;
 
 #[repr(C)]
@@ -30,7 +29,52 @@ pub struct GltfLoader {
     engine_scene: Scene,
 }
 
-pub struct GltfLoadOptions {}
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UpAxis {
+    // The glTF convention
+    #[default]
+    YUp,
+    ZUp,
+}
+
+pub struct GltfLoadOptions {
+    // Images larger than this size on any side are downscaled before being uploaded
+    pub max_texture_size: Option<u32>,
+    // Uniform scale applied to the whole scene
+    pub scale: f32,
+    // The up axis the asset was authored with: the scene is rotated to be Y up
+    pub up_axis: UpAxis,
+    // When true the glTF node tree is kept as a Scene node hierarchy,
+    // otherwise the world transforms are baked into the primitives
+    pub preserve_hierarchy: bool,
+    // Generates the mip chain of the images whose format can be blitted
+    pub generate_mipmaps: bool,
+    // Keeps the meshes' positions and indices on the CPU, see Mesh::cpu_positions
+    pub keep_cpu_data: bool,
+}
+
+impl Default for GltfLoadOptions {
+    fn default() -> Self {
+        Self {
+            max_texture_size: None,
+            scale: 1.0,
+            up_axis: UpAxis::default(),
+            preserve_hierarchy: false,
+            generate_mipmaps: true,
+            keep_cpu_data: false,
+        }
+    }
+}
+
+impl GltfLoadOptions {
+    fn root_transform(&self) -> Matrix4<f32> {
+        let up_conversion = match self.up_axis {
+            UpAxis::YUp => Matrix4::identity(),
+            UpAxis::ZUp => Matrix4::from_axis_angle(&Vector3::x_axis(), -FRAC_PI_2),
+        };
+        Matrix4::new_scaling(self.scale) * up_conversion
+    }
+}
 
 struct LoadedTextures {
     white: ResourceHandle<Texture>,
@@ -44,19 +88,20 @@ impl GltfLoader {
         gpu: &Gpu,
         scene_renderer: &mut R,
         resource_map: &mut ResourceMap,
-        _options: GltfLoadOptions,
+        options: GltfLoadOptions,
     ) -> anyhow::Result<Self> {
         let (document, buffers, mut images) = gltf::import(path)?;
 
         let pbr_master = Self::create_master_pbr_material(gpu, scene_renderer, resource_map)?;
-        let image_views = Self::load_images(gpu, resource_map, &mut images)?;
+        let image_views = Self::load_images(gpu, resource_map, &mut images, &options)?;
         let samplers = Self::load_samplers(gpu, resource_map, &document)?;
         let textures = Self::load_textures(gpu, resource_map, image_views, samplers, &document)?;
         let allocated_materials =
             Self::load_materials(gpu, resource_map, pbr_master, textures, &document)?;
-        let meshes = Self::load_meshes(gpu, resource_map, &document, &buffers)?;
+        let meshes = Self::load_meshes(gpu, resource_map, &document, &buffers, &options)?;
 
-        let engine_scene = Self::build_engine_scene(document, allocated_materials, meshes);
+        let engine_scene =
+            Self::build_engine_scene(document, allocated_materials, meshes, &options);
 
         Ok(Self { engine_scene })
     }
@@ -65,44 +110,92 @@ impl GltfLoader {
         document: Document,
         allocated_materials: Vec<ResourceHandle<MaterialInstance>>,
         meshes: Vec<ResourceHandle<Mesh>>,
+        options: &GltfLoadOptions,
     ) -> Scene {
+        let root_transform = options.root_transform();
         let mut engine_scene = Scene::new();
+        let root_node = if options.preserve_hierarchy {
+            Some(engine_scene.add_node(None, root_transform))
+        } else {
+            None
+        };
         for scene in document.scenes() {
             for node in scene.nodes() {
-                let node_transform = node.transform();
-                let (pos, rot, scale) = node_transform.decomposed();
-                let rotation = UnitQuaternion::from_quaternion(Quaternion::new(
-                    rot[0], rot[1], rot[2], rot[3],
-                ));
-                let rot_matrix = rotation.to_homogeneous();
-
-                let transform = Matrix4::new_translation(&Vector3::from_row_slice(&pos))
-                    * Matrix4::new_nonuniform_scaling(&Vector3::from_row_slice(&scale))
-                    * rot_matrix;
-
-                if let Some(mesh) = node.mesh() {
-                    let mut materials = vec![];
-                    for prim in mesh.primitives() {
-                        let material_index = prim.material().index().unwrap_or(0);
-                        let material = allocated_materials[material_index].clone();
-                        materials.push(material);
-                    }
-                    engine_scene.add(ScenePrimitive {
-                        mesh: meshes[mesh.index()].clone(),
-                        materials,
-                        transform,
-                    });
-                }
+                Self::add_node_to_scene(
+                    &mut engine_scene,
+                    &node,
+                    root_node,
+                    root_transform,
+                    &allocated_materials,
+                    &meshes,
+                );
             }
         }
         engine_scene
     }
 
+    // When parent_node is Some the node is added to the scene hierarchy,
+    // otherwise its world transform is baked into its primitive
+    fn add_node_to_scene(
+        engine_scene: &mut Scene,
+        node: &gltf::Node,
+        parent_node: Option<SceneNodeHandle>,
+        parent_transform: Matrix4<f32>,
+        allocated_materials: &[ResourceHandle<MaterialInstance>],
+        meshes: &[ResourceHandle<Mesh>],
+    ) {
+        let (pos, rot, scale) = node.transform().decomposed();
+        let rotation =
+            UnitQuaternion::from_quaternion(Quaternion::new(rot[0], rot[1], rot[2], rot[3]));
+        let rot_matrix = rotation.to_homogeneous();
+
+        let local_transform = Matrix4::new_translation(&Vector3::from_row_slice(&pos))
+            * Matrix4::new_nonuniform_scaling(&Vector3::from_row_slice(&scale))
+            * rot_matrix;
+        let transform = parent_transform * local_transform;
+        let scene_node =
+            parent_node.map(|parent| engine_scene.add_node(Some(parent), local_transform));
+
+        if let Some(mesh) = node.mesh() {
+            let mut materials = vec![];
+            for prim in mesh.primitives() {
+                let material_index = prim.material().index().unwrap_or(0);
+                let material = allocated_materials[material_index].clone();
+                materials.push(material);
+            }
+            let primitive = ScenePrimitive {
+                mesh: meshes[mesh.index()].clone(),
+                materials,
+                transform,
+            };
+            match scene_node {
+                Some(scene_node) => {
+                    engine_scene.add_to_node(scene_node, primitive);
+                }
+                None => {
+                    engine_scene.add(primitive);
+                }
+            }
+        }
+
+        for child in node.children() {
+            Self::add_node_to_scene(
+                engine_scene,
+                &child,
+                scene_node,
+                transform,
+                allocated_materials,
+                meshes,
+            );
+        }
+    }
+
     fn load_meshes(
         gpu: &Gpu,
         resource_map: &mut ResourceMap,
         document: &Document,
         buffers: &[gltf::buffer::Data],
+        options: &GltfLoadOptions,
     ) -> anyhow::Result<Vec<ResourceHandle<Mesh>>> {
         let mut meshes = vec![];
         for mesh in document.meshes() {
@@ -159,7 +252,10 @@ impl GltfLoader {
             let label = format!("Mesh #{}", mesh.index());
             let create_info = MeshCreateInfo {
                 label: Some(mesh.name().unwrap_or(&label)),
+                topology: gpu::PrimitiveTopology::TriangleList,
+                vertex_encoding: Default::default(),
                 primitives: &primitive_create_infos,
+                keep_cpu_data: options.keep_cpu_data,
             };
             let gpu_mesh = Mesh::new(gpu, &create_info)?;
             meshes.push(resource_map.add(gpu_mesh));
@@ -176,57 +272,48 @@ impl GltfLoader {
         let fragment_module =
             utils::read_file_to_vk_module(gpu, "./shaders/metallic_roughness_pbr.spirv")?;
 
-        let mut params = HashMap::new();
-        params.insert(
-            "base_color".to_owned(),
-            MaterialParameterOffsetSize {
-                offset: 0,
-                size: size_of::<Vector4<f32>>(),
-            },
-        );
-        params.insert(
-            "metallic_roughness".to_owned(),
-            MaterialParameterOffsetSize {
-                offset: size_of::<Vector4<f32>>(),
-                size: size_of::<Vector4<f32>>(),
-            },
-        );
-        params.insert(
-            "emissive_color".to_owned(),
-            MaterialParameterOffsetSize {
-                offset: size_of::<Vector4<f32>>() * 2,
-                size: size_of::<Vector4<f32>>(),
-            },
-        );
+        // Laid out by the PbrPropertiesBlock of the fragment shader, see PbrProperties
+        let params = reflect_shader(fragment_module.code())?.material_parameters();
         let pbr_master = scene_renderer.create_material(
             gpu,
             MaterialDescription {
                 name: "PbrMaterial",
                 domain: MaterialDomain::Surface,
+                topology: gpu::PrimitiveTopology::TriangleList,
+                vertex_encoding: Default::default(),
+                vertex_input: None,
+                stencil_state: None,
+                conservative_raster: false,
                 fragment_module: &fragment_module,
                 vertex_module: &vertex_module,
                 texture_inputs: &[
                     TextureInput {
                         name: "base_texture".to_owned(),
                         format: gpu::ImageFormat::Rgba8,
+                        binding: None,
                     },
                     TextureInput {
                         name: "normal_texture".to_owned(),
                         format: gpu::ImageFormat::Rgba8,
+                        binding: None,
                     },
                     TextureInput {
                         name: "occlusion_texture".to_owned(),
                         format: gpu::ImageFormat::Rgba8,
+                        binding: None,
                     },
                     TextureInput {
                         name: "emissive_texture".to_owned(),
                         format: gpu::ImageFormat::Rgba8,
+                        binding: None,
                     },
                     TextureInput {
                         name: "metallic_roughness".to_owned(),
                         format: gpu::ImageFormat::Rgba8,
+                        binding: None,
                     },
                 ],
+                texture_array_inputs: &[],
                 material_parameters: params,
             },
         )?;
@@ -238,43 +325,58 @@ impl GltfLoader {
         gpu: &Gpu,
         resource_map: &mut ResourceMap,
         images: &mut [Data],
+        options: &GltfLoadOptions,
     ) -> anyhow::Result<Vec<ResourceHandle<TextureImageView>>> {
         let mut allocated_images = vec![];
         let mut allocated_image_views = vec![];
         for (index, gltf_image) in images.iter_mut().enumerate() {
-            let vk_format = match gltf_image.format {
-                gltf::image::Format::R8G8B8A8 => gpu::ImageFormat::Rgba8.to_vk(),
-                gltf::image::Format::R8G8B8 => gpu::ImageFormat::Rgb8.to_vk(),
-                gltf::image::Format::R32G32B32A32FLOAT => gpu::ImageFormat::RgbaFloat.to_vk(),
-                f => panic!("Unsupported format! {:?}", f),
+            let (format, width, height, pixels) = match Self::convert_image(gltf_image) {
+                Some((format, pixels)) => (format, gltf_image.width, gltf_image.height, pixels),
+                None => {
+                    warn!(
+                        "glTF Image #{}: invalid {:?} image data, using a white texture instead",
+                        index, gltf_image.format
+                    );
+                    (
+                        gpu::ImageFormat::Rgba8,
+                        1,
+                        1,
+                        Cow::Owned(vec![255, 255, 255, 255]),
+                    )
+                }
+            };
+            let (width, height, pixels) = match options.max_texture_size {
+                Some(max_size) if width.max(height) > max_size => {
+                    Self::downscale_image(format, width, height, pixels, max_size)
+                }
+                _ => (width, height, pixels),
             };
+            let mip_levels =
+                if options.generate_mipmaps && gpu.supports_mipmap_blits(format.to_vk()) {
+                    Texture::mip_count(width, height)
+                } else {
+                    1
+                };
             let label = format!("glTF Image #{}", index);
             let image_create_info = ImageCreateInfo {
                 label: Some(&label),
-                width: gltf_image.width,
-                height: gltf_image.height,
-                format: vk_format,
-                usage: ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_DST,
+                width,
+                height,
+                format: format.to_vk(),
+                usage: ImageUsageFlags::SAMPLED
+                    | ImageUsageFlags::TRANSFER_SRC
+                    | ImageUsageFlags::TRANSFER_DST,
+                mip_levels,
+                sharing_mode: Default::default(),
+                samples: SampleCount::One,
             };
-            let gpu_image = gpu.create_image(
-                &image_create_info,
-                MemoryDomain::DeviceLocal,
-                Some(&gltf_image.pixels),
-            )?;
+            let gpu_image =
+                gpu.create_image(&image_create_info, MemoryDomain::DeviceLocal, Some(&pixels))?;
+            if mip_levels > 1 {
+                gpu.generate_mipmaps(&gpu_image)?;
+            }
 
-            let gpu_image_view = gpu.create_image_view(&ImageViewCreateInfo {
-                image: &gpu_image,
-                view_type: ImageViewType::TYPE_2D,
-                format: vk_format,
-                components: ComponentMapping::default(),
-                subresource_range: ImageSubresourceRange {
-                    aspect_mask: ImageAspectFlags::COLOR,
-                    base_mip_level: 0,
-                    level_count: 1,
-                    base_array_layer: 0,
-                    layer_count: 1,
-                },
-            })?;
+            let gpu_image_view = gpu.create_default_view(&gpu_image)?;
             let img_index = resource_map.add(ImageResource(gpu_image));
             allocated_images.push(img_index.clone());
             let view_index = resource_map.add(TextureImageView {
@@ -286,6 +388,108 @@ impl GltfLoader {
         Ok(allocated_image_views)
     }
 
+    // Converts the image data to a format that can be uploaded to the gpu:
+    // returns None if the image data doesn't match its format and size
+    fn convert_image(image: &Data) -> Option<(gpu::ImageFormat, Cow<'_, [u8]>)> {
+        use gltf::image::Format;
+        let (channels, channel_size) = match image.format {
+            Format::R8 => (1, 1),
+            Format::R8G8 => (2, 1),
+            Format::R8G8B8 => (3, 1),
+            Format::R8G8B8A8 => (4, 1),
+            Format::R16 => (1, 2),
+            Format::R16G16 => (2, 2),
+            Format::R16G16B16 => (3, 2),
+            Format::R16G16B16A16 => (4, 2),
+            Format::R32G32B32FLOAT => (3, 4),
+            Format::R32G32B32A32FLOAT => (4, 4),
+        };
+        let pixel_size = channels * channel_size;
+        if image.pixels.len() != image.width as usize * image.height as usize * pixel_size {
+            return None;
+        }
+
+        let converted = match image.format {
+            Format::R8G8B8A8 => (gpu::ImageFormat::Rgba8, Cow::Borrowed(image.pixels.as_slice())),
+            Format::R8G8B8 => (gpu::ImageFormat::Rgb8, Cow::Borrowed(image.pixels.as_slice())),
+            Format::R32G32B32A32FLOAT => (
+                gpu::ImageFormat::RgbaFloat,
+                Cow::Borrowed(image.pixels.as_slice()),
+            ),
+            Format::R32G32B32FLOAT => {
+                let mut rgba = Vec::with_capacity(image.pixels.len() / 3 * 4);
+                for pixel in image.pixels.chunks(pixel_size) {
+                    rgba.extend_from_slice(pixel);
+                    rgba.extend_from_slice(&1.0f32.to_ne_bytes());
+                }
+                (gpu::ImageFormat::RgbaFloat, Cow::Owned(rgba))
+            }
+            _ => {
+                // Single and two channels images are treated as luminance (+ alpha),
+                // 16 bit channels are truncated to their most significant byte
+                let mut rgba = Vec::with_capacity(image.pixels.len() / pixel_size * 4);
+                for pixel in image.pixels.chunks(pixel_size) {
+                    let channel = |i: usize| pixel[i * channel_size + channel_size - 1];
+                    let texel = match channels {
+                        1 => [channel(0), channel(0), channel(0), 255],
+                        2 => [channel(0), channel(0), channel(0), channel(1)],
+                        3 => [channel(0), channel(1), channel(2), 255],
+                        _ => [channel(0), channel(1), channel(2), channel(3)],
+                    };
+                    rgba.extend_from_slice(&texel);
+                }
+                (gpu::ImageFormat::Rgba8, Cow::Owned(rgba))
+            }
+        };
+        Some(converted)
+    }
+
+    // Resizes the image so that its largest side is max_size, keeping the aspect ratio
+    fn downscale_image(
+        format: gpu::ImageFormat,
+        width: u32,
+        height: u32,
+        pixels: Cow<'_, [u8]>,
+        max_size: u32,
+    ) -> (u32, u32, Cow<'_, [u8]>) {
+        let scale = max_size as f32 / width.max(height) as f32;
+        let new_width = ((width as f32 * scale).round() as u32).max(1);
+        let new_height = ((height as f32 * scale).round() as u32).max(1);
+        let filter = image::imageops::FilterType::Lanczos3;
+
+        let resized = match format {
+            gpu::ImageFormat::Rgba8 => image::RgbaImage::from_raw(width, height, pixels.to_vec())
+                .map(|img| image::imageops::resize(&img, new_width, new_height, filter).into_raw()),
+            gpu::ImageFormat::Rgb8 => image::RgbImage::from_raw(width, height, pixels.to_vec())
+                .map(|img| image::imageops::resize(&img, new_width, new_height, filter).into_raw()),
+            gpu::ImageFormat::RgbaFloat => {
+                let floats = pixels
+                    .chunks(4)
+                    .map(|c| f32::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+                    .collect();
+                image::Rgba32FImage::from_raw(width, height, floats).map(|img| {
+                    image::imageops::resize(&img, new_width, new_height, filter)
+                        .into_raw()
+                        .into_iter()
+                        .flat_map(f32::to_ne_bytes)
+                        .collect()
+                })
+            }
+            _ => None,
+        };
+
+        match resized {
+            Some(resized) => (new_width, new_height, Cow::Owned(resized)),
+            None => {
+                warn!(
+                    "Could not downscale a {}x{} {:?} image, uploading it at full size",
+                    width, height, format
+                );
+                (width, height, pixels)
+            }
+        }
+    }
+
     fn load_textures(
         gpu: &Gpu,
         resource_map: &mut ResourceMap,
@@ -306,6 +510,7 @@ impl GltfLoader {
             1,
             1,
             &[255, 255, 255, 255],
+            &SamplerSettings::default(),
             Some("White texture"),
         )?;
         let white = resource_map.add(white);
@@ -315,6 +520,7 @@ impl GltfLoader {
             1,
             1,
             &[0, 0, 0, 255],
+            &SamplerSettings::default(),
             Some("Black texture"),
         )?;
         let black = resource_map.add(black);
@@ -333,55 +539,47 @@ impl GltfLoader {
     ) -> anyhow::Result<Vec<ResourceHandle<SamplerResource>>> {
         let mut allocated_samplers = vec![];
         for sampler in document.samplers() {
-            let builder = SamplerCreateInfo::builder()
-                .address_mode_u(match &sampler.wrap_s() {
-                    gltf::texture::WrappingMode::ClampToEdge => SamplerAddressMode::CLAMP_TO_EDGE,
-                    gltf::texture::WrappingMode::MirroredRepeat => {
-                        SamplerAddressMode::MIRRORED_REPEAT
+            let address_mode = |mode: gltf::texture::WrappingMode| match mode {
+                gltf::texture::WrappingMode::ClampToEdge => SamplerAddressMode::CLAMP_TO_EDGE,
+                gltf::texture::WrappingMode::MirroredRepeat => SamplerAddressMode::MIRRORED_REPEAT,
+                gltf::texture::WrappingMode::Repeat => SamplerAddressMode::REPEAT,
+            };
+            let mut settings = SamplerSettings {
+                address_mode_u: address_mode(sampler.wrap_s()),
+                address_mode_v: address_mode(sampler.wrap_t()),
+                ..Default::default()
+            };
+            // Unspecified filters are left to the default trilinear filtering
+            if let Some(mag_filter) = sampler.mag_filter() {
+                settings.mag_filter = match mag_filter {
+                    gltf::texture::MagFilter::Nearest => Filter::NEAREST,
+                    gltf::texture::MagFilter::Linear => Filter::LINEAR,
+                };
+            }
+            if let Some(min_filter) = sampler.min_filter() {
+                use gltf::texture::MinFilter;
+                (settings.min_filter, settings.mipmap_mode) = match min_filter {
+                    MinFilter::Nearest | MinFilter::NearestMipmapNearest => {
+                        (Filter::NEAREST, SamplerMipmapMode::NEAREST)
                     }
-                    gltf::texture::WrappingMode::Repeat => SamplerAddressMode::REPEAT,
-                })
-                .address_mode_v(match &sampler.wrap_t() {
-                    gltf::texture::WrappingMode::ClampToEdge => SamplerAddressMode::CLAMP_TO_EDGE,
-                    gltf::texture::WrappingMode::MirroredRepeat => {
-                        SamplerAddressMode::MIRRORED_REPEAT
+                    MinFilter::Linear | MinFilter::LinearMipmapNearest => {
+                        (Filter::LINEAR, SamplerMipmapMode::NEAREST)
                     }
-                    gltf::texture::WrappingMode::Repeat => SamplerAddressMode::REPEAT,
-                })
-                .mag_filter(
-                    match sampler
-                        .mag_filter()
-                        .unwrap_or(gltf::texture::MagFilter::Nearest)
-                    {
-                        gltf::texture::MagFilter::Nearest => Filter::NEAREST,
-                        gltf::texture::MagFilter::Linear => Filter::LINEAR,
-                    },
-                )
-                .min_filter(
-                    match sampler
-                        .min_filter()
-                        .unwrap_or(gltf::texture::MinFilter::Nearest)
-                    {
-                        gltf::texture::MinFilter::Nearest => Filter::NEAREST,
-                        gltf::texture::MinFilter::Linear => Filter::LINEAR,
-                        x => {
-                            log::warn!("glTF: unsupported filter! {:?}", x);
-                            Filter::LINEAR
-                        }
-                    },
-                );
-            let sam = gpu.create_sampler(&builder.build())?;
+                    MinFilter::NearestMipmapLinear => (Filter::NEAREST, SamplerMipmapMode::LINEAR),
+                    MinFilter::LinearMipmapLinear => (Filter::LINEAR, SamplerMipmapMode::LINEAR),
+                };
+                // The filters without a mipmap mode only sample the first mip
+                if matches!(min_filter, MinFilter::Nearest | MinFilter::Linear) {
+                    settings.max_mip = Some(0);
+                }
+            }
+            let sam = Texture::create_sampler(gpu, &settings)?;
             allocated_samplers.push(resource_map.add(SamplerResource(sam)))
         }
 
         if allocated_samplers.is_empty() {
             // add default sampler
-            let builder = SamplerCreateInfo::builder()
-                .address_mode_u(SamplerAddressMode::REPEAT)
-                .address_mode_v(SamplerAddressMode::REPEAT)
-                .mag_filter(Filter::LINEAR)
-                .min_filter(Filter::LINEAR);
-            let sam = gpu.create_sampler(&builder.build())?;
+            let sam = Texture::create_sampler(gpu, &SamplerSettings::default())?;
             allocated_samplers.push(resource_map.add(SamplerResource(sam)))
         }
 
@@ -449,6 +647,7 @@ impl GltfLoader {
                         gltf_material.index().unwrap_or(0)
                     ),
                     texture_inputs,
+                    texture_array_inputs: HashMap::new(),
                 },
             )?;
             let metallic = gltf_material.pbr_metallic_roughness().metallic_factor();
@@ -7,22 +7,26 @@ use ash::vk::{
     AccessFlags, DependencyFlags, ImageAspectFlags, ImageSubresourceRange,
     PipelineStageFlags, PresentModeKHR,
 };
-use ash::vk::{ImageLayout, Rect2D};
+use ash::vk::{Extent2D, ImageLayout, Rect2D};
 
 use gpu::ColorAttachment;
 use gpu::CommandBufferSubmitInfo;
+use gpu::Gpu;
+use gpu::PresentModeSelection;
 use gpu::{BeginRenderPassInfo, ImageMemoryBarrier, PipelineBarrierInfo};
 use imgui::*;
 use imgui_rs_vulkan_renderer::{DynamicRendering as ImguiDynamicRendering, *};
 use imgui_winit_support::{HiDpiMode, WinitPlatform};
 
 use crate::gltf_loader::{GltfLoadOptions, GltfLoader};
-use engine::{AppState, Backbuffer, Camera, DeferredRenderingPipeline, FxaaSettings, Light, LightType, RenderingPipeline, Scene};
+use engine::{reflect_shader, AppState, Backbuffer, Camera, DebugView, DeferredRenderingPipeline, DofParams, FrameStage, FxaaSettings, Light, LightType, MaterialDescription, MaterialDomain, MaterialInstance, MaterialInstanceDescription, MaterialParameterArena, Mesh, MeshCreateInfo, MeshPrimitiveCreateInfo, PlanarReflection, ReflectionPlane, RenderingPipeline, Scene, ScenePrimitive, SsrSettings, Texture, TextureInput};
 use nalgebra::*;
-use resource_map::ResourceMap;
+use resource_map::{ResourceHandle, ResourceMap};
 use winit::event::{ElementState, Event};
 use winit::event::VirtualKeyCode;
 use winit::event_loop::EventLoop;
+use std::collections::HashMap;
+use std::time::Instant;
 
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -46,6 +50,11 @@ pub struct GLTFViewer {
     movement: Vector3<f32>,
     scene_renderer: DeferredRenderingPipeline,
     gltf_loader: GltfLoader,
+    // The floor under the model reflects the scene
+    reflection: PlanarReflection,
+    mirror_floor: ResourceHandle<MaterialInstance>,
+    // Applied in update, the swapchain can't be recreated while a frame is being drawn
+    vsync: bool,
 
     imgui: Context,
     platform: WinitPlatform,
@@ -79,37 +88,40 @@ impl App for GLTFViewer {
 
         let movement: Vector3<f32> = vector![0.0, 0.0, 0.0];
 
-        let screen_quad_module =
-            utils::read_file_to_vk_module(&app_state.gpu, "./shaders/screen_quad.spirv")?;
-        let gbuffer_combine_module =
-            utils::read_file_to_vk_module(&app_state.gpu, "./shaders/gbuffer_combine.spirv")?;
-        let texture_copy_module =
-            utils::read_file_to_vk_module(&app_state.gpu, "./shaders/texture_copy.spirv")?;
-        let tonemap_module =
-            utils::read_file_to_vk_module(&app_state.gpu, "./shaders/tonemap.spirv")?;
-
-        let mut scene_renderer = DeferredRenderingPipeline::new(
-            &app_state.gpu,
-            screen_quad_module,
-            gbuffer_combine_module,
-            texture_copy_module,
-            tonemap_module,
-        )?;
+        let mut scene_renderer = create_scene_renderer(&app_state.gpu)?;
 
         let mut gltf_loader = GltfLoader::load(
             "gltf_models/bottle/glTF/WaterBottle.gltf",
             &app_state.gpu,
             &mut scene_renderer,
             &mut resource_map,
-            GltfLoadOptions {},
+            GltfLoadOptions::default(),
         )?;
 
         add_scene_lights(gltf_loader.scene_mut());
 
-        engine::app_state_mut()
+        let reflection = PlanarReflection::new(
+            &app_state.gpu,
+            &mut resource_map,
+            create_scene_renderer(&app_state.gpu)?,
+            Extent2D {
+                width: 1024,
+                height: 1024,
+            },
+        )?;
+        let mirror_floor = add_mirror_floor(
+            &app_state.gpu,
+            &mut scene_renderer,
+            &mut resource_map,
+            gltf_loader.scene_mut(),
+            reflection.texture(),
+        )?;
+
+        let selection = engine::app_state_mut()
             .gpu
             .swapchain_mut()
             .select_present_mode(PresentModeKHR::IMMEDIATE)?;
+        let vsync = selection == PresentModeSelection::FallbackToFifo;
 
         let mut imgui = Context::create();
         let mut platform = WinitPlatform::init(&mut imgui);
@@ -139,7 +151,7 @@ impl App for GLTFViewer {
             },
             &mut imgui,
             Some(Options {
-                in_flight_frames: 2,
+                in_flight_frames: app_state.gpu.frames_in_flight(),
                 ..Default::default()
             }),
         )?;
@@ -154,6 +166,9 @@ impl App for GLTFViewer {
             movement,
             scene_renderer,
             gltf_loader,
+            reflection,
+            mirror_floor,
+            vsync,
             imgui,
             renderer,
             platform,
@@ -167,7 +182,7 @@ impl App for GLTFViewer {
     
     fn input(
         &mut self,
-        _app_state: &AppState,
+        app_state: &AppState,
         event: winit::event::DeviceEvent,
     ) -> anyhow::Result<()> {
         match event {
@@ -208,6 +223,10 @@ impl App for GLTFViewer {
                 {
                     self.scene_renderer
                         .set_fxaa_settings_mut(FxaaSettings::default());
+                } else if input.virtual_keycode == Some(VirtualKeyCode::F11)
+                    && input.state == ElementState::Pressed
+                {
+                    app_state.set_fullscreen(!app_state.is_fullscreen());
                 }
             }
 
@@ -224,7 +243,22 @@ impl App for GLTFViewer {
         Ok(())
     }
 
-    fn update(&mut self, _app_state: &mut AppState) -> anyhow::Result<()> {
+    fn update(&mut self, app_state: &mut AppState) -> anyhow::Result<()> {
+        let present_mode = if self.vsync {
+            PresentModeKHR::FIFO
+        } else {
+            PresentModeKHR::IMMEDIATE
+        };
+        if app_state.gpu.swapchain().present_mode() != present_mode {
+            app_state.gpu.wait_device_idle()?;
+            let swapchain = app_state.gpu.swapchain_mut();
+            let selection = swapchain.select_present_mode(present_mode)?;
+            self.vsync |= selection == PresentModeSelection::FallbackToFifo;
+        }
+        self.gltf_loader
+            .scene_mut()
+            .update_particle_systems(app_state.time().delta_frame());
+
         if self.rotation_movement > 0.0 {
             self.rot_y += self.movement.x;
             self.rot_x += -self.movement.y;
@@ -263,8 +297,10 @@ impl App for GLTFViewer {
         
         let swapchain_format = app_state.gpu.swapchain().present_format();
         let swapchain_extents = app_state.gpu.swapchain().extents();
+        let acquire_start = Instant::now();
         let (swapchain_image, swapchain_image_view) =
             app_state.gpu.swapchain_mut().acquire_next_image()?;
+        app_state.record_frame_stage(FrameStage::Acquire, acquire_start);
         
         
         let mut settings = self.scene_renderer.fxaa_settings();
@@ -274,7 +310,64 @@ impl App for GLTFViewer {
         ui.slider("FXAA Edge Threshold", 0.0, 1.0, &mut settings.fxaa_quality_edge_threshold);
         ui.slider("FXAA Edge Threshold min", 0.0, 1.0, &mut settings.fxaa_quality_edge_threshold_min);
         self.scene_renderer.set_fxaa_settings_mut(settings);
-        
+
+        let mut draw_bounds = self.scene_renderer.draw_bounds();
+        ui.checkbox("VSync", &mut self.vsync);
+        if ui.checkbox("Draw bounds", &mut draw_bounds) {
+            self.scene_renderer.set_draw_bounds(draw_bounds);
+        }
+
+        let mut nan_check = self.scene_renderer.debug_view() == DebugView::NanCheck;
+        if ui.checkbox("Show NaNs", &mut nan_check) {
+            self.scene_renderer.set_debug_view(if nan_check {
+                DebugView::NanCheck
+            } else {
+                DebugView::None
+            });
+        }
+
+        let mut dof_enabled = self.scene_renderer.dof().is_some();
+        let mut dof = self.scene_renderer.dof().unwrap_or_default();
+        ui.checkbox("Depth of field", &mut dof_enabled);
+        if dof_enabled {
+            ui.slider("Focus distance", 0.1, 100.0, &mut dof.focus_distance);
+            ui.slider("Aperture", 0.0, 2.0, &mut dof.aperture);
+            ui.slider("Max blur radius", 0.0, DofParams::MAX_BLUR_RADIUS, &mut dof.max_blur_radius);
+        }
+        self.scene_renderer.set_dof(dof_enabled.then_some(dof));
+
+        let mut ssr_enabled = self.scene_renderer.ssr_enabled();
+        if ui.checkbox("Screen space reflections", &mut ssr_enabled) {
+            self.scene_renderer.set_ssr_enabled(ssr_enabled);
+        }
+        if ssr_enabled {
+            let mut ssr = self.scene_renderer.ssr_settings();
+            ui.slider("SSR steps", 1, SsrSettings::MAX_STEPS, &mut ssr.max_steps);
+            ui.slider("SSR step size", 0.01, 1.0, &mut ssr.step_size);
+            ui.slider("SSR thickness", 0.01, 1.0, &mut ssr.thickness);
+            ui.slider("SSR max roughness", 0.0, 1.0, &mut ssr.max_roughness);
+            self.scene_renderer.set_ssr_settings(ssr);
+        }
+
+        let timings = self.scene_renderer.timings();
+        for pass in &timings.passes {
+            ui.text(format!("{}: {:.3} ms", pass.name, pass.milliseconds));
+        }
+        ui.text(format!("GPU total: {:.3} ms", timings.total()));
+
+        // The reflection is sampled by the mirror floor, so it's submitted before the frame
+        let reflection_commands = self.reflection.render(
+            &self.camera,
+            self.gltf_loader.scene(),
+            &self.resource_map,
+            &self.mirror_floor,
+        )?;
+        reflection_commands.submit(&CommandBufferSubmitInfo::default())?;
+        // Uploaded into this frame's copy of the arena when the scene renderer flushes it
+        self.resource_map
+            .get(&self.mirror_floor)
+            .write_parameters_batched(&self.resource_map, self.reflection.view_projection())?;
+
         let mut command_buffer = self.scene_renderer.render(
             &self.camera,
             self.gltf_loader.scene(),
@@ -300,6 +393,7 @@ impl App for GLTFViewer {
                 load_op: gpu::ColorLoadOp::Load,
                 store_op: gpu::AttachmentStoreOp::Store,
                 initial_layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                resolve: None,
             }];
             let render_imgui = command_buffer.begin_render_pass(&BeginRenderPassInfo {
                 color_attachments: &color,
@@ -336,6 +430,7 @@ impl App for GLTFViewer {
                 },
             }],
         });
+        let submit_start = Instant::now();
         let frame = app_state.gpu.get_current_swapchain_frame();
         command_buffer.submit(&CommandBufferSubmitInfo {
             wait_semaphores: &[&frame.image_available_semaphore],
@@ -343,6 +438,7 @@ impl App for GLTFViewer {
             signal_semaphores: &[&frame.render_finished_semaphore],
             fence: Some(&frame.in_flight_fence),
         })?;
+        app_state.record_frame_stage(FrameStage::Submit, submit_start);
         Ok(())
     }
 }
@@ -355,6 +451,7 @@ fn add_scene_lights(scene: &mut Scene) {
         color: vector![1.0, 0.0, 0.0],
         intensity: 1.0,
         enabled: true,
+        ..Default::default()
     });
     scene.add_light(Light {
         ty: LightType::Directional {
@@ -365,7 +462,134 @@ fn add_scene_lights(scene: &mut Scene) {
         color: vector![1.0, 1.0, 1.0],
         intensity: 1.0,
         enabled: true,
+        casts_shadows: true,
+        ..Default::default()
+    });
+}
+
+fn create_scene_renderer(gpu: &Gpu) -> anyhow::Result<DeferredRenderingPipeline> {
+    let screen_quad_module = utils::read_file_to_vk_module(gpu, "./shaders/screen_quad.spirv")?;
+    let gbuffer_combine_module =
+        utils::read_file_to_vk_module(gpu, "./shaders/gbuffer_combine.spirv")?;
+    let texture_copy_module = utils::read_file_to_vk_module(gpu, "./shaders/texture_copy.spirv")?;
+    let tonemap_module = utils::read_file_to_vk_module(gpu, "./shaders/tonemap.spirv")?;
+
+    DeferredRenderingPipeline::new(
+        gpu,
+        screen_quad_module,
+        gbuffer_combine_module,
+        texture_copy_module,
+        tonemap_module,
+    )
+}
+
+/* Adds a floor right under the scene, drawn with planar_reflection.frag:
+ * it shows the scene mirrored by the PlanarReflection rendering into reflection */
+fn add_mirror_floor(
+    gpu: &Gpu,
+    scene_renderer: &mut DeferredRenderingPipeline,
+    resource_map: &mut ResourceMap,
+    scene: &mut Scene,
+    reflection: &ResourceHandle<Texture>,
+) -> anyhow::Result<ResourceHandle<MaterialInstance>> {
+    let bounds = scene
+        .all_primitives()
+        .iter()
+        .map(|primitive| {
+            let mesh = resource_map.get(&primitive.mesh);
+            mesh.bounds.transformed(&primitive.transform)
+        })
+        .reduce(|bounds, other| bounds.union(&other))
+        .unwrap_or_default();
+    let height = bounds.min.y;
+    let half_size = bounds.extents().norm().max(0.5) * 2.0;
+
+    let vertex_module = utils::read_file_to_vk_module(gpu, "./shaders/vertex_deferred.spirv")?;
+    let fragment_module = utils::read_file_to_vk_module(gpu, "./shaders/planar_reflection.spirv")?;
+    // Laid out by the ReflectionParameters block of the fragment shader
+    let params = reflect_shader(fragment_module.code())?.material_parameters();
+    let master = scene_renderer.create_material(
+        gpu,
+        MaterialDescription {
+            name: "MirrorMaterial",
+            domain: MaterialDomain::Surface,
+            topology: gpu::PrimitiveTopology::TriangleList,
+            vertex_encoding: Default::default(),
+            vertex_input: None,
+            stencil_state: None,
+            conservative_raster: false,
+            fragment_module: &fragment_module,
+            vertex_module: &vertex_module,
+            texture_inputs: &[TextureInput {
+                name: "reflectionSampler".to_owned(),
+                format: gpu::ImageFormat::Rgba8,
+                binding: None,
+            }],
+            texture_array_inputs: &[],
+            material_parameters: params,
+        },
+    )?;
+    let master = resource_map.add(master);
+
+    let mut texture_inputs = HashMap::new();
+    texture_inputs.insert("reflectionSampler".to_owned(), reflection.clone());
+    // The view projection changes every frame, so it's written in an arena holding a block per frame
+    let parameters = MaterialParameterArena::new(
+        gpu,
+        std::mem::size_of::<Matrix4<f32>>(),
+        Some("Mirror floor parameters"),
+    )?;
+    let parameters = resource_map.add(parameters);
+    let mut mirror = MaterialInstance::create_instance_in_arena(
+        gpu,
+        master,
+        resource_map,
+        &parameters,
+        &MaterialInstanceDescription {
+            name: "Mirror floor",
+            texture_inputs,
+            texture_array_inputs: HashMap::new(),
+        },
+    )?;
+    mirror.set_reflection_plane(Some(ReflectionPlane::new(
+        point![0.0, height, 0.0],
+        Vector3::y(),
+    )));
+    let mirror = resource_map.add(mirror);
+
+    let floor = Mesh::new(
+        gpu,
+        &MeshCreateInfo {
+            label: Some("Mirror floor"),
+            topology: gpu::PrimitiveTopology::TriangleList,
+            vertex_encoding: Default::default(),
+            primitives: &[MeshPrimitiveCreateInfo {
+                indices: vec![0, 1, 2, 2, 3, 0],
+                positions: vec![
+                    vector![-half_size, height, -half_size],
+                    vector![-half_size, height, half_size],
+                    vector![half_size, height, half_size],
+                    vector![half_size, height, -half_size],
+                ],
+                colors: vec![Vector3::repeat(1.0); 4],
+                normals: vec![Vector3::y(); 4],
+                tangents: vec![Vector3::x(); 4],
+                uvs: vec![
+                    vector![0.0, 0.0],
+                    vector![0.0, 1.0],
+                    vector![1.0, 1.0],
+                    vector![1.0, 0.0],
+                ],
+            }],
+            keep_cpu_data: false,
+        },
+    )?;
+    scene.add(ScenePrimitive {
+        mesh: resource_map.add(floor),
+        materials: vec![mirror.clone()],
+        transform: Matrix4::identity(),
     });
+    Ok(mirror)
 }
 
 fn main() -> anyhow::Result<()> {
@@ -7,7 +7,7 @@ use std::io::BufReader;
 use app::{bootstrap, App};
 use ash::vk::PresentModeKHR;
 
-use engine::{Backbuffer, Camera, DeferredRenderingPipeline, MaterialDescription, MaterialDomain, MaterialInstance, MaterialInstanceDescription, Mesh, MeshCreateInfo, MeshPrimitiveCreateInfo, RenderingPipeline, Scene, ScenePrimitive, Texture, TextureInput};
+use engine::{Backbuffer, Camera, DeferredRenderingPipeline, MaterialDescription, MaterialDomain, MaterialInstance, MaterialInstanceDescription, Mesh, MeshCreateInfo, MeshPrimitiveCreateInfo, RenderingPipeline, SamplerSettings, Scene, ScenePrimitive, Texture, TextureInput};
 use nalgebra::*;
 use resource_map::ResourceMap;
 use winit::{event::ElementState, event_loop::EventLoop};
@@ -72,6 +72,8 @@ impl App for PlanesApp {
 
         let mesh_data = MeshCreateInfo {
             label: Some("Quad mesh"),
+            topology: gpu::PrimitiveTopology::TriangleList,
+            vertex_encoding: Default::default(),
             primitives: &[MeshPrimitiveCreateInfo {
                 indices: vec![0, 1, 2, 2, 3, 0],
                 positions: vec![
@@ -105,6 +107,7 @@ impl App for PlanesApp {
                     vector![1.0, 1.0],
                 ],
             }],
+            keep_cpu_data: false,
         };
 
         let mesh = Mesh::new(&app_state.gpu, &mesh_data)?;
@@ -116,6 +119,7 @@ impl App for PlanesApp {
             cpu_image.width(),
             cpu_image.height(),
             &cpu_image,
+            &SamplerSettings::default(),
             Some("Quad texture david"),
         )?;
         let texture = resource_map.add(texture);
@@ -142,12 +146,19 @@ impl App for PlanesApp {
             MaterialDescription {
                 name: "Simple",
                 domain: MaterialDomain::Surface,
+                topology: gpu::PrimitiveTopology::TriangleList,
+                vertex_encoding: Default::default(),
+                vertex_input: None,
+                stencil_state: None,
+                conservative_raster: false,
                 fragment_module: &fragment_module,
                 vertex_module: &vertex_module,
                 texture_inputs: &[TextureInput {
                     name: "texSampler".to_owned(),
                     format: gpu::ImageFormat::Rgba8,
+                    binding: None,
                 }],
+                texture_array_inputs: &[],
                 material_parameters: Default::default(),
             },
         )?;
@@ -162,6 +173,7 @@ impl App for PlanesApp {
             &MaterialInstanceDescription {
                 name: "simple inst",
                 texture_inputs,
+                texture_array_inputs: HashMap::new(),
             },
         )?;
         let mat_instance = resource_map.add(mat_instance);
@@ -21,6 +21,44 @@ layout(set = 0, binding = 6, std140) readonly buffer LightData {
     LightInfo lights[];
 } light_data;
 
+// Sampled with a comparison sampler: each lookup returns how much the position is lit
+layout(set = 0, binding = 7) uniform sampler2DShadow shadowAtlas;
+
+// Must match CombineParams in static_deferred_renderer.rs
+layout(push_constant) uniform CombineParams {
+    uint mode;
+    // The kernel size with PCF, the number of disk samples with Poisson
+    uint samples;
+    // The disk radius in texels
+    float radius;
+    uint debug_view;
+} combine_params;
+
+const float SHADOW_BIAS = 0.005;
+const uint SHADOW_FILTER_HARD = 0;
+const uint SHADOW_FILTER_PCF = 1;
+const uint SHADOW_FILTER_POISSON = 2;
+const uint DEBUG_VIEW_NAN_CHECK = 1;
+
+const vec2 POISSON_DISK[16] = vec2[](
+    vec2(-0.94201624, -0.39906216),
+    vec2(0.94558609, -0.76890725),
+    vec2(-0.09418410, -0.92938870),
+    vec2(0.34495938, 0.29387760),
+    vec2(-0.91588581, 0.45771432),
+    vec2(-0.81544232, -0.87912464),
+    vec2(-0.38277543, 0.27676845),
+    vec2(0.97484398, 0.75648379),
+    vec2(0.44323325, -0.97511554),
+    vec2(0.53742981, -0.47373420),
+    vec2(-0.26496911, -0.41893023),
+    vec2(0.79197514, 0.19090188),
+    vec2(-0.24188840, 0.99706507),
+    vec2(-0.81409955, 0.91437590),
+    vec2(0.19984126, 0.78641367),
+    vec2(0.14383161, -0.14100790)
+);
+
 struct FragmentInfo {
     vec3 diffuse;
     vec4 emissive;
@@ -30,6 +68,51 @@ struct FragmentInfo {
     float metalness;
 };
 
+float get_shadow_factor(LightInfo info, vec3 position, float view_depth) {
+    if (info.shadow_map_count == 0) {
+        return 1.0;
+    }
+    uint cascade = 0;
+    while (cascade < info.shadow_map_count - 1 && view_depth > info.cascade_splits[cascade]) {
+        cascade ++;
+    }
+    vec4 light_space = info.shadow_view_projections[cascade] * vec4(position, 1.0);
+    vec3 ndc = light_space.xyz / light_space.w;
+    vec2 shadow_uv = ndc.xy * 0.5 + 0.5;
+    if (any(lessThan(shadow_uv, vec2(0.0))) || any(greaterThan(shadow_uv, vec2(1.0))) || ndc.z > 1.0) {
+        return 1.0;
+    }
+    vec4 shadow_rect = info.shadow_rects[cascade];
+    float depth = ndc.z - SHADOW_BIAS;
+    vec2 texel = 1.0 / vec2(textureSize(shadowAtlas, 0));
+    vec2 center = shadow_rect.xy + shadow_uv * shadow_rect.zw;
+    // Keep the samples inside the tile, otherwise they'd read the neighbouring shadow maps
+    vec2 tile_min = shadow_rect.xy + texel * 0.5;
+    vec2 tile_max = shadow_rect.xy + shadow_rect.zw - texel * 0.5;
+
+    if (combine_params.mode == SHADOW_FILTER_PCF) {
+        int half_kernel = int(combine_params.samples) / 2;
+        float lit = 0.0;
+        for (int x = -half_kernel; x <= half_kernel; x ++) {
+            for (int y = -half_kernel; y <= half_kernel; y ++) {
+                vec2 sample_uv = clamp(center + vec2(x, y) * texel, tile_min, tile_max);
+                lit += texture(shadowAtlas, vec3(sample_uv, depth));
+            }
+        }
+        float kernel_size = float(half_kernel * 2 + 1);
+        return lit / (kernel_size * kernel_size);
+    } else if (combine_params.mode == SHADOW_FILTER_POISSON) {
+        uint samples = min(combine_params.samples, 16u);
+        float lit = 0.0;
+        for (uint i = 0; i < samples; i ++) {
+            vec2 sample_uv = clamp(center + POISSON_DISK[i] * combine_params.radius * texel, tile_min, tile_max);
+            lit += texture(shadowAtlas, vec3(sample_uv, depth));
+        }
+        return lit / float(samples);
+    }
+    return texture(shadowAtlas, vec3(clamp(center, tile_min, tile_max), depth));
+}
+
 vec3 get_unnormalized_light_direction(LightInfo info, FragmentInfo frag_info) {
     if (info.type == DIRECTIONAL_LIGHT) {
         return info.direction.xyz;
@@ -121,9 +204,11 @@ vec3 cook_torrance(vec3 view_direction, FragmentInfo frag_info, LightInfo light_
 vec3 calculate_light_influence(FragmentInfo frag_info) {
     vec3 ck = vec3(0.0);
     vec3 view = normalize(per_frame_data.pfd.eye.xyz - frag_info.position);
+    float view_depth = -(per_frame_data.pfd.view * vec4(frag_info.position, 1.0)).z;
     
     for (uint i = 0; i < light_data.light_count; i ++) {
-        ck += cook_torrance(view, frag_info, light_data.lights[i]);
+        LightInfo light = light_data.lights[i];
+        ck += get_shadow_factor(light, frag_info.position, view_depth) * cook_torrance(view, frag_info, light);
     }
     
     return ck + 0.5 * frag_info.diffuse;
@@ -141,4 +226,8 @@ void main() {
     FragmentInfo fragInfo = get_fragment_info(uv);
     vec3 light_a = calculate_light_influence(fragInfo);
     color = vec4(light_a, 1.0) + fragInfo.emissive;
+    if (combine_params.debug_view == DEBUG_VIEW_NAN_CHECK
+        && (any(isnan(color)) || any(isinf(color)))) {
+        color = vec4(1.0, 0.0, 1.0, 1.0);
+    }
 }
\ No newline at end of file
@@ -4,6 +4,13 @@ struct LightInfo {
     vec4 color_intensity;
     vec4 extras;
     uint type;
+    // directional lights have a shadow map for each cascade, zero when the light casts no shadows
+    uint shadow_map_count;
+    mat4 shadow_view_projections[4];
+    // offset and size of each shadow map in the shadow atlas
+    vec4 shadow_rects[4];
+    // the view depth where each cascade ends
+    vec4 cascade_splits;
 };
 
 const uint POINT_LIGHT = 0;
@@ -30,8 +30,8 @@ layout(location = 0) in FragmentOut fragOut;
 void main() {
     outPosition = vec4(fragOut.position, 1.0);
 
-    vec3 T = normalize(vec3(fragOut.model * vec4(fragOut.tangent, 0.0))); // * vec3(-1, -1, 1);
-    vec3 N = normalize(vec3(fragOut.model * vec4(fragOut.normal, 0.0))) ; //* vec3(-1, -1, 1);
+    vec3 T = normalize(fragOut.tangent); // * vec3(-1, -1, 1);
+    vec3 N = normalize(fragOut.normal) ; //* vec3(-1, -1, 1);
     vec3 B = normalize(cross(N, T));
     mat3 TBN = mat3(T, B, N);
     vec3 sample_normal = texture(normalSampler, fragOut.uv).xyz;
@@ -0,0 +1,34 @@
+#version 460
+
+#include "definitions.glsl"
+
+// PlanarReflection::texture
+layout(set = 1, binding = 0) uniform sampler2D reflectionSampler;
+
+layout(set = 1, binding = 1) uniform ReflectionParameters {
+    // PlanarReflection::view_projection
+    mat4 view_projection;
+} reflection_params;
+
+layout(location = 0) out vec4 outPosition;
+layout(location = 1) out vec4 outNormal;
+layout(location = 2) out vec4 outDiffuse;
+layout(location = 3) out vec4 outEmissive;
+layout(location = 4) out vec4 outPbr;
+
+layout(location = 0) in FragmentOut fragOut;
+
+void main() {
+    // The surface lies on the reflection plane, so it's seen by the mirror camera where it is
+    vec4 reflection_clip = reflection_params.view_projection * vec4(fragOut.position, 1.0);
+    vec2 reflection_uv = reflection_clip.xy / reflection_clip.w * 0.5 + 0.5;
+
+    // The reflection is already lit: it's emitted as is, and the surface itself isn't lit
+    outPosition = vec4(fragOut.position, 0.0);
+    // Encoded like the other surfaces, see metallic_roughness_pbr.frag
+    outNormal = vec4((normalize(fragOut.normal) + 1.0) * 0.5, 0.0);
+    outDiffuse = vec4(0.0, 0.0, 0.0, 1.0);
+    outEmissive = texture(reflectionSampler, reflection_uv);
+    // Fully rough and not metallic, so that the lights add no highlights
+    outPbr = vec4(0.0, 1.0, 0.0, 1.0);
+}
@@ -6,6 +6,11 @@ layout(location=0) out vec4 color;
 
 layout(set = 0, binding = 0) uniform sampler2D source;
 
+// Set by the renderer when the backbuffer isn't sRGB, the hardware encodes sRGB backbuffers
+layout(push_constant) uniform TonemapInput {
+    uint encode_srgb;
+} tonemap_input;
+
 // perform ACES approximated Tonemapping
 // https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
 vec4 aces_approx(vec4 x)
@@ -18,7 +23,18 @@ vec4 aces_approx(vec4 x)
     return clamp((x*(a*x+b))/(x*(c*x+d)+e), 0.0, 1.0);
 }
 
+// The sRGB OETF, from linear to encoded colors
+vec3 linear_to_srgb(vec3 linear)
+{
+    vec3 low = linear * 12.92;
+    vec3 high = 1.055 * pow(linear, vec3(1.0 / 2.4)) - 0.055;
+    return mix(high, low, lessThanEqual(linear, vec3(0.0031308)));
+}
+
 void main() {
     vec4 col = texture(source, uv);
     color = aces_approx(col);
-}
\ No newline at end of file
+    if (tonemap_input.encode_srgb != 0) {
+        color.rgb = linear_to_srgb(color.rgb);
+    }
+}
@@ -12,25 +12,35 @@ layout(set = 0, binding = 0) uniform PerFrameDataBlock {
     PerFrameData pfd;
 } per_frame_data;
 
-layout(push_constant) uniform PerObjectData {
+// Must match GpuInstance in static_deferred_renderer.rs
+struct InstanceData {
     mat4 model;
-} pod;
+    // The inverse transpose of the model's upper 3x3
+    mat4 normal;
+};
+
+layout(set = 0, binding = 1) readonly buffer PerInstanceData {
+    InstanceData instances[];
+} instance_data;
 
 layout(location = 0) out FragmentOut frag_out;
 
 void main() {
+    mat4 model = instance_data.instances[gl_InstanceIndex].model;
+    mat3 normal_matrix = mat3(instance_data.instances[gl_InstanceIndex].normal);
     mat4 mv = per_frame_data.pfd.proj * per_frame_data.pfd.view;
-    vec4 world_pos = pod.model  * vec4(in_position, 1.0);
+    vec4 world_pos = model * vec4(in_position, 1.0);
     gl_Position = mv * world_pos;
     frag_out.color = in_color;
     frag_out.uv = in_uv;
     frag_out.position = world_pos.xyz;
-    frag_out.normal = in_normal;
-    frag_out.model = pod.model;
-    frag_out.tangent = in_tangent;
+    vec3 T = normalize(mat3(model) * in_tangent);
+    vec3 N = normalize(normal_matrix * in_normal);
+    // Normal and tangent are in world space
+    frag_out.normal = N;
+    frag_out.model = model;
+    frag_out.tangent = T;
 
-    vec3 T = normalize(vec3(pod.model * vec4(in_tangent, 0.0)));
-    vec3 N = normalize(vec3(pod.model * vec4(in_normal, 0.0)));
     vec3 B = normalize(cross(N, T));
     mat3 TBN = transpose(mat3(T, B, N));
     frag_out.TBN = TBN;